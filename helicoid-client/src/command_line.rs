@@ -1,12 +1,40 @@
 use clap::Parser;
+use std::path::PathBuf;
 
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct HeliconeCommandLineArguments {
-    /// Address of editor server, for connecting to an existing server
+    /// Address of editor server, either host:port or unix:/path/to/socket
     #[arg(short, long, default_value = "127.0.0.1:15566")]
     pub server_address: Option<String>,
+    /// Command starting the editor server with --stdio (e.g. "ssh host helicoid-helixserver --stdio"), used instead of the server address
+    #[arg(long)]
+    pub server_command: Option<String>,
+    /// PEM file with the certificate(s) to trust for the server, enables TLS when set
+    #[arg(long)]
+    pub tls_ca: Option<PathBuf>,
+    /// Name the TLS certificate of the server is issued for
+    #[arg(long, default_value = "localhost")]
+    pub tls_server_name: String,
+    /// Token to authenticate to the server with
+    #[arg(long, conflicts_with = "auth_key_file")]
+    pub auth_token: Option<String>,
+    /// File containing the key to authenticate to the server with
+    #[arg(long)]
+    pub auth_key_file: Option<PathBuf>,
+    /// Connect as a read-only spectator, following the view of a presenter
+    #[arg(long)]
+    pub spectate: bool,
+    /// Session id of the presenter to follow (logged by its client), defaults to the latest connected editor
+    #[arg(long, requires = "spectate")]
+    pub presenter: Option<String>,
+    /// Record the traffic to and from the server to this file, for replay with helicoid-replay
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+    /// Seconds without hearing from the server before the connection is considered lost, 0 disables the keepalive pings
+    #[arg(long, default_value_t = 30)]
+    pub keepalive_timeout: u64,
     /*    /// Number of times to greet
     #[arg(short, long, default_value_t = 1)]
     count: u8,*/
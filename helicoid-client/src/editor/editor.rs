@@ -5,21 +5,38 @@ use std::{
 
 use crate::{
     redraw_scheduler::REDRAW_SCHEDULER,
-    renderer::block_renderer::{SkiaClientRenderBlock, SkiaClientRenderTarget, SkiaGfxManager},
+    renderer::block_renderer::{
+        clear_resources, evict_resource, push_resource, set_font_file, SkiaClientRenderBlock,
+        SkiaClientRenderTarget, SkiaGfxManager,
+    },
     HeliconeCommandLineArguments,
 };
 use helicoid_protocol::{
     block_manager::Manager,
     bridge_logic::{TcpBridgeToClientMessage, TcpBridgeToServerMessage},
-    gfx::{PointF32, RenderBlockId, RenderBlockLocation},
+    gfx::{
+        BlockUpdates, FrameInfo, HelicoidToClientMessage, PointF32, RenderBlockId,
+        RenderBlockLocation,
+    },
     input::{
-        ComplexKeyEvent, HelicoidToServerMessage, KeyModifierStateUpdateEvent, ViewportInfo,
-        VirtualKeycode,
+        ComplexKeyEvent, CursorMovedEvent, HelicoidToServerMessage, KeyModifierStateUpdateEvent,
+        ViewportInfo, VirtualKeycode,
     },
-    tcp_bridge_async::ClientTcpBridge,
+    auth::AuthKey,
+    capabilities::{ClientRole, ServerHello},
+    fonts::{FontDescription, FontStore},
+    keepalive::{ConnectionQuality, KeepaliveOptions, LinkStatus},
+    latency::LatencyStats,
+    payload::{ClipboardSelection, Payload, PayloadAssembler, PayloadKind},
+    recording::SessionRecorder,
+    session::{SessionId, SessionResume},
+    tcp_bridge_async::{ClientBridgeConfig, ClientTcpBridge},
+    tls::ClientTlsConfig,
+    window_control::{AttentionLevel, CursorShape, WindowControl},
 };
+use copypasta::{ClipboardContext, ClipboardProvider};
 use ordered_float::OrderedFloat;
-use skia_safe::Surface;
+use skia_safe::{Color, Paint, Surface};
 use tokio::sync::{
     mpsc::{Receiver, Sender},
     Mutex as TMutex,
@@ -27,12 +44,34 @@ use tokio::sync::{
 use winit::{
     event::{Event, WindowEvent},
     event_loop::ControlFlow,
+    window::{CursorIcon, UserAttentionType},
 };
 
+/* The input latency statistics are logged every time this many more samples are collected */
+const LATENCY_LOG_INTERVAL: u32 = 100;
+/* Latencies longer than this are assumed to come from a server echoing a timestamp from
+before a reconnect (or similar), and are not counted */
+const MAX_INPUT_LATENCY: Duration = Duration::from_secs(60);
+/* How long the window is flashed for the visual bell */
+const BELL_DURATION: Duration = Duration::from_millis(150);
+/* The mouse position is sent to the server at most this often */
+const CURSOR_MOVED_INTERVAL: Duration = Duration::from_millis(20);
+
+/* Where the editor server is reached, either at an address or by starting a command and
+talking to it over its stdin / stdout */
+#[derive(Debug, Clone)]
+enum ServerTarget {
+    Address(String),
+    Command(Vec<String>),
+}
+
 struct HeliconeEditorInner {
     //bridge: ClientTcpBridge,
     sender: Option<Sender<TcpBridgeToServerMessage>>,
     receiver: Option<Receiver<TcpBridgeToClientMessage>>,
+    server_hello: Option<ServerHello>,
+    link_status: Option<Arc<LinkStatus>>,
+    payload_tx: Option<Sender<Payload>>,
     //    scale_factor: f64,
     time_ref_base: Instant,
 }
@@ -40,44 +79,153 @@ pub struct HeliconeEditor {
     inner: Arc<TMutex<Option<HeliconeEditorInner>>>,
     sender: Option<Sender<TcpBridgeToServerMessage>>,
     receiver: Option<Receiver<TcpBridgeToClientMessage>>,
-    server_address: Option<String>,
+    server_target: ServerTarget,
+    bridge_config: ClientBridgeConfig,
     current_viewport_info: Option<ViewportInfo>,
+    /* Updates received for the frame currently being transferred, they are applied when
+    the frame is complete so a partially updated tree is never drawn */
+    pending_frame: Vec<BlockUpdates>,
+    /* The session of the current (or lost) connection, used to resume the session when
+    reconnecting so the blocks held by the renderer do not have to be sent again */
+    session_id: Option<SessionId>,
+    /* Round trip times of the current connection, shown as the connection quality */
+    link_status: Option<Arc<LinkStatus>>,
+    drawn_quality: Option<ConnectionQuality>,
+    /* Reference for the timestamps of the input events, kept across reconnects so the
+    timestamps echoed by the server can always be compared to the current time */
+    time_ref_base: Instant,
+    /* Input timestamp echoed with the newest complete frame, and with the frame last
+    presented (so each input is only measured once) */
+    frame_input_timestamp: Option<u32>,
+    presented_input_timestamp: Option<u32>,
+    input_latency: LatencyStats,
+    /* Payloads being received from the server, and the channel for sending payloads to it */
+    payloads: PayloadAssembler,
+    payload_tx: Option<Sender<Payload>>,
+    /* The system clipboard, shared with the editor on the server. None when the system
+    clipboard is not available. */
+    clipboard: Option<ClipboardContext>,
+    /* The font files of the server, cached by hash */
+    fonts: FontStore,
+    /* Window controls from the server, applied when the window is at hand */
+    pending_window_controls: Vec<WindowControl>,
+    /* When the visual bell was rung last */
+    bell_rung: Option<Instant>,
+    /* The mouse position not yet sent to the server, and when the position was sent last */
+    pending_cursor_position: Option<winit::dpi::PhysicalPosition<f64>>,
+    cursor_moved_sent: Option<Instant>,
     renderer: Manager<SkiaClientRenderBlock>,
     graphics_manager: SkiaGfxManager,
 }
 impl HeliconeEditor {
     pub fn new(args: &HeliconeCommandLineArguments) -> Self {
         let inner: Arc<TMutex<Option<HeliconeEditorInner>>> = Arc::new(TMutex::new(None));
-        if let Some(server_address) = &args.server_address {
-            //            let bridge = ClientTcpBridge::
-            let inner_async = inner.clone();
-            let addr_async = server_address.clone();
-            Self::try_connect(inner_async, addr_async);
+        let mut bridge_config = ClientBridgeConfig::default();
+        if let Some(tls_ca) = &args.tls_ca {
+            let tls_config = ClientTlsConfig::from_pem_file(tls_ca, &args.tls_server_name)
+                .expect("Could not load the trusted TLS certificates");
+            bridge_config.tls = Some(Arc::new(tls_config));
+        }
+        if let Some(key_file) = &args.auth_key_file {
+            bridge_config.auth =
+                Some(AuthKey::from_file(key_file).expect("Could not load the authentication key"));
+        } else if let Some(token) = &args.auth_token {
+            bridge_config.auth =
+                Some(AuthKey::from_token(token).expect("Invalid authentication token"));
+        }
+        if args.spectate {
+            let presenter = args
+                .presenter
+                .as_ref()
+                .map(|presenter| presenter.parse().expect("Invalid presenter session id"));
+            bridge_config.role = ClientRole::Spectator { presenter };
+        }
+        bridge_config.keepalive =
+            KeepaliveOptions::with_timeout(Duration::from_secs(args.keepalive_timeout));
+        if let Some(record) = &args.record {
+            bridge_config.recorder = Some(Arc::new(
+                SessionRecorder::create(record).expect("Could not start recording"),
+            ));
+        }
+        let server_target = if let Some(command) = &args.server_command {
+            let command = shlex::split(command).expect("Could not parse the server command");
+            ServerTarget::Command(command)
+        } else if let Some(server_address) = &args.server_address {
+            ServerTarget::Address(server_address.clone())
         } else {
             panic!("Integrated helicone editor is not supported (yet)");
-        }
+        };
+        //            let bridge = ClientTcpBridge::
+        let inner_async = inner.clone();
+        let time_ref_base = Instant::now();
+        Self::try_connect(
+            inner_async,
+            server_target.clone(),
+            bridge_config.clone(),
+            time_ref_base,
+        );
         Self {
             inner,
             renderer: Manager::new(),
             graphics_manager: SkiaGfxManager::new(),
             sender: None,
             receiver: None,
-            server_address: args.server_address.clone(),
+            server_target,
+            bridge_config,
             current_viewport_info: None,
+            pending_frame: Vec::new(),
+            session_id: None,
+            link_status: None,
+            drawn_quality: None,
+            time_ref_base,
+            frame_input_timestamp: None,
+            presented_input_timestamp: None,
+            input_latency: LatencyStats::default(),
+            payloads: PayloadAssembler::default(),
+            payload_tx: None,
+            clipboard: ClipboardContext::new()
+                .map_err(|e| log::warn!("The system clipboard is not available: {}", e))
+                .ok(),
+            fonts: FontStore::new(
+                dirs::cache_dir()
+                    .unwrap_or_else(std::env::temp_dir)
+                    .join("helicoid")
+                    .join("fonts"),
+            ),
+            pending_window_controls: Vec::new(),
+            bell_rung: None,
+            pending_cursor_position: None,
+            cursor_moved_sent: None,
         }
     }
-    fn try_connect(inner: Arc<TMutex<Option<HeliconeEditorInner>>>, addr: String) {
+    fn try_connect(
+        inner: Arc<TMutex<Option<HeliconeEditorInner>>>,
+        target: ServerTarget,
+        config: ClientBridgeConfig,
+        time_ref_base: Instant,
+    ) {
         let _ = tokio::spawn(async move {
             loop {
-                match ClientTcpBridge::connect(&addr).await {
+                let connection = match &target {
+                    ServerTarget::Address(addr) => {
+                        ClientTcpBridge::connect_with(addr, &config).await
+                    }
+                    ServerTarget::Command(command) => {
+                        ClientTcpBridge::connect_command(command, &config).await
+                    }
+                };
+                match connection {
                     Ok((mut bridge, sender, receiver)) => {
                         {
                             let mut inner_locked = inner.lock().await;
                             *inner_locked = Some(HeliconeEditorInner {
                                 sender: Some(sender),
                                 receiver: Some(receiver),
+                                server_hello: bridge.server_hello().cloned(),
+                                link_status: Some(bridge.link_status()),
+                                payload_tx: Some(bridge.payload_sender()),
                                 //                                scale_factor: 1.0,
-                                time_ref_base: Instant::now(),
+                                time_ref_base,
                             });
                         }
                         match bridge.process_rxtx().await {
@@ -86,13 +234,18 @@ impl HeliconeEditor {
                                 log::warn!("Error during client bridge processing: {:?}", e);
                             }
                         }
+                        log::info!(
+                            "Disconnected from server, {}, {}",
+                            bridge.byte_counters(),
+                            bridge.link_status()
+                        );
                         break;
                     }
                     Err(e) => {
                         /* Try to (re)connect to the sever every 10'th second if it fails */
                         log::warn!(
                             "Error while connecting to editor-server at: {:?} {:?}",
-                            addr,
+                            target,
                             e
                         );
                         tokio::time::sleep(Duration::from_secs(10)).await;
@@ -130,10 +283,37 @@ impl HeliconeEditor {
                     connection establishment functions). */
                     log::trace!("Initalize reconnect");
                     let _ = inner_opt.take();
-                    Self::try_connect(self.inner.clone(), self.server_address.clone().unwrap());
+                    let mut bridge_config = self.bridge_config.clone();
+                    bridge_config.resume = self.session_id.map(|session_id| SessionResume {
+                        session_id,
+                        held_blocks: self.renderer.held_blocks(RenderBlockId::normal(0).unwrap()),
+                    });
+                    Self::try_connect(
+                        self.inner.clone(),
+                        self.server_target.clone(),
+                        bridge_config,
+                        self.time_ref_base,
+                    );
                     return false;
                 }
                 log::trace!("Extract connection channels");
+                if let Some(server_hello) = inner.server_hello.take() {
+                    if !server_hello.resumed {
+                        /* The server starts from scratch, so drop whatever is held from a
+                        previous session */
+                        self.pending_frame.clear();
+                        self.renderer.reset(&mut self.graphics_manager);
+                        self.renderer = Manager::new();
+                    }
+                    log::info!("Connected with session id: {}", server_hello.session_id);
+                    self.session_id = Some(server_hello.session_id);
+                }
+                if let Some(link_status) = inner.link_status.take() {
+                    self.link_status = Some(link_status);
+                }
+                if let Some(payload_tx) = inner.payload_tx.take() {
+                    self.payload_tx = Some(payload_tx);
+                }
                 if let Some(sender) = inner.sender.take() {
                     self.sender = Some(sender);
                 }
@@ -178,8 +358,11 @@ impl HeliconeEditor {
         }
     }
     fn now_timestamp(inner: &HeliconeEditorInner) -> u32 {
+        Self::timestamp_since(inner.time_ref_base)
+    }
+    fn timestamp_since(time_ref_base: Instant) -> u32 {
         (Instant::now()
-            .saturating_duration_since(inner.time_ref_base)
+            .saturating_duration_since(time_ref_base)
             .as_millis()
             % (u32::MAX as u128)) as u32
     }
@@ -239,6 +422,11 @@ impl HeliconeEditor {
             return;
         }
         self.peek_and_process_events();
+        /* A stalled connection sends no frames, so redraw when the quality changes to show it */
+        let quality = self.link_status.as_ref().map(|status| status.quality());
+        if quality != self.drawn_quality {
+            REDRAW_SCHEDULER.queue_next_frame();
+        }
     }
 
     fn forward_keyboard_modifier_changed(
@@ -295,6 +483,31 @@ impl HeliconeEditor {
             .map_err(|e| log::warn!("Error while sending key code update to server: {:?}", e));
     }
 
+    /* The server picks the shape of the mouse cursor from what is under it. The positions
+    are throttled (see CURSOR_MOVED_INTERVAL), the event loop wakes up for every frame so the
+    last position is sent also when the mouse stops. */
+    fn forward_cursor_moved(
+        &self,
+        inner: &mut HeliconeEditorInner,
+        position: &winit::dpi::PhysicalPosition<f64>,
+    ) {
+        let cursor_moved_event = CursorMovedEvent {
+            physical_position_x: OrderedFloat(position.x as f32),
+            physical_position_y: OrderedFloat(position.y as f32),
+            area_id: 0,
+            timestamp: Self::now_timestamp(inner),
+        };
+        let cursor_msg = TcpBridgeToServerMessage {
+            message: HelicoidToServerMessage::CursorMoved(cursor_moved_event),
+        };
+        let _ = self
+            .sender
+            .as_ref()
+            .unwrap()
+            .blocking_send(cursor_msg)
+            .map_err(|e| log::warn!("Error while sending cursor position to server: {:?}", e));
+    }
+
     fn send_size_info(
         sender: Option<&mut Sender<TcpBridgeToServerMessage>>,
         current_viewport_info_out: &mut Option<ViewportInfo>,
@@ -339,6 +552,7 @@ impl HeliconeEditor {
             );*/
             return self.handle_event_disconnected(event, window);
         }
+        self.apply_window_controls(window);
         if let Some(mut inner) = self.inner.try_lock().ok() {
             match event {
                 Event::MainEventsCleared
@@ -401,9 +615,12 @@ impl HeliconeEditor {
                     }
                     WindowEvent::CursorMoved {
                         device_id: _,
-                        position: _,
+                        position,
                         ..
-                    } => {}
+                    } => {
+                        /* Sent when the events are handled, see MainEventsCleared */
+                        self.pending_cursor_position = Some(*position);
+                    }
                     WindowEvent::CursorEntered { device_id: _ } => {}
                     WindowEvent::CursorLeft { device_id: _ } => {}
                     WindowEvent::MouseWheel {
@@ -444,7 +661,17 @@ impl HeliconeEditor {
                     }
                     WindowEvent::ThemeChanged(_) => {}
                     WindowEvent::ReceivedCharacter(_) => {}
-                    WindowEvent::Focused(_) => {}
+                    WindowEvent::Focused(focused) => {
+                        /* Something may have been copied in another window, so let the
+                        editor know the current contents */
+                        if *focused {
+                            Self::send_clipboard(
+                                &mut self.clipboard,
+                                self.payload_tx.as_ref(),
+                                ClipboardSelection::Clipboard,
+                            );
+                        }
+                    }
                     WindowEvent::KeyboardInput {
                         device_id,
                         input,
@@ -478,7 +705,17 @@ impl HeliconeEditor {
                 Event::UserEvent(_) => {}
                 Event::Suspended => {}
                 Event::Resumed => {}
-                Event::MainEventsCleared => {}
+                Event::MainEventsCleared => {
+                    let interval_passed = self
+                        .cursor_moved_sent
+                        .map_or(true, |sent| sent.elapsed() >= CURSOR_MOVED_INTERVAL);
+                    if interval_passed {
+                        if let Some(position) = self.pending_cursor_position.take() {
+                            self.forward_cursor_moved(inner.as_mut().unwrap(), &position);
+                            self.cursor_moved_sent = Some(Instant::now());
+                        }
+                    }
+                }
                 Event::RedrawRequested(_) => {}
                 Event::RedrawEventsCleared => {}
                 Event::LoopDestroyed => {}
@@ -486,6 +723,107 @@ impl HeliconeEditor {
         }
         return None;
     }
+    fn apply_window_controls(&mut self, window: &winit::window::Window) {
+        for control in self.pending_window_controls.drain(..) {
+            log::trace!("Applying window control: {:?}", control);
+            match control {
+                WindowControl::SetTitle(title) => window.set_title(&title),
+                WindowControl::SetCursorShape(shape) => {
+                    window.set_cursor_icon(convert_cursor_shape(shape))
+                }
+                WindowControl::Bell => {
+                    self.bell_rung = Some(Instant::now());
+                    REDRAW_SCHEDULER.queue_next_frame();
+                }
+                WindowControl::RequestAttention(level) => {
+                    window.request_user_attention(level.map(|level| match level {
+                        AttentionLevel::Informational => UserAttentionType::Informational,
+                        AttentionLevel::Critical => UserAttentionType::Critical,
+                    }))
+                }
+            }
+        }
+    }
+    /* Handles a payload sent in chunks by the server, see payload.rs */
+    fn handle_payload(
+        clipboard: &mut Option<ClipboardContext>,
+        fonts: &mut FontStore,
+        payload: Payload,
+    ) {
+        match payload.kind {
+            PayloadKind::Clipboard(selection) => {
+                log::debug!(
+                    "Setting {:?} to contents ({} bytes) from the server",
+                    selection,
+                    payload.data.len()
+                );
+                let Some(clipboard) = clipboard.as_mut() else {
+                    return;
+                };
+                /* The primary selection is not supported by copypasta, the clipboard is used
+                for both */
+                let contents = String::from_utf8_lossy(&payload.data).into_owned();
+                if let Err(e) = clipboard.set_contents(contents) {
+                    log::warn!("Could not set the clipboard: {}", e);
+                }
+            }
+            PayloadKind::Font(hash) => match fonts.received(hash, &payload.data) {
+                Ok(families) => {
+                    for family_id in families {
+                        if let Some(font_file) = fonts.family_file(family_id) {
+                            set_font_file(family_id, font_file);
+                        }
+                    }
+                    REDRAW_SCHEDULER.queue_next_frame();
+                }
+                Err(e) => log::warn!("Could not store font {} from the server: {}", hash, e),
+            },
+            PayloadKind::Resource(kind, name) => push_resource(name, kind, payload.data),
+        }
+    }
+    /* Uses the cached font files announced by the server, and asks for the missing ones */
+    fn handle_fonts(
+        fonts: &mut FontStore,
+        sender: Option<&Sender<TcpBridgeToServerMessage>>,
+        descriptions: &[FontDescription],
+    ) {
+        for hash in fonts.announced(descriptions) {
+            let Some(sender) = sender else {
+                break;
+            };
+            let request = TcpBridgeToServerMessage {
+                message: HelicoidToServerMessage::RequestFont(hash),
+            };
+            if let Err(e) = sender.try_send(request) {
+                log::warn!("Could not request font {} from the server: {}", hash, e);
+            }
+        }
+        for font in descriptions {
+            if let Some(font_file) = fonts.family_file(font.family_id) {
+                set_font_file(font.family_id, font_file);
+            }
+        }
+    }
+    /* Sends the contents of the system clipboard to the server */
+    fn send_clipboard(
+        clipboard: &mut Option<ClipboardContext>,
+        payload_tx: Option<&Sender<Payload>>,
+        selection: ClipboardSelection,
+    ) {
+        let (Some(clipboard), Some(payload_tx)) = (clipboard.as_mut(), payload_tx) else {
+            return;
+        };
+        let contents = match clipboard.get_contents() {
+            Ok(contents) => contents,
+            Err(e) => {
+                log::debug!("Could not read the clipboard: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = payload_tx.try_send(Payload::clipboard(selection, contents)) {
+            log::warn!("Could not send the clipboard to the server: {}", e);
+        }
+    }
     fn reconnect_bridge(&mut self) {
         /* Set editor in disconnected state (i.e. render appropriate graphics) and try to reconnect regularly */
         self.receiver = None;
@@ -501,16 +839,71 @@ impl HeliconeEditor {
                 match receiver.try_recv() {
                     Ok(event) => {
                         log::trace!("Got event from server: {:?}", event);
-                        let updates = &event.message.updates;
-                        let client_id = RenderBlockId::normal(0).unwrap();
-                        //todo!("Instantiate GFX manager, and call handle block update")
-                        self.renderer.handle_block_update(
-                            client_id,
-                            updates,
-                            &mut self.graphics_manager,
-                        );
-                        log::debug!("Redraw scheduled in event processing");
-                        REDRAW_SCHEDULER.queue_next_frame();
+                        match event.message {
+                            HelicoidToClientMessage::BlockUpdates(block_updates) => {
+                                self.pending_frame.push(block_updates);
+                            }
+                            HelicoidToClientMessage::FrameComplete(FrameInfo {
+                                input_timestamp,
+                            }) => {
+                                if input_timestamp.is_some() {
+                                    self.frame_input_timestamp = input_timestamp;
+                                }
+                                let client_id = RenderBlockId::normal(0).unwrap();
+                                //todo!("Instantiate GFX manager, and call handle block update")
+                                for block_updates in self.pending_frame.drain(..) {
+                                    self.renderer.handle_block_update(
+                                        client_id,
+                                        &block_updates.updates,
+                                        &mut self.graphics_manager,
+                                    );
+                                }
+                                log::debug!("Redraw scheduled in event processing");
+                                REDRAW_SCHEDULER.queue_next_frame();
+                                /* Draw the completed frame before applying the next one */
+                                break;
+                            }
+                            HelicoidToClientMessage::Hello(_) => {
+                                log::warn!("Unexpected hello from server after connection setup");
+                            }
+                            /* Answered by the bridge, never passed on */
+                            HelicoidToClientMessage::Keepalive(_) => {}
+                            HelicoidToClientMessage::PayloadChunk(chunk) => {
+                                match self.payloads.add_chunk(chunk) {
+                                    Ok(Some(payload)) => {
+                                        Self::handle_payload(
+                                            &mut self.clipboard,
+                                            &mut self.fonts,
+                                            payload,
+                                        )
+                                    }
+                                    Ok(None) => {}
+                                    Err(e) => {
+                                        log::warn!("Dropping payload from the server: {}", e)
+                                    }
+                                }
+                            }
+                            HelicoidToClientMessage::WindowControl(control) => {
+                                self.pending_window_controls.push(control);
+                            }
+                            HelicoidToClientMessage::Fonts(descriptions) => {
+                                Self::handle_fonts(
+                                    &mut self.fonts,
+                                    self.sender.as_ref(),
+                                    &descriptions,
+                                );
+                            }
+                            HelicoidToClientMessage::EvictResource(name) => {
+                                evict_resource(&name);
+                            }
+                            HelicoidToClientMessage::RequestClipboard(selection) => {
+                                Self::send_clipboard(
+                                    &mut self.clipboard,
+                                    self.payload_tx.as_ref(),
+                                    selection,
+                                );
+                            }
+                        }
                     }
                     Err(e) => match e {
                         tokio::sync::mpsc::error::TryRecvError::Empty => {
@@ -526,8 +919,16 @@ impl HeliconeEditor {
             }
         }
         if disconnected {
-            self.renderer.reset(&mut self.graphics_manager);
-            self.renderer = Manager::new();
+            /* The blocks are kept as they are until it is known if the session can be
+            resumed, a partially received frame can not be used though */
+            self.pending_frame.clear();
+            /* The transfers are not continued on the next connection */
+            self.payloads = PayloadAssembler::default();
+            self.payload_tx = None;
+            /* The next server may use other fonts, the font files are kept in the cache */
+            self.fonts.reset();
+            clear_resources();
+            log::info!("Connection lost, {}", self.input_latency);
             self.reconnect_bridge()
         }
     }
@@ -551,9 +952,87 @@ impl HeliconeEditor {
         };
         self.renderer
             .process_blocks_for_client(client_id, &mut target);
+        self.draw_connection_quality(root_surface);
+        self.draw_bell(root_surface);
+        self.record_input_latency();
         // render(root_surface);
         false
     }
+    /* Measures the time from the newest input the server has processed until the frame made
+    after it is presented (now) */
+    fn record_input_latency(&mut self) {
+        let Some(input_timestamp) = self.frame_input_timestamp else {
+            return;
+        };
+        if self.presented_input_timestamp == Some(input_timestamp) {
+            return;
+        }
+        self.presented_input_timestamp = Some(input_timestamp);
+        let latency = Duration::from_millis(
+            Self::timestamp_since(self.time_ref_base).wrapping_sub(input_timestamp) as u64,
+        );
+        if latency > MAX_INPUT_LATENCY {
+            return;
+        }
+        self.input_latency.add(latency);
+        if self.input_latency.count() % LATENCY_LOG_INTERVAL == 0 {
+            log::info!("{}", self.input_latency);
+        }
+    }
+    /* Draws a small dot in the top right corner, coloured by the round trip time to the
+    server */
+    fn draw_connection_quality(&mut self, root_surface: &mut Surface) {
+        const RADIUS: f32 = 4.0;
+        let Some(link_status) = self.link_status.as_ref() else {
+            return;
+        };
+        let quality = link_status.quality();
+        self.drawn_quality = Some(quality);
+        let color = match quality {
+            ConnectionQuality::Unknown => Color::from_argb(160, 128, 128, 128),
+            ConnectionQuality::Good => Color::from_argb(160, 0, 200, 0),
+            ConnectionQuality::Fair => Color::from_argb(200, 230, 180, 0),
+            ConnectionQuality::Poor => Color::from_argb(230, 220, 0, 0),
+        };
+        let mut paint = Paint::default();
+        paint.set_color(color);
+        paint.set_anti_alias(true);
+        let width = root_surface.width() as f32;
+        root_surface
+            .canvas()
+            .draw_circle((width - RADIUS * 3.0, RADIUS * 3.0), RADIUS, &paint);
+    }
+    /* Flashes the window for the visual bell, fading out */
+    fn draw_bell(&mut self, root_surface: &mut Surface) {
+        let Some(rung) = self.bell_rung else {
+            return;
+        };
+        let elapsed = rung.elapsed();
+        if elapsed >= BELL_DURATION {
+            self.bell_rung = None;
+            return;
+        }
+        let alpha = 1.0 - elapsed.as_secs_f32() / BELL_DURATION.as_secs_f32();
+        let mut paint = Paint::default();
+        paint.set_color(Color::from_argb((alpha * 80.0) as u8, 255, 255, 255));
+        root_surface.canvas().draw_paint(&paint);
+        REDRAW_SCHEDULER.queue_next_frame();
+    }
+}
+
+fn convert_cursor_shape(shape: CursorShape) -> CursorIcon {
+    match shape {
+        CursorShape::Default => CursorIcon::Default,
+        CursorShape::Text => CursorIcon::Text,
+        CursorShape::Pointer => CursorIcon::Hand,
+        CursorShape::Crosshair => CursorIcon::Crosshair,
+        CursorShape::Move => CursorIcon::Move,
+        CursorShape::NotAllowed => CursorIcon::NotAllowed,
+        CursorShape::Wait => CursorIcon::Wait,
+        CursorShape::Progress => CursorIcon::Progress,
+        CursorShape::ColumnResize => CursorIcon::ColResize,
+        CursorShape::RowResize => CursorIcon::RowResize,
+    }
 }
 
 fn convert_virtual_keycodes(winit_code: Option<winit::event::VirtualKeyCode>) -> VirtualKeycode {
@@ -1,14 +1,19 @@
 use hashbrown::HashMap;
 use std::cell::RefCell;
 use std::hash::{BuildHasher, Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use helicoid_protocol::block_manager::{BlockGfx, ManagerGfx, MetaBlock};
 use helicoid_protocol::gfx::{
     FontPaint, PathVerb, PointF32, RenderBlockLocation, SimpleDrawElement, SimplePaint,
     SVG_RESOURCE_NAME_LEN,
 };
-use helicoid_protocol::gfx::{RenderBlockDescription, RenderBlockId};
+use helicoid_protocol::gfx::{ImageSource, RenderBlockDescription, RenderBlockId};
+use helicoid_protocol::resources::{Resource, ResourceKind, ResourceName, ResourceStore};
 use helicoid_protocol::text::ShapedStringMetadataCoordinates;
+use lru::LruCache;
 use parking_lot::Mutex;
 use skia_safe as skia;
 
@@ -27,7 +32,108 @@ lazy_static! {
 
 thread_local! {
     pub static SHAPED_BLOB_BUILDER : RefCell<ShapedBlobBuilder> = RefCell::new(ShapedBlobBuilder::new());
+    /* The decoded images of the image blocks, by the hash of their source */
+    static DECODED_IMAGES: RefCell<LruCache<u64, Image>> =
+        RefCell::new(LruCache::new(NonZeroUsize::new(DECODED_IMAGES_CAPACITY).unwrap()));
 }
+/* Uses a font file from the server for a font family when shaping text */
+pub fn set_font_file(font_id: u8, font_file: PathBuf) {
+    SHAPED_BLOB_BUILDER
+        .with(|blob_builder| blob_builder.borrow_mut().set_font_file(font_id, font_file));
+}
+/* Stores a resource pushed by the server, see resources.rs in helicoid-protocol. Fonts are
+given to the shaper, the other resources are drawn by the svg elements. */
+pub fn push_resource(name: ResourceName, kind: ResourceKind, data: Vec<u8>) {
+    forget_decoded_image(&name);
+    if kind == ResourceKind::Font {
+        let font_name = String::from_utf8_lossy(&name).into_owned();
+        SHAPED_BLOB_BUILDER.with(|blob_builder| {
+            blob_builder
+                .borrow_mut()
+                .set_font_resource(font_name, Some(Arc::new(data)))
+        });
+    } else {
+        SVG_CACHE.push(name, kind, data);
+    }
+}
+pub fn evict_resource(name: &ResourceName) {
+    forget_decoded_image(name);
+    let font_name = String::from_utf8_lossy(name).into_owned();
+    SHAPED_BLOB_BUILDER
+        .with(|blob_builder| blob_builder.borrow_mut().set_font_resource(font_name, None));
+    SVG_CACHE.evict(name);
+}
+/* Forgets the resources pushed by the server, when the connection is lost */
+pub fn clear_resources() {
+    SHAPED_BLOB_BUILDER.with(|blob_builder| blob_builder.borrow_mut().clear_font_resources());
+    SVG_CACHE.clear_pushed();
+    DECODED_IMAGES.with(|images| images.borrow_mut().clear());
+}
+fn forget_decoded_image(name: &ResourceName) {
+    let key = image_key(&ImageSource::Resource(name.clone()));
+    DECODED_IMAGES.with(|images| images.borrow_mut().pop(&key));
+}
+fn image_key(source: &ImageSource) -> u64 {
+    let mut hasher = ahash::random_state::RandomState::with_seeds(S1, S2, S3, S4).build_hasher();
+    source.hash(&mut hasher);
+    hasher.finish()
+}
+/* The image of an image block, decoded the first time it is drawn. None when the image is not
+available (yet), or can not be decoded. */
+fn decoded_image(source: &ImageSource) -> Option<Image> {
+    let key = image_key(source);
+    if let Some(image) = DECODED_IMAGES.with(|images| images.borrow_mut().get(&key).cloned()) {
+        return Some(image);
+    }
+    let image = match source {
+        ImageSource::Resource(name) => {
+            let Some(resource) = SVG_CACHE.pushed_resource(name) else {
+                log::trace!(
+                    "Image resource not received: {:?}",
+                    String::from_utf8_lossy(name)
+                );
+                return None;
+            };
+            match resource.kind {
+                ResourceKind::Png | ResourceKind::Jpeg => {
+                    Image::from_encoded(Data::new_copy(&resource.data))
+                }
+                kind => {
+                    log::warn!("Can not draw {:?} resource as an image", kind);
+                    None
+                }
+            }
+        }
+        ImageSource::Pixels(pixels) => {
+            if !pixels.is_valid() {
+                log::warn!(
+                    "Image data does not match its size: {}x{}",
+                    pixels.width,
+                    pixels.height
+                );
+                return None;
+            }
+            Image::from_raster_data(
+                &ImageInfo::new(
+                    ISize::new(pixels.width as i32, pixels.height as i32),
+                    skia::ColorType::RGBA8888,
+                    skia::AlphaType::Unpremul,
+                    None,
+                ),
+                Data::new_copy(&pixels.data),
+                4 * pixels.width as usize,
+            )
+        }
+    };
+    if image.is_none() {
+        log::warn!("Could not decode image");
+    }
+    let image = image?;
+    DECODED_IMAGES.with(|images| images.borrow_mut().put(key, image.clone()));
+    Some(image)
+}
+/* How many decoded images of image blocks are kept */
+const DECODED_IMAGES_CAPACITY: usize = 64;
 /* Seeds for hashes: The hashes should stay consistent so we can compare them */
 const S1: u64 = 0x1199AACCDD117766;
 const S2: u64 = 0x99AACCDD11776611;
@@ -36,13 +142,31 @@ const S4: u64 = 0xCCDD117766AACE7D;
 
 struct SvgResourcePixmapCache {
     resources: Mutex<HashMap<SmallVec<[u8; SVG_RESOURCE_NAME_LEN]>, HashMap<(u16, u16), Vec<u8>>>>,
+    /* The resources pushed by the server, used instead of the assets with the same name */
+    pushed: Mutex<ResourceStore>,
 }
 impl SvgResourcePixmapCache {
     pub fn new() -> Self {
         Self {
             resources: Mutex::new(Default::default()),
+            pushed: Mutex::new(Default::default()),
         }
     }
+    pub fn push(&self, name: ResourceName, kind: ResourceKind, data: Vec<u8>) {
+        self.resources.lock().remove(&name);
+        self.pushed.lock().insert(name, kind, data);
+    }
+    pub fn evict(&self, name: &ResourceName) {
+        self.resources.lock().remove(name);
+        self.pushed.lock().evict(name);
+    }
+    pub fn pushed_resource(&self, name: &ResourceName) -> Option<Resource> {
+        self.pushed.lock().get(name).cloned()
+    }
+    pub fn clear_pushed(&self) {
+        self.resources.lock().clear();
+        self.pushed.lock().clear();
+    }
     pub fn fetch_resource<F: FnOnce(&Vec<u8>, u32, u32) -> V, V>(
         &self,
         name: &SmallVec<[u8; SVG_RESOURCE_NAME_LEN]>,
@@ -54,7 +178,10 @@ impl SvgResourcePixmapCache {
             res.insert(name.clone(), HashMap::default());
         }
         let name_resource = res.get_mut(name).unwrap();
-        let resource_name_str = std::str::from_utf8(name).unwrap();
+        let Ok(resource_name_str) = std::str::from_utf8(name) else {
+            log::warn!("Resource name is not utf-8: {:?}", name);
+            return None;
+        };
         let sx = size.x().round() as u16;
         let sy = size.y().round() as u16;
         match name_resource.entry((sx, sy)) {
@@ -62,44 +189,92 @@ impl SvgResourcePixmapCache {
                 Some(handle(e.into_mut(), sx as u32, sy as u32))
             }
             hashbrown::hash_map::Entry::Vacant(ve) => {
-                let exe_path = std::env::current_exe().unwrap();
-                let resource_path = exe_path
-                    .as_path()
-                    .parent()
-                    .unwrap()
-                    .parent()
-                    .unwrap()
-                    .parent()
-                    .unwrap()
-                    .join("assets")
-                    .join(resource_name_str)
-                    .with_extension("svg");
-                let Ok(resource_contents) = std::fs::read_to_string(resource_path) else {
-                        log::trace!("Could not load data from svg path");
-                return None;
-            };
-
-                if let Ok(svg_tree) = usvg::Tree::from_str(&resource_contents, &Default::default())
-                {
-                    let mut pixmap = tiny_skia::Pixmap::new(sx as u32, sy as u32).unwrap();
-                    resvg::render(
-                        &svg_tree,
-                        usvg::FitTo::Size(sx as u32, sy as u32),
-                        tiny_skia::Transform::identity(),
-                        pixmap.as_mut(),
-                    )
-                    .unwrap();
-                    let rdata = pixmap.data();
-                    let mut rvec = Vec::with_capacity(rdata.len());
-                    rvec.extend_from_slice(rdata);
-                    Some(handle(ve.insert(rvec), sx as u32, sy as u32))
-                } else {
-                    log::trace!("Coult not parse svg");
-                    None
-                }
+                let pushed = self.pushed.lock().get(name).cloned();
+                let rendered = match pushed {
+                    Some(Resource {
+                        kind: ResourceKind::Svg,
+                        data,
+                    }) => rasterize_svg(&data, sx, sy),
+                    Some(Resource {
+                        kind: ResourceKind::Png,
+                        data,
+                    }) => rasterize_png(&data, sx, sy),
+                    Some(Resource { kind, .. }) => {
+                        log::warn!("Can not draw {:?} resource: {:?}", kind, resource_name_str);
+                        None
+                    }
+                    None => Self::load_asset(resource_name_str, sx, sy),
+                };
+                rendered.map(|rvec| handle(ve.insert(rvec), sx as u32, sy as u32))
             }
         }
     }
+    /* Loads an svg from the assets of the client */
+    fn load_asset(resource_name_str: &str, sx: u16, sy: u16) -> Option<Vec<u8>> {
+        /* Make sure this is an acceptable / valid file name */
+        if !resource_name_str.chars().all(|c| c.is_ascii_alphanumeric()) {
+            log::warn!("Invalid svg asset name: {:?}", resource_name_str);
+            return None;
+        }
+        let exe_path = std::env::current_exe().unwrap();
+        let resource_path = exe_path
+            .as_path()
+            .parent()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .join("assets")
+            .join(resource_name_str)
+            .with_extension("svg");
+        let Ok(resource_contents) = std::fs::read(resource_path) else {
+            log::trace!("Could not load data from svg path");
+            return None;
+        };
+        rasterize_svg(&resource_contents, sx, sy)
+    }
+}
+fn rasterize_svg(data: &[u8], sx: u16, sy: u16) -> Option<Vec<u8>> {
+    let Ok(svg_tree) = usvg::Tree::from_data(data, &Default::default()) else {
+        log::trace!("Coult not parse svg");
+        return None;
+    };
+    let mut pixmap = tiny_skia::Pixmap::new(sx as u32, sy as u32)?;
+    resvg::render(
+        &svg_tree,
+        usvg::FitTo::Size(sx as u32, sy as u32),
+        tiny_skia::Transform::identity(),
+        pixmap.as_mut(),
+    )
+    .unwrap();
+    Some(pixmap.data().to_vec())
+}
+/* Scales a png to the size it is drawn at */
+fn rasterize_png(data: &[u8], sx: u16, sy: u16) -> Option<Vec<u8>> {
+    let image = match tiny_skia::Pixmap::decode_png(data) {
+        Ok(image) => image,
+        Err(e) => {
+            log::trace!("Could not decode png: {}", e);
+            return None;
+        }
+    };
+    let mut pixmap = tiny_skia::Pixmap::new(sx as u32, sy as u32)?;
+    pixmap.draw_pixmap(
+        0,
+        0,
+        image.as_ref(),
+        &tiny_skia::PixmapPaint {
+            quality: tiny_skia::FilterQuality::Bicubic,
+            ..Default::default()
+        },
+        tiny_skia::Transform::from_scale(
+            sx as f32 / image.width() as f32,
+            sy as f32 / image.height() as f32,
+        ),
+        None,
+    );
+    Some(pixmap.data().to_vec())
 }
 pub struct SkiaGfxManager {}
 
@@ -575,13 +750,10 @@ impl SkiaClientRenderBlock {
                 }
                 SimpleDrawElement::SvgResource(svg) => {
                     /* TODO: We should really cache this svg as a pixmap */
-                    let resource_name_str = std::str::from_utf8(&svg.resource_name).unwrap();
-                    /* Make sure this is an acceptable / valid resource name */
-                    log::trace!("Render svg: {:?}", resource_name_str);
-                    assert!(resource_name_str.chars().all(|c| (('A' <= c && c <= 'Z')
-                        || ('a' <= c && c <= 'z')
-                        || ('0' <= c && c <= '9'))));
-                    assert!(resource_name_str.len() < 64);
+                    log::trace!(
+                        "Render svg: {:?}",
+                        String::from_utf8_lossy(&svg.resource_name)
+                    );
                     /* This is slow when rendering, ideally it should be made async and communicate when it is done */
                     SVG_CACHE.fetch_resource(&svg.resource_name, &svg.extent, |data, sx, sy| {
                         let sk_paint = simple_paint_to_sk_paint(&svg.paint, true);
@@ -608,6 +780,39 @@ impl SkiaClientRenderBlock {
         canvas.restore();
     }
 
+    pub fn render_image(
+        &mut self,
+        location: &RenderBlockLocation,
+        target: &mut SkiaClientRenderTarget<'_>,
+        meta: &mut MetaBlock<SkiaClientRenderBlock>,
+    ) {
+        let Some(RenderBlockDescription::Image(image_block)) = &meta.wire_description() else {
+            panic!("Render image should not be called with a description that is not an Image")
+        };
+        log::trace!("Render image: {:?} {:?}", meta.parent_path(), meta.id());
+        let Some(image) = decoded_image(&image_block.source) else {
+            return;
+        };
+        let placement =
+            image_block.placement(PointF32::new(image.width() as f32, image.height() as f32));
+        let mut paint = Paint::default();
+        paint.set_blend_mode(BlendMode::SrcOver);
+        paint.set_anti_alias(true);
+        let canvas = target.target_surface.canvas();
+        canvas.save();
+        canvas.translate(Vector::new(location.location.x(), location.location.y()));
+        canvas.draw_image_rect(
+            &image,
+            Some((
+                &as_skrect(&placement.source_origin, &placement.source_extent),
+                skia::canvas::SrcRectConstraint::Fast,
+            )),
+            as_skrect(&placement.destination_origin, &placement.destination_extent),
+            &paint,
+        );
+        canvas.restore();
+    }
+
     /* // Remove the hashing from the renderer, that is the domain of the meta
     fn hash_block_recursively<H: Hasher>(&self, hasher: &mut H,
         meta: &mut MetaBlock<SkiaClientRenderBlock>
@@ -866,6 +1071,7 @@ impl BlockGfx for SkiaClientRenderBlock {
                     self.render_simple_draw(location, target, block)
                 }
                 RenderBlockDescription::MetaBox(_) => self.render_meta_box(location, target, block),
+                RenderBlockDescription::Image(_) => self.render_image(location, target, block),
             }
         }
     }
@@ -914,6 +1120,10 @@ fn build_sub_surface(context: &mut DirectContext, image_info: ImageInfo) -> Surf
 fn as_skpoint(p: &PointF32) -> Point {
     Point { x: p.x(), y: p.y() }
 }
+
+fn as_skrect(origin: &PointF32, extent: &PointF32) -> skia::Rect {
+    skia::Rect::from_xywh(origin.x(), origin.y(), extent.x(), extent.y())
+}
 /*
 trait BlockContentsRenderer {
     fn render(&self, desc: &RenderBlockDescription, storage: &BlockManager, target: &mut Surface);
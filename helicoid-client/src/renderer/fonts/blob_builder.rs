@@ -8,7 +8,8 @@ use helicoid_protocol::text::{
 use ordered_float::OrderedFloat;
 
 use std::num::NonZeroUsize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use log::{trace, warn};
 use lru::LruCache;
@@ -28,6 +29,11 @@ pub struct ShapedBlobBuilder {
     //scale_factor: f32,
     font_cache: HashMap<SmallFontOptions, KeyedFont>,
     font_names: Vec<Option<String>>,
+    /* The font files the server shapes with, by family id, used instead of the named fonts */
+    font_files: Vec<Option<PathBuf>>,
+    /* Font resources pushed by the server, used instead of the font files with the same
+    name */
+    font_resources: HashMap<String, Arc<Vec<u8>>>,
     default_font: HashMap<FontParameters, KeyedFont>,
     font_manager: FontMgr,
     //    fudge_factor: f32,
@@ -43,6 +49,8 @@ impl ShapedBlobBuilder {
             blob_cache: LruCache::new(NonZeroUsize::new(10000).unwrap()),
             font_cache: Default::default(),
             font_names: Vec::new(),
+            font_files: Vec::new(),
+            font_resources: Default::default(),
             default_font,
             font_manager,
             //fudge_factor: 1.0,
@@ -87,6 +95,37 @@ impl ShapedBlobBuilder {
         }
         self.font_names[font_id as usize] = Some(font_name);
     }
+    /* Uses a font file for a family, the text already built with the family is rebuilt */
+    pub fn set_font_file(&mut self, font_id: u8, font_file: PathBuf) {
+        if font_id as usize >= self.font_files.len() {
+            self.font_files.resize(font_id as usize + 1, None);
+        }
+        self.font_files[font_id as usize] = Some(font_file);
+        self.font_cache
+            .retain(|font_info, _| font_info.family_id != font_id);
+        self.blob_cache.clear();
+    }
+    /* Sets (or with None removes) a font resource, the text already built is rebuilt */
+    pub fn set_font_resource(&mut self, font_name: String, data: Option<Arc<Vec<u8>>>) {
+        let changed = match data {
+            Some(data) => {
+                self.font_resources.insert(font_name, data);
+                true
+            }
+            None => self.font_resources.remove(&font_name).is_some(),
+        };
+        if changed {
+            self.font_cache.clear();
+            self.blob_cache.clear();
+        }
+    }
+    pub fn clear_font_resources(&mut self) {
+        if !self.font_resources.is_empty() {
+            self.font_resources.clear();
+            self.font_cache.clear();
+            self.blob_cache.clear();
+        }
+    }
     pub fn adjust_font_cache_size(&self) {
         let current_font_cache_size = font_cache_limit() as f32;
         let percent_font_cache_used = font_cache_used() as f32 / current_font_cache_size;
@@ -119,17 +158,38 @@ impl ShapedBlobBuilder {
 
                 font
             } else {
-                if let Some(font_name) = self.font_names.get(run.font_info.family_id as usize) {
+                if let Some(Some(font_file)) = self.font_files.get(run.font_info.family_id as usize)
+                {
+                    let loaded = KeyedFont::load_file(
+                        FontKey::from_parameters(run.font_info.font_parameters.clone(), None),
+                        font_file,
+                        *run.font_info.font_parameters.size,
+                    );
+                    if let Some(loaded) = loaded {
+                        log::trace!("Succeded loading font from file: {:?}", font_file);
+                        self.font_cache.insert(run.font_info.clone(), loaded);
+                    } else {
+                        log::warn!("Failed loading font from file: {:?}", font_file);
+                    }
+                } else if let Some(font_name) =
+                    self.font_names.get(run.font_info.family_id as usize)
+                {
                     if let Some(font_name) = font_name {
-                        let loaded = KeyedFont::load_keyed(
-                            &mut self.font_manager,
-                            &base_asset_path(),
-                            FontKey::from_parameters(
-                                run.font_info.font_parameters.clone(),
-                                Some(font_name.clone()),
-                            ),
-                            *run.font_info.font_parameters.size,
+                        let font_key = FontKey::from_parameters(
+                            run.font_info.font_parameters.clone(),
+                            Some(font_name.clone()),
                         );
+                        let font_size = *run.font_info.font_parameters.size;
+                        let loaded = if let Some(data) = self.font_resources.get(font_name) {
+                            KeyedFont::load_data(font_key, data, font_size)
+                        } else {
+                            KeyedFont::load_keyed(
+                                &mut self.font_manager,
+                                &base_asset_path(),
+                                font_key,
+                                font_size,
+                            )
+                        };
                         if let Some(loaded) = loaded {
                             log::trace!(
                                 "Succeded loading font with name: {} at {:?} {:?}",
@@ -270,6 +330,15 @@ impl KeyedFont {
             KeyedFont::new(font_key, Font::from_typeface(typeface, font_size))
         }
     }
+    fn load_file(font_key: FontKey, font_file: &Path, font_size: f32) -> Option<Self> {
+        trace!("Loading font file {:?} {}", font_file, font_size);
+        let font_data_vec = std::fs::read(font_file).ok()?;
+        Self::load_data(font_key, &font_data_vec, font_size)
+    }
+    fn load_data(font_key: FontKey, font_data: &[u8], font_size: f32) -> Option<Self> {
+        let typeface = Typeface::from_data(Data::new_copy(font_data), 0)?;
+        KeyedFont::new(font_key, Font::from_typeface(typeface, font_size))
+    }
     fn skia_font(&self) -> &Font {
         &self.skia_font
     }
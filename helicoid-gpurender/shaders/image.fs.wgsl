@@ -0,0 +1,15 @@
+@group(0)@binding(1)
+var image_sampler: sampler;
+
+@group(0)@binding(2)
+var image_texture: texture_2d<f32>;
+
+struct VertexOutput {
+    @location(0) t_position: vec2<f32>,
+    @builtin(position) position: vec4<f32>,
+};
+
+@fragment
+fn main(vo: VertexOutput) -> @location(0) vec4<f32> {
+    return textureSample(image_texture, image_sampler, vo.t_position);
+}
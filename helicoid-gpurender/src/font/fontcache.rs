@@ -23,7 +23,7 @@ use crate::font::texture_atlases::{self, AtlasLocation, TextureAtlas, TextureAtl
 pub trait FontOwner {
     fn swash_font(&self) -> FontRef<'_>;
 }
-const POINTS_PER_SQUARE: usize = 6;
+pub const POINTS_PER_SQUARE: usize = 6;
 pub type FontId = u8;
 
 thread_local! {
@@ -1169,6 +1169,23 @@ impl RenderSquare {
             },
         }
     }
+    /* Moves the square by the given amount of pixels, the squares of a run are relative to
+    the block the text is part of */
+    pub fn translated(&self, x: f32, y: f32) -> Self {
+        let translate = |point: &RenderPoint| RenderPoint {
+            dx: (f32::from_bits(point.dx) + x).to_bits(),
+            dy: (f32::from_bits(point.dy) + y).to_bits(),
+            ..*point
+        };
+        Self {
+            top_left1: translate(&self.top_left1),
+            bottom_left1: translate(&self.bottom_left1),
+            top_right1: translate(&self.top_right1),
+            top_right2: translate(&self.top_right2),
+            bottom_right2: translate(&self.bottom_right2),
+            bottom_left2: translate(&self.bottom_left2),
+        }
+    }
 }
 impl RenderedRun {
     pub fn reset(&mut self) {
@@ -546,6 +546,23 @@ impl BackedUpTexture {
             format,
         }
     }
+    /* A texture with the given contents, the data has to match the extent and format */
+    pub fn with_data(extent: wgpu::Extent3d, format: wgpu::TextureFormat, data: Vec<u8>) -> Self {
+        let bpp = format.block_size(None).unwrap() as usize;
+        debug_assert_eq!(
+            data.len(),
+            extent.width as usize * extent.height as usize * bpp
+        );
+        Self {
+            host_data: data,
+            gpu: None,
+            gpu_outdated: true,
+            layout: ImageDataLayout::default(),
+            label: None,
+            extent,
+            format,
+        }
+    }
     fn ensure_texture_parameters(&mut self, device: &wgpu::Device) {
         /* Check if a texture already exists with the requested parameters */
         if let Some(texture_info) = self.gpu.as_ref() {
@@ -650,6 +667,10 @@ impl BackedUpTexture {
             &self.gpu
         }
     }
+    /* If the host data has changed since it was written to the texture on the device */
+    pub fn is_outdated(&self) -> bool {
+        self.gpu_outdated
+    }
     pub fn host_data_mut(&mut self) -> &mut [u8] {
         self.gpu_outdated = true;
         self.host_data.as_mut_slice()
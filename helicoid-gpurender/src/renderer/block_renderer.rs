@@ -16,11 +16,14 @@ use smallvec::SmallVec;
 
 use cosmic_text;
 
-use crate::font::fontcache::{FontCache, FontId, RenderSpec, RenderTargetId, RenderedRun};
+use crate::font::fontcache::{
+    FontCache, FontId, RenderSpec, RenderSquare, RenderTargetId, RenderedRun, POINTS_PER_SQUARE,
+};
 use crate::font::swash_font::SwashFont;
 use crate::font::texture_atlases::{AtlasLocation, TextureInfo};
 
 use super::fontconverter::FontConverter;
+use super::image_cache::{ImageCache, ImageQuad};
 
 /* Seeds for hashes: The hashes should stay consistent so we can compare them */
 const S1: u64 = 0x1199AACCDD117766;
@@ -115,6 +118,16 @@ impl WGpuClientRenderBlock {
             panic!("Render text box should not be called with a description that is not a ShapedTextBlock")
         };
         log::trace!("Render text box: {:?} {:?}", meta.parent_path(), meta.id());
+        if let RenderBlockInner::None = self.inner {
+            /* The runs are converted the first time the block is rendered */
+            self.inner = RenderBlockInner::TextBlock(TextRenderBlockInner {
+                spec: stb.clone(),
+                source: RenderSpec::default(),
+                runs: SmallVec::new(),
+                source_hash: 0,
+                spec_hash: None,
+            });
+        }
         match &mut self.inner {
             RenderBlockInner::None => {}
             RenderBlockInner::TextBlock(inner) => {
@@ -124,13 +137,13 @@ impl WGpuClientRenderBlock {
                         ahash::random_state::RandomState::with_seeds(S1, S2, S3, S4).build_hasher();
                     stb.hash(&mut hasher);
 
-                    inner.spec_hash != Some(hasher.finish())
+                    hash != hasher.finish()
                 } else {
                     true
                 };
                 let reconvert = if !spec_changed {
                     /* If not spec changed, make sure all altlas references still are valid */
-                    inner
+                    !inner
                         .spec
                         .metadata
                         .runs
@@ -140,10 +153,10 @@ impl WGpuClientRenderBlock {
                             let font_cache =
                                 value_or_backup(target.font_caches, &run.font_info.family_id, &0);
                             if let Some(rendered_run) = inner.runs.get(idx) {
-                                !(rendered_run.0 == run.font_info.family_id
-                                    && rendered_run.1.compatible(font_cache.atlases_ref()))
+                                rendered_run.0 == run.font_info.family_id
+                                    && rendered_run.1.compatible(font_cache.atlases_ref())
                             } else {
-                                true
+                                false
                             }
                         })
                 } else {
@@ -163,7 +176,7 @@ impl WGpuClientRenderBlock {
                             value_or_backup(target.font_caches, &run.font_info.family_id, &0);
                         let rendered_run = font_cache
                             .render_run(
-                                &target.target_device,
+                                target.target_device,
                                 target
                                     .font_convertor
                                     .convert_and_set(&inner.spec, idx)
@@ -172,46 +185,30 @@ impl WGpuClientRenderBlock {
                             .unwrap();
                         inner.runs.push((run.font_info.family_id, rendered_run));
                     }
-                    /* Render the converted runs to the pipeline, this is done every frame */
-
-                    for (cache_id, rendered_run) in inner.runs.iter() {
-                        let font_cache = value_or_backup(target.font_caches, &cache_id, &0);
-
-                        /* If the texture has changed because of the conversion above, it should be transferred to the device.
-                        TODOS: Consider handling this on a higher level. What happens if the atlas is transformed after
-                               the text is converted, before the frame is rendered?*/
-                        font_cache
-                            .atlas(&AtlasLocation::atlas_only(0))
-                            .unwrap()
-                            .update_texture(&target.target_device, &target.target_queue);
-                        /* TODO: Can we avoid using an index buffer */
-                        /*
-                        font_cache.renderer(&0).unwrap().setup_pipeline(&mut pass);
-                        let buffer_indices = text_render_run.gpu_indices.as_ref().unwrap();
-                        pass.set_index_buffer(buffer_indices.slice(..), wgpu::IndexFormat::Uint16);
-
-                        let text_vbo = text_render_run.gpu_vertices.as_ref().unwrap();
-                        pass.set_vertex_buffer(0, text_vbo.slice(..));
-
-                        pass.draw_indexed(
-                            0..(buffer_indices.size() as u32 / std::mem::size_of::<u16>() as u32),
-                            0,
-                            0..1,
-                        );*/
-
-                        //target.fon
-
-                        //target.target_pass
-                    }
+                }
+                /* The converted runs are added to the draw list every frame, the glyphs are
+                positioned relative to the window */
+                let x = target.offset.x() + location.location.x();
+                let y = target.offset.y() + location.location.y();
+                for (family_id, rendered_run) in inner.runs.iter() {
+                    let font_id = if target.font_caches.contains_key(family_id) {
+                        *family_id
+                    } else {
+                        0
+                    };
+                    target.draw_list.push_text(
+                        font_id,
+                        rendered_run
+                            .host_vertices
+                            .iter()
+                            .map(|square| square.translated(x, y)),
+                    );
                 }
             }
             RenderBlockInner::MetaBlock() => panic!(
                 "A meta block inner should not be present for a shaped text block description"
             ),
         };
-        // TODO: Render text using wgpu (see helcoid-wgpu main)
-        /* Create a vertexlist etc. and hash it. If the source and atlas haven't
-        changed reuse the vertex list. */
 
         /*
                /* TODO: Use and configuration  of blob builder and storage of fonts should be improved,
@@ -511,6 +508,25 @@ impl WGpuClientRenderBlock {
         */
     }
 
+    pub fn render_image(
+        &mut self,
+        location: &RenderBlockLocation,
+        target: &mut WGpuClientRenderTarget<'_>,
+        meta: &mut MetaBlock<WGpuClientRenderBlock>,
+    ) {
+        let Some(RenderBlockDescription::Image(image_block)) = &meta.wire_description() else {
+            panic!("Render image should not be called with a description that is not an Image")
+        };
+        log::trace!("Render image: {:?} {:?}", meta.parent_path(), meta.id());
+        let position = PointF32::new(
+            target.offset.x() + location.location.x(),
+            target.offset.y() + location.location.y(),
+        );
+        if let Some(quad) = target.image_cache.quad(image_block, position) {
+            target.draw_list.images.push(quad);
+        }
+    }
+
     /* // Remove the hashing from the renderer, that is the domain of the meta
     fn hash_block_recursively<H: Hasher>(&self, hasher: &mut H,
         meta: &mut MetaBlock<WGpuClientRenderBlock>
@@ -555,7 +571,14 @@ impl WGpuClientRenderBlock {
             parent: popt,
             gfx_block: &mut parents.gfx_block,
         };*/
+        /* The locations of the sub blocks are relative to this block */
+        let parent_offset = target.offset;
+        target.offset = PointF32::new(
+            parent_offset.x() + location.location.x(),
+            parent_offset.y() + location.location.y(),
+        );
         meta.process_block_recursively(self, target);
+        target.offset = parent_offset;
 
         /*
         // How do we sort the blocks?
@@ -580,14 +603,159 @@ impl WGpuClientRenderBlock {
     }
 }
 
+/* The blocks are not drawn directly to a render pass, as the pass would have to borrow the
+buffers of the blocks for as long as it lives. Instead the blocks add what they draw to the
+draw list, which is drawn to the pass when all blocks are rendered. */
 pub struct WGpuClientRenderTarget<'a> {
-    pub location: &'a RenderBlockLocation,
-    pub target_pass: &'a mut RenderPass<'a>,
-    pub target_device: &'a mut wgpu::Device,
-    pub target_queue: &'a mut wgpu::Queue,
+    /* Position of the parent of the rendered block, relative to the window */
+    pub offset: PointF32,
+    pub target_device: &'a wgpu::Device,
     pub target_id: RenderTargetId,
     pub font_caches: &'a mut HashMap<FontId, FontCache<SwashFont>>,
     pub font_convertor: &'a mut FontConverter,
+    pub image_cache: &'a mut ImageCache,
+    pub draw_list: &'a mut WGpuDrawList,
+}
+
+#[derive(Debug, Default)]
+struct TextDraw {
+    squares: Vec<RenderSquare>,
+    vertices: Option<wgpu::Buffer>,
+    vertex_count: u32,
+}
+
+/* Everything drawn by the blocks in a frame, the images are drawn below the text. The vertex
+buffers are kept between the frames, and are only reallocated when they grow. */
+#[derive(Debug, Default)]
+pub struct WGpuDrawList {
+    text: HashMap<FontId, TextDraw>,
+    images: Vec<ImageQuad>,
+    image_vertices: Option<wgpu::Buffer>,
+}
+
+impl WGpuDrawList {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /* Called before the blocks are rendered for a new frame */
+    pub fn clear(&mut self) {
+        for text in self.text.values_mut() {
+            text.squares.clear();
+        }
+        self.images.clear();
+    }
+    fn push_text<I>(&mut self, font_id: FontId, squares: I)
+    where
+        I: Iterator<Item = RenderSquare>,
+    {
+        self.text
+            .entry(font_id)
+            .or_default()
+            .squares
+            .extend(squares);
+    }
+    /* Transfers the vertices, the images and the glyphs added to the font atlases while
+    rendering the blocks, to the device. Must be called before the render pass is started. */
+    pub fn upload(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        target_id: RenderTargetId,
+        resolution: (f32, f32),
+        font_caches: &mut HashMap<FontId, FontCache<SwashFont>>,
+        image_cache: &mut ImageCache,
+    ) {
+        if !self.images.is_empty() {
+            image_cache.upload(device, queue, resolution, &self.images);
+            let quad_size = std::mem::size_of_val(&self.images[0].vertices) as u64;
+            let size = quad_size * self.images.len() as u64;
+            if self
+                .image_vertices
+                .as_ref()
+                .map_or(true, |buffer| buffer.size() < size)
+            {
+                self.image_vertices = Some(device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Block image vertex buffer"),
+                    size,
+                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::VERTEX,
+                    mapped_at_creation: false,
+                }));
+            }
+            let image_vertices = self.image_vertices.as_ref().unwrap();
+            for (idx, quad) in self.images.iter().enumerate() {
+                queue.write_buffer(
+                    image_vertices,
+                    idx as u64 * quad_size,
+                    bytemuck::cast_slice(&quad.vertices),
+                );
+            }
+        }
+        for (font_id, text) in self.text.iter_mut() {
+            text.vertex_count = 0;
+            if text.squares.is_empty() {
+                continue;
+            }
+            let Some(font_cache) = font_caches.get_mut(font_id) else {
+                continue;
+            };
+            font_cache
+                .atlas(&AtlasLocation::atlas_only(0))
+                .unwrap()
+                .update_texture(device, queue);
+            let Some(renderer) = font_cache.renderer(&target_id) else {
+                continue;
+            };
+            renderer.resolution_changed(resolution);
+            renderer.sync_globals(queue);
+
+            let size = (text.squares.len() * std::mem::size_of::<RenderSquare>()) as u64;
+            if text
+                .vertices
+                .as_ref()
+                .map_or(true, |buffer| buffer.size() < size)
+            {
+                text.vertices = Some(device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Block text vertex buffer"),
+                    size,
+                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::VERTEX,
+                    mapped_at_creation: false,
+                }));
+            }
+            queue.write_buffer(
+                text.vertices.as_ref().unwrap(),
+                0,
+                bytemuck::cast_slice(text.squares.as_slice()),
+            );
+            text.vertex_count = (text.squares.len() * POINTS_PER_SQUARE) as u32;
+        }
+    }
+    pub fn draw<'p>(
+        &'p self,
+        pass: &mut RenderPass<'p>,
+        target_id: RenderTargetId,
+        font_caches: &'p mut HashMap<FontId, FontCache<SwashFont>>,
+        image_cache: &'p ImageCache,
+    ) {
+        if let Some(image_vertices) = self.image_vertices.as_ref() {
+            if !self.images.is_empty() {
+                image_cache.draw(pass, image_vertices, &self.images);
+            }
+        }
+        for (font_id, font_cache) in font_caches.iter_mut() {
+            let Some(text) = self.text.get(font_id) else {
+                continue;
+            };
+            if text.vertex_count == 0 {
+                continue;
+            }
+            let Some(renderer) = font_cache.renderer(&target_id) else {
+                continue;
+            };
+            renderer.setup_pipeline(pass);
+            pass.set_vertex_buffer(0, text.vertices.as_ref().unwrap().slice(..));
+            pass.draw(0..text.vertex_count, 0..1);
+        }
+    }
 }
 impl BlockGfx for WGpuClientRenderBlock {
     type RenderTarget<'b> = WGpuClientRenderTarget<'b>;
@@ -615,6 +783,7 @@ impl BlockGfx for WGpuClientRenderBlock {
                     self.render_simple_draw(location, target, block)
                 }
                 RenderBlockDescription::MetaBox(_) => self.render_meta_box(location, target, block),
+                RenderBlockDescription::Image(_) => self.render_image(location, target, block),
             }
         }
     }
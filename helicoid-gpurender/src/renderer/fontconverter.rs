@@ -18,7 +18,7 @@ impl FontConverter {
         run_idx: usize,
     ) -> Option<&RenderSpec> {
         self.temp_spec.clear();
-        if text.metadata.runs.len() >= run_idx {
+        if run_idx >= text.metadata.runs.len() {
             return None;
         }
         let run = text.metadata.runs.get(run_idx).unwrap();
@@ -0,0 +1,426 @@
+/* Textures for the image blocks (see ImageBlock in helicoid-protocol). The images are decoded
+the first time they are drawn, uploaded to the device with the rest of the frame, and kept
+while they are drawn. Textures that have not been drawn for UNUSED_FRAMES_BEFORE_EVICTION
+frames are dropped. The images are drawn as textured quads, added to the draw list (see
+WGpuDrawList) while the blocks are rendered. */
+
+use std::hash::{BuildHasher, Hash, Hasher};
+
+use hashbrown::HashMap;
+use helicoid_protocol::{
+    gfx::{ImageBlock, ImageSource, PointF32},
+    resources::{ResourceKind, ResourceName, ResourceStore},
+};
+use image::ImageFormat;
+use wgpu::{Extent3d, RenderPass, TextureFormat};
+
+use crate::font::texture_atlases::BackedUpTexture;
+
+const UNUSED_FRAMES_BEFORE_EVICTION: u32 = 120;
+const VERTICES_PER_QUAD: usize = 6;
+
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub(crate) enum ImageKey {
+    Resource(ResourceName),
+    /* Pixels sent with the block, by the hash of the pixels */
+    Pixels(u64),
+}
+
+#[derive(Debug)]
+struct CachedImage {
+    texture: BackedUpTexture,
+    /* Created when the texture is uploaded, refers to the texture on the device */
+    bind_group: Option<wgpu::BindGroup>,
+    last_used_frame: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ImageVertex {
+    position: [f32; 2],
+    texture_position: [f32; 2],
+}
+
+unsafe impl bytemuck::Pod for ImageVertex {}
+unsafe impl bytemuck::Zeroable for ImageVertex {}
+
+/* An image drawn in a frame, the positions of the vertices are relative to the window */
+#[derive(Debug)]
+pub(crate) struct ImageQuad {
+    key: ImageKey,
+    pub(crate) vertices: [ImageVertex; VERTICES_PER_QUAD],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct ImageGlobals {
+    resolution: [f32; 2],
+    offset: [f32; 2],
+}
+
+unsafe impl bytemuck::Pod for ImageGlobals {}
+unsafe impl bytemuck::Zeroable for ImageGlobals {}
+
+struct ImageRenderer {
+    bind_group_layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::RenderPipeline,
+    globals_ubo: wgpu::Buffer,
+}
+
+#[derive(Default)]
+pub struct ImageCache {
+    /* The image resources pushed by the server */
+    resources: ResourceStore,
+    textures: HashMap<ImageKey, CachedImage>,
+    /* For the hashes of the pixels, which have to be the same for the same pixels */
+    hash_state: ahash::RandomState,
+    frame: u32,
+    /* Nothing is drawn before the renderer is set up for the target */
+    renderer: Option<ImageRenderer>,
+}
+
+impl ImageCache {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    pub fn push_resource(&mut self, name: ResourceName, kind: ResourceKind, data: Vec<u8>) {
+        self.textures.remove(&ImageKey::Resource(name.clone()));
+        self.resources.insert(name, kind, data);
+    }
+    pub fn evict_resource(&mut self, name: &ResourceName) {
+        self.textures.remove(&ImageKey::Resource(name.clone()));
+        self.resources.evict(name);
+    }
+    /* Forgets the resources pushed by the server, when the connection is lost */
+    pub fn clear_resources(&mut self) {
+        self.resources.clear();
+        self.textures
+            .retain(|key, _| !matches!(key, ImageKey::Resource(_)));
+    }
+    /* The quad drawing an image block at the given position (relative to the window). None
+    when the image is not available (yet), or can not be decoded. */
+    pub(crate) fn quad(
+        &mut self,
+        image_block: &ImageBlock,
+        position: PointF32,
+    ) -> Option<ImageQuad> {
+        let key = match &image_block.source {
+            ImageSource::Resource(name) => ImageKey::Resource(name.clone()),
+            ImageSource::Pixels(pixels) => {
+                let mut hasher = self.hash_state.build_hasher();
+                pixels.hash(&mut hasher);
+                ImageKey::Pixels(hasher.finish())
+            }
+        };
+        if !self.textures.contains_key(&key) {
+            let texture = self.decode(&image_block.source)?;
+            self.textures.insert(
+                key.clone(),
+                CachedImage {
+                    texture,
+                    bind_group: None,
+                    last_used_frame: self.frame,
+                },
+            );
+        }
+        let cached = self.textures.get_mut(&key).unwrap();
+        cached.last_used_frame = self.frame;
+        let extent = cached.texture.extent();
+        let (width, height) = (extent.width as f32, extent.height as f32);
+        let placement = image_block.placement(PointF32::new(width, height));
+        if placement.destination_extent.x() <= 0.0 || placement.destination_extent.y() <= 0.0 {
+            return None;
+        }
+        let left = position.x() + placement.destination_origin.x();
+        let top = position.y() + placement.destination_origin.y();
+        let right = left + placement.destination_extent.x();
+        let bottom = top + placement.destination_extent.y();
+        let source_left = placement.source_origin.x() / width;
+        let source_top = placement.source_origin.y() / height;
+        let source_right = (placement.source_origin.x() + placement.source_extent.x()) / width;
+        let source_bottom = (placement.source_origin.y() + placement.source_extent.y()) / height;
+        let vertex = |x: f32, y: f32, sx: f32, sy: f32| ImageVertex {
+            position: [x, y],
+            texture_position: [sx, sy],
+        };
+        let top_left = vertex(left, top, source_left, source_top);
+        let bottom_left = vertex(left, bottom, source_left, source_bottom);
+        let top_right = vertex(right, top, source_right, source_top);
+        let bottom_right = vertex(right, bottom, source_right, source_bottom);
+        Some(ImageQuad {
+            key,
+            vertices: [
+                top_left,
+                bottom_left,
+                top_right,
+                top_right,
+                bottom_right,
+                bottom_left,
+            ],
+        })
+    }
+    /* Creates the pipeline the images are drawn with, for a target like the one the text of
+    the font caches is drawn to */
+    pub fn setup_renderer(&mut self, device: &wgpu::Device, multisample: wgpu::MultisampleState) {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Image bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(
+                            std::mem::size_of::<ImageGlobals>() as u64,
+                        ),
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let vs_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Image vs"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("./../../shaders/image.vs.wgsl").into()),
+        });
+        let fs_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Image fs"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("./../../shaders/image.fs.wgsl").into()),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+            label: None,
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Image pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &vs_module,
+                entry_point: "main",
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: std::mem::size_of::<ImageVertex>() as u64,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes: &[
+                        wgpu::VertexAttribute {
+                            offset: 0,
+                            format: wgpu::VertexFormat::Float32x2,
+                            shader_location: 0,
+                        },
+                        wgpu::VertexAttribute {
+                            offset: std::mem::size_of::<[f32; 2]>() as u64,
+                            format: wgpu::VertexFormat::Float32x2,
+                            shader_location: 1,
+                        },
+                    ],
+                }],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &fs_module,
+                entry_point: "main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: wgpu::TextureFormat::Bgra8UnormSrgb,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                front_face: wgpu::FrontFace::Ccw,
+                strip_index_format: None,
+                cull_mode: None,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            /* The images are drawn in the order of the blocks, and do not hide each other */
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: wgpu::TextureFormat::Depth32Float,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Always,
+                stencil: wgpu::StencilState {
+                    front: wgpu::StencilFaceState::IGNORE,
+                    back: wgpu::StencilFaceState::IGNORE,
+                    read_mask: 0,
+                    write_mask: 0,
+                },
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample,
+            multiview: None,
+        });
+        let globals_ubo = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Image globals ubo"),
+            size: std::mem::size_of::<ImageGlobals>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        /* The bind groups refer to the globals of the previous renderer */
+        for cached in self.textures.values_mut() {
+            cached.bind_group = None;
+        }
+        self.renderer = Some(ImageRenderer {
+            bind_group_layout,
+            pipeline,
+            globals_ubo,
+        });
+    }
+    /* Transfers the textures of the quads (if they have changed) to the device */
+    pub(crate) fn upload(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        resolution: (f32, f32),
+        quads: &[ImageQuad],
+    ) {
+        let Some(renderer) = self.renderer.as_ref() else {
+            return;
+        };
+        queue.write_buffer(
+            &renderer.globals_ubo,
+            0,
+            bytemuck::cast_slice(&[ImageGlobals {
+                resolution: [resolution.0, resolution.1],
+                offset: [0.0, 0.0],
+            }]),
+        );
+        for quad in quads {
+            let Some(cached) = self.textures.get_mut(&quad.key) else {
+                continue;
+            };
+            if cached.texture.is_outdated() {
+                cached.texture.update_texture(device, queue);
+                cached.bind_group = None;
+            }
+            if cached.bind_group.is_some() {
+                continue;
+            }
+            let Some(texture) = cached.texture.texture().as_ref() else {
+                continue;
+            };
+            cached.bind_group = Some(device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Image bind group"),
+                layout: &renderer.bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::Buffer(
+                            renderer.globals_ubo.as_entire_buffer_binding(),
+                        ),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&texture.view),
+                    },
+                ],
+            }));
+        }
+    }
+    /* Draws the quads, the vertices of the quads must have been written to the vertex buffer
+    in the same order */
+    pub(crate) fn draw<'p>(
+        &'p self,
+        pass: &mut RenderPass<'p>,
+        vertices: &'p wgpu::Buffer,
+        quads: &[ImageQuad],
+    ) {
+        let Some(renderer) = self.renderer.as_ref() else {
+            return;
+        };
+        pass.set_pipeline(&renderer.pipeline);
+        pass.set_vertex_buffer(0, vertices.slice(..));
+        for (idx, quad) in quads.iter().enumerate() {
+            let Some(bind_group) = self
+                .textures
+                .get(&quad.key)
+                .and_then(|cached| cached.bind_group.as_ref())
+            else {
+                continue;
+            };
+            pass.set_bind_group(0, bind_group, &[]);
+            let first = (idx * VERTICES_PER_QUAD) as u32;
+            pass.draw(first..first + VERTICES_PER_QUAD as u32, 0..1);
+        }
+    }
+    /* Called when a frame is drawn, drops the textures that are no longer drawn */
+    pub fn end_frame(&mut self) {
+        let frame = self.frame;
+        self.textures.retain(|_, cached| {
+            frame.wrapping_sub(cached.last_used_frame) < UNUSED_FRAMES_BEFORE_EVICTION
+        });
+        self.frame = frame.wrapping_add(1);
+    }
+    fn decode(&self, source: &ImageSource) -> Option<BackedUpTexture> {
+        let (width, height, data) = match source {
+            ImageSource::Resource(name) => {
+                let Some(resource) = self.resources.get(name) else {
+                    log::trace!(
+                        "Image resource not received: {:?}",
+                        String::from_utf8_lossy(name)
+                    );
+                    return None;
+                };
+                let format = match resource.kind {
+                    ResourceKind::Png => ImageFormat::Png,
+                    ResourceKind::Jpeg => ImageFormat::Jpeg,
+                    kind => {
+                        log::warn!("Can not draw {:?} resource as an image", kind);
+                        return None;
+                    }
+                };
+                let image = image::load_from_memory_with_format(&resource.data, format)
+                    .map_err(|e| log::warn!("Could not decode image: {}", e))
+                    .ok()?
+                    .into_rgba8();
+                (image.width(), image.height(), image.into_raw())
+            }
+            ImageSource::Pixels(pixels) => {
+                if !pixels.is_valid() {
+                    log::warn!(
+                        "Image data does not match its size: {}x{}",
+                        pixels.width,
+                        pixels.height
+                    );
+                    return None;
+                }
+                (
+                    pixels.width as u32,
+                    pixels.height as u32,
+                    pixels.data.clone(),
+                )
+            }
+        };
+        if width == 0 || height == 0 {
+            return None;
+        }
+        Some(BackedUpTexture::with_data(
+            Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            TextureFormat::Rgba8UnormSrgb,
+            data,
+        ))
+    }
+}
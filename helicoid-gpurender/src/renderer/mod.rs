@@ -4,6 +4,7 @@
 //pub mod grid_renderer;
 pub mod block_renderer;
 pub mod fontconverter;
+pub mod image_cache;
 //pub mod profiler;
 //mod rendered_window;
 //mod text_box_renderer;
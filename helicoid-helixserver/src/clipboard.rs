@@ -0,0 +1,92 @@
+use std::{
+    borrow::Cow,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use helicoid_protocol::payload::ClipboardSelection;
+use helix_view::clipboard::{ClipboardProvider, ClipboardType};
+use tokio::sync::broadcast;
+
+/* Room for the clipboard messages of a burst of yanks, a client that lags behind more than
+this only misses the older contents */
+const CLIPBOARD_CHANNEL_LENGTH: usize = 16;
+
+/* Messages for the clients from the clipboard provider of the editor */
+#[derive(Debug, Clone)]
+pub enum ClipboardMessage {
+    /* The editor has set the clipboard, the clients are to set theirs to the contents */
+    Set(ClipboardSelection, Arc<String>),
+    /* The editor has read the clipboard, the clients are asked for their current contents */
+    Request(ClipboardSelection),
+}
+
+/* Clipboard provider of the editor that uses the clipboards of the connected clients. The
+provider is asked for the contents synchronously while the client is on the other end of a
+connection, so it answers with the contents the clients sent most recently, and asks them
+for the current contents for the next time.
+
+This means a paste can give stale contents: if something is copied in another application
+while the editor window keeps the focus, the first paste after that gives what the client
+sent before, and only the next one gives the new contents. The clients send their clipboards
+when the window gets the focus, which covers the common case of copying in another window.
+Waiting for the answer is not an option, as the editor is called from the task of the
+connection, which is the one that would receive the answer. */
+#[derive(Debug, Clone)]
+pub struct ClientClipboard {
+    /* The contents of the clipboard and of the selection */
+    contents: Arc<Mutex<[String; 2]>>,
+    message_tx: broadcast::Sender<ClipboardMessage>,
+}
+
+fn selection_of(clipboard_type: ClipboardType) -> ClipboardSelection {
+    match clipboard_type {
+        ClipboardType::Clipboard => ClipboardSelection::Clipboard,
+        ClipboardType::Selection => ClipboardSelection::Selection,
+    }
+}
+
+fn index_of(selection: ClipboardSelection) -> usize {
+    match selection {
+        ClipboardSelection::Clipboard => 0,
+        ClipboardSelection::Selection => 1,
+    }
+}
+
+impl ClientClipboard {
+    pub fn new() -> Self {
+        let (message_tx, _) = broadcast::channel(CLIPBOARD_CHANNEL_LENGTH);
+        Self {
+            contents: Default::default(),
+            message_tx,
+        }
+    }
+    pub fn subscribe(&self) -> broadcast::Receiver<ClipboardMessage> {
+        self.message_tx.subscribe()
+    }
+    /* Called with the clipboard contents sent by a client */
+    pub fn contents_received(&self, selection: ClipboardSelection, contents: String) {
+        self.contents.lock().unwrap()[index_of(selection)] = contents;
+    }
+    fn send(&self, message: ClipboardMessage) {
+        /* There are no receivers when no clients are connected */
+        let _ = self.message_tx.send(message);
+    }
+}
+
+impl ClipboardProvider for ClientClipboard {
+    fn name(&self) -> Cow<str> {
+        Cow::Borrowed("helicoid-client")
+    }
+    fn get_contents(&self, clipboard_type: ClipboardType) -> Result<String> {
+        let selection = selection_of(clipboard_type);
+        self.send(ClipboardMessage::Request(selection));
+        Ok(self.contents.lock().unwrap()[index_of(selection)].clone())
+    }
+    fn set_contents(&mut self, contents: String, clipboard_type: ClipboardType) -> Result<()> {
+        let selection = selection_of(clipboard_type);
+        self.contents.lock().unwrap()[index_of(selection)] = contents.clone();
+        self.send(ClipboardMessage::Set(selection, Arc::new(contents)));
+        Ok(())
+    }
+}
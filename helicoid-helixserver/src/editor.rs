@@ -3,8 +3,11 @@ use std::sync::Arc;
 use arc_swap::ArcSwap;
 use tokio::sync::broadcast::{self};
 
+use helicoid_protocol::session::SessionId;
 use helix_core::{config::user_syntax_loader, syntax};
-use helix_view::{editor::Config, graphics::Rect, theme, Editor as VEditor};
+use helix_view::{editor::Config, graphics::Rect, theme, Editor as VEditor, ViewId};
+
+use crate::clipboard::ClientClipboard;
 
 /* Architecture:
 The (Dummy)Editor object is stored in a shared Arc<TMutex<>> object, and is cloned
@@ -14,6 +17,11 @@ needs an update. */
 pub struct Editor {
     editor_state_changed_send: tokio::sync::broadcast::Sender<()>,
     editor: VEditor,
+    /* The views of the editing clients (by session), that spectators can follow. The most
+    recently connected presenter is last. */
+    presenter_views: Vec<(SessionId, ViewId)>,
+    /* The clipboard provider of the editor, which uses the clipboards of the clients */
+    clipboard: ClientClipboard,
 }
 
 impl Editor {
@@ -48,16 +56,53 @@ impl Editor {
             Err(err) => return Err(Error::new(err)),
         };*/
         let config = Arc::new(ArcSwap::from_pointee(Config::default()));
-        let veditor = VEditor::new(Rect::new(0, 0, 10, 10), theme_loader, syn_loader, config);
+        let mut veditor = VEditor::new(Rect::new(0, 0, 10, 10), theme_loader, syn_loader, config);
+        let clipboard = ClientClipboard::new();
+        veditor.clipboard_provider = Box::new(clipboard.clone());
 
         Self {
             editor_state_changed_send,
             editor: veditor, //            text: String::new(),
+            presenter_views: Vec::new(),
+            clipboard,
         }
     }
     pub fn update_receiver(&self) -> tokio::sync::broadcast::Receiver<()> {
         self.editor_state_changed_send.subscribe()
     }
+    /* Tells all the clients that the editing model has changed. The channel only holds one
+    message, as the clients resync all they display anyway */
+    pub fn notify_changed(&self) {
+        /* There are no receivers when no clients are connected */
+        let _ = self.editor_state_changed_send.send(());
+    }
+    pub fn add_presenter_view(&mut self, session_id: SessionId, view_id: ViewId) {
+        self.presenter_views.retain(|(id, _)| *id != session_id);
+        self.presenter_views.push((session_id, view_id));
+    }
+    pub fn presenter_view(&self, presenter: &SessionId) -> Option<ViewId> {
+        self.presenter_views
+            .iter()
+            .find(|(id, _)| id == presenter)
+            .map(|(_, view_id)| *view_id)
+    }
+    pub fn latest_presenter_view(&self) -> Option<ViewId> {
+        self.presenter_views.last().map(|(_, view_id)| *view_id)
+    }
+    pub fn is_presenter_view(&self, view_id: ViewId) -> bool {
+        self.presenter_views.iter().any(|(_, id)| *id == view_id)
+    }
+    /* Closes a view of a client that has gone away, spectators following it have to find
+    another presenter */
+    pub fn close_view(&mut self, view_id: ViewId) {
+        self.presenter_views.retain(|(_, id)| *id != view_id);
+        if self.editor.tree.contains(view_id) {
+            self.editor.close(view_id);
+        }
+    }
+    pub fn clipboard(&self) -> &ClientClipboard {
+        &self.clipboard
+    }
     pub fn editor_mut(&mut self) -> &mut VEditor {
         &mut self.editor
     }
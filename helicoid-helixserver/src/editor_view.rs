@@ -3,6 +3,7 @@ use crate::{center::CenterModel, editor::Editor as HcEditor, statusline::StatusL
 use helicoid_protocol::{
     caching_shaper::CachingShaper,
     gfx::{PointF32, PointU32, RenderBlockId, RenderBlockLocation, RenderBlockPath},
+    session::HeldBlockSet,
     shadowblocks::{
         ContainerBlockLogic, NoContainerBlockLogic, ShadowMetaBlock, ShadowMetaContainerBlock,
         VisitingContext,
@@ -485,6 +486,13 @@ impl EditorTree {
             .inner_mut()
             .client_transfer_messages(&parent_path, location, transfer_buffer);
     }
+    /* Makes the next transfer resend the blocks the client does not hold, see
+    ShadowMetaContainerBlockInner::reconcile_client_blocks */
+    pub fn reconcile_client_blocks(&mut self, parent_path: &RenderBlockPath, held: &HeldBlockSet) {
+        self.root
+            .inner_mut()
+            .reconcile_client_blocks(parent_path, held);
+    }
     pub fn top_container_id(&self) -> RenderBlockId {
         self.root.inner_ref().id()
     }
@@ -495,6 +503,21 @@ impl EditorTree {
     pub fn current_view_id(&self) -> Option<ViewId> {
         self.root.logic_ref().view_id
     }
+    /* If the point (relative to the tree) is in the area showing the text of the document */
+    pub fn text_area_contains(&self, point: PointF32) -> bool {
+        let Some((block, location)) = self.root.child(RenderBlockId(EDITOR_CHILD_CENTER)) else {
+            return false;
+        };
+        let (origin, extent) = (&location.location, block.extent());
+        point.x() >= origin.x()
+            && point.y() >= origin.y()
+            && point.x() < origin.x() + extent.x()
+            && point.y() < origin.y() + extent.y()
+    }
+    /* Shows another view in the tree, used by spectators to follow a presenter */
+    pub fn set_view_id(&mut self, view_id: Option<ViewId>) {
+        self.root.logic_mut().view_id = view_id;
+    }
 }
 impl EditorModel {
     fn new(line_height: f32, scale_factor: f32, font_info: Metrics) -> Self {
@@ -0,0 +1,18 @@
+/* The helix editor server, used by the server binary and by frontends embedding the editor
+in the same process (see HelicoidServer::connect_in_process) */
+
+#[cfg(test)]
+#[macro_use]
+extern crate lazy_static;
+
+mod center;
+mod clipboard;
+mod compositor;
+mod constants;
+mod editor;
+mod editor_view;
+pub mod server;
+mod statusline;
+
+#[cfg(test)]
+mod tests;
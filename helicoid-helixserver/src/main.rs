@@ -1,12 +1,12 @@
-#[cfg(test)]
-#[macro_use]
-extern crate lazy_static;
-
 use anyhow::Result;
 use clap::Parser;
 use futures::StreamExt;
-use server::HelicoidServer;
-use std::future;
+use helicoid_helixserver::server::HelicoidServer;
+use helicoid_protocol::{
+    auth::AuthKey, keepalive::KeepaliveOptions, tcp_bridge_async::ServerBridgeConfig,
+    tls::ServerTlsConfig,
+};
+use std::{future, path::PathBuf, sync::Arc, time::Duration};
 
 use termion::{event::Key, raw::IntoRawMode};
 use termion_input_tokio::TermReadAsync;
@@ -14,23 +14,33 @@ use tokio::runtime::Runtime;
 use tokio::time as ttime;
 //use futures_util::stream::stream::StreamExt;
 
-mod center;
-mod compositor;
-mod constants;
-mod editor;
-mod editor_view;
-mod server;
-mod statusline;
-
-#[cfg(test)]
-mod tests;
-
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct CommandLineArguments {
-    /// Listening address of editor server, for connecting to an existing server
+    /// Listening address of editor server, either host:port or unix:/path/to/socket
     #[arg(short, long, default_value = "127.0.0.1:15566")]
     pub server_address: String,
+    /// PEM file with the TLS certificate (chain) of the server, enables TLS when set together with the key
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM file with the private key for the TLS certificate
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// Token clients have to authenticate with, a random token is generated if neither this nor a key file is given
+    #[arg(long, conflicts_with = "auth_key_file")]
+    pub auth_token: Option<String>,
+    /// File containing the key clients have to authenticate with
+    #[arg(long)]
+    pub auth_key_file: Option<PathBuf>,
+    /// Serve a single client over stdin / stdout instead of listening (e.g. when started through ssh)
+    #[arg(long, conflicts_with_all = ["tls_cert", "auth_token", "auth_key_file"])]
+    pub stdio: bool,
+    /// Record the traffic of each connection to a file in this directory, for replay with helicoid-replay
+    #[arg(long)]
+    pub record_dir: Option<PathBuf>,
+    /// Seconds without hearing from a client before its connection is considered lost, 0 disables the keepalive pings
+    #[arg(long, default_value_t = 30)]
+    pub keepalive_timeout: u64,
     /*    /// Number of times to greet
     #[arg(short, long, default_value_t = 1)]
     count: u8,*/
@@ -44,8 +54,29 @@ fn main() -> Result<()> {
         .thread_stack_size(16 * 1024 * 1024) // Especially in debug mode the font shaping stuff may need some more stack
         .enable_io()
         .build()?;
+    if args.stdio {
+        /* Stdout is used for the connection, so logging has to go to stderr (the env_logger
+        default) and nothing else may be printed to stdout */
+        runtime.block_on(async move {
+            let bridge_config = ServerBridgeConfig {
+                record_directory: args.record_dir,
+                keepalive: KeepaliveOptions::with_timeout(Duration::from_secs(
+                    args.keepalive_timeout,
+                )),
+                ..Default::default()
+            };
+            let mut bridge_server = HelicoidServer::new(args.server_address, bridge_config).await?;
+            bridge_server.serve_stdio().await
+        })?;
+        /* Don't wait for the blocking stdin reader, it only returns when more data arrives */
+        runtime.shutdown_timeout(std::time::Duration::from_millis(100));
+        return Ok(());
+    }
+    let bridge_config = bridge_config(&args)?;
     runtime.spawn(async move {
-        let mut bridge_server = HelicoidServer::new(args.server_address).await.unwrap();
+        let mut bridge_server = HelicoidServer::new(args.server_address, bridge_config)
+            .await
+            .unwrap();
         bridge_server.event_loop().await.unwrap();
     });
     wait_for_input();
@@ -53,6 +84,32 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+fn bridge_config(args: &CommandLineArguments) -> Result<ServerBridgeConfig> {
+    let mut config = ServerBridgeConfig {
+        record_directory: args.record_dir.clone(),
+        keepalive: KeepaliveOptions::with_timeout(Duration::from_secs(args.keepalive_timeout)),
+        ..Default::default()
+    };
+    if let (Some(cert), Some(key)) = (args.tls_cert.as_ref(), args.tls_key.as_ref()) {
+        config.tls = Some(Arc::new(ServerTlsConfig::from_pem_files(cert, key)?));
+        log::info!("TLS enabled using certificate: {:?}", cert);
+    } else {
+        log::warn!("TLS is not enabled, the connection to the client is not encrypted");
+    }
+    config.auth = Some(if let Some(key_file) = args.auth_key_file.as_ref() {
+        AuthKey::from_file(key_file)?
+    } else if let Some(token) = args.auth_token.as_ref() {
+        AuthKey::from_token(token)?
+    } else {
+        /* Never run without authentication, print the token so it can be given to the client.
+        It goes to stderr (like the log) so it is shown regardless of the log level. */
+        let token = AuthKey::generate_token();
+        eprintln!("Clients can connect using: --auth-token {}", token);
+        AuthKey::from_token(&token)?
+    });
+    Ok(config)
+}
+
 fn wait_for_input() {
     let rt = Runtime::new().unwrap();
     rt.block_on(async move {
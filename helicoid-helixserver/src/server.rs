@@ -7,30 +7,44 @@ use hashbrown::HashMap;
 use helicoid_protocol::{
     bridge_logic::TcpBridgeToServerMessage,
     caching_shaper::CachingShaper,
+    capabilities::{Capabilities, ClientHello, ClientRole, ServerHello},
+    fonts::{FontHash, ServedFonts},
     gfx::{
-        MetaDrawBlock, NewRenderBlock, PointF32, RenderBlockDescription, RenderBlockId,
-        RenderBlockLocation, RenderBlockPath,
+        HelicoidToClientMessage, MetaDrawBlock, NewRenderBlock, PointF32, RenderBlockDescription,
+        RenderBlockId, RenderBlockLocation, RenderBlockPath,
     },
     input::{HelicoidToServerMessage, ViewportInfo, VirtualKeycode},
-    tcp_bridge_async::{TcpBridgeServer, TcpBridgeServerConnectionState},
+    payload::{ClipboardSelection, Payload, PayloadAssembler, PayloadKind},
+    session::{HeldBlockSet, SessionResume},
+    tcp_bridge_async::{
+        ConnectionInfo, PeerAddress, SendQueueStatus, ServerBridgeConfig, TcpBridgeServer,
+        TcpBridgeServerConnectionState,
+    },
     text::SmallFontOptions,
     transferbuffer::TransferBuffer,
+    window_control::{AttentionLevel, CursorShape, WindowControl},
+};
+use helix_core::{
+    graphemes::prev_grapheme_boundary,
+    movement::{move_horizontally, move_vertically, Direction},
+    Tendril, Transaction,
 };
-use helix_core::movement::{move_horizontally, move_vertically, Direction};
+use helix_view::{editor::Action, DocumentId, ViewId};
 
 use ordered_float::OrderedFloat;
 use smallvec::{smallvec, SmallVec};
 use std::{
     hash::{Hash, Hasher},
-    net::SocketAddr,
     sync::Arc,
 };
 use tokio::sync::{
+    broadcast::error::RecvError,
     broadcast::Receiver as BReceiver,
     mpsc::{Receiver, Sender},
     Mutex as TMutex,
 };
 
+use crate::clipboard::{ClientClipboard, ClipboardMessage};
 use crate::editor::Editor as HcEditor;
 use crate::editor_view::{ContentVisitor, EditorTree};
 
@@ -38,6 +52,9 @@ const CONTAINER_IDS_BASE: u16 = 0x100;
 const ENCLOSURE_ID: u16 = 0x0;
 
 const UNSCALED_FONT_SIZE: f32 = 12f32;
+/* Editor updates are not synced to a client while this many frames are waiting to be sent
+to it, the screen is synced when the frames are sent instead */
+const MAX_UNSENT_FRAMES: usize = 1;
 struct Compositor {
     containers: HashMap<RenderBlockId, EditorTree>,
     content_visitor: ContentVisitor,
@@ -56,16 +73,42 @@ struct ServerStateData {
     compositor: Option<Box<Compositor>>,
     enclosure: Option<EditorEnclosure>,
     enclosure_hash: Option<u64>,
+    /* The view made for this client, it is closed when the state is dropped. Spectators
+    show the view of a presenter instead, and do not have one. */
+    own_view: Option<ViewId>,
+    served_fonts: Arc<ServedFonts>,
 }
 
 struct ServerState {
     _pending_message: Option<TcpBridgeToServerMessage>,
-    _peer_address: SocketAddr,
+    _peer_address: PeerAddress,
     channel_tx: Sender<Arc<TransferBuffer>>,
     channel_rx: Receiver<TcpBridgeToServerMessage>,
     close_rx: BReceiver<()>,
     editor_update_rx: BReceiver<()>,
     state_data: ServerStateData,
+    /* Capabilities negotiated with the client */
+    capabilities: Capabilities,
+    role: ClientRole,
+    /* Set when the connection resumed a session, until the shadow tree is reconciled with
+    the blocks the client holds */
+    resume: Option<SessionResume>,
+    /* Timestamp of the newest input from the client, echoed with each frame */
+    latest_input_timestamp: Option<u32>,
+    send_queue: Arc<SendQueueStatus>,
+    /* Set when an editor update was not synced because the client is behind */
+    sync_deferred: bool,
+    /* Payloads being received from the client */
+    payloads: PayloadAssembler,
+    /* For sending payloads to the client, None for clients in the same process */
+    payload_tx: Option<Sender<Payload>>,
+    clipboard: ClientClipboard,
+    clipboard_rx: BReceiver<ClipboardMessage>,
+    /* The window title and mouse cursor shape last sent to the client */
+    window_title: Option<String>,
+    cursor_shape: CursorShape,
+    /* Window controls sent with the next screen sync */
+    window_controls: Vec<WindowControl>,
 
     viewport_size: Option<ViewportInfo>,
 }
@@ -73,16 +116,24 @@ pub struct HelicoidServer {
     editor: Arc<TMutex<HcEditor>>,
     listen_address: String,
     bridge: Arc<TMutex<TcpBridgeServer<ServerState>>>,
+    /* The document opened when the first client connects, all clients get a view of it */
+    initial_document: Option<DocumentId>,
+    /* The font files announced to the clients, read when the first client connects */
+    served_fonts: Option<Arc<ServedFonts>>,
 }
 impl HelicoidServer {
-    pub async fn new(listen_address: String) -> Result<Self> {
+    pub async fn new(listen_address: String, bridge_config: ServerBridgeConfig) -> Result<Self> {
         let editor = Arc::new(TMutex::new(HcEditor::new()));
-        let bridge = Arc::new(TMutex::new(TcpBridgeServer::<ServerState>::new().await?));
+        let bridge = Arc::new(TMutex::new(
+            TcpBridgeServer::<ServerState>::with_config(bridge_config).await?,
+        ));
         //bridge.bind(&listen_address).await;
         Ok(Self {
             editor,
             bridge,
             listen_address,
+            initial_document: None,
+            served_fonts: None,
         })
     }
 
@@ -112,78 +163,132 @@ impl HelicoidServer {
         }
         ContentVisitor::new(line_height, shaper, editor)
     }
-    pub async fn event_loop(&mut self) -> Result<Self> {
-        log::trace!("Helicoid test server event loop start");
-        loop {
-            let mut visitor = Self::make_content_visitor(1.0f32, self.editor.clone());
+    /* Builds the state (compositor with the initial editor tree) handed to a new connection.
+    Each client gets its own view of the editor, so the clients can have different viewport
+    sizes, scale factors and focused views. */
+    async fn make_state_data(&mut self) -> ServerStateData {
+        let mut visitor = Self::make_content_visitor(1.0f32, self.editor.clone());
 
-            let shaper = visitor.shaper();
-            let mut font_options = SmallFontOptions {
-                family_id: 0,
-                font_parameters: shaper.default_parameters(),
-            };
-            font_options.font_parameters.size = OrderedFloat(UNSCALED_FONT_SIZE);
-            let font_metrics = shaper.info(&font_options).unwrap().0;
+        let shaper = visitor.shaper();
+        let mut font_options = SmallFontOptions {
+            family_id: 0,
+            font_parameters: shaper.default_parameters(),
+        };
+        font_options.font_parameters.size = OrderedFloat(UNSCALED_FONT_SIZE);
+        let font_metrics = shaper.info(&font_options).unwrap().0;
+        let served_fonts = self
+            .served_fonts
+            .get_or_insert_with(|| Arc::new(ServedFonts::from_shaper(visitor.shaper_ref())))
+            .clone();
 
-            let mut state_data = ServerStateData {
-                enclosure: None,
-                enclosure_hash: None,
-                compositor: Some(Box::new(Compositor {
-                    containers: HashMap::default(),
-                    content_visitor: visitor,
-                    transfer_buffer_scratch: Default::default(),
-                    lent_out_buffer_scratch: Default::default(),
-                })),
-            };
-            let view_id = {
-                let mut editor = self.editor.lock().await;
-                let heditor = editor.editor_mut();
-                //                let doc_id = Some(heditor.new_file(Action::VerticalSplit));
-                let doc_id = heditor.open(
-                    &std::env::current_dir().unwrap().join("src/center.rs"),
-                    helix_view::editor::Action::VerticalSplit,
-                );
-                let view_id = heditor.tree.focus;
-                assert_eq!(heditor.tree.get(view_id).doc, doc_id.unwrap());
-                Some(view_id)
-            };
-            let mut initial_container = EditorTree::new(
-                RenderBlockPath::new(smallvec![RenderBlockId(ENCLOSURE_ID)]),
-                RenderBlockId(CONTAINER_IDS_BASE),
-                UNSCALED_FONT_SIZE,
-                1.0f32, /* Scale factor is determined when a resize event occurs */
-                font_metrics,
-                view_id,
-                PointF32::default(),
-            );
-            let initial_container = {
-                let mut compositor = state_data.compositor.take();
-                let (initial_container, compositor) = tokio::task::spawn_blocking(move || {
-                    initial_container.initialize(&mut compositor.as_mut().unwrap().content_visitor);
-                    (initial_container, compositor)
-                })
-                .await
-                .unwrap();
-                state_data.compositor = compositor;
-                initial_container
+        let mut state_data = ServerStateData {
+            enclosure: None,
+            enclosure_hash: None,
+            compositor: Some(Box::new(Compositor {
+                containers: HashMap::default(),
+                content_visitor: visitor,
+                transfer_buffer_scratch: Default::default(),
+                lent_out_buffer_scratch: Default::default(),
+            })),
+            own_view: None,
+            served_fonts,
+        };
+        let view_id = {
+            let mut editor = self.editor.lock().await;
+            let heditor = editor.editor_mut();
+            //                let doc_id = Some(heditor.new_file(Action::VerticalSplit));
+            let doc_id = match self
+                .initial_document
+                .filter(|doc_id| heditor.documents.contains_key(doc_id))
+            {
+                Some(doc_id) => {
+                    heditor.switch(doc_id, Action::VerticalSplit);
+                    doc_id
+                }
+                None => {
+                    let doc_id = heditor
+                        .open(
+                            &std::env::current_dir().unwrap().join("src/center.rs"),
+                            Action::VerticalSplit,
+                        )
+                        .unwrap();
+                    self.initial_document = Some(doc_id);
+                    doc_id
+                }
             };
-            state_data
-                .compositor
-                .as_mut()
-                .unwrap()
-                .containers
-                .insert(RenderBlockId(CONTAINER_IDS_BASE), initial_container);
-
-            log::trace!("Helicoid test server event loop iterate");
-            tokio::select! {
-            _result = TcpBridgeServer::wait_for_connection(self.bridge.clone(), &self.listen_address, state_data) =>{
-                    /* Currently all event handling is done inside the state */
-
-                },
-                /* Maybe add select on program close-channel here to close cleanly */
-            }
+            let view_id = heditor.tree.focus;
+            assert_eq!(heditor.tree.get(view_id).doc, doc_id);
+            Some(view_id)
+        };
+        state_data.own_view = view_id;
+        let mut initial_container = EditorTree::new(
+            RenderBlockPath::new(smallvec![RenderBlockId(ENCLOSURE_ID)]),
+            RenderBlockId(CONTAINER_IDS_BASE),
+            UNSCALED_FONT_SIZE,
+            1.0f32, /* Scale factor is determined when a resize event occurs */
+            font_metrics,
+            view_id,
+            PointF32::default(),
+        );
+        let initial_container = {
+            let mut compositor = state_data.compositor.take();
+            let (initial_container, compositor) = tokio::task::spawn_blocking(move || {
+                initial_container.initialize(&mut compositor.as_mut().unwrap().content_visitor);
+                (initial_container, compositor)
+            })
+            .await
+            .unwrap();
+            state_data.compositor = compositor;
+            initial_container
+        };
+        state_data
+            .compositor
+            .as_mut()
+            .unwrap()
+            .containers
+            .insert(RenderBlockId(CONTAINER_IDS_BASE), initial_container);
+        state_data
+    }
+    /* Serves a single client over stdin / stdout, returns when the client disconnects */
+    pub async fn serve_stdio(&mut self) -> Result<()> {
+        log::trace!("Helicoid server serving stdio");
+        let state_data = self.make_state_data().await;
+        TcpBridgeServer::serve_stdio(self.bridge.clone(), state_data).await
+    }
+    /* Connects a client running in the same process, see TcpBridgeServer::connect_in_process.
+    Not used by the server binary itself, it is meant for frontends embedding the server. */
+    pub async fn connect_in_process(
+        &mut self,
+        client_hello: ClientHello,
+    ) -> (
+        ServerHello,
+        Sender<TcpBridgeToServerMessage>,
+        Receiver<Arc<TransferBuffer>>,
+    ) {
+        let state_data = self.make_state_data().await;
+        TcpBridgeServer::connect_in_process(self.bridge.clone(), client_hello, state_data).await
+    }
+    pub async fn event_loop(&mut self) -> Result<Self> {
+        log::trace!("Helicoid server event loop start");
+        TcpBridgeServer::bind(self.bridge.clone(), &self.listen_address).await?;
+        loop {
+            log::trace!("Helicoid server event loop iterate");
+            let accepted =
+                match TcpBridgeServer::accept_connection(self.bridge.clone(), &self.listen_address)
+                    .await
+                {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::warn!("Could not accept connection: {}", e);
+                        continue;
+                    }
+                };
+            /* The state is made when the client has connected, the connection runs on its own
+            task so more clients can connect to the same editor */
+            let state_data = self.make_state_data().await;
+            TcpBridgeServer::spawn_accepted(self.bridge.clone(), accepted, state_data).await;
         }
-        //log::trace!("Helicoid test server event loop completed");
+        //log::trace!("Helicoid server event loop completed");
     }
 }
 
@@ -191,7 +296,21 @@ impl ServerState {
     //    async fn process_event(&mut self, e: &mut DummyEditor) {}
     async fn handle_client_message(&mut self, message: TcpBridgeToServerMessage) -> Result<()> {
         log::trace!("Handle client message: {:?}", message.message);
+        if self.is_spectator() && Self::is_input(&message.message) {
+            log::trace!("Dropping input from spectator");
+            return Ok(());
+        }
+        /* The mouse motion only changes the cursor shape, its timestamp would replace the one
+        of the input that the next frame is drawn for */
+        if !matches!(message.message, HelicoidToServerMessage::CursorMoved(_)) {
+            if let Some(timestamp) = message.message.input_timestamp() {
+                self.latest_input_timestamp = Some(timestamp);
+            }
+        }
         match message.message {
+            HelicoidToServerMessage::Hello(_) => {
+                log::warn!("Ignoring hello received after connection setup");
+            }
             HelicoidToServerMessage::ViewportSizeUpdate(viewportinfo) => {
                 self.viewport_size = Some(viewportinfo);
                 self.sync_screen().await?;
@@ -199,10 +318,31 @@ impl ServerState {
             HelicoidToServerMessage::KeyModifierStateUpdate(_keymodifierstateupdateevent) => {}
             HelicoidToServerMessage::KeyPressedEvent(_simplekeytappedevent) => {}
             HelicoidToServerMessage::MouseButtonStateChange(_mousebuttonstatechangeevent) => {}
-            HelicoidToServerMessage::CursorMoved(_cursormovedevent) => {}
+            HelicoidToServerMessage::CursorMoved(event) => {
+                let position =
+                    PointF32::new(event.physical_position_x.0, event.physical_position_y.0);
+                let cursor_shape = self
+                    .state_data
+                    .compositor
+                    .as_ref()
+                    .unwrap()
+                    .cursor_shape_at(position);
+                if cursor_shape != self.cursor_shape {
+                    self.cursor_shape = cursor_shape;
+                    self.send_window_control(WindowControl::SetCursorShape(cursor_shape))
+                        .await?;
+                }
+            }
             HelicoidToServerMessage::CharReceived(_ch) => {}
             HelicoidToServerMessage::Ime(_imeevent) => {}
-            HelicoidToServerMessage::ClipboardEvent(_clipboard) => {}
+            HelicoidToServerMessage::PayloadChunk(chunk) => match self.payloads.add_chunk(chunk) {
+                Ok(Some(payload)) => self.handle_payload(payload),
+                Ok(None) => {}
+                Err(e) => log::warn!("Dropping payload from the client: {}", e),
+            },
+            /* Answered by the bridge, never passed on */
+            HelicoidToServerMessage::Keepalive(_) => {}
+            HelicoidToServerMessage::RequestFont(hash) => self.send_font(hash).await?,
             HelicoidToServerMessage::KeyInputEvent(event) => {
                 if event.pressed {
                     let text = match event.virtual_keycode {
@@ -234,51 +374,42 @@ impl ServerState {
                         VirtualKeycode::Z => Some('Z'),
                         VirtualKeycode::Space => Some(' '),
                         VirtualKeycode::Up => {
-                            self.move_document(None, Some(Direction::Backward)).await;
+                            if !self.move_document(None, Some(Direction::Backward)).await {
+                                self.send_window_control(WindowControl::Bell).await?;
+                            }
                             self.sync_screen().await?;
                             None
                         }
                         VirtualKeycode::Down => {
-                            self.move_document(None, Some(Direction::Forward)).await;
+                            if !self.move_document(None, Some(Direction::Forward)).await {
+                                self.send_window_control(WindowControl::Bell).await?;
+                            }
                             self.sync_screen().await?;
                             None
                         }
                         VirtualKeycode::Left => {
-                            self.move_document(Some(Direction::Backward), None).await;
+                            if !self.move_document(Some(Direction::Backward), None).await {
+                                self.send_window_control(WindowControl::Bell).await?;
+                            }
                             self.sync_screen().await?;
                             None
                         }
                         VirtualKeycode::Right => {
-                            self.move_document(Some(Direction::Forward), None).await;
+                            if !self.move_document(Some(Direction::Forward), None).await {
+                                self.send_window_control(WindowControl::Bell).await?;
+                            }
                             self.sync_screen().await?;
                             None
                         }
                         _ => None,
                     };
-                    if let Some(_text) = text {
-                        let _editor = self
-                            .state_data
-                            .compositor
-                            .as_mut()
-                            .unwrap()
-                            .content_visitor
-                            .editor()
-                            .lock()
-                            .await;
-                        //editor.text += &text.to_string();
+                    if let Some(text) = text {
+                        self.insert_text(text).await;
                     }
                     if let VirtualKeycode::Backspace = event.virtual_keycode {
-                        let _editor = self
-                            .state_data
-                            .compositor
-                            .as_mut()
-                            .unwrap()
-                            .content_visitor
-                            .editor()
-                            .lock()
-                            .await;
-                        //let textlen = editor.text.len().saturating_sub(1);
-                        //editor.text.truncate(textlen);
+                        if !self.delete_backward().await {
+                            self.send_window_control(WindowControl::Bell).await?;
+                        }
                     }
                 }
             }
@@ -288,9 +419,148 @@ impl ServerState {
         Ok(())
     }
 
-    async fn move_document(&mut self, dx: Option<Direction>, dy: Option<Direction>) {
-        let view_id = self
-            .state_data
+    fn is_spectator(&self) -> bool {
+        matches!(self.role, ClientRole::Spectator { .. })
+    }
+    /* Messages that (can) change the editor, these are not accepted from spectators */
+    fn is_input(message: &HelicoidToServerMessage) -> bool {
+        matches!(
+            message,
+            HelicoidToServerMessage::KeyModifierStateUpdate(_)
+                | HelicoidToServerMessage::KeyPressedEvent(_)
+                | HelicoidToServerMessage::MouseButtonStateChange(_)
+                | HelicoidToServerMessage::CursorMoved(_)
+                | HelicoidToServerMessage::CharReceived(_)
+                | HelicoidToServerMessage::Ime(_)
+                | HelicoidToServerMessage::PayloadChunk(_)
+                | HelicoidToServerMessage::KeyInputEvent(_)
+        )
+    }
+    /* Handles a payload sent in chunks by the client, see payload.rs */
+    fn handle_payload(&mut self, payload: Payload) {
+        match payload.kind {
+            PayloadKind::Font(hash) => {
+                log::warn!("Ignoring font {} sent by the client", hash);
+            }
+            PayloadKind::Resource(kind, name) => {
+                log::warn!(
+                    "Ignoring {:?} resource {} sent by the client",
+                    kind,
+                    String::from_utf8_lossy(&name)
+                );
+            }
+            PayloadKind::Clipboard(selection) => {
+                log::debug!(
+                    "Received {:?} contents ({} bytes) from the client",
+                    selection,
+                    payload.data.len()
+                );
+                let contents = String::from_utf8_lossy(&payload.data).into_owned();
+                self.clipboard.contents_received(selection, contents);
+            }
+        }
+    }
+    /* Passes clipboard changes made in the editor on to the client, and asks it for its
+    clipboard when the editor has read it. Spectators do not share their clipboards. */
+    async fn handle_clipboard_message(&mut self, message: ClipboardMessage) -> Result<()> {
+        if self.is_spectator() {
+            return Ok(());
+        }
+        match message {
+            ClipboardMessage::Set(selection, contents) => {
+                let Some(payload_tx) = self.payload_tx.as_ref() else {
+                    log::debug!("No payload channel to the client, not setting its clipboard");
+                    return Ok(());
+                };
+                payload_tx
+                    .send(Payload::clipboard(selection, contents.as_ref().clone()))
+                    .await?;
+            }
+            ClipboardMessage::Request(selection) => self.request_clipboard(selection).await?,
+        }
+        Ok(())
+    }
+    /* Sends a font file the client asked for, see fonts.rs */
+    async fn send_font(&mut self, hash: FontHash) -> Result<()> {
+        let Some(data) = self.state_data.served_fonts.data(&hash) else {
+            log::warn!("The client asked for unknown font {}", hash);
+            return Ok(());
+        };
+        let Some(payload_tx) = self.payload_tx.as_ref() else {
+            log::debug!(
+                "No payload channel to the client, not sending font {}",
+                hash
+            );
+            return Ok(());
+        };
+        payload_tx
+            .send(Payload::new(PayloadKind::Font(hash), data.as_ref().clone()))
+            .await?;
+        Ok(())
+    }
+    async fn request_clipboard(&mut self, selection: ClipboardSelection) -> Result<()> {
+        self.send_message(HelicoidToClientMessage::RequestClipboard(selection))
+            .await
+    }
+    async fn send_window_control(&mut self, control: WindowControl) -> Result<()> {
+        self.send_message(HelicoidToClientMessage::WindowControl(control))
+            .await
+    }
+    /* Sends a message to the client right away, instead of with the next screen sync */
+    async fn send_message(&mut self, message: HelicoidToClientMessage) -> Result<()> {
+        let mut transfer_buffer = TransferBuffer::new();
+        transfer_buffer.add_message(message);
+        self.channel_tx.send(Arc::new(transfer_buffer)).await?;
+        Ok(())
+    }
+    /* The title of the window of the client: the name of the document in the view it shows,
+    marked when the document has unsaved changes */
+    async fn window_title(&self) -> Option<String> {
+        let compositor = self.state_data.compositor.as_ref().unwrap();
+        let view_id = compositor.current_view_id()?;
+        let editor_locked = compositor.content_visitor.editor().clone();
+        let editor = editor_locked.lock().await;
+        let heditor = editor.editor();
+        if !heditor.tree.contains(view_id) {
+            return None;
+        }
+        let doc = heditor.document(heditor.tree.get(view_id).doc)?;
+        let modified = if doc.is_modified() { " [+]" } else { "" };
+        Some(format!("{}{} - helicoid", doc.display_name(), modified))
+    }
+    /* Makes a spectator show the view of the presenter it follows, returns false if there is
+    no presenter to follow (yet). The view is rendered with the extent and scale factor of the
+    spectator, so it follows the scroll position of the presenter at the spectator's size. */
+    async fn follow_presenter(&mut self) -> bool {
+        let ClientRole::Spectator { presenter } = &self.role else {
+            return true;
+        };
+        let compositor = self.state_data.compositor.as_mut().unwrap();
+        let editor_locked = compositor.content_visitor.editor().clone();
+        let editor = editor_locked.lock().await;
+        let current = compositor.current_view_id();
+        let view_id = match presenter {
+            Some(presenter) => editor.presenter_view(presenter),
+            None => current
+                .filter(|view_id| editor.is_presenter_view(*view_id))
+                .or_else(|| editor.latest_presenter_view()),
+        };
+        if view_id != current {
+            log::info!("Spectator follows view: {:?}", view_id);
+            compositor.follow_view(view_id);
+            /* Let the spectator know the presenter changed, unless this is the first one */
+            if current.is_some() && view_id.is_some() {
+                self.window_controls
+                    .push(WindowControl::RequestAttention(Some(
+                        AttentionLevel::Informational,
+                    )));
+            }
+        }
+        view_id.is_some()
+    }
+    /* The view focused by this client */
+    fn focused_view_id(&self) -> ViewId {
+        self.state_data
             .compositor
             .as_ref()
             .unwrap()
@@ -298,7 +568,60 @@ impl ServerState {
             .get(&RenderBlockId(CONTAINER_IDS_BASE))
             .unwrap()
             .current_view_id()
-            .unwrap();
+            .unwrap()
+    }
+    /* Inserts the text at the selections of the focused view, the other clients are told
+    about the change through the editor */
+    async fn insert_text(&mut self, text: char) {
+        let view_id = self.focused_view_id();
+        let editor_locked = self
+            .state_data
+            .compositor
+            .as_ref()
+            .unwrap()
+            .content_visitor
+            .editor();
+        let mut editor = editor_locked.lock().await;
+        let doc_id = editor.editor().tree.get(view_id).doc;
+        let doc = editor.editor_mut().documents.get_mut(&doc_id).unwrap();
+        let transaction = Transaction::insert(
+            doc.text(),
+            doc.selection(view_id),
+            Tendril::from(text.to_string()),
+        );
+        doc.apply(&transaction, view_id);
+        editor.notify_changed();
+    }
+    /* Returns false if there was nothing to delete */
+    async fn delete_backward(&mut self) -> bool {
+        let view_id = self.focused_view_id();
+        let editor_locked = self
+            .state_data
+            .compositor
+            .as_ref()
+            .unwrap()
+            .content_visitor
+            .editor();
+        let mut editor = editor_locked.lock().await;
+        let doc_id = editor.editor().tree.get(view_id).doc;
+        let doc = editor.editor_mut().documents.get_mut(&doc_id).unwrap();
+        let transaction = {
+            let text = doc.text().slice(..);
+            Transaction::change_by_selection(doc.text(), doc.selection(view_id), |range| {
+                let pos = range.cursor(text);
+                (prev_grapheme_boundary(text, pos), pos, None)
+            })
+        };
+        if transaction.changes().is_empty() {
+            return false;
+        }
+        doc.apply(&transaction, view_id);
+        editor.notify_changed();
+        true
+    }
+    /* Returns false if the cursors could not move (e.g. at the end of the document) */
+    async fn move_document(&mut self, dx: Option<Direction>, dy: Option<Direction>) -> bool {
+        let view_id = self.focused_view_id();
         let context = &mut self.state_data.compositor.as_mut().unwrap().content_visitor;
         let editor_locked = context.editor();
         let mut editor = editor_locked.lock().await;
@@ -350,7 +673,11 @@ impl ServerState {
             old_selection,
             selection
         );
+        let moved = selection != old_selection;
         doc_mut.set_selection(view_id, selection);
+        /* Spectators following this view show the cursor too */
+        editor.notify_changed();
+        moved
     }
 
     async fn maintain_enclosure(&mut self) -> Result<()> {
@@ -434,6 +761,10 @@ impl ServerState {
         Ok(())
     }
     async fn sync_screen(&mut self) -> Result<()> {
+        if !self.follow_presenter().await {
+            log::trace!("No presenter to follow, nothing to show the spectator");
+            return Ok(());
+        }
         //        self.send_simple_test_shaped_string().await?;
         let mut compositor = self.state_data.compositor.take();
         {
@@ -471,30 +802,68 @@ impl ServerState {
         .unwrap();
         self.state_data.compositor = compositor;
         self.maintain_enclosure().await?;
-        self.state_data
-            .compositor
-            .as_mut()
-            .unwrap()
-            .transfer_messages_to_client(&mut self.channel_tx)
+        /* The document shown, or its modified state, may have changed */
+        let window_title = self.window_title().await;
+        if window_title.is_some() && window_title != self.window_title {
+            self.window_title = window_title.clone();
+            self.window_controls
+                .push(WindowControl::SetTitle(window_title.unwrap()));
+        }
+        let compositor = self.state_data.compositor.as_mut().unwrap();
+        for control in self.window_controls.drain(..) {
+            compositor
+                .transfer_buffer_scratch
+                .as_mut()
+                .unwrap()
+                .add_message(HelicoidToClientMessage::WindowControl(control));
+        }
+        compositor
+            .transfer_messages_to_client(
+                &mut self.channel_tx,
+                &self.capabilities,
+                self.latest_input_timestamp,
+            )
             .await?;
         Ok(())
     }
 
     async fn editor_updated(&mut self) -> Result<()> {
-        let _editor = self
-            .state_data
-            .compositor
-            .as_mut()
-            .unwrap()
-            .content_visitor
-            .editor()
-            .lock();
-        /* Assess if the update is relevant for the client represented by this server state,
-        update internal shadow state and send any relevant updates to the client
-        (after unlocking the editor)*/
+        /* The update may come from any client (including this one). Syncing the screen
+        updates the shadow state of this client, and only the blocks that changed are sent
+        to the client. Nothing is shown before the viewport of the client is known. */
+        if self.viewport_size.is_none() {
+            return Ok(());
+        }
+        /* The changes are picked up by the next sync, as the shadow state is only updated
+        when syncing */
+        if self.send_queue.unsent() >= MAX_UNSENT_FRAMES {
+            log::trace!("Client is behind, deferring sync");
+            self.sync_deferred = true;
+            return Ok(());
+        }
+        self.sync_deferred = false;
+        self.sync_screen().await?;
         Ok(())
     }
 }
+impl Drop for ServerStateData {
+    fn drop(&mut self) {
+        /* Close the view of the client when its state is dropped (the connection is closed
+        and the session is not kept), the other clients keep their views */
+        let (Some(compositor), Some(view_id)) = (self.compositor.as_ref(), self.own_view) else {
+            return;
+        };
+        let editor = compositor.content_visitor.editor().clone();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                let mut editor = editor.lock().await;
+                editor.close_view(view_id);
+                /* Spectators following the view have to find another one */
+                editor.notify_changed();
+            });
+        }
+    }
+}
 impl EditorEnclosure {
     pub async fn insert_message(&mut self, transfer_buffer: &mut TransferBuffer) -> Result<()> {
         let newblock = NewRenderBlock {
@@ -514,13 +883,13 @@ impl EditorEnclosure {
 impl TcpBridgeServerConnectionState for ServerState {
     type StateData = ServerStateData;
     async fn new_state(
-        peer_address: SocketAddr,
+        connection_info: ConnectionInfo,
         channel_tx: Sender<Arc<TransferBuffer>>,
         channel_rx: Receiver<TcpBridgeToServerMessage>,
         close_rx: BReceiver<()>,
-        state_data: Self::StateData,
+        mut state_data: Self::StateData,
     ) -> Self {
-        let editor_update_rx = {
+        let (editor_update_rx, clipboard) = {
             let inner_editor_locked = state_data
                 .compositor
                 .as_ref()
@@ -529,24 +898,85 @@ impl TcpBridgeServerConnectionState for ServerState {
                 .editor()
                 .clone();
 
-            let inner_editor = inner_editor_locked.lock().await;
-            inner_editor.update_receiver()
+            let mut inner_editor = inner_editor_locked.lock().await;
+            match connection_info.client_hello.role {
+                ClientRole::Editor => {
+                    if let Some(view_id) = state_data.own_view {
+                        inner_editor.add_presenter_view(connection_info.session_id, view_id);
+                    }
+                }
+                ClientRole::Spectator { .. } => {
+                    /* The view of a presenter is shown instead, see follow_presenter */
+                    if let Some(view_id) = state_data.own_view.take() {
+                        inner_editor.close_view(view_id);
+                    }
+                    state_data.compositor.as_mut().unwrap().follow_view(None);
+                    log::info!(
+                        "Client at {} connected as spectator",
+                        connection_info.peer_address
+                    );
+                }
+            }
+            (
+                inner_editor.update_receiver(),
+                inner_editor.clipboard().clone(),
+            )
         };
+        let clipboard_rx = clipboard.subscribe();
         Self {
             _pending_message: None,
-            _peer_address: peer_address,
+            _peer_address: connection_info.peer_address,
             channel_tx,
             channel_rx,
             close_rx,
             state_data,
             editor_update_rx,
+            capabilities: connection_info.capabilities,
+            role: connection_info.client_hello.role.clone(),
+            resume: connection_info
+                .resumed
+                .then_some(connection_info.client_hello.resume)
+                .flatten(),
+            latest_input_timestamp: None,
+            send_queue: connection_info.send_queue,
+            sync_deferred: false,
+            payloads: PayloadAssembler::default(),
+            payload_tx: connection_info.payload_tx,
+            clipboard,
+            clipboard_rx,
+            window_title: None,
+            cursor_shape: CursorShape::Default,
+            window_controls: Vec::new(),
             viewport_size: None,
         }
     }
     async fn initialize(&mut self) -> Result<()> {
+        if let Some(resume) = self.resume.take() {
+            let held = HeldBlockSet::new(&resume.held_blocks);
+            log::debug!("Resuming session, the client holds {} blocks", held.len());
+            self.state_data
+                .compositor
+                .as_mut()
+                .unwrap()
+                .reconcile_client_blocks(&held);
+            /* The enclosure is cheap to resend, so it is always sent on resumption */
+            self.state_data.enclosure = None;
+        }
+        /* Tell the client which fonts the text is shaped with, before any text is sent */
+        let fonts = self.state_data.served_fonts.descriptions().to_vec();
+        self.send_message(HelicoidToClientMessage::Fonts(fonts))
+            .await?;
+        /* Get the clipboard of the client, so it can be pasted from the start */
+        if !self.is_spectator() {
+            self.request_clipboard(ClipboardSelection::Clipboard)
+                .await?;
+            self.request_clipboard(ClipboardSelection::Selection)
+                .await?;
+        }
         Ok(())
     }
     async fn event_loop(&mut self) -> Result<()> {
+        let send_queue = self.send_queue.clone();
         loop {
             tokio::select! {
                 client_message = self.channel_rx.recv() =>{
@@ -558,6 +988,18 @@ impl TcpBridgeServerConnectionState for ServerState {
                 _editor_message = self.editor_update_rx.recv() =>{
                     self.editor_updated().await?
                 }
+                _drained = send_queue.drained(), if self.sync_deferred =>{
+                    self.editor_updated().await?
+                }
+                clipboard_message = self.clipboard_rx.recv() =>{
+                    match clipboard_message {
+                        Ok(message) => self.handle_clipboard_message(message).await?,
+                        Err(RecvError::Lagged(missed)) => {
+                            log::debug!("Missed {} clipboard messages", missed);
+                        }
+                        Err(RecvError::Closed) => {}
+                    }
+                }
                 _close_message = self.close_rx.recv() =>{
                     break;
                 }
@@ -565,6 +1007,9 @@ impl TcpBridgeServerConnectionState for ServerState {
         }
         Ok(())
     }
+    fn suspend(self) -> Option<Self::StateData> {
+        Some(self.state_data)
+    }
 }
 
 impl Compositor {
@@ -590,14 +1035,49 @@ impl Compositor {
     async fn transfer_messages_to_client(
         &mut self,
         channel_tx: &mut Sender<Arc<TransferBuffer>>,
+        capabilities: &Capabilities,
+        input_timestamp: Option<u32>,
     ) -> anyhow::Result<()> {
         log::trace!("Send message to client: {:?}", self.transfer_buffer_scratch);
-        let transfer_buffer = self.transfer_buffer_scratch.take().unwrap();
+        let mut transfer_buffer = self.transfer_buffer_scratch.take().unwrap();
+        transfer_buffer.retain_supported(capabilities);
+        transfer_buffer.set_input_timestamp(input_timestamp);
         let send_buffer = Arc::new(transfer_buffer);
         channel_tx.send(send_buffer.clone()).await?;
         self.lent_out_buffer_scratch = Some(send_buffer);
         Ok(())
     }
+    /* The mouse cursor for a position in the window: a text cursor over the text area, and
+    the regular one elsewhere (like over the gutters and the status line) */
+    fn cursor_shape_at(&self, position: PointF32) -> CursorShape {
+        let over_text = self
+            .containers
+            .values()
+            .any(|tree| tree.text_area_contains(position));
+        if over_text {
+            CursorShape::Text
+        } else {
+            CursorShape::Default
+        }
+    }
+    fn current_view_id(&self) -> Option<ViewId> {
+        self.containers
+            .get(&RenderBlockId(CONTAINER_IDS_BASE))
+            .and_then(|tree| tree.current_view_id())
+    }
+    fn follow_view(&mut self, view_id: Option<ViewId>) {
+        for (_id, tree) in self.containers.iter_mut() {
+            tree.set_view_id(view_id);
+        }
+    }
+    fn reconcile_client_blocks(&mut self, held: &HeldBlockSet) {
+        for (_id, tree) in self.containers.iter_mut() {
+            tree.reconcile_client_blocks(
+                &RenderBlockPath::new(smallvec![RenderBlockId(ENCLOSURE_ID)]),
+                held,
+            );
+        }
+    }
     pub fn containers(&self) -> &HashMap<RenderBlockId, EditorTree> {
         &self.containers
     }
@@ -0,0 +1,180 @@
+/* Headless client that connects to a helicoid server, tells it the size of an imaginary
+viewport and prints the block tree it receives. Useful for debugging the layout made by the
+server without a GPU, e.g. in CI. */
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use helicoid_protocol::{
+    auth::AuthKey,
+    block_manager::Manager,
+    bridge_logic::TcpBridgeToServerMessage,
+    caching_shaper::base_asset_path,
+    gfx::{HelicoidToClientMessage, RenderBlockId},
+    input::{HelicoidToServerMessage, ViewportInfo},
+    null_gfx::{NullGfx, NullManagerGfx},
+    tcp_bridge_async::{ClientBridgeConfig, ClientTcpBridge},
+    tls::ClientTlsConfig,
+};
+use ordered_float::OrderedFloat;
+use tree::{dump_tree, GlyphDecoder};
+
+mod tree;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct CommandLineArguments {
+    /// Address of editor server, either host:port or unix:/path/to/socket
+    #[arg(short, long, default_value = "127.0.0.1:15566")]
+    pub server_address: String,
+    /// Width of the viewport reported to the server, in physical pixels
+    #[arg(long, default_value_t = 1024)]
+    pub width: u32,
+    /// Height of the viewport reported to the server, in physical pixels
+    #[arg(long, default_value_t = 768)]
+    pub height: u32,
+    /// Scale factor of the viewport reported to the server
+    #[arg(long, default_value_t = 1.0)]
+    pub scale_factor: f32,
+    /// Number of complete frames to receive before printing the tree
+    #[arg(long, default_value_t = 1)]
+    pub frames: usize,
+    /// Seconds to wait for the frames, the tree received so far is printed when it expires
+    #[arg(long, default_value_t = 10)]
+    pub timeout: u64,
+    /// Fonts used by the server, in order of family id, used to show the text of the text blocks
+    #[arg(long, default_values_t = [String::from("FiraCodeNerdFont-Regular")])]
+    pub font: Vec<String>,
+    /// Directory containing the fonts
+    #[arg(long)]
+    pub font_dir: Option<PathBuf>,
+    /// PEM file with the certificate(s) to trust for the server, enables TLS when set
+    #[arg(long)]
+    pub tls_ca: Option<PathBuf>,
+    /// Name the TLS certificate of the server is issued for
+    #[arg(long, default_value = "localhost")]
+    pub tls_server_name: String,
+    /// Token to authenticate to the server with
+    #[arg(long, conflicts_with = "auth_key_file")]
+    pub auth_token: Option<String>,
+    /// File containing the key to authenticate to the server with
+    #[arg(long)]
+    pub auth_key_file: Option<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let args = CommandLineArguments::parse();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_time()
+        .enable_io()
+        .build()?;
+    let font_directory = args
+        .font_dir
+        .clone()
+        .unwrap_or_else(|| base_asset_path().join("fonts"));
+    let decoder = GlyphDecoder::load(&font_directory, &args.font);
+    let (manager, frames) = runtime.block_on(receive_tree(&args))?;
+    print!(
+        "{}",
+        dump_tree(&manager, RenderBlockId::normal(0).unwrap(), &decoder)
+    );
+    if frames < args.frames {
+        return Err(anyhow!(
+            "Received {} of {} frames within {} seconds",
+            frames,
+            args.frames,
+            args.timeout
+        ));
+    }
+    Ok(())
+}
+
+fn bridge_config(args: &CommandLineArguments) -> Result<ClientBridgeConfig> {
+    let mut config = ClientBridgeConfig::default();
+    if let Some(tls_ca) = &args.tls_ca {
+        config.tls = Some(Arc::new(ClientTlsConfig::from_pem_file(
+            tls_ca,
+            &args.tls_server_name,
+        )?));
+    }
+    if let Some(key_file) = &args.auth_key_file {
+        config.auth = Some(AuthKey::from_file(key_file)?);
+    } else if let Some(token) = &args.auth_token {
+        config.auth = Some(AuthKey::from_token(token)?);
+    }
+    Ok(config)
+}
+
+/* Applies the updates from the server until the requested number of frames is complete (or
+the timeout expires), returns the resulting tree and the number of complete frames */
+async fn receive_tree(args: &CommandLineArguments) -> Result<(Manager<NullGfx>, usize)> {
+    let (mut bridge, sender, mut receiver) =
+        ClientTcpBridge::connect_with(&args.server_address, &bridge_config(args)?).await?;
+    tokio::spawn(async move {
+        if let Err(e) = bridge.process_rxtx().await {
+            log::warn!("Connection to the server closed: {}", e);
+        }
+    });
+    sender
+        .send(TcpBridgeToServerMessage {
+            message: HelicoidToServerMessage::ViewportSizeUpdate(ViewportInfo {
+                physical_size: (args.width, args.height),
+                scale_factor: OrderedFloat(args.scale_factor),
+                container_physical_size: None,
+                container_scale_factor: None,
+            }),
+        })
+        .await
+        .map_err(|_| anyhow!("Connection closed before the viewport size was sent"))?;
+
+    let client_id = RenderBlockId::normal(0).unwrap();
+    let mut manager = Manager::new();
+    let mut gfx_manager = NullManagerGfx::default();
+    let mut pending_frame = Vec::new();
+    let mut frames = 0;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(args.timeout);
+    while frames < args.frames {
+        let message = match tokio::time::timeout_at(deadline, receiver.recv()).await {
+            Ok(Some(message)) => message,
+            Ok(None) => {
+                log::warn!("The server closed the connection");
+                break;
+            }
+            Err(_) => {
+                log::warn!("Timed out waiting for frames from the server");
+                break;
+            }
+        };
+        match message.message {
+            HelicoidToClientMessage::BlockUpdates(block_updates) => {
+                pending_frame.push(block_updates);
+            }
+            /* Like the regular client only complete frames are applied */
+            HelicoidToClientMessage::FrameComplete(_) => {
+                for block_updates in pending_frame.drain(..) {
+                    manager.handle_block_update(
+                        client_id,
+                        &block_updates.updates,
+                        &mut gfx_manager,
+                    );
+                }
+                frames += 1;
+            }
+            HelicoidToClientMessage::Hello(_) => {
+                log::warn!("Unexpected hello from server after connection setup");
+            }
+            /* The tree is all that is shown, there is no clipboard or window */
+            HelicoidToClientMessage::Keepalive(_)
+            | HelicoidToClientMessage::PayloadChunk(_)
+            | HelicoidToClientMessage::RequestClipboard(_)
+            | HelicoidToClientMessage::WindowControl(_)
+            | HelicoidToClientMessage::Fonts(_)
+            | HelicoidToClientMessage::EvictResource(_) => {}
+        }
+    }
+    Ok((manager, frames))
+}
@@ -0,0 +1,277 @@
+/* Printing of the block tree received from the server. The blocks are kept in a regular
+block manager, but with graphics types that do nothing, so no GPU (or window) is needed. */
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+
+use helicoid_protocol::{
+    block_manager::{Block, BlockContainer, InteriorBlockContainer, Manager},
+    gfx::{
+        ImageSource, RenderBlockDescription, RenderBlockId, RenderBlockLocation, RenderBlockPath,
+    },
+    null_gfx::NullGfx,
+    swash_font::SwashFont,
+    text::ShapedTextBlock,
+};
+
+/* Maps glyphs back to the characters they were shaped from, using the same fonts as the
+server. Glyphs that can not be mapped (e.g. ligatures) are shown as the replacement
+character. */
+#[derive(Debug, Default)]
+pub struct GlyphDecoder {
+    fonts: HashMap<u8, HashMap<u16, char>>,
+}
+
+impl GlyphDecoder {
+    /* Loads the fonts the server uses, the position in the list is the family id */
+    pub fn load(font_directory: &Path, font_names: &[String]) -> Self {
+        let mut decoder = Self::default();
+        for (family_id, name) in font_names.iter().enumerate() {
+            let path = font_directory.join(format!("{}.ttf", name));
+            match SwashFont::from_path(&path, 0) {
+                Some(font) => decoder.add_font(family_id as u8, &font),
+                None => log::warn!("Could not load font {:?}, its text is not decoded", path),
+            }
+        }
+        decoder
+    }
+    pub fn add_font(&mut self, family_id: u8, font: &SwashFont) {
+        let mut glyphs = HashMap::new();
+        font.as_ref().charmap().enumerate(|codepoint, glyph| {
+            if let Some(character) = char::from_u32(codepoint) {
+                /* Keep the lowest code point when several map to the same glyph */
+                glyphs.entry(glyph).or_insert(character);
+            }
+        });
+        self.fonts.insert(family_id, glyphs);
+    }
+    fn decode_glyph(&self, family_id: u8, glyph: u16) -> char {
+        self.fonts
+            .get(&family_id)
+            .and_then(|glyphs| glyphs.get(&glyph))
+            .copied()
+            .unwrap_or(char::REPLACEMENT_CHARACTER)
+    }
+    /* The spans of the block cover consecutive glyphs, and refer to the run (and with it the
+    font) the glyphs were shaped with. Glyphs not covered by a span use the first run. */
+    pub fn decode(&self, block: &ShapedTextBlock) -> String {
+        let family_of_run = |run: usize| {
+            block
+                .metadata
+                .runs
+                .get(run)
+                .map(|run| run.font_info.family_id)
+                .unwrap_or(0)
+        };
+        let mut families = Vec::with_capacity(block.glyphs.len());
+        for span in block.metadata.spans.iter() {
+            let family_id = family_of_run(span.metadata_info as usize);
+            families.extend(std::iter::repeat_n(
+                family_id,
+                span.substring_length as usize,
+            ));
+        }
+        block
+            .glyphs
+            .iter()
+            .enumerate()
+            .map(|(idx, glyph)| {
+                let family_id = families
+                    .get(idx)
+                    .copied()
+                    .unwrap_or_else(|| family_of_run(0));
+                self.decode_glyph(family_id, glyph.glyph())
+            })
+            .collect()
+    }
+}
+
+/* Prints the tree of the client, one line per block with the blocks of each container
+in the order they are rendered (by layer). Blocks that have not been given a location are
+not rendered, and are listed last. */
+pub fn dump_tree(
+    manager: &Manager<NullGfx>,
+    client_id: RenderBlockId,
+    decoder: &GlyphDecoder,
+) -> String {
+    let mut out = String::new();
+    match manager
+        .block(client_id)
+        .and_then(|block| block.meta().container())
+    {
+        Some(container) => dump_container(&mut out, container, 0, decoder),
+        None => out.push_str("(no blocks received)\n"),
+    }
+    out
+}
+
+fn dump_container(
+    out: &mut String,
+    container: &InteriorBlockContainer<NullGfx>,
+    depth: usize,
+    decoder: &GlyphDecoder,
+) {
+    let mut blocks: Vec<_> = container
+        .block_ids()
+        .map(|id| (container.block_location(id), id))
+        .collect();
+    blocks.sort_by_key(|(location, id)| {
+        (location.as_ref().map_or(u16::MAX, |l| l.layer as u16), *id)
+    });
+    for (location, id) in blocks {
+        let Some(block) = container.block(id) else {
+            continue;
+        };
+        dump_block(out, block, location.as_ref(), depth, decoder);
+        if let Some(child_container) = block.meta().container() {
+            dump_container(out, child_container, depth + 1, decoder);
+        }
+    }
+}
+
+fn dump_block(
+    out: &mut String,
+    block: &Block<NullGfx>,
+    location: Option<&RenderBlockLocation>,
+    depth: usize,
+    decoder: &GlyphDecoder,
+) {
+    let meta = block.meta();
+    let _ = write!(
+        out,
+        "{:indent$}{}",
+        "",
+        format_path(meta.parent_path(), *meta.id()),
+        indent = depth * 2
+    );
+    match location {
+        Some(location) => {
+            let _ = write!(
+                out,
+                " layer {} at ({}, {})",
+                location.layer,
+                location.location.x(),
+                location.location.y()
+            );
+        }
+        None => out.push_str(" unplaced"),
+    }
+    let _ = match meta.wire_description() {
+        Some(RenderBlockDescription::MetaBox(meta_box)) => write!(
+            out,
+            " extent {}x{} meta box{}{}",
+            meta_box.extent.x(),
+            meta_box.extent.y(),
+            if meta_box.buffered { " buffered" } else { "" },
+            meta_box
+                .alpha
+                .map(|alpha| format!(" alpha {}", alpha))
+                .unwrap_or_default()
+        ),
+        Some(RenderBlockDescription::SimpleDraw(simple_draw)) => write!(
+            out,
+            " extent {}x{} simple draw ({} elements)",
+            simple_draw.extent.x(),
+            simple_draw.extent.y(),
+            simple_draw.draw_elements.len()
+        ),
+        Some(RenderBlockDescription::ShapedTextBlock(text)) => write!(
+            out,
+            " extent {}x{} text {:?}",
+            text.extent.x(),
+            text.extent.y(),
+            decoder.decode(text)
+        ),
+        Some(RenderBlockDescription::Image(image)) => write!(
+            out,
+            " extent {}x{} image {} {:?}",
+            image.extent.x(),
+            image.extent.y(),
+            match &image.source {
+                ImageSource::Resource(name) => String::from_utf8_lossy(name).into_owned(),
+                ImageSource::Pixels(pixels) => format!("{}x{} pixels", pixels.width, pixels.height),
+            },
+            image.scaling
+        ),
+        None => write!(out, " (no contents)"),
+    };
+    out.push('\n');
+}
+
+/* Paths are printed as the ids from the top, in hex */
+fn format_path(parent: &RenderBlockPath, id: RenderBlockId) -> String {
+    let mut formatted = String::new();
+    for parent_id in parent.path().iter().chain(std::iter::once(&id)) {
+        let _ = write!(formatted, "/{:04x}", parent_id.0);
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helicoid_protocol::gfx::{
+        MetaDrawBlock, NewRenderBlock, PointF32, RemoteSingleChange, RemoteSingleChangeElement,
+    };
+    use helicoid_protocol::null_gfx::NullManagerGfx;
+    use smallvec::smallvec;
+
+    #[test]
+    fn tree_is_dumped_in_layer_order() {
+        let client_id = RenderBlockId::normal(0).unwrap();
+        let meta_box = |width: f32| {
+            RenderBlockDescription::MetaBox(MetaDrawBlock {
+                extent: PointF32::new(width, 10.0),
+                buffered: false,
+                alpha: None,
+                sub_blocks: smallvec![],
+            })
+        };
+        let location = |id: u16, layer: u8| RenderBlockLocation {
+            id: RenderBlockId(id),
+            location: PointF32::new(1.0, 2.0),
+            layer,
+        };
+        let updates = vec![
+            RemoteSingleChange {
+                parent: RenderBlockPath::top(),
+                change: RemoteSingleChangeElement::NewRenderBlocks(smallvec![
+                    NewRenderBlock {
+                        id: RenderBlockId(1),
+                        contents: meta_box(100.0),
+                        update: false,
+                    },
+                    NewRenderBlock {
+                        id: RenderBlockId(2),
+                        contents: meta_box(50.0),
+                        update: false,
+                    },
+                ]),
+            },
+            RemoteSingleChange {
+                parent: RenderBlockPath::top(),
+                change: RemoteSingleChangeElement::MoveBlockLocations(smallvec![
+                    location(1, 2),
+                    location(2, 1),
+                ]),
+            },
+            RemoteSingleChange {
+                parent: RenderBlockPath::new(smallvec![RenderBlockId(1)]),
+                change: RemoteSingleChangeElement::NewRenderBlocks(smallvec![NewRenderBlock {
+                    id: RenderBlockId(3),
+                    contents: meta_box(20.0),
+                    update: false,
+                }]),
+            },
+        ];
+        let mut manager = Manager::<NullGfx>::new();
+        manager.handle_block_update(client_id, &updates, &mut NullManagerGfx::default());
+        assert_eq!(
+            dump_tree(&manager, client_id, &GlyphDecoder::default()),
+            "/0002 layer 1 at (1, 2) extent 50x10 meta box\n\
+             /0001 layer 2 at (1, 2) extent 100x10 meta box\n\
+             \x20\x20/0001/0003 unplaced extent 20x10 meta box\n"
+        );
+    }
+}
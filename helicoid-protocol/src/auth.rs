@@ -0,0 +1,189 @@
+/* Authentication of clients before they are given access to the editor. Both ends share a
+secret (a token, or the contents of a key file). The server sends a random challenge, and the
+client answers with a HMAC-SHA256 of the challenge keyed with the secret, so the secret itself
+is never sent over the connection. The exchange uses fixed size raw messages and happens
+before any rkyv framed traffic. */
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::tcp_bridge_async::{BridgeReadHalf, BridgeWriteHalf};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const AUTH_MAGIC: [u8; 8] = *b"HCAUTH01";
+const CHALLENGE_LENGTH: usize = 32;
+const RESPONSE_LENGTH: usize = 32;
+const AUTH_ACCEPTED: u8 = 1;
+const AUTH_REJECTED: u8 = 0;
+/* The client has to answer the challenge within this time, or the connection is closed. The
+client gives up on a server that has not accepted it within the same time. */
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+pub struct AuthKey {
+    secret: Vec<u8>,
+}
+
+impl std::fmt::Debug for AuthKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthKey").finish_non_exhaustive()
+    }
+}
+
+impl AuthKey {
+    pub fn from_token(token: &str) -> Result<Self> {
+        Self::from_secret(token.trim().as_bytes().to_vec())
+    }
+    /* Uses the contents of the file as the shared secret, trailing whitespace (e.g. a
+    newline added by an editor) is ignored */
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let mut secret = std::fs::read(path)
+            .map_err(|e| anyhow!("Could not read authentication key {:?}: {}", path, e))?;
+        while secret.last().is_some_and(|c| c.is_ascii_whitespace()) {
+            secret.pop();
+        }
+        Self::from_secret(secret)
+    }
+    fn from_secret(secret: Vec<u8>) -> Result<Self> {
+        if secret.is_empty() {
+            return Err(anyhow!("The authentication key can not be empty"));
+        }
+        Ok(Self { secret })
+    }
+    /* Generates a random token (hex encoded) suitable for passing to the client on the
+    command line */
+    pub fn generate_token() -> String {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+    fn mac(&self, challenge: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key size");
+        mac.update(&AUTH_MAGIC);
+        mac.update(challenge);
+        mac
+    }
+}
+
+/* Runs the server side of the handshake, returns an error describing why the client was
+rejected if it did not prove that it knows the key. */
+pub async fn authenticate_client(
+    reader: &mut BridgeReadHalf,
+    writer: &mut BridgeWriteHalf,
+    key: &AuthKey,
+) -> Result<()> {
+    let mut challenge = [0u8; CHALLENGE_LENGTH];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    let mut greeting = [0u8; AUTH_MAGIC.len() + CHALLENGE_LENGTH];
+    greeting[..AUTH_MAGIC.len()].copy_from_slice(&AUTH_MAGIC);
+    greeting[AUTH_MAGIC.len()..].copy_from_slice(&challenge);
+    writer.write_all(&greeting).await?;
+    writer.flush().await?;
+
+    let mut response = [0u8; RESPONSE_LENGTH];
+    match tokio::time::timeout(AUTH_TIMEOUT, reader.read_exact(&mut response)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => {
+            return Err(anyhow!("Connection lost during authentication: {}", e));
+        }
+        Err(_) => {
+            return Err(anyhow!(
+                "No authentication response within {:?}",
+                AUTH_TIMEOUT
+            ));
+        }
+    }
+    if key.mac(&challenge).verify_slice(&response).is_err() {
+        /* Tell the client why the connection is closed, ignore errors as the connection
+        is dropped anyway */
+        let _ = writer.write_all(&[AUTH_REJECTED]).await;
+        let _ = writer.flush().await;
+        return Err(anyhow!("Invalid authentication response"));
+    }
+    writer.write_all(&[AUTH_ACCEPTED]).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/* Runs the client side of the handshake, a server that does not complete it within
+AUTH_TIMEOUT is given up on */
+pub async fn authenticate_to_server(
+    reader: &mut BridgeReadHalf,
+    writer: &mut BridgeWriteHalf,
+    key: &AuthKey,
+) -> Result<()> {
+    let exchange = async {
+        let mut greeting = [0u8; AUTH_MAGIC.len() + CHALLENGE_LENGTH];
+        reader.read_exact(&mut greeting).await?;
+        if greeting[..AUTH_MAGIC.len()] != AUTH_MAGIC {
+            return Err(anyhow!(
+                "The server did not send an authentication challenge"
+            ));
+        }
+        let response = key
+            .mac(&greeting[AUTH_MAGIC.len()..])
+            .finalize()
+            .into_bytes();
+        writer.write_all(&response).await?;
+        writer.flush().await?;
+        let mut status = [0u8; 1];
+        reader
+            .read_exact(&mut status)
+            .await
+            .map_err(|_| anyhow!("Connection closed by the server during authentication"))?;
+        match status[0] {
+            AUTH_ACCEPTED => Ok(()),
+            _ => Err(anyhow!("The server rejected the authentication key")),
+        }
+    };
+    tokio::time::timeout(AUTH_TIMEOUT, exchange)
+        .await
+        .map_err(|_| anyhow!("Authentication not completed within {:?}", AUTH_TIMEOUT))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn handshake(server_key: AuthKey, client_key: AuthKey) -> (Result<()>, Result<()>) {
+        let (client_stream, server_stream) = tokio::io::duplex(256);
+        let (sr, sw) = tokio::io::split(server_stream);
+        let (cr, cw) = tokio::io::split(client_stream);
+        let (mut sr, mut sw): (BridgeReadHalf, BridgeWriteHalf) = (Box::new(sr), Box::new(sw));
+        let (mut cr, mut cw): (BridgeReadHalf, BridgeWriteHalf) = (Box::new(cr), Box::new(cw));
+        tokio::join!(
+            authenticate_client(&mut sr, &mut sw, &server_key),
+            authenticate_to_server(&mut cr, &mut cw, &client_key)
+        )
+    }
+
+    #[tokio::test]
+    async fn matching_key_is_accepted() {
+        let token = AuthKey::generate_token();
+        let (server, client) = handshake(
+            AuthKey::from_token(&token).unwrap(),
+            AuthKey::from_token(&token).unwrap(),
+        )
+        .await;
+        assert!(server.is_ok());
+        assert!(client.is_ok());
+    }
+
+    #[tokio::test]
+    async fn wrong_key_is_rejected() {
+        let (server, client) = handshake(
+            AuthKey::from_token("secret").unwrap(),
+            AuthKey::from_token("guess").unwrap(),
+        )
+        .await;
+        assert!(server.is_err());
+        assert!(client.is_err());
+    }
+}
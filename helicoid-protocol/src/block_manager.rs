@@ -10,6 +10,8 @@ use crate::gfx::RenderBlockId;
 use crate::gfx::RenderBlockLocation;
 use crate::gfx::RenderBlockPath;
 use crate::gfx::SimpleDrawElement;
+use crate::session::block_content_hash;
+use crate::session::HeldBlock;
 use hashbrown::HashMap;
 use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
@@ -196,6 +198,23 @@ impl<G: BlockGfx> InteriorBlockContainer<G> {
         cblock.layer = Some(new_location.layer);
         //        cblock.location = new_location.location;
     }
+    pub fn block_ids(&self) -> impl Iterator<Item = RenderBlockId> + '_ {
+        self.blocks.keys().copied()
+    }
+    /* The location of the block, None if the block does not exist or has not been given a
+    location yet (in which case it is not rendered) */
+    pub fn block_location(&self, id: RenderBlockId) -> Option<RenderBlockLocation> {
+        let layer = self.blocks.get(&id)?.layer?;
+        self.layers
+            .get(&layer)?
+            .iter()
+            .find(|(block_id, _)| *block_id == id)
+            .map(|(_, location)| RenderBlockLocation {
+                id,
+                location: *location,
+                layer,
+            })
+    }
     fn _container_block_mut(&mut self, id: RenderBlockId) -> Option<&mut ContainerBlock<G>> {
         self.blocks.get_mut(&id).map(|b| {
             b.last_changed = b.last_changed.wrapping_add(1);
@@ -259,6 +278,7 @@ impl<BG: BlockGfx> Manager<BG> {
                                 let container = match block.contents {
                                     RenderBlockDescription::ShapedTextBlock(_) => None,
                                     RenderBlockDescription::SimpleDraw(_) => None,
+                                    RenderBlockDescription::Image(_) => None,
                                     RenderBlockDescription::MetaBox(_) => {
                                         Some(InteriorBlockContainer::new(RenderBlockPath::child(
                                             &update.parent,
@@ -352,6 +372,17 @@ impl<BG: BlockGfx> Manager<BG> {
             );
         }
     }
+    /* Lists the blocks held for the client, sent to the server when resuming a session so
+    it only has to resend what is missing */
+    pub fn held_blocks(&self, client_id: RenderBlockId) -> Vec<HeldBlock> {
+        let mut held = Vec::new();
+        if let Some(mgr_entry) = self.containers.get(&client_id) {
+            if let Some(container) = mgr_entry.meta.container.as_ref() {
+                container.collect_held_blocks(&mut held);
+            }
+        }
+        held
+    }
     pub fn block_for_path_mut(
         &mut self,
         id: RenderBlockId,
@@ -463,6 +494,7 @@ impl<BG: BlockGfx> Block<BG> {
                         let new_block_container = match block.contents {
                             RenderBlockDescription::ShapedTextBlock(_) => None,
                             RenderBlockDescription::SimpleDraw(_) => None,
+                            RenderBlockDescription::Image(_) => None,
                             RenderBlockDescription::MetaBox(_) => {
                                 Some(InteriorBlockContainer::new(RenderBlockPath::child(
                                     &update.parent,
@@ -629,6 +661,9 @@ impl<BG: BlockGfx> MetaBlock<BG> {
             }
         }
     }
+    pub fn container(&self) -> Option<&InteriorBlockContainer<BG>> {
+        self.container.as_ref()
+    }
     pub fn as_container(&self) -> Option<&dyn BlockContainer<BG>> {
         self.container
             .as_ref()
@@ -658,6 +693,23 @@ impl<BG: BlockGfx> MetaBlock<BG> {
     }
 }
 
+impl<BG: BlockGfx> InteriorBlockContainer<BG> {
+    fn collect_held_blocks(&self, held: &mut Vec<HeldBlock>) {
+        for block in self.blocks.values().filter_map(|b| b.block.as_ref()) {
+            if let Some(wire_description) = block.meta.wire_description.as_ref() {
+                held.push(HeldBlock {
+                    parent: block.meta.id.parent_path.clone(),
+                    id: block.meta.id.id,
+                    hash: block_content_hash(wire_description),
+                });
+            }
+            if let Some(container) = block.meta.container.as_ref() {
+                container.collect_held_blocks(held);
+            }
+        }
+    }
+}
+
 impl<BG: BlockGfx> BlockContainer<BG> for InteriorBlockContainer<BG> {
     fn path(&self) -> &RenderBlockPath {
         &self.path
@@ -734,7 +786,19 @@ impl<BG: BlockGfx> BlockContainer<BG> for InteriorBlockContainer<BG> {
             } else {
             }
         } else {
-            todo!("Removing multiple blocks at a time is not implemented yet")
+            /* The bits set in the mask can have any value, see RenderBlockRemoveInstruction */
+            let matching = base_id.0 & !mask_id.0;
+            let removed_ids = self
+                .blocks
+                .keys()
+                .filter(|id| id.0 & !mask_id.0 == matching)
+                .cloned()
+                .collect::<SmallVec<[RenderBlockId; 16]>>();
+            for id in removed_ids {
+                if let Some(removed) = self.blocks.remove(&id) {
+                    Self::remove_from_layer(&mut self.layers, &removed, id);
+                }
+            }
         }
     }
 
@@ -808,3 +872,177 @@ impl<BG: BlockGfx> BlockContainer<BG> for Manager<BG> {
         todo!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gfx::{
+        MetaDrawBlock, NewRenderBlock, RemoteSingleChangeElement, RenderBlockRemoveInstruction,
+    };
+    use crate::null_gfx::{RecordingGfx, RecordingManagerGfx, RenderedBlock};
+    use smallvec::smallvec;
+
+    fn meta_box(width: f32) -> RenderBlockDescription {
+        RenderBlockDescription::MetaBox(MetaDrawBlock {
+            extent: PointF32::new(width, 10.0),
+            buffered: false,
+            alpha: None,
+            sub_blocks: smallvec![],
+        })
+    }
+    fn new_blocks(parent: RenderBlockPath, ids: &[u16], update: bool) -> RemoteSingleChange {
+        RemoteSingleChange {
+            parent,
+            change: RemoteSingleChangeElement::NewRenderBlocks(
+                ids.iter()
+                    .map(|id| NewRenderBlock {
+                        id: RenderBlockId(*id),
+                        contents: meta_box(*id as f32),
+                        update,
+                    })
+                    .collect(),
+            ),
+        }
+    }
+    fn location(id: u16, layer: u8) -> RenderBlockLocation {
+        RenderBlockLocation {
+            id: RenderBlockId(id),
+            location: PointF32::new(id as f32, 0.0),
+            layer,
+        }
+    }
+    fn move_blocks(parent: RenderBlockPath, locations: &[(u16, u8)]) -> RemoteSingleChange {
+        RemoteSingleChange {
+            parent,
+            change: RemoteSingleChangeElement::MoveBlockLocations(
+                locations
+                    .iter()
+                    .map(|(id, layer)| location(*id, *layer))
+                    .collect(),
+            ),
+        }
+    }
+    fn rendered(manager: &mut Manager<RecordingGfx>, client_id: RenderBlockId) -> Vec<u16> {
+        let mut target = Vec::new();
+        manager.process_blocks_for_client(client_id, &mut target);
+        target.iter().map(|rendered| rendered.location.id.0).collect()
+    }
+
+    #[test]
+    fn blocks_are_rendered_in_layer_order() {
+        let client_id = RenderBlockId::normal(0).unwrap();
+        let child_path = RenderBlockPath::new(smallvec![RenderBlockId(1)]);
+        let mut manager = Manager::new();
+        let mut gfx_manager = RecordingManagerGfx::default();
+        manager.handle_block_update(
+            client_id,
+            &vec![
+                new_blocks(RenderBlockPath::top(), &[1, 2, 3], false),
+                move_blocks(RenderBlockPath::top(), &[(1, 2), (2, 0), (3, 1)]),
+                new_blocks(child_path.clone(), &[4], false),
+                move_blocks(child_path.clone(), &[(4, 0)]),
+            ],
+            &mut gfx_manager,
+        );
+        assert_eq!(gfx_manager.created.len(), 4);
+        let mut target = Vec::new();
+        manager.process_blocks_for_client(client_id, &mut target);
+        assert_eq!(
+            target.iter().map(|r| r.location.id.0).collect::<Vec<_>>(),
+            vec![2, 3, 1, 4]
+        );
+        /* The child is rendered with its parent as part of rendering the parent */
+        assert_eq!(
+            target[3],
+            RenderedBlock {
+                parent: child_path,
+                location: location(4, 0),
+            }
+        );
+        /* Moving a block to another layer changes the order */
+        manager.handle_block_update(
+            client_id,
+            &vec![move_blocks(RenderBlockPath::top(), &[(2, 3)])],
+            &mut gfx_manager,
+        );
+        assert_eq!(rendered(&mut manager, client_id), vec![3, 1, 4, 2]);
+    }
+
+    #[test]
+    fn removed_blocks_are_not_rendered() {
+        let client_id = RenderBlockId::normal(0).unwrap();
+        let mut manager = Manager::new();
+        let mut gfx_manager = RecordingManagerGfx::default();
+        manager.handle_block_update(
+            client_id,
+            &vec![
+                new_blocks(RenderBlockPath::top(), &[1, 2], false),
+                move_blocks(RenderBlockPath::top(), &[(1, 0), (2, 1)]),
+                RemoteSingleChange {
+                    parent: RenderBlockPath::top(),
+                    change: RemoteSingleChangeElement::RemoveRenderBlocks(smallvec![
+                        RenderBlockRemoveInstruction {
+                            offset: RenderBlockId(1),
+                            mask: RenderBlockId(0),
+                        }
+                    ]),
+                },
+            ],
+            &mut gfx_manager,
+        );
+        assert_eq!(rendered(&mut manager, client_id), vec![2]);
+        assert_eq!(manager.held_blocks(client_id).len(), 1);
+    }
+
+    #[test]
+    fn masked_removal_removes_a_range_of_blocks() {
+        let client_id = RenderBlockId::normal(0).unwrap();
+        let mut manager = Manager::new();
+        let mut gfx_manager = RecordingManagerGfx::default();
+        manager.handle_block_update(
+            client_id,
+            &vec![
+                new_blocks(RenderBlockPath::top(), &[3, 4, 5, 7, 8], false),
+                move_blocks(
+                    RenderBlockPath::top(),
+                    &[(3, 0), (4, 1), (5, 2), (7, 3), (8, 4)],
+                ),
+                RemoteSingleChange {
+                    parent: RenderBlockPath::top(),
+                    change: RemoteSingleChangeElement::RemoveRenderBlocks(smallvec![
+                        RenderBlockRemoveInstruction {
+                            offset: RenderBlockId(4),
+                            mask: RenderBlockId(3),
+                        }
+                    ]),
+                },
+            ],
+            &mut gfx_manager,
+        );
+        /* Ids in the range that are not there (6) are skipped */
+        assert_eq!(rendered(&mut manager, client_id), vec![3, 8]);
+        assert_eq!(manager.held_blocks(client_id).len(), 2);
+    }
+
+    #[test]
+    fn updated_blocks_keep_their_location() {
+        let client_id = RenderBlockId::normal(0).unwrap();
+        let mut manager = Manager::new();
+        let mut gfx_manager = RecordingManagerGfx::default();
+        manager.handle_block_update(
+            client_id,
+            &vec![
+                new_blocks(RenderBlockPath::top(), &[1], false),
+                move_blocks(RenderBlockPath::top(), &[(1, 0)]),
+                new_blocks(RenderBlockPath::top(), &[1], true),
+            ],
+            &mut gfx_manager,
+        );
+        /* Updating the contents does not make a new gfx block */
+        assert_eq!(gfx_manager.created.len(), 1);
+        assert_eq!(rendered(&mut manager, client_id), vec![1]);
+        manager.reset(&mut gfx_manager);
+        assert_eq!(gfx_manager.resets, 1);
+        assert!(rendered(&mut manager, client_id).is_empty());
+    }
+}
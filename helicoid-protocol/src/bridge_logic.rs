@@ -1,13 +1,20 @@
 use rkyv::ser::ScratchSpace;
 use rkyv::ser::{serializers::AllocSerializer, Serializer};
+use rkyv::validation::validators::DefaultValidator;
 use rkyv::{AlignedVec, Archive, Deserialize, Serialize};
 
+use std::fmt;
 use std::io::Write;
 
 use std::marker::PhantomData;
+use std::sync::Arc;
 
+use crate::compression::{decompress_frame, ByteCounters, COMPRESSED_FLAG, PACKET_LENGTH_MASK};
 use crate::gfx::HelicoidToClientMessage;
 use crate::input::HelicoidToServerMessage;
+use crate::keepalive::Keepalive;
+use crate::payload::PayloadChunk;
+use crate::recording::{RecordDirection, SessionRecorder};
 
 use anyhow::Result;
 use bytecheck::CheckBytes;
@@ -20,7 +27,7 @@ pub struct TcpBridgeToClientMessage {
     pub message: HelicoidToClientMessage,
 }
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
-#[archive_attr(derive(Debug))]
+#[archive_attr(derive(CheckBytes, Debug))]
 pub struct TcpBridgeToServerMessage {
     pub message: HelicoidToServerMessage,
 }
@@ -32,6 +39,28 @@ pub trait SerializeWith {
         dummy_serializer: &mut D,
     ) -> Result<usize, ()>;
 }
+/* Messages that can carry the keepalive messages the bridges exchange by themselves */
+pub trait KeepaliveCarrier {
+    fn from_keepalive(keepalive: Keepalive) -> Self;
+    fn keepalive(&self) -> Option<Keepalive>;
+}
+/* Messages that can carry the chunks of the payloads sent by the bridges, see payload.rs */
+pub trait PayloadCarrier {
+    fn from_payload_chunk(chunk: PayloadChunk) -> Self;
+}
+/* Messages handed to a bridge for sending. Wire is the message type they are sent as, which
+the keepalive messages and payload chunks sent in the same direction are wrapped in. */
+pub trait BridgeSendMessage: SerializeWith {
+    type Wire: SerializeWith + KeepaliveCarrier + PayloadCarrier;
+    /* Merges a message queued after this one into it, when the connection can not keep up.
+    Returns the later message if it can not be merged, then both are sent. */
+    fn coalesce(&mut self, later: Self) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        Some(later)
+    }
+}
 #[derive(Debug, Default)]
 pub struct DummyWriter {}
 
@@ -50,7 +79,8 @@ impl SerializeWith for TcpBridgeToClientMessage {
         serializer: &mut R,
         dummy_serializer: &mut D,
     ) -> Result<usize, ()> {
-        let dummy_start_pos = dummy_serializer.serialize_value(self).map_err(|_e| ())?;
+        let dummy_start_pos = dummy_serializer.pos();
+        dummy_serializer.serialize_value(self).map_err(|_e| ())?;
         serializer
             .write(&u32::to_le_bytes(
                 (dummy_serializer.pos() - dummy_start_pos) as u32,
@@ -67,7 +97,8 @@ impl SerializeWith for TcpBridgeToServerMessage {
         serializer: &mut R,
         dummy_serializer: &mut D,
     ) -> Result<usize, ()> {
-        let dummy_start_pos = dummy_serializer.serialize_value(self).map_err(|_e| ())?;
+        let dummy_start_pos = dummy_serializer.pos();
+        dummy_serializer.serialize_value(self).map_err(|_e| ())?;
         serializer
             .write(&u32::to_le_bytes(
                 (dummy_serializer.pos() - dummy_start_pos) as u32,
@@ -77,8 +108,59 @@ impl SerializeWith for TcpBridgeToServerMessage {
     }
 }
 
+impl KeepaliveCarrier for TcpBridgeToClientMessage {
+    fn from_keepalive(keepalive: Keepalive) -> Self {
+        Self {
+            message: HelicoidToClientMessage::Keepalive(keepalive),
+        }
+    }
+    fn keepalive(&self) -> Option<Keepalive> {
+        match self.message {
+            HelicoidToClientMessage::Keepalive(keepalive) => Some(keepalive),
+            _ => None,
+        }
+    }
+}
+
+impl KeepaliveCarrier for TcpBridgeToServerMessage {
+    fn from_keepalive(keepalive: Keepalive) -> Self {
+        Self {
+            message: HelicoidToServerMessage::Keepalive(keepalive),
+        }
+    }
+    fn keepalive(&self) -> Option<Keepalive> {
+        match self.message {
+            HelicoidToServerMessage::Keepalive(keepalive) => Some(keepalive),
+            _ => None,
+        }
+    }
+}
+
+impl PayloadCarrier for TcpBridgeToClientMessage {
+    fn from_payload_chunk(chunk: PayloadChunk) -> Self {
+        Self {
+            message: HelicoidToClientMessage::PayloadChunk(chunk),
+        }
+    }
+}
+
+impl PayloadCarrier for TcpBridgeToServerMessage {
+    fn from_payload_chunk(chunk: PayloadChunk) -> Self {
+        Self {
+            message: HelicoidToServerMessage::PayloadChunk(chunk),
+        }
+    }
+}
+
+impl BridgeSendMessage for TcpBridgeToServerMessage {
+    type Wire = Self;
+}
+
 const PACKET_HEADER_LENGTH: usize = 4;
 const PACKET_HEADER_ADJUST: usize = 16 - PACKET_HEADER_LENGTH;
+/* Packets announcing a larger size than this are treated as invalid, to avoid a corrupt
+(or malicious) header making the receiver allocate huge amounts of memory */
+pub const MAX_PACKET_LENGTH: usize = 64 * 1024 * 1024;
 
 pub enum TcpBridgeReceiveState {
     WaitingForHeader,
@@ -86,8 +168,31 @@ pub enum TcpBridgeReceiveState {
     WaitingForContents,
     WaitingForContentsRead,
     WaitingForExtract,
+    /* A packet has failed validation, the stream can not be trusted anymore */
+    Invalid,
+}
+
+/* Returned when a received packet is not a valid archive of the expected message type.
+The connection should be closed when this happens, as there is no way to find the start
+of the next packet reliably. */
+#[derive(Debug)]
+pub struct InvalidPacketError {
+    pub packet_length: usize,
+    pub reason: String,
 }
 
+impl fmt::Display for InvalidPacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid packet of {} bytes received: {}",
+            self.packet_length, self.reason
+        )
+    }
+}
+
+impl std::error::Error for InvalidPacketError {}
+
 impl Default for TcpBridgeReceiveState {
     fn default() -> Self {
         TcpBridgeReceiveState::WaitingForHeader
@@ -102,11 +207,13 @@ pub struct TcpBridgeReceiveProcessor<M> {
     message_type: PhantomData<M>,
     state: TcpBridgeReceiveState,
     current_offset: usize, // NB: There are some invariants between state and current_offset
+    byte_counters: Option<Arc<ByteCounters>>,
+    recorder: Option<(Arc<SessionRecorder>, RecordDirection)>,
 }
 
 impl<M: Archive> TcpBridgeReceiveProcessor<M>
 where
-    M::Archived: Deserialize<M, rkyv::Infallible>,
+    M::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + Deserialize<M, rkyv::Infallible>,
 {
     pub fn new() -> Self {
         Self {
@@ -114,8 +221,18 @@ where
             message_type: PhantomData,
             state: Default::default(),
             current_offset: Default::default(),
+            byte_counters: None,
+            recorder: None,
         }
     }
+    /* Counts the received bytes in the supplied counters */
+    pub fn set_byte_counters(&mut self, byte_counters: Arc<ByteCounters>) {
+        self.byte_counters = Some(byte_counters);
+    }
+    /* Records the (decompressed) contents of the received frames */
+    pub fn set_recorder(&mut self, recorder: Arc<SessionRecorder>, direction: RecordDirection) {
+        self.recorder = Some((recorder, direction));
+    }
     pub fn next_read_buffer(&mut self) -> Option<&mut [u8]> {
         match self.state {
             TcpBridgeReceiveState::WaitingForHeader => {
@@ -138,7 +255,8 @@ where
             }
             TcpBridgeReceiveState::WaitingForExtract
             | TcpBridgeReceiveState::WaitingForHeaderRead
-            | TcpBridgeReceiveState::WaitingForContentsRead => None,
+            | TcpBridgeReceiveState::WaitingForContentsRead
+            | TcpBridgeReceiveState::Invalid => None,
         }
     }
     pub fn mark_data_read(&mut self, read_length: usize) {
@@ -153,6 +271,13 @@ where
                 } else {
                     TcpBridgeReceiveState::WaitingForHeader
                 };
+                /* No message serializes to nothing, an empty packet can only come from a
+                broken (or hostile) peer */
+                if self.current_offset >= PACKET_HEADER_LENGTH
+                    && (self.packet_length() == 0 || self.packet_length() > MAX_PACKET_LENGTH)
+                {
+                    self.state = TcpBridgeReceiveState::Invalid;
+                }
             }
             TcpBridgeReceiveState::WaitingForContentsRead => {
                 self.current_offset += read_length;
@@ -165,7 +290,8 @@ where
             }
             TcpBridgeReceiveState::WaitingForHeader
             | TcpBridgeReceiveState::WaitingForExtract
-            | TcpBridgeReceiveState::WaitingForContents => {}
+            | TcpBridgeReceiveState::WaitingForContents
+            | TcpBridgeReceiveState::Invalid => {}
         }
     }
     fn offsetted_message_buffer(&mut self, local_offset: usize, length: usize) -> &mut [u8] {
@@ -175,10 +301,17 @@ where
         &mut self.recv_buffer[slice_start..(slice_start + length)]
     }
     fn packet_length(&self) -> usize {
+        (self.packet_header() & PACKET_LENGTH_MASK) as usize
+    }
+    fn packet_compressed(&self) -> bool {
+        self.packet_header() & COMPRESSED_FLAG != 0
+    }
+    fn packet_header(&self) -> u32 {
         assert!(match self.state {
             TcpBridgeReceiveState::WaitingForContents
             | TcpBridgeReceiveState::WaitingForContentsRead
-            | TcpBridgeReceiveState::WaitingForExtract => true,
+            | TcpBridgeReceiveState::WaitingForExtract
+            | TcpBridgeReceiveState::Invalid => true,
             TcpBridgeReceiveState::WaitingForHeader
             | TcpBridgeReceiveState::WaitingForHeaderRead => false,
         });
@@ -186,7 +319,7 @@ where
             self.recv_buffer[PACKET_HEADER_ADJUST..(PACKET_HEADER_ADJUST + PACKET_HEADER_LENGTH)]
                 .try_into()
                 .unwrap(),
-        ) as usize
+        )
     }
 
     /* Returns true if a partial read in in progress
@@ -195,12 +328,28 @@ where
     pub fn partial_read(&self) -> bool {
         self.current_offset > 0
     }
-    pub fn transform_element(buffer: &[u8]) -> Option<M> {
-        let archived = unsafe { rkyv::archived_root::<M>(buffer) };
-        Deserialize::<M, _>::deserialize(archived, &mut rkyv::Infallible).ok()
+    /* Validates the contents of the buffer before deserializing it, as the data comes
+    straight from the network */
+    pub fn transform_element(buffer: &[u8]) -> Result<M, InvalidPacketError> {
+        let archived = rkyv::check_archived_root::<M>(buffer).map_err(|e| InvalidPacketError {
+            packet_length: buffer.len(),
+            reason: e.to_string(),
+        })?;
+        Deserialize::<M, _>::deserialize(archived, &mut rkyv::Infallible).map_err(|_| {
+            InvalidPacketError {
+                packet_length: buffer.len(),
+                reason: String::from("Deserialization failed"),
+            }
+        })
     }
-    pub fn extract_archive(&mut self) -> Option<M> {
+    /* Returns the next received message if one is complete, or an error if the received
+    data is invalid. After an error is returned the processor stays in an invalid state. */
+    pub fn extract_archive(&mut self) -> Result<Option<M>, InvalidPacketError> {
         match self.state {
+            TcpBridgeReceiveState::Invalid => Err(InvalidPacketError {
+                packet_length: self.packet_length(),
+                reason: String::from("Packet too large, or a previous packet was invalid"),
+            }),
             TcpBridgeReceiveState::WaitingForExtract => {
                 let packet_length = self.packet_length();
                 debug_assert!(
@@ -208,9 +357,43 @@ where
                         <= self.recv_buffer.len()
                 );
                 debug_assert!(PACKET_HEADER_LENGTH + packet_length <= self.current_offset);
+                let compressed = self.packet_compressed();
+                let byte_counters = self.byte_counters.clone();
+                let recorder = self.recorder.clone();
                 let element_data =
                     self.offsetted_message_buffer(PACKET_HEADER_LENGTH, packet_length);
-                let result = Self::transform_element(element_data);
+                let transformed = if compressed {
+                    decompress_frame(element_data).and_then(|decompressed| {
+                        if let Some(byte_counters) = byte_counters.as_ref() {
+                            byte_counters.add_received(
+                                PACKET_HEADER_LENGTH + decompressed.len(),
+                                PACKET_HEADER_LENGTH + packet_length,
+                            );
+                        }
+                        if let Some((recorder, direction)) = recorder.as_ref() {
+                            recorder.record(*direction, &decompressed);
+                        }
+                        Self::transform_element(&decompressed)
+                    })
+                } else {
+                    if let Some(byte_counters) = byte_counters.as_ref() {
+                        byte_counters.add_received(
+                            PACKET_HEADER_LENGTH + packet_length,
+                            PACKET_HEADER_LENGTH + packet_length,
+                        );
+                    }
+                    if let Some((recorder, direction)) = recorder.as_ref() {
+                        recorder.record(*direction, element_data);
+                    }
+                    Self::transform_element(element_data)
+                };
+                let result = match transformed {
+                    Ok(element) => element,
+                    Err(e) => {
+                        self.state = TcpBridgeReceiveState::Invalid;
+                        return Err(e);
+                    }
+                };
                 let current_element_end =
                     PACKET_HEADER_ADJUST + PACKET_HEADER_LENGTH + packet_length;
                 let current_read_data_end = PACKET_HEADER_ADJUST + self.current_offset;
@@ -226,9 +409,162 @@ where
                 } else {
                     TcpBridgeReceiveState::WaitingForHeader
                 };
-                result
+                Ok(Some(result))
             }
-            _ => None,
+            _ => Ok(None),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gfx::{
+        FrameInfo, MetaDrawBlock, NewRenderBlock, PathVerb, PointF32, RenderBlockDescription,
+        RenderBlockId, RenderBlockLocation, RenderBlockPath, SimpleDrawBlock, SimpleDrawElement,
+        SimpleDrawPath, SimplePaint,
+    };
+    use crate::transferbuffer::TransferBuffer;
+    use rkyv::ser::serializers::{AllocScratch, CompositeSerializer, WriteSerializer};
+    use smallvec::smallvec;
+
+    fn serialized_transfer_buffer() -> Vec<u8> {
+        let mut buffer = TransferBuffer::new();
+        let path = SimpleDrawPath {
+            paint: SimplePaint::new(Some(0xFF00FF00), None, Some(1.0)),
+            draw_elements: smallvec![
+                (
+                    PathVerb::Move,
+                    PointF32::new(1.0, 1.0),
+                    PointF32::default(),
+                    PointF32::default()
+                ),
+                (
+                    PathVerb::Quad,
+                    PointF32::new(2.0, 3.0),
+                    PointF32::new(4.0, 5.0),
+                    PointF32::default()
+                ),
+            ],
+        };
+        buffer.add_news(
+            &RenderBlockPath::top(),
+            &[
+                NewRenderBlock {
+                    id: RenderBlockId(1),
+                    contents: RenderBlockDescription::SimpleDraw(SimpleDrawBlock {
+                        extent: PointF32::new(10.0, 10.0),
+                        draw_elements: smallvec![SimpleDrawElement::Path(path)],
+                    }),
+                    update: true,
+                },
+                NewRenderBlock {
+                    id: RenderBlockId(2),
+                    contents: RenderBlockDescription::MetaBox(MetaDrawBlock {
+                        extent: PointF32::new(20.0, 20.0),
+                        buffered: false,
+                        alpha: None,
+                        sub_blocks: smallvec![],
+                    }),
+                    update: false,
+                },
+            ],
+        );
+        buffer.add_moves(
+            &RenderBlockPath::top(),
+            &[RenderBlockLocation {
+                id: RenderBlockId(1),
+                location: PointF32::new(3.0, 4.0),
+                layer: 1,
+            }],
+        );
+        buffer.set_input_timestamp(Some(1234));
+        let mut serializer = TBSSerializer::default();
+        let mut dummy_serializer = CompositeSerializer::new(
+            WriteSerializer::new(DummyWriter::default()),
+            AllocScratch::new(),
+            rkyv::Infallible,
+        );
+        SerializeWith::serialize(&buffer, &mut serializer, &mut dummy_serializer).unwrap();
+        serializer.into_serializer().into_inner().to_vec()
+    }
+
+    fn receive_all(data: &[u8]) -> Result<Vec<TcpBridgeToClientMessage>, InvalidPacketError> {
+        let mut processor = TcpBridgeReceiveProcessor::<TcpBridgeToClientMessage>::new();
+        let mut messages = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            if let Some(read_buffer) = processor.next_read_buffer() {
+                let length = read_buffer.len();
+                read_buffer.copy_from_slice(&data[offset..offset + length]);
+                processor.mark_data_read(length);
+                offset += length;
+            }
+            while let Some(message) = processor.extract_archive()? {
+                messages.push(message);
+            }
+        }
+        Ok(messages)
+    }
+
+    #[test]
+    fn valid_packets_are_received() {
+        let messages = receive_all(&serialized_transfer_buffer()).unwrap();
+        /* All the changes are batched in one packet, followed by the frame marker */
+        assert_eq!(messages.len(), 2);
+        match &messages[0].message {
+            HelicoidToClientMessage::BlockUpdates(block_updates) => {
+                /* The additions and then the moves */
+                assert_eq!(block_updates.updates.len(), 2);
+            }
+            other => panic!("Expected block updates, got: {:?}", other),
+        }
+        /* The frame marker echoes the input timestamp */
+        assert_eq!(
+            messages[1].message,
+            HelicoidToClientMessage::FrameComplete(FrameInfo {
+                input_timestamp: Some(1234)
+            })
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn compressed_packets_are_received() {
+        use crate::compression::{compress_frames, CompressionMethod, FrameCompression};
+        let data = serialized_transfer_buffer();
+        let compression = FrameCompression {
+            method: Some(CompressionMethod::Lz4),
+            threshold: 0,
+        };
+        let mut compressed = Vec::new();
+        compress_frames(&data, &compression, &mut compressed);
+        assert_ne!(compressed, data);
+        assert_eq!(
+            receive_all(&compressed).unwrap(),
+            receive_all(&data).unwrap()
+        );
+    }
+
+    #[test]
+    fn corrupt_packet_is_rejected() {
+        let mut data = serialized_transfer_buffer();
+        let first_length = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+        for byte in data[4..4 + first_length].iter_mut() {
+            *byte = 0xFF;
+        }
+        assert!(receive_all(&data).is_err());
+    }
+
+    #[test]
+    fn oversized_packet_is_rejected() {
+        let data = u32::to_le_bytes(u32::MAX);
+        assert!(receive_all(&data).is_err());
+    }
+
+    #[test]
+    fn empty_packet_is_rejected() {
+        let data = u32::to_le_bytes(0);
+        assert!(receive_all(&data).is_err());
+    }
+}
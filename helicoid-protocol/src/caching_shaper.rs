@@ -9,7 +9,11 @@ use crate::text::{
 use smallvec::SmallVec;
 use std::env;
 
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use parking_lot::{RwLock, RwLockUpgradableReadGuard};
 
@@ -48,6 +52,20 @@ pub fn base_asset_path() -> PathBuf {
         .unwrap()
         .join("assets")
 }
+/* The file a font name is loaded from */
+fn font_file_path(base_directory: &Path, family_name: &str) -> PathBuf {
+    base_directory
+        .join("fonts")
+        .join(format!("{}.ttf", family_name))
+}
+/* The contents of the font file used for a font name, which is the default font when there is
+no such file (like when the font is loaded for shaping) */
+pub fn font_file_data(base_directory: &Path, family_name: &str) -> Vec<u8> {
+    std::fs::read(font_file_path(base_directory, family_name)).unwrap_or_else(|e| {
+        trace!("Using the default font for {}: {}", family_name, e);
+        DEFAULT_FONT.to_vec()
+    })
+}
 #[derive(new, Clone, Hash, PartialEq, Eq, Debug)]
 struct ShapeKey {
     pub text: String,
@@ -143,6 +161,16 @@ impl CachingShaper {
             .filter_map(|s| s.as_ref().map(|s| s.clone()))
             .collect()
     }
+    /* The font names set, with their family ids */
+    pub fn font_keys(&self) -> Vec<(u8, String)> {
+        let inner = self.inner.read();
+        inner
+            .font_names
+            .iter()
+            .enumerate()
+            .filter_map(|(id, name)| name.as_ref().map(|name| (id as u8, name.clone())))
+            .collect()
+    }
     pub fn set_font_key(&mut self, font_id: u8, font_name: String) {
         let mut inner = self.inner.write();
         if font_id as usize >= inner.font_names.len() {
@@ -601,9 +629,7 @@ impl KeyedSwashFont {
         //        let font_style = font_style(font_key.bold, font_key.italic);
         if let Some(family_name) = &name {
             trace!("KSFLoading font {:?}", name);
-            let font_file_path = base_directory
-                .join("fonts")
-                .join(format!("{}.ttf", family_name));
+            let font_file_path = font_file_path(base_directory, family_name);
             //            let typeface = font_manager.match_family_style(family_name, font_style)?;
             let res = SwashFont::from_path(&font_file_path, 0)
                 .map(|font| KeyedSwashFont::new_string(name.clone(), font));
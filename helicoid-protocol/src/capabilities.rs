@@ -0,0 +1,131 @@
+/* Protocol version and capabilities exchanged when a client connects.
+
+The protocol version is exchanged in a small fixed size preamble before any rkyv encoded
+messages, as the layout of the archives can change between versions and can not be
+trusted to be read correctly by a peer built from a different version. After the versions
+are known to match, the client sends its capabilities in a ClientHello, and the server
+answers with the negotiated capabilities (those supported by both ends) in a ServerHello. */
+
+use bytecheck::CheckBytes;
+use num_enum::IntoPrimitive;
+use rkyv::{Archive, Deserialize, Serialize};
+
+use crate::compression::CompressionMethod;
+use crate::session::{SessionId, SessionResume};
+
+/* Increase this every time the wire format (the framing, or the layout of any of the
+messages) changes */
+pub const PROTOCOL_VERSION: u32 = 12;
+
+/* The kinds of render blocks (variants of RenderBlockDescription) a client can display */
+#[derive(Debug, Hash, Eq, Clone, Copy, PartialEq, IntoPrimitive)]
+#[repr(u8)]
+pub enum BlockKind {
+    ShapedText,
+    SimpleDraw,
+    MetaBox,
+    Image,
+}
+
+#[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize, CheckBytes)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct Capabilities {
+    /* Bitmask of supported block kinds, indexed by BlockKind */
+    pub block_kinds: u32,
+    /* Bitmask of supported frame compression methods, indexed by CompressionMethod */
+    pub compression: u32,
+    /* If raster images can be displayed */
+    pub images: bool,
+}
+
+/* What a client does on the shared editor. Spectators are shown the view of a presenter
+(an editing client), scaled to their own viewport, and can not change anything. */
+#[derive(Debug, Hash, Eq, Clone, PartialEq, Default, Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub enum ClientRole {
+    #[default]
+    Editor,
+    Spectator {
+        /* The session of the presenter to follow, any presenter is followed if not set */
+        presenter: Option<SessionId>,
+    },
+}
+
+#[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize, CheckBytes)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct ClientHello {
+    pub capabilities: Capabilities,
+    /* Set when reconnecting to resume the session of an earlier connection */
+    pub resume: Option<SessionResume>,
+    pub role: ClientRole,
+}
+
+#[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize, CheckBytes)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct ServerHello {
+    /* The capabilities supported by both the client and the server, the server will only
+    use these for the rest of the connection */
+    pub capabilities: Capabilities,
+    /* Id the client can use to resume the session if the connection is lost */
+    pub session_id: SessionId,
+    /* True if the session the client asked to resume was found, the server then only sends
+    the blocks the client does not already hold. Otherwise the client should drop what it
+    holds, as it starts over with a new session. */
+    pub resumed: bool,
+}
+
+impl BlockKind {
+    fn mask(self) -> u32 {
+        1 << u8::from(self)
+    }
+}
+
+impl Capabilities {
+    /* No capabilities, a peer with this set can not display anything */
+    pub fn none() -> Self {
+        Self {
+            block_kinds: 0,
+            compression: 0,
+            images: false,
+        }
+    }
+    pub fn with_block_kind(mut self, kind: BlockKind) -> Self {
+        self.block_kinds |= kind.mask();
+        self
+    }
+    pub fn supports_block_kind(&self, kind: BlockKind) -> bool {
+        self.block_kinds & kind.mask() != 0
+    }
+    pub fn with_compression(mut self, method: CompressionMethod) -> Self {
+        self.compression |= method.mask();
+        self
+    }
+    pub fn supports_compression(&self, method: CompressionMethod) -> bool {
+        self.compression & method.mask() != 0
+    }
+    /* Returns the capabilities supported by both self and other */
+    pub fn intersection(&self, other: &Capabilities) -> Capabilities {
+        Capabilities {
+            block_kinds: self.block_kinds & other.block_kinds,
+            compression: self.compression & other.compression,
+            images: self.images && other.images,
+        }
+    }
+}
+
+/* The default capabilities are everything this version of the protocol implementation
+supports */
+impl Default for Capabilities {
+    fn default() -> Self {
+        let capabilities = Self::none()
+            .with_block_kind(BlockKind::ShapedText)
+            .with_block_kind(BlockKind::SimpleDraw)
+            .with_block_kind(BlockKind::MetaBox)
+            .with_block_kind(BlockKind::Image);
+        #[cfg(feature = "compression")]
+        let capabilities = capabilities
+            .with_compression(CompressionMethod::Lz4)
+            .with_compression(CompressionMethod::Zstd);
+        capabilities
+    }
+}
@@ -0,0 +1,286 @@
+/* Optional compression of the frames sent over the bridge. Which methods can be used is
+negotiated through the capabilities (see capabilities.rs), and each end compresses the frames
+it sends with the preferred method supported by both ends. Frames smaller than a threshold
+are sent uncompressed as compressing them does not pay off.
+
+A compressed frame has the highest bit of the length header set, and its contents are the
+compression method (one byte) and the uncompressed length (u32, little endian) followed by
+the compressed data. */
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use rkyv::AlignedVec;
+
+use crate::bridge_logic::{InvalidPacketError, MAX_PACKET_LENGTH};
+
+pub(crate) const COMPRESSED_FLAG: u32 = 1 << 31;
+pub(crate) const PACKET_LENGTH_MASK: u32 = !COMPRESSED_FLAG;
+const COMPRESSED_HEADER_LENGTH: usize = 5;
+/* Frames with less contents than this are not compressed by default */
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 256;
+#[cfg(feature = "compression")]
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Hash, Eq, Clone, Copy, PartialEq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum CompressionMethod {
+    Lz4,
+    Zstd,
+}
+
+/* Local settings for compression, the methods are negotiated while the threshold is up
+to each end */
+#[derive(Debug, Clone)]
+pub struct CompressionOptions {
+    pub threshold: usize,
+}
+
+/* The compression used when sending frames on a connection */
+#[derive(Debug, Clone, Default)]
+pub struct FrameCompression {
+    pub method: Option<CompressionMethod>,
+    pub threshold: usize,
+}
+
+/* Byte counters for a connection, the wire counters count the bytes actually sent /
+received (after compression), while the payload counters count the bytes before
+compression. The difference shows how much the compression saves. */
+#[derive(Debug, Default)]
+pub struct ByteCounters {
+    sent_payload: AtomicU64,
+    sent_wire: AtomicU64,
+    received_payload: AtomicU64,
+    received_wire: AtomicU64,
+}
+
+impl CompressionMethod {
+    /* Methods in order of preference when several are supported by both ends */
+    const PREFERENCE: [CompressionMethod; 2] = [CompressionMethod::Zstd, CompressionMethod::Lz4];
+
+    pub(crate) fn mask(self) -> u32 {
+        1 << u8::from(self)
+    }
+    /* Picks the preferred method among the ones in the (negotiated) mask */
+    pub fn preferred(mask: u32) -> Option<CompressionMethod> {
+        Self::PREFERENCE
+            .iter()
+            .copied()
+            .find(|method| mask & method.mask() != 0)
+    }
+    #[cfg(feature = "compression")]
+    fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            CompressionMethod::Lz4 => Some(lz4_flex::block::compress(data)),
+            CompressionMethod::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok(),
+        }
+    }
+    /* Without the compression feature no methods are advertised, so frames are never
+    compressed (and no compressed frames should be received) */
+    #[cfg(not(feature = "compression"))]
+    fn compress(self, _data: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+    #[cfg(feature = "compression")]
+    fn decompress(self, data: &[u8], uncompressed_length: usize) -> Result<Vec<u8>, String> {
+        let decompressed = match self {
+            CompressionMethod::Lz4 => {
+                lz4_flex::block::decompress(data, uncompressed_length).map_err(|e| e.to_string())?
+            }
+            CompressionMethod::Zstd => {
+                zstd::bulk::decompress(data, uncompressed_length).map_err(|e| e.to_string())?
+            }
+        };
+        if decompressed.len() != uncompressed_length {
+            return Err(format!(
+                "Decompressed to {} bytes, expected {}",
+                decompressed.len(),
+                uncompressed_length
+            ));
+        }
+        Ok(decompressed)
+    }
+    #[cfg(not(feature = "compression"))]
+    fn decompress(self, _data: &[u8], _uncompressed_length: usize) -> Result<Vec<u8>, String> {
+        Err(format!(
+            "{:?} compression is not supported by this build",
+            self
+        ))
+    }
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+}
+
+impl FrameCompression {
+    pub fn negotiated(compression_mask: u32, options: &CompressionOptions) -> Self {
+        Self {
+            method: CompressionMethod::preferred(compression_mask),
+            threshold: options.threshold,
+        }
+    }
+}
+
+impl ByteCounters {
+    pub fn sent_payload(&self) -> u64 {
+        self.sent_payload.load(Ordering::Relaxed)
+    }
+    pub fn sent_wire(&self) -> u64 {
+        self.sent_wire.load(Ordering::Relaxed)
+    }
+    pub fn received_payload(&self) -> u64 {
+        self.received_payload.load(Ordering::Relaxed)
+    }
+    pub fn received_wire(&self) -> u64 {
+        self.received_wire.load(Ordering::Relaxed)
+    }
+    pub(crate) fn add_sent(&self, payload: usize, wire: usize) {
+        self.sent_payload
+            .fetch_add(payload as u64, Ordering::Relaxed);
+        self.sent_wire.fetch_add(wire as u64, Ordering::Relaxed);
+    }
+    pub(crate) fn add_received(&self, payload: usize, wire: usize) {
+        self.received_payload
+            .fetch_add(payload as u64, Ordering::Relaxed);
+        self.received_wire.fetch_add(wire as u64, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for ByteCounters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent {} bytes ({} uncompressed), received {} bytes ({} uncompressed)",
+            self.sent_wire(),
+            self.sent_payload(),
+            self.received_wire(),
+            self.received_payload()
+        )
+    }
+}
+
+/* Compresses the frames in a buffer of serialized (length prefixed) frames into out. Frames
+below the threshold, or that do not get smaller, are copied as they are. */
+pub(crate) fn compress_frames(frames: &[u8], compression: &FrameCompression, out: &mut Vec<u8>) {
+    out.clear();
+    let method = match compression.method {
+        Some(method) => method,
+        None => {
+            out.extend_from_slice(frames);
+            return;
+        }
+    };
+    let mut offset = 0;
+    while offset + 4 <= frames.len() {
+        let length = u32::from_le_bytes(frames[offset..offset + 4].try_into().unwrap()) as usize;
+        let contents = &frames[offset + 4..offset + 4 + length];
+        let compressed = if length >= compression.threshold {
+            method
+                .compress(contents)
+                .filter(|c| c.len() + COMPRESSED_HEADER_LENGTH < length)
+        } else {
+            None
+        };
+        match compressed {
+            Some(compressed) => {
+                let wire_length = (compressed.len() + COMPRESSED_HEADER_LENGTH) as u32;
+                out.extend_from_slice(&(wire_length | COMPRESSED_FLAG).to_le_bytes());
+                out.push(method.into());
+                out.extend_from_slice(&(length as u32).to_le_bytes());
+                out.extend_from_slice(&compressed);
+            }
+            None => {
+                out.extend_from_slice(&frames[offset..offset + 4 + length]);
+            }
+        }
+        offset += 4 + length;
+    }
+    debug_assert_eq!(offset, frames.len());
+}
+
+/* Decompresses the contents of a compressed frame (without the length header) into an
+aligned buffer that the archive can be read from */
+pub(crate) fn decompress_frame(contents: &[u8]) -> Result<AlignedVec, InvalidPacketError> {
+    let invalid = |reason: String| InvalidPacketError {
+        packet_length: contents.len(),
+        reason,
+    };
+    if contents.len() < COMPRESSED_HEADER_LENGTH {
+        return Err(invalid(String::from("Compressed frame is too short")));
+    }
+    let method = CompressionMethod::try_from(contents[0])
+        .map_err(|_| invalid(format!("Unknown compression method: {}", contents[0])))?;
+    let uncompressed_length = u32::from_le_bytes(contents[1..5].try_into().unwrap()) as usize;
+    if uncompressed_length > MAX_PACKET_LENGTH {
+        return Err(invalid(format!(
+            "Uncompressed frame too large: {} bytes",
+            uncompressed_length
+        )));
+    }
+    let decompressed = method
+        .decompress(&contents[COMPRESSED_HEADER_LENGTH..], uncompressed_length)
+        .map_err(invalid)?;
+    let mut aligned = AlignedVec::with_capacity(decompressed.len());
+    aligned.extend_from_slice(&decompressed);
+    Ok(aligned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "compression")]
+    fn frame(contents: &[u8]) -> Vec<u8> {
+        let mut frame = (contents.len() as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(contents);
+        frame
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn frames_are_compressed_above_threshold() {
+        let small = frame(&[1u8; 16]);
+        let large_contents = [7u8; 1024];
+        let mut frames = small.clone();
+        frames.extend_from_slice(&frame(&large_contents));
+        for method in [CompressionMethod::Lz4, CompressionMethod::Zstd] {
+            let compression = FrameCompression {
+                method: Some(method),
+                threshold: 64,
+            };
+            let mut out = Vec::new();
+            compress_frames(&frames, &compression, &mut out);
+            assert!(out.len() < frames.len());
+            /* The small frame is left as it is */
+            assert_eq!(&out[..small.len()], &small[..]);
+            let header = u32::from_le_bytes(out[small.len()..small.len() + 4].try_into().unwrap());
+            assert_ne!(header & COMPRESSED_FLAG, 0);
+            let contents = &out[small.len() + 4..];
+            assert_eq!(contents.len(), (header & PACKET_LENGTH_MASK) as usize);
+            assert_eq!(
+                &decompress_frame(contents).unwrap()[..],
+                &large_contents[..]
+            );
+        }
+    }
+
+    #[test]
+    fn preferred_method_is_negotiated() {
+        let both = CompressionMethod::Lz4.mask() | CompressionMethod::Zstd.mask();
+        assert_eq!(
+            CompressionMethod::preferred(both),
+            Some(CompressionMethod::Zstd)
+        );
+        assert_eq!(
+            CompressionMethod::preferred(CompressionMethod::Lz4.mask()),
+            Some(CompressionMethod::Lz4)
+        );
+        assert_eq!(CompressionMethod::preferred(0), None);
+    }
+}
@@ -0,0 +1,210 @@
+/* Distribution of the font files the server shapes text with. The glyphs of the shaped text
+are sent as glyph ids, which only mean something with the exact font file used by the server.
+The server announces the font file behind each family id (by name and hash of the contents),
+and the client asks for the files it does not have. The files are sent as payloads (see
+payload.rs), and kept by the client in a cache directory named by their hash, so each file
+is only transferred once even if it is used by several servers.
+Only the descriptions sent in the messages are built without the tokio feature, the fonts are
+only distributed over a connection. */
+
+use std::fmt;
+#[cfg(feature = "tokio")]
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+};
+
+#[cfg(feature = "tokio")]
+use anyhow::{anyhow, Result};
+use bytecheck::CheckBytes;
+use rkyv::{Archive, Deserialize, Serialize};
+#[cfg(feature = "tokio")]
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "tokio")]
+use crate::caching_shaper::{base_asset_path, font_file_data, CachingShaper};
+
+/* SHA-256 of the contents of a font file */
+#[derive(Hash, Eq, Clone, Copy, PartialEq, Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct FontHash(pub [u8; 32]);
+
+#[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct FontDescription {
+    pub family_id: u8,
+    /* Name of the font at the server, for logging */
+    pub name: String,
+    pub hash: FontHash,
+    /* Length of the font file */
+    pub length: u32,
+}
+
+/* The font files used by the server, by hash */
+#[cfg(feature = "tokio")]
+#[derive(Debug, Default)]
+pub struct ServedFonts {
+    descriptions: Vec<FontDescription>,
+    files: HashMap<FontHash, Arc<Vec<u8>>>,
+}
+
+/* The font files of a client, cached on disk */
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+pub struct FontStore {
+    directory: PathBuf,
+    /* The fonts announced by the server, by family id */
+    families: HashMap<u8, FontDescription>,
+    /* Fonts asked for, that have not arrived yet */
+    requested: HashSet<FontHash>,
+}
+
+#[cfg(feature = "tokio")]
+impl FontHash {
+    pub fn of(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+}
+
+impl fmt::Display for FontHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for FontHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FontHash({})", self)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl ServedFonts {
+    /* The font files behind the font names set on the shaper */
+    pub fn from_shaper(shaper: &CachingShaper) -> Self {
+        let mut fonts = Self::default();
+        for (family_id, name) in shaper.font_keys() {
+            let data = font_file_data(&base_asset_path(), &name);
+            fonts.add(family_id, name, data);
+        }
+        fonts
+    }
+    pub fn add(&mut self, family_id: u8, name: String, data: Vec<u8>) {
+        let hash = FontHash::of(&data);
+        log::debug!("Serving font {} ({} bytes) as {}", name, data.len(), hash);
+        self.descriptions.retain(|font| font.family_id != family_id);
+        self.descriptions.push(FontDescription {
+            family_id,
+            name,
+            hash,
+            length: data.len() as u32,
+        });
+        self.files.entry(hash).or_insert_with(|| Arc::new(data));
+    }
+    pub fn descriptions(&self) -> &[FontDescription] {
+        &self.descriptions
+    }
+    pub fn data(&self, hash: &FontHash) -> Option<Arc<Vec<u8>>> {
+        self.files.get(hash).cloned()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl FontStore {
+    pub fn new(directory: PathBuf) -> Self {
+        Self {
+            directory,
+            families: HashMap::new(),
+            requested: HashSet::new(),
+        }
+    }
+    fn path(&self, hash: &FontHash) -> PathBuf {
+        self.directory.join(format!("{}.ttf", hash))
+    }
+    /* Takes the fonts announced by the server, returns the ones to ask the server for */
+    pub fn announced(&mut self, fonts: &[FontDescription]) -> Vec<FontHash> {
+        let mut missing = Vec::new();
+        for font in fonts {
+            self.families.insert(font.family_id, font.clone());
+            if !self.path(&font.hash).exists() && self.requested.insert(font.hash) {
+                missing.push(font.hash);
+            }
+        }
+        missing
+    }
+    /* Stores a font file from the server in the cache, returns the family ids using it */
+    pub fn received(&mut self, hash: FontHash, data: &[u8]) -> Result<Vec<u8>> {
+        self.requested.remove(&hash);
+        if FontHash::of(data) != hash {
+            return Err(anyhow!("Received font does not match its hash {}", hash));
+        }
+        std::fs::create_dir_all(&self.directory)?;
+        /* Written under another name first, so a partially written file is never used */
+        let path = self.path(&hash);
+        let partial_path = path.with_extension("partial");
+        std::fs::write(&partial_path, data)?;
+        std::fs::rename(&partial_path, &path)?;
+        Ok(self
+            .families
+            .values()
+            .filter(|font| font.hash == hash)
+            .map(|font| font.family_id)
+            .collect())
+    }
+    /* The cached file of the font the server uses for a family, None when it is not here
+    (yet) */
+    pub fn family_file(&self, family_id: u8) -> Option<PathBuf> {
+        let font = self.families.get(&family_id)?;
+        let path = self.path(&font.hash);
+        path.exists().then_some(path)
+    }
+    /* Forgets the fonts of the server, when connecting to another one */
+    pub fn reset(&mut self) {
+        self.families.clear();
+        self.requested.clear();
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_fonts_are_requested_once_and_cached() {
+        let directory = std::env::temp_dir().join(format!("helicoid-fonts-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let mut served = ServedFonts::default();
+        served.add(0, String::from("Regular"), b"regular font".to_vec());
+        served.add(1, String::from("Same"), b"regular font".to_vec());
+        served.add(2, String::from("Bold"), b"bold font".to_vec());
+        let fonts = served.descriptions().to_vec();
+
+        let mut store = FontStore::new(directory.clone());
+        let missing = store.announced(&fonts);
+        /* The first two families use the same file */
+        assert_eq!(missing, vec![fonts[0].hash, fonts[2].hash]);
+        assert!(store.announced(&fonts).is_empty());
+        assert!(store.family_file(0).is_none());
+
+        assert!(store.received(fonts[2].hash, b"not the bold font").is_err());
+        let data = served.data(&fonts[0].hash).unwrap();
+        let mut families = store.received(fonts[0].hash, &data).unwrap();
+        families.sort();
+        assert_eq!(families, vec![0, 1]);
+        assert!(store.family_file(1).is_some());
+        assert!(store.family_file(2).is_none());
+
+        /* The cached file is used by the next connection */
+        let mut store = FontStore::new(directory.clone());
+        assert_eq!(store.announced(&fonts), vec![fonts[2].hash]);
+        assert_eq!(
+            std::fs::read(store.family_file(0).unwrap()).unwrap(),
+            b"regular font"
+        );
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
@@ -2,7 +2,13 @@ use std::hash::Hash;
 
 use crate::{
     block_manager::{Block, BlockContainer, BlockGfx},
+    capabilities::{BlockKind, ServerHello},
+    fonts::FontDescription,
+    keepalive::Keepalive,
+    payload::{ClipboardSelection, PayloadChunk},
+    resources::ResourceName,
     text::ShapedTextBlock,
+    window_control::WindowControl,
 };
 use bytecheck::CheckBytes;
 use num_enum::IntoPrimitive;
@@ -159,7 +165,7 @@ pub struct PointU16 {
 }
 
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize, IntoPrimitive)]
-#[archive_attr(derive(Debug))]
+#[archive_attr(derive(CheckBytes, Debug))]
 #[repr(u8)]
 pub enum PathVerb {
     Move,
@@ -172,7 +178,7 @@ pub enum PathVerb {
 }
 
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
-#[archive_attr(derive(Debug))]
+#[archive_attr(derive(CheckBytes, Debug))]
 pub struct SimpleDrawPath {
     pub paint: SimplePaint,
     pub draw_elements: SmallVec<[(PathVerb, PointF32, PointF32, PointF32); 16]>,
@@ -207,6 +213,7 @@ pub struct SimpleRoundRect {
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
 #[archive_attr(derive(CheckBytes, Debug))]
 pub struct SimpleSvg {
+    /* Name of an SVG or PNG resource, see resources.rs */
     pub resource_name: SmallVec<[u8; SVG_RESOURCE_NAME_LEN]>,
     pub location: PointF32,
     pub extent: PointF32,
@@ -214,7 +221,7 @@ pub struct SimpleSvg {
 }
 
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
-#[archive_attr(derive(Debug))]
+#[archive_attr(derive(CheckBytes, Debug))]
 pub enum SimpleDrawElement {
     Path(SimpleDrawPath),
     Polygon(SimpleDrawPolygon),
@@ -237,12 +244,60 @@ pub struct MetaDrawBlock {
     pub alpha: Option<u8>, // If alpha is 0, the block is skipped, otherwise only applies to buffered blocks
     pub sub_blocks: SmallVec<[RenderBlockLocation; 32]>,
 }
+/* How an image is fitted to the area it is drawn in */
+#[derive(Debug, Hash, Eq, Clone, Copy, PartialEq, Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub enum ImageScaling {
+    /* Scaled to the area, ignoring the aspect ratio */
+    Stretch,
+    /* Scaled to fit inside the area keeping the aspect ratio, centered */
+    Fit,
+    /* Scaled to cover the area keeping the aspect ratio, centered and cropped */
+    Fill,
+    /* Drawn at its own size from the top left corner, cropped to the area */
+    Original,
+}
+/* Pixels sent with the image block, 8 bit RGBA (not premultiplied) with the rows from the
+top. Suited for small images, larger images are better sent as resources. */
+#[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize, CheckBytes)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct ImagePixels {
+    pub width: u16,
+    pub height: u16,
+    pub data: Vec<u8>,
+}
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
-#[archive_attr(derive(Debug))]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub enum ImageSource {
+    /* A PNG or JPEG resource, see resources.rs */
+    Resource(ResourceName),
+    Pixels(ImagePixels),
+}
+#[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize, CheckBytes)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct ImageBlock {
+    pub source: ImageSource,
+    /* The area of the block the image is drawn in */
+    pub location: PointF32,
+    pub extent: PointF32,
+    pub scaling: ImageScaling,
+}
+/* Where an image is drawn: The part of the image (in pixels of the image) drawn to the part
+of the block */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImagePlacement {
+    pub source_origin: PointF32,
+    pub source_extent: PointF32,
+    pub destination_origin: PointF32,
+    pub destination_extent: PointF32,
+}
+#[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
 pub enum RenderBlockDescription {
     ShapedTextBlock(ShapedTextBlock),
     SimpleDraw(SimpleDrawBlock),
     MetaBox(MetaDrawBlock),
+    Image(ImageBlock),
 }
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize, CheckBytes)]
 #[archive_attr(derive(CheckBytes, Debug))]
@@ -263,6 +318,9 @@ pub struct RenderBlockLocation {
                    Blocks with same number can be rendered in any order */
 }
 
+/* Removes the blocks with ids matching offset in all the bits that are not set in mask, a mask
+of 0 removes the single block with the offset as id. E.g. offset 0x10 with mask 0x0f removes
+the blocks 0x10 to 0x1f. */
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize, CheckBytes)]
 #[archive_attr(derive(CheckBytes, Debug))]
 pub struct RenderBlockRemoveInstruction {
@@ -280,7 +338,7 @@ pub struct RemoteBoxUpdate {
 }
 
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
-#[archive_attr(derive(Debug))]
+#[archive_attr(derive(CheckBytes, Debug))]
 pub enum RemoteSingleChangeElement {
     NewRenderBlocks(SmallVec<[NewRenderBlock; 4]>),
     RemoveRenderBlocks(SmallVec<[RenderBlockRemoveInstruction; 4]>),
@@ -296,10 +354,44 @@ pub struct RemoteSingleChange {
 
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize, CheckBytes)]
 #[archive_attr(derive(CheckBytes, Debug))]
-pub struct HelicoidToClientMessage {
+pub struct BlockUpdates {
     pub updates: Vec<RemoteSingleChange>,
 }
 
+#[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize, CheckBytes)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct FrameInfo {
+    /* Timestamp (as sent by the client) of the newest input processed by the server before
+    the frame was made, lets the client measure the latency from input to presented frame */
+    pub input_timestamp: Option<u32>,
+}
+
+#[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub enum HelicoidToClientMessage {
+    /* Only sent as an answer to the client hello during connection setup */
+    Hello(ServerHello),
+    /* All the changes of a single update, in the order they have to be applied */
+    BlockUpdates(BlockUpdates),
+    /* Sent after the updates making up a frame, the client should not present a frame
+    before this is received to avoid showing a partially updated tree */
+    FrameComplete(FrameInfo),
+    /* Handled by the bridge itself, see keepalive.rs */
+    Keepalive(Keepalive),
+    /* Part of a large payload, see payload.rs */
+    PayloadChunk(PayloadChunk),
+    /* Asks the client for the contents of its clipboard, which it answers with a clipboard
+    payload. The clipboard of the client is set by sending it a clipboard payload (as the
+    contents can be large). */
+    RequestClipboard(ClipboardSelection),
+    /* Effects on the window of the client, see window_control.rs */
+    WindowControl(WindowControl),
+    /* The font files the server shapes text with, by family id, see fonts.rs */
+    Fonts(Vec<FontDescription>),
+    /* Removes a resource pushed by the server from the client, see resources.rs */
+    EvictResource(ResourceName),
+}
+
 impl SimplePaint {
     pub fn new(line_color: Option<u32>, fill_color: Option<u32>, line_width: Option<f32>) -> Self {
         Self {
@@ -326,6 +418,80 @@ impl SimplePaint {
         f32::from(self.background_blur_amount)
     }
 }
+impl RenderBlockDescription {
+    pub fn kind(&self) -> BlockKind {
+        match self {
+            RenderBlockDescription::ShapedTextBlock(_) => BlockKind::ShapedText,
+            RenderBlockDescription::SimpleDraw(_) => BlockKind::SimpleDraw,
+            RenderBlockDescription::MetaBox(_) => BlockKind::MetaBox,
+            RenderBlockDescription::Image(_) => BlockKind::Image,
+        }
+    }
+}
+impl ImagePixels {
+    /* If the data has the length given by the size */
+    pub fn is_valid(&self) -> bool {
+        self.data.len() == self.width as usize * self.height as usize * 4
+    }
+}
+impl ImageBlock {
+    /* Where an image of the given size (in pixels) is drawn in the block */
+    pub fn placement(&self, image_size: PointF32) -> ImagePlacement {
+        let (iw, ih) = (image_size.x(), image_size.y());
+        let (ew, eh) = (self.extent.x(), self.extent.y());
+        let whole_image = PointF32::new(iw, ih);
+        let centered = |w: f32, h: f32| {
+            PointF32::new(
+                self.location.x() + (ew - w) / 2.0,
+                self.location.y() + (eh - h) / 2.0,
+            )
+        };
+        if iw <= 0.0 || ih <= 0.0 {
+            return ImagePlacement {
+                source_origin: PointF32::default(),
+                source_extent: whole_image,
+                destination_origin: self.location,
+                destination_extent: PointF32::default(),
+            };
+        }
+        match self.scaling {
+            ImageScaling::Stretch => ImagePlacement {
+                source_origin: PointF32::default(),
+                source_extent: whole_image,
+                destination_origin: self.location,
+                destination_extent: self.extent,
+            },
+            ImageScaling::Fit => {
+                let scale = (ew / iw).min(eh / ih);
+                ImagePlacement {
+                    source_origin: PointF32::default(),
+                    source_extent: whole_image,
+                    destination_origin: centered(iw * scale, ih * scale),
+                    destination_extent: PointF32::new(iw * scale, ih * scale),
+                }
+            }
+            ImageScaling::Fill => {
+                let scale = (ew / iw).max(eh / ih);
+                let (sw, sh) = (ew / scale, eh / scale);
+                ImagePlacement {
+                    source_origin: PointF32::new((iw - sw) / 2.0, (ih - sh) / 2.0),
+                    source_extent: PointF32::new(sw, sh),
+                    destination_origin: self.location,
+                    destination_extent: self.extent,
+                }
+            }
+            ImageScaling::Original => {
+                let shown = PointF32::new(iw.min(ew), ih.min(eh));
+                ImagePlacement {
+                    source_origin: PointF32::default(),
+                    source_extent: shown,
+                    destination_origin: self.location,
+                    destination_extent: shown,
+                }
+            }
+        }
+    }
+}
 impl SimpleDrawElement {
     pub fn fill(paint: SimplePaint) -> Self {
         Self::Fill(SimpleFill { paint })
@@ -602,3 +768,44 @@ impl RenderBlockId {
         self.0 == Self::RELATIVE_ID
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_are_placed_by_scaling() {
+        let mut block = ImageBlock {
+            source: ImageSource::Pixels(ImagePixels {
+                width: 0,
+                height: 0,
+                data: Vec::new(),
+            }),
+            location: PointF32::new(10.0, 20.0),
+            extent: PointF32::new(100.0, 50.0),
+            scaling: ImageScaling::Fit,
+        };
+        /* A square image is fitted to the height, and centered horizontally */
+        let square = PointF32::new(200.0, 200.0);
+        let fit = block.placement(square);
+        assert_eq!(fit.source_extent, square);
+        assert_eq!(fit.destination_origin, PointF32::new(35.0, 20.0));
+        assert_eq!(fit.destination_extent, PointF32::new(50.0, 50.0));
+
+        /* and cropped to the middle half when filling the area */
+        block.scaling = ImageScaling::Fill;
+        let fill = block.placement(square);
+        assert_eq!(fill.source_origin, PointF32::new(0.0, 50.0));
+        assert_eq!(fill.source_extent, PointF32::new(200.0, 100.0));
+        assert_eq!(fill.destination_extent, block.extent);
+
+        block.scaling = ImageScaling::Original;
+        let original = block.placement(square);
+        assert_eq!(original.source_extent, PointF32::new(100.0, 50.0));
+        assert_eq!(original.destination_origin, block.location);
+
+        block.scaling = ImageScaling::Stretch;
+        let empty = block.placement(PointF32::new(0.0, 10.0));
+        assert_eq!(empty.destination_extent, PointF32::default());
+    }
+}
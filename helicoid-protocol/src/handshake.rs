@@ -0,0 +1,219 @@
+/* Connection setup: exchange of protocol versions followed by the client and server hello
+messages (see capabilities.rs). This runs on the raw stream halves before they are handed
+to the tcp bridge. */
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use bytecheck::CheckBytes;
+use rkyv::ser::serializers::{AllocScratch, CompositeSerializer, WriteSerializer};
+use rkyv::validation::validators::DefaultValidator;
+use rkyv::Infallible;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::bridge_logic::{
+    DummyWriter, SerializeWith, TBSSerializer, TcpBridgeReceiveProcessor, TcpBridgeToClientMessage,
+    TcpBridgeToServerMessage,
+};
+use crate::capabilities::{Capabilities, ClientHello, ServerHello, PROTOCOL_VERSION};
+use crate::gfx::HelicoidToClientMessage;
+use crate::input::HelicoidToServerMessage;
+use crate::session::SessionId;
+use crate::tcp_bridge_async::{BridgeReadHalf, BridgeWriteHalf, TcpBridgeReceive};
+
+const VERSION_MAGIC: [u8; 8] = *b"HELICOID";
+const VERSION_PREAMBLE_LENGTH: usize = VERSION_MAGIC.len() + 4;
+/* The client has to complete the handshake within this time, or the connection is closed */
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/* Both ends send their version before reading the version of the other end, and refuse
+to continue if they differ */
+async fn exchange_versions(
+    reader: &mut BridgeReadHalf,
+    writer: &mut BridgeWriteHalf,
+) -> Result<()> {
+    let mut preamble = [0u8; VERSION_PREAMBLE_LENGTH];
+    preamble[..VERSION_MAGIC.len()].copy_from_slice(&VERSION_MAGIC);
+    preamble[VERSION_MAGIC.len()..].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    writer.write_all(&preamble).await?;
+    writer.flush().await?;
+
+    let mut peer_preamble = [0u8; VERSION_PREAMBLE_LENGTH];
+    reader
+        .read_exact(&mut peer_preamble)
+        .await
+        .map_err(|e| anyhow!("Connection lost while exchanging protocol versions: {}", e))?;
+    if peer_preamble[..VERSION_MAGIC.len()] != VERSION_MAGIC {
+        return Err(anyhow!("The peer does not speak the helicoid protocol"));
+    }
+    let peer_version = u32::from_le_bytes(peer_preamble[VERSION_MAGIC.len()..].try_into()?);
+    if peer_version != PROTOCOL_VERSION {
+        return Err(anyhow!(
+            "Protocol version mismatch, local version: {} peer version: {}",
+            PROTOCOL_VERSION,
+            peer_version
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) async fn write_message<M: SerializeWith>(
+    writer: &mut BridgeWriteHalf,
+    message: &M,
+) -> Result<()> {
+    let mut serializer = TBSSerializer::default();
+    let mut dummy_serializer = CompositeSerializer::new(
+        WriteSerializer::new(DummyWriter::default()),
+        AllocScratch::new(),
+        Infallible,
+    );
+    message
+        .serialize(&mut serializer, &mut dummy_serializer)
+        .map_err(|_| anyhow!("Could not serialize message"))?;
+    let bytes = serializer.into_serializer().into_inner();
+    writer.write_all(&bytes).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn read_message<M>(reader: &mut BridgeReadHalf) -> Result<M>
+where
+    M: rkyv::Archive,
+    M::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + rkyv::Deserialize<M, Infallible>,
+{
+    let mut processor = TcpBridgeReceiveProcessor::new();
+    TcpBridgeReceive::read_packet(reader, &mut processor)
+        .await?
+        .ok_or_else(|| anyhow!("Connection closed during handshake"))
+}
+
+/* Runs the server side of the handshake for a new session, returns the hello of the client
+and the hello sent back to it (containing the negotiated capabilities) */
+pub async fn server_handshake(
+    reader: &mut BridgeReadHalf,
+    writer: &mut BridgeWriteHalf,
+    server_capabilities: &Capabilities,
+) -> Result<(ClientHello, ServerHello)> {
+    let client_hello = read_client_hello(reader, writer).await?;
+    let server_hello = ServerHello {
+        capabilities: client_hello.capabilities.intersection(server_capabilities),
+        session_id: SessionId::generate(),
+        resumed: false,
+    };
+    send_server_hello(writer, &server_hello).await?;
+    Ok((client_hello, server_hello))
+}
+
+/* First part of the server side of the handshake, exchanges versions and reads the hello of
+the client. The server hello is sent with send_server_hello when the server has decided what
+to answer (e.g. if the session can be resumed). */
+pub async fn read_client_hello(
+    reader: &mut BridgeReadHalf,
+    writer: &mut BridgeWriteHalf,
+) -> Result<ClientHello> {
+    let handshake = async {
+        exchange_versions(reader, writer).await?;
+        let message: TcpBridgeToServerMessage = read_message(reader).await?;
+        match message.message {
+            HelicoidToServerMessage::Hello(client_hello) => Ok(client_hello),
+            other => Err(anyhow!(
+                "Expected a hello from the client, got: {:?}",
+                other
+            )),
+        }
+    };
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| anyhow!("Handshake not completed within {:?}", HANDSHAKE_TIMEOUT))?
+}
+
+pub async fn send_server_hello(
+    writer: &mut BridgeWriteHalf,
+    server_hello: &ServerHello,
+) -> Result<()> {
+    write_message(
+        writer,
+        &TcpBridgeToClientMessage {
+            message: HelicoidToClientMessage::Hello(server_hello.clone()),
+        },
+    )
+    .await
+}
+
+/* Runs the client side of the handshake, returns the hello of the server */
+pub async fn client_handshake(
+    reader: &mut BridgeReadHalf,
+    writer: &mut BridgeWriteHalf,
+    client_hello: &ClientHello,
+) -> Result<ServerHello> {
+    exchange_versions(reader, writer).await?;
+    write_message(
+        writer,
+        &TcpBridgeToServerMessage {
+            message: HelicoidToServerMessage::Hello(client_hello.clone()),
+        },
+    )
+    .await?;
+    let message: TcpBridgeToClientMessage = read_message(reader).await?;
+    match message.message {
+        HelicoidToClientMessage::Hello(server_hello) => Ok(server_hello),
+        other => Err(anyhow!(
+            "Expected a hello from the server, got: {:?}",
+            other
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capabilities::{BlockKind, ClientRole};
+
+    fn halves(stream: tokio::io::DuplexStream) -> (BridgeReadHalf, BridgeWriteHalf) {
+        let (r, w) = tokio::io::split(stream);
+        (Box::new(r), Box::new(w))
+    }
+
+    #[tokio::test]
+    async fn capabilities_are_negotiated() {
+        let (client_stream, server_stream) = tokio::io::duplex(1024);
+        let (mut sr, mut sw) = halves(server_stream);
+        let (mut cr, mut cw) = halves(client_stream);
+        let client_hello = ClientHello {
+            capabilities: Capabilities::none().with_block_kind(BlockKind::MetaBox),
+            resume: None,
+            role: ClientRole::Editor,
+        };
+        let server_capabilities = Capabilities::default();
+        let (server, client) = tokio::join!(
+            server_handshake(&mut sr, &mut sw, &server_capabilities),
+            client_handshake(&mut cr, &mut cw, &client_hello)
+        );
+        let (received_hello, server_hello) = server.unwrap();
+        assert_eq!(received_hello, client_hello);
+        assert_eq!(client.unwrap(), server_hello);
+        assert!(server_hello
+            .capabilities
+            .supports_block_kind(BlockKind::MetaBox));
+        assert!(!server_hello
+            .capabilities
+            .supports_block_kind(BlockKind::ShapedText));
+    }
+
+    #[tokio::test]
+    async fn version_mismatch_is_rejected() {
+        let (client_stream, server_stream) = tokio::io::duplex(1024);
+        let (mut sr, mut sw) = halves(server_stream);
+        let (mut cr, mut cw) = halves(client_stream);
+        let mut preamble = [0u8; VERSION_PREAMBLE_LENGTH];
+        preamble[..VERSION_MAGIC.len()].copy_from_slice(&VERSION_MAGIC);
+        preamble[VERSION_MAGIC.len()..].copy_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+        cw.write_all(&preamble).await.unwrap();
+        let result = server_handshake(&mut sr, &mut sw, &Capabilities::default()).await;
+        assert!(result.unwrap_err().to_string().contains("version mismatch"));
+        /* The server still tells the client about its version before closing */
+        let mut server_preamble = [0u8; VERSION_PREAMBLE_LENGTH];
+        cr.read_exact(&mut server_preamble).await.unwrap();
+        assert_eq!(&server_preamble[..VERSION_MAGIC.len()], &VERSION_MAGIC);
+    }
+}
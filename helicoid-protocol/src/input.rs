@@ -1,4 +1,8 @@
 //use crate::text::ShapedTextBlock;
+use crate::capabilities::ClientHello;
+use crate::fonts::FontHash;
+use crate::keepalive::Keepalive;
+use crate::payload::PayloadChunk;
 use bytecheck::CheckBytes;
 use num_enum::IntoPrimitive;
 use ordered_float::OrderedFloat;
@@ -9,8 +13,10 @@ use smallvec::SmallVec;
 (as a wrapping u32). There is no definition of a start time, the events are
 relative to eachother in a session. */
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
-#[archive_attr(derive(Debug))]
+#[archive_attr(derive(CheckBytes, Debug))]
 pub enum HelicoidToServerMessage {
+    /* Only sent as the first message during connection setup */
+    Hello(ClientHello),
     ViewportSizeUpdate(ViewportInfo),
     KeyModifierStateUpdate(KeyModifierStateUpdateEvent),
     KeyInputEvent(ComplexKeyEvent),
@@ -19,15 +25,32 @@ pub enum HelicoidToServerMessage {
     CursorMoved(CursorMovedEvent),
     CharReceived(u32),
     Ime(ImeEvent),
-    /* Answer a request (from the editor server) for system keyboard contents.
-    Currently the answer is limited to 15kb (to fit the TCPBridge without any fuzz) */
-    ClipboardEvent(String),
+    /* Part of a large payload, e.g. the clipboard contents requested by the editor server,
+    see payload.rs */
+    PayloadChunk(PayloadChunk),
+    /* Handled by the bridge itself, see keepalive.rs */
+    Keepalive(Keepalive),
+    /* Asks for a font file the server announced, which is sent as a payload, see fonts.rs */
+    RequestFont(FontHash),
     /* It is probably desirable to report more detailed keyboard movement at a later point to
     enable as much keyboard control as possible */
     //    ExtendedKeyEvent(ExtendedKeyEvent),
 }
+impl HelicoidToServerMessage {
+    /* The timestamp of input events, None for messages without one */
+    pub fn input_timestamp(&self) -> Option<u32> {
+        match self {
+            HelicoidToServerMessage::KeyModifierStateUpdate(event) => Some(event.timestamp),
+            HelicoidToServerMessage::KeyInputEvent(event) => Some(event.timestamp),
+            HelicoidToServerMessage::KeyPressedEvent(event) => Some(event.timestamp),
+            HelicoidToServerMessage::MouseButtonStateChange(event) => Some(event.timestamp),
+            HelicoidToServerMessage::CursorMoved(event) => Some(event.timestamp),
+            _ => None,
+        }
+    }
+}
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
-#[archive_attr(derive(Debug))]
+#[archive_attr(derive(CheckBytes, Debug))]
 pub struct ViewportInfo {
     pub physical_size: (u32, u32),
     pub scale_factor: OrderedFloat<f32>,
@@ -35,13 +58,13 @@ pub struct ViewportInfo {
     pub container_scale_factor: Option<u32>,
 }
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
-#[archive_attr(derive(Debug))]
+#[archive_attr(derive(CheckBytes, Debug))]
 pub struct SimpleKeyTappedEvent {
     pub key_code: u32, /* Virutual key code, as represented in winit */
     pub timestamp: u32,
 }
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
-#[archive_attr(derive(Debug))]
+#[archive_attr(derive(CheckBytes, Debug))]
 pub struct ComplexKeyEvent {
     pub key_code: u32, /* Virutual key code, as represented in winit */
     pub timestamp: u32,
@@ -50,7 +73,7 @@ pub struct ComplexKeyEvent {
     pub synthetic: bool,
 }
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
-#[archive_attr(derive(Debug))]
+#[archive_attr(derive(CheckBytes, Debug))]
 pub struct KeyModifierStateUpdateEvent {
     pub caps_pressed: bool,
     pub lshift_pressed: bool,
@@ -66,14 +89,14 @@ pub struct KeyModifierStateUpdateEvent {
     pub timestamp: u32,
 }
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
-#[archive_attr(derive(Debug))]
+#[archive_attr(derive(CheckBytes, Debug))]
 pub struct MouseButtonStateChangeEvent {
     pub pressed: bool,
     pub button: u16,
     pub timestamp: u32,
 }
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
-#[archive_attr(derive(Debug))]
+#[archive_attr(derive(CheckBytes, Debug))]
 pub struct CursorMovedEvent {
     pub physical_position_x: OrderedFloat<f32>,
     pub physical_position_y: OrderedFloat<f32>,
@@ -83,7 +106,7 @@ pub struct CursorMovedEvent {
 /* See winit Ime event for details, expects the strings in the smallvec to
 be utf-8 encoded, and relatively short (max len 255 bytes) */
 #[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
-#[archive_attr(derive(Debug))]
+#[archive_attr(derive(CheckBytes, Debug))]
 pub enum ImeEvent {
     Enabled,
     Preedit((SmallVec<[u8; 20]>, Option<(u8, u8)>)),
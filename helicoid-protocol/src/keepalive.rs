@@ -0,0 +1,231 @@
+/* Keepalive pings and round trip time measurement for bridge connections.
+
+Each end of a connection pings the other at a regular interval, and the pings are answered
+by the bridge of the other end (they are never passed on to the connection state or the user
+interface). The time until the answer arrives is the round trip time of the connection. When
+nothing at all is received from the other end within the timeout the connection is considered
+dead and is closed, so a half-open connection (e.g. after the network changed, or the other
+end was suspended) does not look like an idle peer forever. */
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use bytecheck::CheckBytes;
+use parking_lot::Mutex;
+use rkyv::{Archive, Deserialize, Serialize};
+
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+pub const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/* Round trip times (smoothed) below these are shown as a good / fair connection */
+const GOOD_ROUND_TRIP_TIME: Duration = Duration::from_millis(50);
+const FAIR_ROUND_TRIP_TIME: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Hash, Eq, Clone, Copy, PartialEq, Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub enum Keepalive {
+    /* Sent at the keepalive interval, the number identifies the ping */
+    Ping(u32),
+    /* Answer to the ping with the same number */
+    Pong(u32),
+}
+
+#[derive(Debug, Clone)]
+pub struct KeepaliveOptions {
+    /* Time between the pings sent to the other end */
+    pub interval: Duration,
+    /* The connection is closed when nothing is received from the other end for this long.
+    Zero disables the keepalive, then no pings are sent either. */
+    pub timeout: Duration,
+}
+
+/* Rough quality of a connection, for showing to the user */
+#[derive(Debug, Hash, Eq, Clone, Copy, PartialEq)]
+pub enum ConnectionQuality {
+    /* No round trip time is measured yet */
+    Unknown,
+    Good,
+    Fair,
+    Poor,
+}
+
+/* The round trip times measured on a connection, shared by the bridge (which updates it)
+and the users of the connection */
+#[derive(Debug)]
+pub struct LinkStatus {
+    inner: Mutex<LinkStatusInner>,
+}
+
+#[derive(Debug)]
+struct LinkStatusInner {
+    last_received: Instant,
+    next_ping: u32,
+    /* The ping waiting for its answer, and when it was sent */
+    outstanding: Option<(u32, Instant)>,
+    latest: Option<Duration>,
+    /* Exponentially weighted average of the round trip times, like TCP's SRTT */
+    smoothed: Option<Duration>,
+    min: Option<Duration>,
+    max: Option<Duration>,
+    samples: u32,
+}
+
+impl KeepaliveOptions {
+    /* No pings, and the connection is never closed for being silent */
+    pub fn disabled() -> Self {
+        Self {
+            interval: DEFAULT_KEEPALIVE_INTERVAL,
+            timeout: Duration::ZERO,
+        }
+    }
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            /* Ping often enough that several pings are lost before the timeout expires */
+            interval: DEFAULT_KEEPALIVE_INTERVAL.min(timeout / 3),
+            timeout,
+        }
+    }
+    pub fn enabled(&self) -> bool {
+        !self.timeout.is_zero() && !self.interval.is_zero()
+    }
+}
+
+impl Default for KeepaliveOptions {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_KEEPALIVE_INTERVAL,
+            timeout: DEFAULT_KEEPALIVE_TIMEOUT,
+        }
+    }
+}
+
+impl Default for LinkStatus {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(LinkStatusInner {
+                last_received: Instant::now(),
+                next_ping: 0,
+                outstanding: None,
+                latest: None,
+                smoothed: None,
+                min: None,
+                max: None,
+                samples: 0,
+            }),
+        }
+    }
+}
+
+impl LinkStatus {
+    /* Called for everything received from the other end, which proves it is alive */
+    pub fn packet_received(&self) {
+        self.inner.lock().last_received = Instant::now();
+    }
+    pub fn since_last_received(&self) -> Duration {
+        self.inner.lock().last_received.elapsed()
+    }
+    /* Returns the number of a new ping to send. A previous ping still waiting for its answer
+    is given up, so a late answer to it is not mistaken for a long round trip time. */
+    pub fn ping_sent(&self) -> u32 {
+        let mut inner = self.inner.lock();
+        let id = inner.next_ping;
+        inner.next_ping = id.wrapping_add(1);
+        inner.outstanding = Some((id, Instant::now()));
+        id
+    }
+    /* Returns the round trip time if the answer is to the outstanding ping */
+    pub fn pong_received(&self, id: u32) -> Option<Duration> {
+        let mut inner = self.inner.lock();
+        match inner.outstanding {
+            Some((outstanding, sent)) if outstanding == id => {
+                inner.outstanding = None;
+                let round_trip_time = sent.elapsed();
+                inner.add_sample(round_trip_time);
+                Some(round_trip_time)
+            }
+            _ => None,
+        }
+    }
+    /* The smoothed round trip time, None until the first ping is answered */
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.inner.lock().smoothed
+    }
+    pub fn latest_round_trip_time(&self) -> Option<Duration> {
+        self.inner.lock().latest
+    }
+    /* A ping waiting longer for its answer than the measured round trip time counts as a
+    round trip that long, so the quality drops as soon as the connection stalls */
+    pub fn quality(&self) -> ConnectionQuality {
+        let inner = self.inner.lock();
+        let waiting = inner.outstanding.map(|(_, sent)| sent.elapsed());
+        let round_trip_time = match (inner.smoothed, waiting) {
+            (Some(smoothed), Some(waiting)) => smoothed.max(waiting),
+            (Some(smoothed), None) => smoothed,
+            (None, Some(waiting)) if waiting > FAIR_ROUND_TRIP_TIME => waiting,
+            (None, _) => return ConnectionQuality::Unknown,
+        };
+        if round_trip_time < GOOD_ROUND_TRIP_TIME {
+            ConnectionQuality::Good
+        } else if round_trip_time < FAIR_ROUND_TRIP_TIME {
+            ConnectionQuality::Fair
+        } else {
+            ConnectionQuality::Poor
+        }
+    }
+}
+
+impl LinkStatusInner {
+    fn add_sample(&mut self, round_trip_time: Duration) {
+        self.latest = Some(round_trip_time);
+        self.smoothed = Some(match self.smoothed {
+            Some(smoothed) => (smoothed * 7 + round_trip_time) / 8,
+            None => round_trip_time,
+        });
+        self.min = Some(
+            self.min
+                .map_or(round_trip_time, |min| min.min(round_trip_time)),
+        );
+        self.max = Some(
+            self.max
+                .map_or(round_trip_time, |max| max.max(round_trip_time)),
+        );
+        self.samples += 1;
+    }
+}
+
+impl fmt::Display for LinkStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.lock();
+        match (inner.smoothed, inner.min, inner.max) {
+            (Some(smoothed), Some(min), Some(max)) => write!(
+                f,
+                "round trip time {:.1} ms (min {:.1} ms, max {:.1} ms, {} samples)",
+                smoothed.as_secs_f64() * 1000.0,
+                min.as_secs_f64() * 1000.0,
+                max.as_secs_f64() * 1000.0,
+                inner.samples
+            ),
+            _ => write!(f, "no round trip time measured"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_outstanding_ping_is_measured() {
+        let status = LinkStatus::default();
+        assert_eq!(status.quality(), ConnectionQuality::Unknown);
+        let first = status.ping_sent();
+        let second = status.ping_sent();
+        /* The first ping was given up when the second was sent */
+        assert!(status.pong_received(first).is_none());
+        let round_trip_time = status.pong_received(second).unwrap();
+        assert_eq!(status.round_trip_time(), Some(round_trip_time));
+        assert_eq!(status.latest_round_trip_time(), Some(round_trip_time));
+        assert!(status.pong_received(second).is_none());
+        assert_eq!(status.quality(), ConnectionQuality::Good);
+    }
+}
@@ -0,0 +1,108 @@
+/* Statistics of the latency from an input event to the presentation of the first frame the
+server made after processing it. The server echoes the timestamp of the newest input it has
+processed with each frame (see FrameInfo), and the client compares it to the time the frame
+was presented. The latencies are kept in a histogram with millisecond resolution, so the
+percentiles of a whole session can be found without keeping every sample. */
+
+use std::fmt;
+use std::time::Duration;
+
+/* Latencies at or above this are counted in the last bucket */
+const HISTOGRAM_BUCKETS: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct LatencyStats {
+    /* Number of samples for each whole number of milliseconds */
+    buckets: Vec<u32>,
+    count: u32,
+    max: Duration,
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; HISTOGRAM_BUCKETS + 1],
+            count: 0,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl LatencyStats {
+    pub fn add(&mut self, latency: Duration) {
+        let bucket = (latency.as_millis() as usize).min(HISTOGRAM_BUCKETS);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.max = self.max.max(latency);
+    }
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+    /* The latency (rounded down to whole milliseconds) that the given fraction (0 to 1) of the
+    samples are at or below, None when there are no samples */
+    pub fn percentile(&self, fraction: f32) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let wanted = ((self.count as f32 * fraction.clamp(0.0, 1.0)).ceil() as u32).max(1);
+        let mut seen = 0;
+        for (millis, samples) in self.buckets.iter().enumerate() {
+            seen += samples;
+            if seen >= wanted {
+                /* The overflow bucket has no upper bound, use the largest sample seen */
+                return Some(if millis == HISTOGRAM_BUCKETS {
+                    self.max
+                } else {
+                    Duration::from_millis(millis as u64)
+                });
+            }
+        }
+        Some(self.max)
+    }
+}
+
+impl fmt::Display for LatencyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (
+            self.percentile(0.5),
+            self.percentile(0.9),
+            self.percentile(0.99),
+        ) {
+            (Some(p50), Some(p90), Some(p99)) => write!(
+                f,
+                "input latency p50 {} ms, p90 {} ms, p99 {} ms, max {} ms ({} samples)",
+                p50.as_millis(),
+                p90.as_millis(),
+                p99.as_millis(),
+                self.max.as_millis(),
+                self.count
+            ),
+            _ => write!(f, "no input latency measured"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_are_found_from_the_histogram() {
+        let mut stats = LatencyStats::default();
+        assert_eq!(stats.percentile(0.5), None);
+        for millis in 1..=100 {
+            stats.add(Duration::from_millis(millis));
+        }
+        stats.add(Duration::from_secs(5));
+        assert_eq!(stats.count(), 101);
+        assert_eq!(stats.percentile(0.5), Some(Duration::from_millis(51)));
+        assert_eq!(stats.percentile(0.9), Some(Duration::from_millis(91)));
+        assert_eq!(stats.percentile(0.0), Some(Duration::from_millis(1)));
+        /* The slowest sample is beyond the histogram, and is reported exactly */
+        assert_eq!(stats.percentile(1.0), Some(Duration::from_secs(5)));
+        assert_eq!(stats.max(), Duration::from_secs(5));
+    }
+}
@@ -1,16 +1,33 @@
+#[cfg(feature = "tokio")]
+pub mod auth;
 pub mod block_manager;
 pub mod bridge_logic;
 pub mod caching_shaper;
+pub mod capabilities;
+pub mod compression;
 pub mod font_options;
+pub mod fonts;
 pub mod gfx;
+#[cfg(feature = "tokio")]
+pub mod handshake;
 pub mod input;
+pub mod keepalive;
+pub mod latency;
+pub mod null_gfx;
+pub mod payload;
+pub mod recording;
+pub mod resources;
+pub mod session;
 pub mod shadowblocks;
 pub mod swash_font;
 #[cfg(feature = "tokio")]
 pub mod tcp_bridge_async;
 pub mod tcp_bridge_sync;
 pub mod text;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod transferbuffer;
+pub mod window_control;
 
 #[macro_use]
 extern crate derive_new;
@@ -0,0 +1,95 @@
+/* Graphics implementations for the block manager that do not draw anything, so the client
+side tree can be maintained (and tested) without a graphics backend. NullGfx ignores
+everything, while RecordingGfx records the blocks rendered (in the order they are rendered)
+and the blocks created, so the tree maintenance can be checked. */
+
+use crate::block_manager::{BlockGfx, ManagerGfx, MetaBlock, RenderBlockFullId};
+use crate::gfx::{RenderBlockDescription, RenderBlockId, RenderBlockLocation, RenderBlockPath};
+
+#[derive(Debug, Default)]
+pub struct NullGfx {}
+
+#[derive(Debug, Default)]
+pub struct NullManagerGfx {}
+
+/* A block rendered by RecordingGfx */
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderedBlock {
+    pub parent: RenderBlockPath,
+    pub location: RenderBlockLocation,
+}
+
+#[derive(Debug, Default)]
+pub struct RecordingGfx {}
+
+#[derive(Debug, Default)]
+pub struct RecordingManagerGfx {
+    /* The blocks that gfx blocks are created for, in the order they are created */
+    pub created: Vec<RenderBlockFullId>,
+    pub resets: usize,
+}
+
+impl BlockGfx for NullGfx {
+    type RenderTarget<'a> = ();
+    fn render<'b>(
+        &mut self,
+        _location: &RenderBlockLocation,
+        _block: &mut MetaBlock<Self>,
+        _target: &mut Self::RenderTarget<'b>,
+    ) {
+    }
+}
+
+impl ManagerGfx<NullGfx> for NullManagerGfx {
+    fn create_gfx_block(
+        &mut self,
+        _wire_description: &RenderBlockDescription,
+        _parent_path: RenderBlockPath,
+        _id: RenderBlockId,
+    ) -> NullGfx {
+        NullGfx::default()
+    }
+    fn create_top_block(&mut self, _id: RenderBlockId) -> NullGfx {
+        NullGfx::default()
+    }
+    fn reset(&mut self) {}
+}
+
+/* Records the rendered block and the blocks of its container (like a real renderer
+rendering a meta box would) */
+impl BlockGfx for RecordingGfx {
+    type RenderTarget<'a> = Vec<RenderedBlock>;
+    fn render<'b>(
+        &mut self,
+        location: &RenderBlockLocation,
+        block: &mut MetaBlock<Self>,
+        target: &mut Self::RenderTarget<'b>,
+    ) {
+        target.push(RenderedBlock {
+            parent: block.parent_path().clone(),
+            location: location.clone(),
+        });
+        if block.container().is_some() {
+            block.process_block_recursively(self, target);
+        }
+    }
+}
+
+impl ManagerGfx<RecordingGfx> for RecordingManagerGfx {
+    fn create_gfx_block(
+        &mut self,
+        _wire_description: &RenderBlockDescription,
+        parent_path: RenderBlockPath,
+        id: RenderBlockId,
+    ) -> RecordingGfx {
+        self.created.push(RenderBlockFullId { id, parent_path });
+        RecordingGfx::default()
+    }
+    fn create_top_block(&mut self, _id: RenderBlockId) -> RecordingGfx {
+        RecordingGfx::default()
+    }
+    fn reset(&mut self) {
+        self.resets += 1;
+        self.created.clear();
+    }
+}
@@ -0,0 +1,242 @@
+/* Transfer of payloads that are too large for a single message, like clipboard contents,
+fonts and images. A payload is split in chunks of bounded size, each sent as a message with
+the id of the transfer it belongs to. The sending bridge sends a single chunk at a time when
+no other messages are waiting, so a long transfer does not hold up the interactive updates.
+The chunks are passed on like any other message by the receiving bridge, and put back
+together by a PayloadAssembler. */
+
+use std::collections::VecDeque;
+
+use anyhow::{anyhow, Result};
+use bytecheck::CheckBytes;
+use rkyv::{Archive, Deserialize, Serialize};
+
+use crate::{
+    fonts::FontHash,
+    resources::{ResourceKind, ResourceName},
+};
+
+/* Maximum amount of payload data in a single chunk */
+pub const PAYLOAD_CHUNK_SIZE: usize = 16 * 1024;
+/* Payloads announced as larger than this are refused by the receiver */
+pub const MAX_PAYLOAD_LENGTH: usize = 256 * 1024 * 1024;
+
+/* What a payload contains, which decides how the receiver uses it */
+#[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub enum PayloadKind {
+    /* Clipboard contents as utf-8 text. Sent by the server to set the clipboard of the
+    client, and by the client when the server asks for the clipboard contents. */
+    Clipboard(ClipboardSelection),
+    /* A font file the client asked for, see fonts.rs */
+    Font(FontHash),
+    /* A resource pushed by the server under a name, see resources.rs */
+    Resource(ResourceKind, ResourceName),
+}
+
+/* The system clipboard, or the primary selection (on platforms that have one, elsewhere the
+clipboard is used for both) */
+#[derive(Debug, Hash, Eq, Clone, Copy, PartialEq, Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub enum ClipboardSelection {
+    Clipboard,
+    Selection,
+}
+
+#[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct PayloadChunk {
+    /* Identifies the transfer, unique among the transfers in progress from the same end */
+    pub transfer_id: u32,
+    pub kind: PayloadKind,
+    /* Length of the whole payload */
+    pub total_length: u32,
+    /* Position of the data in the payload, the chunks of a transfer are sent in order */
+    pub offset: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Hash, Eq, Clone, PartialEq)]
+pub struct Payload {
+    pub kind: PayloadKind,
+    pub data: Vec<u8>,
+}
+
+/* Splits the payloads waiting to be sent in chunks, the payloads are sent one after the
+other */
+#[derive(Debug, Default)]
+pub struct PayloadSplitter {
+    /* The payloads waiting, with their transfer id and how much of them is sent */
+    queue: VecDeque<(u32, Payload, usize)>,
+    next_transfer_id: u32,
+}
+
+#[derive(Debug)]
+struct PartialPayload {
+    kind: PayloadKind,
+    total_length: usize,
+    data: Vec<u8>,
+}
+
+/* Puts the chunks received back together to payloads. The payloads are sent one after the
+other (see PayloadSplitter), so only one transfer is assembled at a time. */
+#[derive(Debug, Default)]
+pub struct PayloadAssembler {
+    transfer: Option<(u32, PartialPayload)>,
+}
+
+impl Payload {
+    pub fn new(kind: PayloadKind, data: Vec<u8>) -> Self {
+        Self { kind, data }
+    }
+    pub fn clipboard(selection: ClipboardSelection, contents: String) -> Self {
+        Self::new(PayloadKind::Clipboard(selection), contents.into_bytes())
+    }
+    pub fn resource(kind: ResourceKind, name: ResourceName, data: Vec<u8>) -> Self {
+        Self::new(PayloadKind::Resource(kind, name), data)
+    }
+}
+
+impl PayloadSplitter {
+    /* Queues a payload, payloads larger than the receiver accepts are dropped */
+    pub fn push(&mut self, payload: Payload) {
+        if payload.data.len() > MAX_PAYLOAD_LENGTH {
+            log::warn!(
+                "Not sending {:?} payload of {} bytes, larger than the limit of {} bytes",
+                payload.kind,
+                payload.data.len(),
+                MAX_PAYLOAD_LENGTH
+            );
+            return;
+        }
+        let transfer_id = self.next_transfer_id;
+        self.next_transfer_id = transfer_id.wrapping_add(1);
+        self.queue.push_back((transfer_id, payload, 0));
+    }
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+    /* The next chunk to send, None when all the payloads are sent */
+    pub fn next_chunk(&mut self) -> Option<PayloadChunk> {
+        let (transfer_id, payload, sent) = self.queue.front_mut()?;
+        let end = (*sent + PAYLOAD_CHUNK_SIZE).min(payload.data.len());
+        let chunk = PayloadChunk {
+            transfer_id: *transfer_id,
+            kind: payload.kind.clone(),
+            total_length: payload.data.len() as u32,
+            offset: *sent as u32,
+            data: payload.data[*sent..end].to_vec(),
+        };
+        *sent = end;
+        if end == payload.data.len() {
+            self.queue.pop_front();
+        }
+        Some(chunk)
+    }
+}
+
+impl PayloadAssembler {
+    /* Adds a received chunk, returns the payload when it is complete. A chunk not following
+    the previous chunk of its transfer, or starting a new transfer before the previous one is
+    complete, is an error, and the transfer in progress is dropped. */
+    pub fn add_chunk(&mut self, chunk: PayloadChunk) -> Result<Option<Payload>> {
+        let total_length = chunk.total_length as usize;
+        if total_length > MAX_PAYLOAD_LENGTH {
+            return Err(anyhow!(
+                "Refusing payload of {} bytes, larger than the limit of {} bytes",
+                total_length,
+                MAX_PAYLOAD_LENGTH
+            ));
+        }
+        if let Some((transfer_id, _)) = self.transfer.as_ref() {
+            if *transfer_id != chunk.transfer_id {
+                let transfer_id = *transfer_id;
+                self.transfer = None;
+                return Err(anyhow!(
+                    "Chunk of transfer {} received before transfer {} is complete, dropping both",
+                    chunk.transfer_id,
+                    transfer_id
+                ));
+            }
+        }
+        /* The data is not reserved up front, the length is only what the sender claims */
+        let (_, partial) = self.transfer.get_or_insert_with(|| {
+            (
+                chunk.transfer_id,
+                PartialPayload {
+                    kind: chunk.kind.clone(),
+                    total_length,
+                    data: Vec::new(),
+                },
+            )
+        });
+        if partial.kind != chunk.kind
+            || partial.total_length != total_length
+            || partial.data.len() != chunk.offset as usize
+            || partial.data.len() + chunk.data.len() > total_length
+        {
+            self.transfer = None;
+            return Err(anyhow!(
+                "Chunk at offset {} does not fit transfer {}, dropping the transfer",
+                chunk.offset,
+                chunk.transfer_id
+            ));
+        }
+        partial.data.extend_from_slice(&chunk.data);
+        if partial.data.len() < partial.total_length {
+            return Ok(None);
+        }
+        let (_, partial) = self.transfer.take().unwrap();
+        Ok(Some(Payload::new(partial.kind, partial.data)))
+    }
+    /* True when a transfer is started but not complete */
+    pub fn in_progress(&self) -> bool {
+        self.transfer.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_are_split_and_assembled() {
+        let large = Payload::new(
+            PayloadKind::Clipboard(ClipboardSelection::Clipboard),
+            (0..PAYLOAD_CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect(),
+        );
+        let empty = Payload::clipboard(ClipboardSelection::Selection, String::new());
+        let mut splitter = PayloadSplitter::default();
+        splitter.push(large.clone());
+        splitter.push(empty.clone());
+        let mut chunks = Vec::new();
+        while let Some(chunk) = splitter.next_chunk() {
+            assert!(chunk.data.len() <= PAYLOAD_CHUNK_SIZE);
+            chunks.push(chunk);
+        }
+        assert!(splitter.is_empty());
+        /* Three chunks for the large payload, and one (empty) for the empty one */
+        assert_eq!(chunks.len(), 4);
+
+        let mut assembler = PayloadAssembler::default();
+        let mut assembled = Vec::new();
+        for chunk in chunks.iter().cloned() {
+            if let Some(payload) = assembler.add_chunk(chunk).unwrap() {
+                assembled.push(payload);
+            }
+        }
+        assert_eq!(assembled, vec![large, empty.clone()]);
+        assert!(!assembler.in_progress());
+
+        /* A chunk missing in the middle is detected */
+        assembler.add_chunk(chunks[0].clone()).unwrap();
+        assert!(assembler.add_chunk(chunks[2].clone()).is_err());
+        assert!(!assembler.in_progress());
+
+        /* As is a new transfer started before the previous one is complete */
+        assembler.add_chunk(chunks[0].clone()).unwrap();
+        assert!(assembler.add_chunk(chunks[3].clone()).is_err());
+        assert!(!assembler.in_progress());
+        assert_eq!(assembler.add_chunk(chunks[3].clone()).unwrap(), Some(empty));
+    }
+}
@@ -0,0 +1,266 @@
+/* Recording of the frames sent over a bridge connection, so a session can be replayed
+later (e.g. to reproduce a rendering bug in the client or an editor bug in the server without
+the other side present). The frames are recorded after serialization and before compression,
+together with the time since the recording started and the direction they were sent in.
+
+A recording starts with a header (magic and protocol version), followed by entries of:
+direction (one byte), timestamp in microseconds (u64, little endian), length of the frame
+contents (u32, little endian) and the frame contents (the archived message, without the
+length header used on the wire). */
+
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use bytecheck::CheckBytes;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use parking_lot::Mutex;
+use rkyv::validation::validators::DefaultValidator;
+use rkyv::{AlignedVec, Archive, Deserialize};
+
+use crate::bridge_logic::{TcpBridgeReceiveProcessor, MAX_PACKET_LENGTH};
+use crate::capabilities::PROTOCOL_VERSION;
+
+const RECORDING_MAGIC: [u8; 8] = *b"HCRECORD";
+const ENTRY_HEADER_LENGTH: usize = 1 + 8 + 4;
+
+#[derive(Debug, Hash, Eq, Clone, Copy, PartialEq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum RecordDirection {
+    /* Messages from the client to the server (TcpBridgeToServerMessage) */
+    ToServer,
+    /* Messages from the server to the client (TcpBridgeToClientMessage) */
+    ToClient,
+}
+
+/* Records frames to a writer, shared by the send and receive halves of a connection (and
+by subsequent connections when the client reconnects). The frames are written by a thread of
+its own, so the connection is never held up by the file. */
+pub struct SessionRecorder {
+    start: Instant,
+    entries: Mutex<Option<mpsc::Sender<Vec<u8>>>>,
+    writer_thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+pub struct RecordedFrame {
+    pub direction: RecordDirection,
+    /* Time since the recording started */
+    pub timestamp: Duration,
+    pub contents: AlignedVec,
+}
+
+/* Reads the frames of a recording in the order they were recorded */
+pub struct RecordingReader<R> {
+    reader: R,
+}
+
+impl SessionRecorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .map_err(|e| anyhow!("Could not create recording {:?}: {}", path, e))?;
+        Self::new(Box::new(BufWriter::new(file)))
+    }
+    pub fn new(mut writer: Box<dyn Write + Send>) -> Result<Self> {
+        writer.write_all(&RECORDING_MAGIC)?;
+        writer.write_all(&PROTOCOL_VERSION.to_le_bytes())?;
+        let (entries, received_entries) = mpsc::channel();
+        let writer_thread = std::thread::Builder::new()
+            .name(String::from("helicoid-recorder"))
+            .spawn(move || write_entries(writer, received_entries))?;
+        Ok(Self {
+            start: Instant::now(),
+            entries: Mutex::new(Some(entries)),
+            writer_thread: Some(writer_thread),
+        })
+    }
+    /* Records the contents of a single frame. Failing to record is logged, but does not
+    affect the connection. */
+    pub fn record(&self, direction: RecordDirection, contents: &[u8]) {
+        let timestamp = self.start.elapsed().as_micros() as u64;
+        let mut entry = Vec::with_capacity(ENTRY_HEADER_LENGTH + contents.len());
+        entry.push(direction.into());
+        entry.extend_from_slice(&timestamp.to_le_bytes());
+        entry.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        entry.extend_from_slice(contents);
+        if let Some(entries) = self.entries.lock().as_ref() {
+            /* Only fails when the writer thread has stopped, which it has logged */
+            let _ = entries.send(entry);
+        }
+    }
+    /* Records each frame in a buffer of serialized (length prefixed) frames */
+    pub fn record_frames(&self, direction: RecordDirection, frames: &[u8]) {
+        let mut offset = 0;
+        while offset + 4 <= frames.len() {
+            let length =
+                u32::from_le_bytes(frames[offset..offset + 4].try_into().unwrap()) as usize;
+            if offset + 4 + length > frames.len() {
+                log::warn!(
+                    "Not recording frame of {} bytes, only {} bytes left in the buffer",
+                    length,
+                    frames.len() - offset - 4
+                );
+                break;
+            }
+            self.record(direction, &frames[offset + 4..offset + 4 + length]);
+            offset += 4 + length;
+        }
+    }
+}
+
+/* The frames recorded are all written when the recorder is dropped */
+impl Drop for SessionRecorder {
+    fn drop(&mut self) {
+        self.entries.lock().take();
+        if let Some(writer_thread) = self.writer_thread.take() {
+            let _ = writer_thread.join();
+        }
+    }
+}
+
+fn write_entries(mut writer: Box<dyn Write + Send>, entries: mpsc::Receiver<Vec<u8>>) {
+    for entry in entries.iter() {
+        /* Flush after each frame, so the recording is complete also if the process crashes
+        (which may well be what is being reproduced) */
+        let result = writer.write_all(&entry).and_then(|_| writer.flush());
+        if let Err(e) = result {
+            log::warn!("Could not record frame, stopping the recording: {}", e);
+            return;
+        }
+    }
+}
+
+impl RecordedFrame {
+    /* Validates and deserializes the recorded message, M is TcpBridgeToServerMessage or
+    TcpBridgeToClientMessage depending on the direction */
+    pub fn decode<M: Archive>(&self) -> Result<M>
+    where
+        M::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + Deserialize<M, rkyv::Infallible>,
+    {
+        Ok(TcpBridgeReceiveProcessor::<M>::transform_element(
+            &self.contents,
+        )?)
+    }
+}
+
+impl RecordingReader<std::io::BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).map_err(|e| anyhow!("Could not open recording {:?}: {}", path, e))?;
+        Self::new(std::io::BufReader::new(file))
+    }
+}
+
+impl<R: Read> RecordingReader<R> {
+    /* Checks the header of the recording, recordings made with another protocol version
+    can not be replayed */
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0u8; RECORDING_MAGIC.len() + 4];
+        reader.read_exact(&mut header)?;
+        if header[..RECORDING_MAGIC.len()] != RECORDING_MAGIC {
+            return Err(anyhow!("Not a helicoid recording"));
+        }
+        let version = u32::from_le_bytes(header[RECORDING_MAGIC.len()..].try_into()?);
+        if version != PROTOCOL_VERSION {
+            return Err(anyhow!(
+                "Recording made with protocol version {}, this is version {}",
+                version,
+                PROTOCOL_VERSION
+            ));
+        }
+        Ok(Self { reader })
+    }
+    /* Returns the next frame, or None at the end of the recording */
+    pub fn next_frame(&mut self) -> Result<Option<RecordedFrame>> {
+        let mut header = [0u8; ENTRY_HEADER_LENGTH];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            /* A recording cut short (e.g. by a crash) ends at the last complete frame */
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let direction = RecordDirection::try_from(header[0])
+            .map_err(|_| anyhow!("Invalid direction in recording: {}", header[0]))?;
+        let timestamp = Duration::from_micros(u64::from_le_bytes(header[1..9].try_into()?));
+        let length = u32::from_le_bytes(header[9..].try_into()?) as usize;
+        if length > MAX_PACKET_LENGTH {
+            return Err(anyhow!("Frame too large in recording: {} bytes", length));
+        }
+        let mut contents = AlignedVec::with_capacity(length);
+        contents.resize(length, 0);
+        match self.reader.read_exact(&mut contents) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Some(RecordedFrame {
+            direction,
+            timestamp,
+            contents,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for RecordingReader<R> {
+    type Item = Result<RecordedFrame>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge_logic::TcpBridgeToServerMessage;
+    use crate::input::HelicoidToServerMessage;
+    use std::sync::Arc;
+
+    /* Writer appending to a buffer shared with the test */
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn recorded_frames_are_read_back() {
+        let buffer = SharedBuffer::default();
+        let recorder = SessionRecorder::new(Box::new(buffer.clone())).unwrap();
+        let message = TcpBridgeToServerMessage {
+            message: HelicoidToServerMessage::CharReceived('h' as u32),
+        };
+        let contents = rkyv::to_bytes::<_, 256>(&message).unwrap();
+        let mut frames = (contents.len() as u32).to_le_bytes().to_vec();
+        frames.extend_from_slice(&contents);
+        recorder.record_frames(RecordDirection::ToServer, &frames);
+        /* A frame that does not fit in the buffer is not recorded */
+        recorder.record_frames(RecordDirection::ToServer, &frames[..frames.len() - 1]);
+        recorder.record(RecordDirection::ToClient, &[1, 2, 3]);
+        /* Waits for the frames to be written */
+        drop(recorder);
+        /* A frame cut short at the end is ignored */
+        let mut recorded = buffer.0.lock().clone();
+        recorded.extend_from_slice(&[0, 1, 2]);
+
+        let mut reader = RecordingReader::new(&recorded[..]).unwrap();
+        let first = reader.next_frame().unwrap().unwrap();
+        assert_eq!(first.direction, RecordDirection::ToServer);
+        assert_eq!(first.decode::<TcpBridgeToServerMessage>().unwrap(), message);
+        let second = reader.next_frame().unwrap().unwrap();
+        assert_eq!(second.direction, RecordDirection::ToClient);
+        assert!(second.timestamp >= first.timestamp);
+        assert_eq!(&second.contents[..], &[1, 2, 3]);
+        assert!(reader.next_frame().unwrap().is_none());
+    }
+}
@@ -0,0 +1,118 @@
+/* Named resources the blocks refer to, like the image of a SimpleSvg. The server pushes the
+contents of a resource to the client as a payload (see payload.rs) under its name, and the
+client keeps it until the server evicts it or the connection is closed. This lets icons (like
+file type glyphs and diagnostic markers) come from the theme of the server. Names that are not
+pushed by the server are looked up in the assets of the client. The blocks already drawn with
+a resource are not redrawn by the client when the resource changes, the server updates them. */
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use bytecheck::CheckBytes;
+use rkyv::{Archive, Deserialize, Serialize};
+use smallvec::SmallVec;
+
+use crate::gfx::SVG_RESOURCE_NAME_LEN;
+
+pub type ResourceName = SmallVec<[u8; SVG_RESOURCE_NAME_LEN]>;
+
+/* The format of the contents of a resource */
+#[derive(Debug, Hash, Eq, Clone, Copy, PartialEq, Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub enum ResourceKind {
+    Svg,
+    Png,
+    Jpeg,
+    /* A TrueType or OpenType font file, usable as a font name for the shaper */
+    Font,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resource {
+    pub kind: ResourceKind,
+    pub data: Arc<Vec<u8>>,
+}
+
+/* The resources pushed by the server to a client */
+#[derive(Debug, Default)]
+pub struct ResourceStore {
+    resources: HashMap<ResourceName, Resource>,
+}
+
+/* The name of a resource, names longer than SVG_RESOURCE_NAME_LEN bytes can not be used */
+pub fn resource_name(name: &str) -> Result<ResourceName> {
+    if name.is_empty() || name.len() > SVG_RESOURCE_NAME_LEN {
+        return Err(anyhow!(
+            "Resource name {:?} is not between 1 and {} bytes long",
+            name,
+            SVG_RESOURCE_NAME_LEN
+        ));
+    }
+    Ok(SmallVec::from_slice(name.as_bytes()))
+}
+
+impl ResourceStore {
+    /* Stores a resource, returns the resource it replaces */
+    pub fn insert(
+        &mut self,
+        name: ResourceName,
+        kind: ResourceKind,
+        data: Vec<u8>,
+    ) -> Option<Resource> {
+        log::debug!(
+            "Storing {:?} resource {} ({} bytes)",
+            kind,
+            String::from_utf8_lossy(&name),
+            data.len()
+        );
+        self.resources.insert(
+            name,
+            Resource {
+                kind,
+                data: Arc::new(data),
+            },
+        )
+    }
+    pub fn evict(&mut self, name: &[u8]) -> Option<Resource> {
+        self.resources.remove(name)
+    }
+    pub fn get(&self, name: &[u8]) -> Option<&Resource> {
+        self.resources.get(name)
+    }
+    pub fn len(&self) -> usize {
+        self.resources.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.resources.is_empty()
+    }
+    pub fn clear(&mut self) {
+        self.resources.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resources_are_replaced_and_evicted() {
+        assert!(resource_name("").is_err());
+        assert!(resource_name(&"x".repeat(SVG_RESOURCE_NAME_LEN + 1)).is_err());
+        let icon = resource_name("filetype_rust").unwrap();
+
+        let mut store = ResourceStore::default();
+        assert!(store
+            .insert(icon.clone(), ResourceKind::Svg, b"<svg/>".to_vec())
+            .is_none());
+        let replaced = store
+            .insert(icon.clone(), ResourceKind::Png, b"png".to_vec())
+            .unwrap();
+        assert_eq!(replaced.kind, ResourceKind::Svg);
+        assert_eq!(store.get(&icon).unwrap().kind, ResourceKind::Png);
+        assert_eq!(store.len(), 1);
+
+        assert!(store.evict(&icon).is_some());
+        assert!(store.evict(&icon).is_none());
+        assert!(store.is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+/* Session resumption after a dropped connection. The server gives each connection a session
+id in the ServerHello, and keeps the state of the session (including the shadow tree of what
+the client holds) for a grace period after the connection is lost. A client reconnecting
+within the grace period sends the session id together with a list of the blocks it still
+holds (identified by their path, id and a hash of their contents), so the server only has to
+resend the blocks that are missing or outdated. */
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+use bytecheck::CheckBytes;
+use rkyv::{Archive, Deserialize, Serialize};
+
+use crate::gfx::{RenderBlockDescription, RenderBlockId, RenderBlockPath};
+
+#[derive(Debug, Hash, Eq, Clone, Copy, PartialEq, Archive, Serialize, Deserialize, CheckBytes)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct SessionId(pub [u8; 16]);
+
+/* Hash of the contents of a block, see block_content_hash. Stored as bytes to keep the
+alignment of the archived messages low. */
+#[derive(Debug, Hash, Eq, Clone, Copy, PartialEq, Archive, Serialize, Deserialize, CheckBytes)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct BlockHash(pub [u8; 8]);
+
+#[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize, CheckBytes)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct HeldBlock {
+    pub parent: RenderBlockPath,
+    pub id: RenderBlockId,
+    pub hash: BlockHash,
+}
+
+#[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize, CheckBytes)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct SessionResume {
+    pub session_id: SessionId,
+    /* The blocks the client still holds from the previous connection */
+    pub held_blocks: Vec<HeldBlock>,
+}
+
+/* The blocks held by a resuming client, indexed for lookup while reconciling the shadow
+tree of the server */
+#[derive(Debug, Default)]
+pub struct HeldBlockSet {
+    blocks: HashMap<(RenderBlockPath, RenderBlockId), BlockHash>,
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/* Hashes the serialized form of the block, so the client and the server get the same hash
+regardless of platform and build (as long as the protocol version is the same). The
+hashers used for the shadow blocks are seeded per process, so they can not be used for this. */
+pub fn block_content_hash(description: &RenderBlockDescription) -> BlockHash {
+    let bytes = rkyv::to_bytes::<_, 1024>(description).expect("Serializing to memory can not fail");
+    let hash = bytes.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    });
+    BlockHash(hash.to_le_bytes())
+}
+
+impl SessionId {
+    #[cfg(feature = "tokio")]
+    pub fn generate() -> Self {
+        use rand::RngCore;
+        let mut id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+        Self(id)
+    }
+}
+
+/* Session ids are shown as hex, e.g. so a spectator can be told which presenter to follow */
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SessionId {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid session id: {:?}", s);
+        if s.len() != 32 || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let mut id = [0u8; 16];
+        for (idx, byte) in id.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[idx * 2..idx * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(id))
+    }
+}
+
+impl HeldBlockSet {
+    pub fn new(held_blocks: &[HeldBlock]) -> Self {
+        Self {
+            blocks: held_blocks
+                .iter()
+                .map(|held| ((held.parent.clone(), held.id), held.hash))
+                .collect(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+    /* Returns true if the client holds the block with the given contents */
+    pub fn holds(
+        &self,
+        parent: &RenderBlockPath,
+        id: RenderBlockId,
+        description: &RenderBlockDescription,
+    ) -> bool {
+        self.blocks
+            .get(&(parent.clone(), id))
+            .map(|hash| *hash == block_content_hash(description))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gfx::{MetaDrawBlock, PointF32};
+    use smallvec::smallvec;
+
+    fn meta_box(width: f32) -> RenderBlockDescription {
+        RenderBlockDescription::MetaBox(MetaDrawBlock {
+            extent: PointF32::new(width, 10.0),
+            buffered: false,
+            alpha: None,
+            sub_blocks: smallvec![],
+        })
+    }
+
+    #[test]
+    fn held_blocks_are_matched_by_contents() {
+        let parent = RenderBlockPath::top();
+        let held = HeldBlockSet::new(&[HeldBlock {
+            parent: parent.clone(),
+            id: RenderBlockId(1),
+            hash: block_content_hash(&meta_box(10.0)),
+        }]);
+        assert_eq!(
+            block_content_hash(&meta_box(10.0)),
+            block_content_hash(&meta_box(10.0))
+        );
+        assert!(held.holds(&parent, RenderBlockId(1), &meta_box(10.0)));
+        assert!(!held.holds(&parent, RenderBlockId(1), &meta_box(20.0)));
+        assert!(!held.holds(&parent, RenderBlockId(2), &meta_box(10.0)));
+    }
+
+    #[test]
+    fn session_ids_are_parsed() {
+        let id = SessionId([0xa5; 16]);
+        assert_eq!(id.to_string().parse::<SessionId>().unwrap(), id);
+        assert!("a5".parse::<SessionId>().is_err());
+        assert!("x".repeat(32).parse::<SessionId>().is_err());
+    }
+}
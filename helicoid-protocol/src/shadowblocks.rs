@@ -11,6 +11,7 @@ use crate::{
         MetaDrawBlock, NewRenderBlock, PointF32, RenderBlockDescription, RenderBlockId,
         RenderBlockLocation, RenderBlockPath, SimpleDrawBlock,
     },
+    session::HeldBlockSet,
     text::ShapedTextBlock,
     transferbuffer::TransferBuffer,
 };
@@ -616,6 +617,18 @@ where
         /* Make sure to update hash to cover any changes that have been pushed to the client */
         self.rehash();
     }
+    /* Called when a client resumes a session: forgets what the client is supposed to hold
+    for the blocks in this container that the client did not report holding (with the
+    current contents), so they are transferred again on the next sync */
+    pub fn reconcile_client_blocks(&mut self, parent: &RenderBlockPath, held: &HeldBlockSet) {
+        let child_path = RenderBlockPath::child(parent, self.id);
+        for element in self.child_blocks.iter_mut() {
+            element.reconcile_client_blocks(&child_path, held);
+        }
+        self.client_hash = None;
+        self.client_meta_hash = None;
+        self.location = None;
+    }
     pub fn id(&self) -> RenderBlockId {
         self.id
     }
@@ -694,6 +707,16 @@ where
             }
         }
     }
+    pub fn reconcile_client_blocks(&mut self, parent: &RenderBlockPath, held: &HeldBlockSet) {
+        match self {
+            ShadowMetaBlock::WrappedContainer(wc) => {
+                wc.inner_mut().reconcile_client_blocks(parent, held)
+            }
+            ShadowMetaBlock::Container(c) => c.inner_mut().reconcile_client_blocks(parent, held),
+            ShadowMetaBlock::Draw(d) => d.client_hash = None,
+            ShadowMetaBlock::Text(t) => t.reconcile_client_blocks(parent, held),
+        }
+    }
     pub fn extent_mut(&mut self) -> &mut PointF32 {
         match self {
             ShadowMetaBlock::WrappedContainer(wc) => &mut wc.inner_mut().wire.extent,
@@ -802,4 +825,11 @@ impl ShadowMetaTextBlock {
         );
         transfer_buffer.add_moves(&parent, &[self.location.clone().unwrap()]);
     }
+    pub fn reconcile_client_blocks(&mut self, parent: &RenderBlockPath, held: &HeldBlockSet) {
+        let description = RenderBlockDescription::ShapedTextBlock(self.wire.clone());
+        if !held.holds(parent, self.id, &description) {
+            self.client_hash = None;
+            self.location = None;
+        }
+    }
 }
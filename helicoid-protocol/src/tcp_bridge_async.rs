@@ -1,74 +1,251 @@
-/* Used to talk to a helix / helicoid backend over a relyable TCP connection
-managed by Tokio, and connected to the user interface by channels */
+/* Used to talk to a helix / helicoid backend over a relyable TCP connection (or a unix
+domain socket, or the stdin / stdout of a process) managed by Tokio, and connected to the
+user interface by channels. The backend can also run in the same process as the user
+interface, then the channels are connected directly to the connection state without any
+serialization. */
 
 use async_trait::async_trait;
+use bytecheck::CheckBytes;
 use rkyv::ser::serializers::AllocSerializer;
 use rkyv::ser::serializers::{
     AlignedSerializer, AllocScratch, CompositeSerializer, WriteSerializer,
 };
+use rkyv::validation::validators::DefaultValidator;
 use rkyv::{Archive, Deserialize, Infallible};
 use std::collections::HashMap;
+use std::fmt;
 
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::auth::{authenticate_client, authenticate_to_server, AuthKey, AUTH_TIMEOUT};
 use crate::bridge_logic::{
-    DummyWriter, SerializeWith, TcpBridgeReceiveProcessor, TcpBridgeToClientMessage,
-    TcpBridgeToServerMessage,
+    BridgeSendMessage, DummyWriter, KeepaliveCarrier, PayloadCarrier, SerializeWith,
+    TcpBridgeReceiveProcessor, TcpBridgeToClientMessage, TcpBridgeToServerMessage,
 };
+use crate::capabilities::{Capabilities, ClientHello, ClientRole, ServerHello};
+use crate::compression::{compress_frames, ByteCounters, CompressionOptions, FrameCompression};
+use crate::handshake::{client_handshake, read_client_hello, send_server_hello};
+use crate::keepalive::{Keepalive, KeepaliveOptions, LinkStatus};
+use crate::payload::{Payload, PayloadSplitter};
+use crate::recording::{RecordDirection, SessionRecorder};
+use crate::session::{SessionId, SessionResume};
+#[cfg(feature = "tls")]
+use crate::tls::{ClientTlsConfig, ServerTlsConfig};
 use crate::transferbuffer::TransferBuffer;
 use anyhow::{anyhow, Result};
-use tokio::io::AsyncWriteExt;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::process::{Child, Command};
 use tokio::sync::{
     broadcast::{self, Receiver as BReceiver, Sender as BSender},
     mpsc::{self, Receiver, Sender},
     oneshot::{self, Receiver as OReceiver, Sender as OSender},
-    Mutex as TMutex,
+    Mutex as TMutex, Notify,
 };
+use tokio::time::MissedTickBehavior;
+
+/* The bridge is agnostic to what kind of stream it runs over (plain TCP, TLS etc.),
+the connection is split into a read and a write half that are processed independently */
+pub type BridgeReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+pub type BridgeWriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+/* Address to listen on or connect to. Addresses on the form unix:/path/to/socket refer
+to a unix domain socket, anything else is treated as a TCP address (host:port) */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BridgeAddress {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/* The address of the remote end of a connection. Clients connecting over unix domain
+sockets are usually unnamed, so they are identified by the socket they connected to.
+Stdio is used for the single client talking to the server through its stdin / stdout,
+and InProcess for clients running in the same process as the server. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerAddress {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Stdio,
+    InProcess,
+}
 
 pub struct TcpBridgeSend<M> {
-    tcp_conn: OwnedWriteHalf,
+    tcp_conn: BridgeWriteHalf,
     serializer: Option<TBSSerializer>,
     dummy_serializer:
         Option<CompositeSerializer<WriteSerializer<DummyWriter>, AllocScratch, Infallible>>,
     chan: Receiver<M>,
     close_chan: OReceiver<()>,
+    compression: FrameCompression,
+    /* Scratch buffer for the compressed frames */
+    compressed: Vec<u8>,
+    byte_counters: Arc<ByteCounters>,
+    recorder: Option<(Arc<SessionRecorder>, RecordDirection)>,
+    keepalive: KeepaliveOptions,
+    /* Answers to the pings from the other end, queued by the receive half */
+    keepalive_chan: Receiver<Keepalive>,
+    link_status: Arc<LinkStatus>,
+    send_queue: Arc<SendQueueStatus>,
+    /* Payloads to send in chunks between the regular messages */
+    payload_chan: Receiver<Payload>,
+    payloads: PayloadSplitter,
 }
 pub struct TcpBridgeReceive<M> {
-    tcp_conn: OwnedReadHalf,
+    tcp_conn: BridgeReadHalf,
     chan: Sender<M>,
     close_chan: Option<OSender<()>>,
     processor: TcpBridgeReceiveProcessor<M>,
+    keepalive_chan: Sender<Keepalive>,
+    link_status: Arc<LinkStatus>,
 }
 pub struct ClientTcpBridge {
     send: TcpBridgeSend<TcpBridgeToServerMessage>,
     receive: TcpBridgeReceive<TcpBridgeToClientMessage>,
+    byte_counters: Arc<ByteCounters>,
+    link_status: Arc<LinkStatus>,
+    send_queue: Arc<SendQueueStatus>,
+    payload_tx: Sender<Payload>,
+    server_hello: Option<ServerHello>,
+    /* The server process when connected through its stdin / stdout, it is killed when
+    the bridge is dropped */
+    _child: Option<Child>,
+}
+
+/* Configuration for how the client connects to the server, the default is a plain
+(unencrypted and unauthenticated) TCP connection */
+#[derive(Default, Clone)]
+pub struct ClientBridgeConfig {
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<ClientTlsConfig>>,
+    pub auth: Option<AuthKey>,
+    /* Capabilities advertised to the server */
+    pub capabilities: Capabilities,
+    pub compression: CompressionOptions,
+    /* Set when reconnecting, to ask the server to resume the previous session */
+    pub resume: Option<SessionResume>,
+    pub role: ClientRole,
+    /* Records the traffic of the connection(s) to the server, see recording.rs */
+    pub recorder: Option<Arc<SessionRecorder>>,
+    pub keepalive: KeepaliveOptions,
+}
+
+/* Configuration for how the server accepts connections, the default is plain
+(unencrypted and unauthenticated) TCP connections. When an authentication key is set
+clients have to prove they know it before they get any access to the editor. */
+#[derive(Clone)]
+pub struct ServerBridgeConfig {
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<ServerTlsConfig>>,
+    pub auth: Option<AuthKey>,
+    /* Capabilities of the server, the capabilities used for a connection are the ones
+    supported by both the server and the client */
+    pub capabilities: Capabilities,
+    pub compression: CompressionOptions,
+    /* How long the state of a session is kept after its connection is lost, so the client
+    can resume it. Zero disables session resumption. */
+    pub session_grace_period: Duration,
+    /* When set the traffic of each connection is recorded to a file in this directory,
+    named by the session id and the time the connection was made */
+    pub record_directory: Option<PathBuf>,
+    pub keepalive: KeepaliveOptions,
+}
+
+pub const DEFAULT_SESSION_GRACE_PERIOD: Duration = Duration::from_secs(120);
+
+/* Information about an established connection, available when the connection state is created */
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub peer_address: PeerAddress,
+    pub client_hello: ClientHello,
+    /* The capabilities supported by both the client and the server */
+    pub capabilities: Capabilities,
+    /* Traffic on the connection, updated while the connection is running */
+    pub byte_counters: Arc<ByteCounters>,
+    /* Round trip times measured by the keepalive pings, updated while the connection is
+    running */
+    pub link_status: Arc<LinkStatus>,
+    /* How many of the transfer buffers sent to the client are still waiting to be written,
+    updated while the connection is running */
+    pub send_queue: Arc<SendQueueStatus>,
+    /* Sends payloads (see payload.rs) to the client, None for clients in the same process
+    as they only receive transfer buffers */
+    pub payload_tx: Option<Sender<Payload>>,
+    pub session_id: SessionId,
+    /* True if the connection resumed a suspended session, the state data is then the one
+    of the previous connection and client_hello.resume lists the blocks the client holds */
+    pub resumed: bool,
 }
 
-pub struct TcpBridgeServer<S> {
+pub struct TcpBridgeServer<S: TcpBridgeServerConnectionState> {
     close_sender: BSender<()>,
-    _listener: Option<TcpListener>,
-    _connections: HashMap<SocketAddr, TcpBridgeServerConnection<S>>,
+    config: ServerBridgeConfig,
+    /* The listener is bound on the first call to wait_for_connection (or bind), and kept so
+    clients can connect while others are connected */
+    listener: Option<(String, Arc<BridgeListener>)>,
+    /* The clients currently connected, several clients can be connected at the same time */
+    connections: HashMap<SessionId, PeerAddress>,
+    /* Sessions whose connection was lost, waiting for their client to reconnect */
+    sessions: HashMap<SessionId, SuspendedSession<S::StateData>>,
+}
+
+struct SuspendedSession<D> {
+    state_data: D,
+    expires: Instant,
 }
 
-#[allow(dead_code)]
-pub struct TcpBridgeServerConnection<S> {
-    bridge: ServerSingleTcpBridge,
-    connection_state: S,
+enum BridgeListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+trait BridgeStream: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> BridgeStream for T {}
+
+/* A connection accepted by the listener, the state data for it is supplied when the
+connection is set up with spawn_accepted */
+pub struct AcceptedConnection {
+    stream: Box<dyn BridgeStream>,
+    peer_address: PeerAddress,
 }
 
 pub struct ServerSingleTcpBridge {
     send: TcpBridgeSend<Arc<TransferBuffer>>,
     receive: TcpBridgeReceive<TcpBridgeToServerMessage>,
+    byte_counters: Arc<ByteCounters>,
+    link_status: Arc<LinkStatus>,
+    send_queue: Arc<SendQueueStatus>,
+    payload_tx: Sender<Payload>,
+}
+
+/* The messages handed to the send half of a bridge that are not written to the connection
+yet, shared by the bridge (which updates it) and the users of the connection. When the
+connection can not keep up the queued messages are merged where possible (see
+BridgeSendMessage::coalesce), and the sender can hold back new messages until the queue is
+drained instead of producing messages that are merged away anyway. */
+#[derive(Debug, Default)]
+pub struct SendQueueStatus {
+    /* Messages waiting in the queue, and the one being written */
+    unsent: AtomicUsize,
+    /* Messages merged into another message instead of being sent on their own */
+    coalesced: AtomicUsize,
+    drained: Notify,
 }
 
 #[async_trait]
-pub trait TcpBridgeServerConnectionState: Send {
+pub trait TcpBridgeServerConnectionState: Send + 'static {
     type StateData: Send + 'static;
     async fn new_state(
-        peer_address: SocketAddr,
+        connection_info: ConnectionInfo,
         channel_tx: Sender<Arc<TransferBuffer>>,
         channel_rx: Receiver<TcpBridgeToServerMessage>,
         close_rx: BReceiver<()>,
@@ -76,6 +253,78 @@ pub trait TcpBridgeServerConnectionState: Send {
     ) -> Self;
     async fn initialize(&mut self) -> Result<()>;
     async fn event_loop(&mut self) -> Result<()>;
+    /* Called when the connection is lost, the returned state data is kept for the grace
+    period and passed to new_state if the client resumes the session. States that can not
+    be resumed return None. */
+    fn suspend(self) -> Option<Self::StateData>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+const UNIX_ADDRESS_PREFIX: &str = "unix:";
+
+impl BridgeAddress {
+    pub fn parse(addr: &str) -> Result<Self> {
+        match addr.strip_prefix(UNIX_ADDRESS_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => {
+                if path.is_empty() {
+                    return Err(anyhow!("Missing socket path in address: {}", addr));
+                }
+                Ok(BridgeAddress::Unix(PathBuf::from(path)))
+            }
+            #[cfg(not(unix))]
+            Some(_) => Err(anyhow!(
+                "Unix domain sockets are not supported on this platform: {}",
+                addr
+            )),
+            None => Ok(BridgeAddress::Tcp(addr.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for BridgeAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BridgeAddress::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            BridgeAddress::Unix(path) => write!(f, "{}{}", UNIX_ADDRESS_PREFIX, path.display()),
+        }
+    }
+}
+
+impl fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddress::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            PeerAddress::Unix(path) => write!(f, "{}{}", UNIX_ADDRESS_PREFIX, path.display()),
+            PeerAddress::Stdio => write!(f, "stdio"),
+            PeerAddress::InProcess => write!(f, "in-process"),
+        }
+    }
+}
+
+/* Binds a unix domain socket listener. A socket file left behind by an earlier listener is
+replaced, and the socket is only made accessible to the current user. */
+#[cfg(unix)]
+fn bind_unix_listener(path: &Path) -> Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!(
+                "Refusing to replace {:?} as it is not a unix socket",
+                path
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
 }
 
 impl ClientTcpBridge {
@@ -86,22 +335,198 @@ impl ClientTcpBridge {
         Sender<TcpBridgeToServerMessage>,
         Receiver<TcpBridgeToClientMessage>,
     )> {
-        //        Ok(Self{})
-        //        unimplemented!()
-        let stream = TcpStream::connect(addr).await?;
-        let (r, w) = stream.into_split();
+        Self::connect_with(addr, &ClientBridgeConfig::default()).await
+    }
+    pub async fn connect_with(
+        addr: &String,
+        config: &ClientBridgeConfig,
+    ) -> Result<(
+        Self,
+        Sender<TcpBridgeToServerMessage>,
+        Receiver<TcpBridgeToClientMessage>,
+    )> {
+        match BridgeAddress::parse(addr)? {
+            BridgeAddress::Tcp(tcp_addr) => {
+                Self::connect_stream(TcpStream::connect(tcp_addr).await?, config).await
+            }
+            #[cfg(unix)]
+            BridgeAddress::Unix(path) => {
+                Self::connect_stream(UnixStream::connect(path).await?, config).await
+            }
+        }
+    }
+    async fn connect_stream<T>(
+        stream: T,
+        config: &ClientBridgeConfig,
+    ) -> Result<(
+        Self,
+        Sender<TcpBridgeToServerMessage>,
+        Receiver<TcpBridgeToClientMessage>,
+    )>
+    where
+        T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (mut r, mut w) = Self::secure_stream(stream, config).await?;
+        if let Some(auth) = config.auth.as_ref() {
+            authenticate_to_server(&mut r, &mut w, auth).await?;
+        }
+        Self::connect_halves(r, w, config).await
+    }
+    /* Starts the server command (e.g. ssh somehost helicoid-helixserver --stdio) and talks
+    to it over its stdin / stdout. The first element of the command is the program to run.
+    TLS and authentication are not used, the command is expected to take care of that (ssh
+    does). Anything the command writes to stderr is passed through to our stderr. */
+    pub async fn connect_command(
+        command: &[String],
+        config: &ClientBridgeConfig,
+    ) -> Result<(
+        Self,
+        Sender<TcpBridgeToServerMessage>,
+        Receiver<TcpBridgeToClientMessage>,
+    )> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| anyhow!("Empty server command"))?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow!("Could not start server command {:?}: {}", program, e))?;
+        let r: BridgeReadHalf = Box::new(child.stdout.take().unwrap());
+        let w: BridgeWriteHalf = Box::new(child.stdin.take().unwrap());
+        let (mut bridge, sender, receiver) = Self::connect_halves(r, w, config).await?;
+        bridge._child = Some(child);
+        Ok((bridge, sender, receiver))
+    }
+    /* Runs the handshake on a connection that is already secured / authenticated */
+    async fn connect_halves(
+        mut r: BridgeReadHalf,
+        mut w: BridgeWriteHalf,
+        config: &ClientBridgeConfig,
+    ) -> Result<(
+        Self,
+        Sender<TcpBridgeToServerMessage>,
+        Receiver<TcpBridgeToClientMessage>,
+    )> {
+        let client_hello = ClientHello {
+            capabilities: config.capabilities.clone(),
+            resume: config.resume.clone(),
+            role: config.role.clone(),
+        };
+        let server_hello = client_handshake(&mut r, &mut w, &client_hello).await?;
+        log::debug!("Connected to server, negotiated: {:?}", server_hello);
+        let (mut bridge, sender, receiver) = Self::from_halves(r, w)?;
+        bridge.set_compression(FrameCompression::negotiated(
+            server_hello.capabilities.compression,
+            &config.compression,
+        ));
+        bridge.server_hello = Some(server_hello);
+        if let Some(recorder) = config.recorder.as_ref() {
+            bridge.set_recorder(recorder.clone());
+        }
+        bridge.set_keepalive(config.keepalive.clone());
+        Ok((bridge, sender, receiver))
+    }
+    async fn secure_stream<T>(
+        stream: T,
+        config: &ClientBridgeConfig,
+    ) -> Result<(BridgeReadHalf, BridgeWriteHalf)>
+    where
+        T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = config.tls.as_ref() {
+            return tls.connect(stream).await;
+        }
+        #[cfg(not(feature = "tls"))]
+        let _ = config;
+        let (r, w) = tokio::io::split(stream);
+        Ok((Box::new(r), Box::new(w)))
+    }
+    pub fn from_halves(
+        r: BridgeReadHalf,
+        w: BridgeWriteHalf,
+    ) -> Result<(
+        Self,
+        Sender<TcpBridgeToServerMessage>,
+        Receiver<TcpBridgeToClientMessage>,
+    )> {
         let (cs, cr) = oneshot::channel();
-        let (send, send_channel) = TcpBridgeSend::new(w, cr)?;
-        let (receive, receive_channel) = TcpBridgeReceive::new(r, cs)?;
-        Ok((Self { send, receive }, send_channel, receive_channel))
+        let (ks, kr) = mpsc::channel(KEEPALIVE_QUEUE_LENGTH);
+        let byte_counters = Arc::new(ByteCounters::default());
+        let link_status = Arc::new(LinkStatus::default());
+        let send_queue = Arc::new(SendQueueStatus::default());
+        let (payload_tx, payload_rx) = mpsc::channel(PAYLOAD_QUEUE_LENGTH);
+        let (send, send_channel) = TcpBridgeSend::new(
+            w,
+            cr,
+            kr,
+            byte_counters.clone(),
+            link_status.clone(),
+            send_queue.clone(),
+            payload_rx,
+        )?;
+        let (receive, receive_channel) =
+            TcpBridgeReceive::new(r, cs, ks, byte_counters.clone(), link_status.clone())?;
+        Ok((
+            Self {
+                send,
+                receive,
+                byte_counters,
+                link_status,
+                send_queue,
+                payload_tx,
+                server_hello: None,
+                _child: None,
+            },
+            send_channel,
+            receive_channel,
+        ))
+    }
+    /* The hello received from the server during connection setup, containing the
+    negotiated capabilities. None if the bridge was made from already set up halves. */
+    pub fn server_hello(&self) -> Option<&ServerHello> {
+        self.server_hello.as_ref()
+    }
+    /* Sets how frames sent to the server are compressed, the method has to be one
+    negotiated with the server */
+    pub fn set_compression(&mut self, compression: FrameCompression) {
+        self.send.compression = compression;
+    }
+    pub fn set_recorder(&mut self, recorder: Arc<SessionRecorder>) {
+        self.send.recorder = Some((recorder.clone(), RecordDirection::ToServer));
+        self.receive
+            .processor
+            .set_recorder(recorder, RecordDirection::ToClient);
+    }
+    /* Sets how often the server is pinged, and how long it may be silent before the
+    connection is closed */
+    pub fn set_keepalive(&mut self, keepalive: KeepaliveOptions) {
+        self.send.keepalive = keepalive;
+    }
+    pub fn byte_counters(&self) -> Arc<ByteCounters> {
+        self.byte_counters.clone()
+    }
+    pub fn link_status(&self) -> Arc<LinkStatus> {
+        self.link_status.clone()
+    }
+    pub fn send_queue(&self) -> Arc<SendQueueStatus> {
+        self.send_queue.clone()
+    }
+    /* Payloads sent through this are sent in chunks when no other messages are waiting */
+    pub fn payload_sender(&self) -> Sender<Payload> {
+        self.payload_tx.clone()
     }
     pub async fn process_rxtx(&mut self) -> Result<()> {
-        let ClientTcpBridge { send, receive } = self;
+        let ClientTcpBridge { send, receive, .. } = self;
         let send_proc_fut = send.process();
         let recv_proc_fut = receive.process();
-        let (send_proc_res, rec_proc_res) = tokio::join!(send_proc_fut, recv_proc_fut);
-        send_proc_res?;
-        rec_proc_res?;
+        /* Stop at the first error, the receive half may wait forever on a connection the
+        send half has given up on */
+        tokio::try_join!(send_proc_fut, recv_proc_fut)?;
         log::trace!("TcpCB: Processrxtx complete");
         Ok(())
         // Need to call process on send and on receive
@@ -116,47 +541,365 @@ impl ServerSingleTcpBridge {
         Receiver<TcpBridgeToServerMessage>,
     )> {
         let (r, w) = stream.into_split();
+        Self::handle_halves(Box::new(r), Box::new(w))
+    }
+    pub fn handle_halves(
+        r: BridgeReadHalf,
+        w: BridgeWriteHalf,
+    ) -> Result<(
+        Self,
+        Sender<Arc<TransferBuffer>>,
+        Receiver<TcpBridgeToServerMessage>,
+    )> {
         let (cs, cr) = oneshot::channel();
-        let (send, send_channel) = TcpBridgeSend::new(w, cr)?;
-        let (receive, receive_channel) = TcpBridgeReceive::new(r, cs)?;
-        Ok((Self { send, receive }, send_channel, receive_channel))
+        let (ks, kr) = mpsc::channel(KEEPALIVE_QUEUE_LENGTH);
+        let byte_counters = Arc::new(ByteCounters::default());
+        let link_status = Arc::new(LinkStatus::default());
+        let send_queue = Arc::new(SendQueueStatus::default());
+        let (payload_tx, payload_rx) = mpsc::channel(PAYLOAD_QUEUE_LENGTH);
+        let (send, send_channel) = TcpBridgeSend::new(
+            w,
+            cr,
+            kr,
+            byte_counters.clone(),
+            link_status.clone(),
+            send_queue.clone(),
+            payload_rx,
+        )?;
+        let (receive, receive_channel) =
+            TcpBridgeReceive::new(r, cs, ks, byte_counters.clone(), link_status.clone())?;
+        Ok((
+            Self {
+                send,
+                receive,
+                byte_counters,
+                link_status,
+                send_queue,
+                payload_tx,
+            },
+            send_channel,
+            receive_channel,
+        ))
+    }
+    /* Sets how frames sent to the client are compressed, the method has to be one
+    negotiated with the client */
+    pub fn set_compression(&mut self, compression: FrameCompression) {
+        self.send.compression = compression;
+    }
+    pub fn set_recorder(&mut self, recorder: Arc<SessionRecorder>) {
+        self.send.recorder = Some((recorder.clone(), RecordDirection::ToClient));
+        self.receive
+            .processor
+            .set_recorder(recorder, RecordDirection::ToServer);
+    }
+    /* Sets how often the client is pinged, and how long it may be silent before the
+    connection is closed */
+    pub fn set_keepalive(&mut self, keepalive: KeepaliveOptions) {
+        self.send.keepalive = keepalive;
+    }
+    pub fn byte_counters(&self) -> Arc<ByteCounters> {
+        self.byte_counters.clone()
+    }
+    pub fn link_status(&self) -> Arc<LinkStatus> {
+        self.link_status.clone()
+    }
+    pub fn send_queue(&self) -> Arc<SendQueueStatus> {
+        self.send_queue.clone()
+    }
+    /* Payloads sent through this are sent in chunks when no other messages are waiting */
+    pub fn payload_sender(&self) -> Sender<Payload> {
+        self.payload_tx.clone()
     }
     pub async fn process_rxtx(&mut self) -> Result<()> {
-        let ServerSingleTcpBridge { send, receive } = self;
+        let ServerSingleTcpBridge { send, receive, .. } = self;
         let send_proc_fut = send.process();
         let recv_proc_fut = receive.process();
-        let (send_proc_res, rec_proc_res) = tokio::join!(send_proc_fut, recv_proc_fut);
-        send_proc_res?;
-        rec_proc_res?;
+        /* Stop at the first error, the receive half may wait forever on a connection the
+        send half has given up on */
+        tokio::try_join!(send_proc_fut, recv_proc_fut)?;
         Ok(())
         // Need to call process on send and on receive
     }
 }
 
+impl Default for ServerBridgeConfig {
+    fn default() -> Self {
+        Self {
+            #[cfg(feature = "tls")]
+            tls: None,
+            auth: None,
+            capabilities: Default::default(),
+            compression: Default::default(),
+            session_grace_period: DEFAULT_SESSION_GRACE_PERIOD,
+            record_directory: None,
+            keepalive: Default::default(),
+        }
+    }
+}
+
 impl<S: TcpBridgeServerConnectionState> TcpBridgeServer<S> {
     pub async fn new() -> Result<Self> {
+        Self::with_config(ServerBridgeConfig::default()).await
+    }
+    pub async fn with_config(config: ServerBridgeConfig) -> Result<Self> {
         let (close_sender, _) = broadcast::channel(1);
 
         Ok(Self {
-            _listener: None,
-            _connections: Default::default(),
+            listener: None,
+            connections: Default::default(),
             close_sender,
+            config,
+            sessions: Default::default(),
         })
     }
 
-    async fn establish_connection(
-        stream: TcpStream,
-        peer_addr: SocketAddr,
+    /* Wraps the stream in the configured security layer (if any) and splits it */
+    async fn secure_stream<T>(
+        stream: T,
+        config: &ServerBridgeConfig,
+    ) -> Result<(BridgeReadHalf, BridgeWriteHalf)>
+    where
+        T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = config.tls.as_ref() {
+            return tls.accept(stream).await;
+        }
+        #[cfg(not(feature = "tls"))]
+        let _ = config;
+        let (r, w) = tokio::io::split(stream);
+        Ok((Box::new(r), Box::new(w)))
+    }
+
+    async fn establish_connection<T>(
+        this: Arc<TMutex<Self>>,
+        stream: T,
+        peer_addr: PeerAddress,
         close_receiver: BReceiver<()>,
+        config: ServerBridgeConfig,
         state_data: S::StateData,
-    ) -> Result<()> {
+    ) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
         //let local_address = socket.local_addr()?;
         log::trace!("Handle connection");
-        let (mut bridge, channel_tx, channel_rx) =
-            ServerSingleTcpBridge::handle_connection(stream).unwrap();
-        tokio::spawn(async move { bridge.process_rxtx().await.unwrap() });
-        let mut connection_state = S::new_state(
+        /* A peer that does not complete the TLS handshake and the authentication in time does
+        not get to hold on to the connection */
+        let secured = async {
+            let (mut r, mut w) = Self::secure_stream(stream, &config).await?;
+            if let Some(auth) = config.auth.as_ref() {
+                authenticate_client(&mut r, &mut w, auth).await?;
+                log::info!("Client at {} authenticated", peer_addr);
+            }
+            Ok::<_, anyhow::Error>((r, w))
+        };
+        let (r, w) = match tokio::time::timeout(AUTH_TIMEOUT, secured).await {
+            Ok(Ok(halves)) => halves,
+            Ok(Err(e)) => {
+                /* The connection is closed when the stream is dropped */
+                log::warn!(
+                    "Closing unauthenticated connection from {}: {}",
+                    peer_addr,
+                    e
+                );
+                return Ok(());
+            }
+            Err(_) => {
+                log::warn!(
+                    "Closing connection from {}, not authenticated within {:?}",
+                    peer_addr,
+                    AUTH_TIMEOUT
+                );
+                return Ok(());
+            }
+        };
+        Self::run_connection(this, r, w, peer_addr, close_receiver, config, state_data).await
+    }
+    /* Runs the handshake on a secured and authenticated connection, and then the connection
+    state until the connection is closed. If the client asks to resume a suspended session
+    the state data of that session is used instead of the supplied one, and when the
+    connection is lost the state is suspended for the grace period. */
+    async fn run_connection(
+        this: Arc<TMutex<Self>>,
+        mut r: BridgeReadHalf,
+        mut w: BridgeWriteHalf,
+        peer_addr: PeerAddress,
+        close_receiver: BReceiver<()>,
+        config: ServerBridgeConfig,
+        state_data: S::StateData,
+    ) -> Result<()> {
+        let client_hello = match read_client_hello(&mut r, &mut w).await {
+            Ok(client_hello) => client_hello,
+            Err(e) => {
+                log::warn!("Closing connection from {}: {}", peer_addr, e);
+                return Ok(());
+            }
+        };
+        let (session_id, resumed_state) =
+            Self::resume_session(&this, client_hello.resume.as_ref()).await;
+        let server_hello = ServerHello {
+            capabilities: client_hello.capabilities.intersection(&config.capabilities),
+            session_id,
+            resumed: resumed_state.is_some(),
+        };
+        if let Err(e) = send_server_hello(&mut w, &server_hello).await {
+            log::warn!("Closing connection from {}: {}", peer_addr, e);
+            /* Keep the session for another attempt by the client */
+            if let Some(state_data) = resumed_state {
+                Self::suspend_session(&this, session_id, state_data, &config).await;
+            }
+            return Ok(());
+        }
+        if server_hello.resumed {
+            log::info!("Client at {} resumed its session", peer_addr);
+        }
+        log::debug!(
+            "Client at {} connected with: {:?}",
+            peer_addr,
+            client_hello.capabilities
+        );
+        let (mut bridge, channel_tx, channel_rx) = ServerSingleTcpBridge::handle_halves(r, w)?;
+        let compression = FrameCompression::negotiated(
+            server_hello.capabilities.compression,
+            &config.compression,
+        );
+        log::debug!(
+            "Compressing frames to {} using: {:?}",
             peer_addr,
+            compression
+        );
+        bridge.set_compression(compression);
+        if let Some(record_directory) = config.record_directory.as_ref() {
+            match Self::session_recorder(record_directory, &session_id) {
+                Ok(recorder) => bridge.set_recorder(Arc::new(recorder)),
+                Err(e) => log::warn!("Not recording connection from {}: {}", peer_addr, e),
+            }
+        }
+        bridge.set_keepalive(config.keepalive.clone());
+        let byte_counters = bridge.byte_counters();
+        let link_status = bridge.link_status();
+        let send_queue = bridge.send_queue();
+        let payload_tx = bridge.payload_sender();
+        Self::register_connection(&this, session_id, &peer_addr).await;
+        let bridge_peer_addr = peer_addr.clone();
+        tokio::spawn(async move {
+            if let Err(e) = bridge.process_rxtx().await {
+                log::warn!("Connection to {} closed: {}", bridge_peer_addr, e);
+            }
+            log::info!(
+                "Connection to {} ended, {}, {}, {}",
+                bridge_peer_addr,
+                bridge.byte_counters(),
+                bridge.link_status(),
+                bridge.send_queue()
+            );
+        });
+        let state_result = Self::run_state(
+            ConnectionInfo {
+                peer_address: peer_addr,
+                client_hello,
+                capabilities: server_hello.capabilities,
+                byte_counters,
+                link_status,
+                send_queue,
+                payload_tx: Some(payload_tx),
+                session_id,
+                resumed: server_hello.resumed,
+            },
+            channel_tx,
+            channel_rx,
+            close_receiver,
+            resumed_state.unwrap_or(state_data),
+        )
+        .await;
+        Self::unregister_connection(&this, &session_id).await;
+        if let Some(state_data) = state_result? {
+            Self::suspend_session(&this, session_id, state_data, &config).await;
+        }
+        Ok(())
+    }
+    fn session_recorder(
+        record_directory: &Path,
+        session_id: &SessionId,
+    ) -> Result<SessionRecorder> {
+        let connected = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+        let path = record_directory.join(format!("{}-{}.hcrec", session_id, connected));
+        log::info!("Recording connection to {:?}", path);
+        SessionRecorder::create(&path)
+    }
+    /* Looks up the session the client asks to resume, expired sessions are dropped first (in
+    case their timers have not run yet). Returns the id to use for the connection and the state data if the session was found. */
+    async fn resume_session(
+        this: &Arc<TMutex<Self>>,
+        resume: Option<&SessionResume>,
+    ) -> (SessionId, Option<S::StateData>) {
+        let mut this_locked = this.lock().await;
+        let now = Instant::now();
+        this_locked
+            .sessions
+            .retain(|_, session| session.expires > now);
+        if let Some(resume) = resume {
+            if let Some(session) = this_locked.sessions.remove(&resume.session_id) {
+                return (resume.session_id, Some(session.state_data));
+            }
+            log::debug!("Session to resume not found, starting a new one");
+        }
+        (SessionId::generate(), None)
+    }
+    async fn suspend_session(
+        this: &Arc<TMutex<Self>>,
+        session_id: SessionId,
+        state_data: S::StateData,
+        config: &ServerBridgeConfig,
+    ) {
+        if config.session_grace_period.is_zero() {
+            return;
+        }
+        let expires = Instant::now() + config.session_grace_period;
+        this.lock().await.sessions.insert(
+            session_id,
+            SuspendedSession {
+                state_data,
+                expires,
+            },
+        );
+        /* The session is dropped when it expires, also when no client connects again, so its
+        state (e.g. the view of the client in a shared editor) does not outlive it */
+        let server = Arc::downgrade(this);
+        tokio::spawn(async move {
+            tokio::time::sleep_until(expires.into()).await;
+            let Some(server) = server.upgrade() else {
+                return;
+            };
+            let mut server_locked = server.lock().await;
+            /* The session may have been resumed and suspended again since */
+            let expired = server_locked
+                .sessions
+                .get(&session_id)
+                .is_some_and(|session| session.expires <= Instant::now());
+            if expired {
+                /* The state is dropped without holding the lock */
+                let session = server_locked.sessions.remove(&session_id);
+                drop(server_locked);
+                drop(session);
+                log::debug!("Suspended session expired");
+            }
+        });
+    }
+    /* Creates the connection state and runs it until the connection is closed, returns
+    the state data to keep if the state can be resumed */
+    async fn run_state(
+        connection_info: ConnectionInfo,
+        channel_tx: Sender<Arc<TransferBuffer>>,
+        channel_rx: Receiver<TcpBridgeToServerMessage>,
+        close_receiver: BReceiver<()>,
+        state_data: S::StateData,
+    ) -> Result<Option<S::StateData>> {
+        let mut connection_state = S::new_state(
+            connection_info,
             channel_tx,
             channel_rx,
             close_receiver,
@@ -168,27 +911,214 @@ impl<S: TcpBridgeServerConnectionState> TcpBridgeServer<S> {
         log::trace!("Connection intialized, run connection event loop");
         connection_state.event_loop().await?;
         log::trace!("Connection event loop completed");
-        Ok(())
+        Ok(connection_state.suspend())
+    }
+    /* Binds the listener for the address, unless it is already bound */
+    pub async fn bind(this: Arc<TMutex<Self>>, addr: &String) -> Result<()> {
+        Self::listener(&this, addr).await.map(|_| ())
+    }
+    async fn listener(this: &Arc<TMutex<Self>>, addr: &String) -> Result<Arc<BridgeListener>> {
+        let mut this_locked = this.lock().await;
+        if let Some((bound_addr, listener)) = this_locked.listener.as_ref() {
+            if bound_addr == addr {
+                return Ok(listener.clone());
+            }
+        }
+        let listener = Arc::new(match BridgeAddress::parse(addr)? {
+            BridgeAddress::Tcp(tcp_addr) => {
+                BridgeListener::Tcp(TcpListener::bind(&tcp_addr).await?)
+            }
+            #[cfg(unix)]
+            BridgeAddress::Unix(path) => BridgeListener::Unix(bind_unix_listener(&path)?, path),
+        });
+        log::trace!("Listening for connections, bound {}", addr);
+        this_locked.listener = Some((addr.clone(), listener.clone()));
+        Ok(listener)
+    }
+    /* Waits for a client to connect. The server is not locked while waiting, so connections
+    accepted earlier are not held up. */
+    pub async fn accept_connection(
+        this: Arc<TMutex<Self>>,
+        addr: &String,
+    ) -> Result<AcceptedConnection> {
+        let listener = Self::listener(&this, addr).await?;
+        let accepted = match listener.as_ref() {
+            BridgeListener::Tcp(listener) => {
+                let (socket, peer_addr) = listener.accept().await?;
+                AcceptedConnection {
+                    stream: Box::new(socket),
+                    peer_address: PeerAddress::Tcp(peer_addr),
+                }
+            }
+            #[cfg(unix)]
+            BridgeListener::Unix(listener, path) => {
+                let (socket, _) = listener.accept().await?;
+                AcceptedConnection {
+                    stream: Box::new(socket),
+                    peer_address: PeerAddress::Unix(path.clone()),
+                }
+            }
+        };
+        log::trace!("Accepted connection from {}", accepted.peer_address);
+        Ok(accepted)
+    }
+    /* Sets up an accepted connection and runs it on its own task */
+    pub async fn spawn_accepted(
+        this: Arc<TMutex<Self>>,
+        accepted: AcceptedConnection,
+        state_data: S::StateData,
+    ) {
+        Self::spawn_connection(this, accepted.stream, accepted.peer_address, state_data).await;
     }
     pub async fn wait_for_connection(
         this: Arc<TMutex<Self>>,
         addr: &String,
         state_data: S::StateData,
     ) -> Result<()> {
-        let addr_spawn = addr.clone();
-        let listener = TcpListener::bind(&addr).await?;
-        // Asynchronously wait for an inbound socket.
-        log::trace!("Waiting for connection, bound {}", addr_spawn);
-        let (socket, peer_addr) = listener.accept().await?;
-        log::trace!("Waiting for connection, accepted {}", addr_spawn);
-        let close_receiver = {
+        let accepted = Self::accept_connection(this.clone(), addr).await?;
+        Self::spawn_accepted(this, accepted, state_data).await;
+        Ok(())
+    }
+    /* The number of clients currently connected */
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+    async fn register_connection(
+        this: &Arc<TMutex<Self>>,
+        session_id: SessionId,
+        peer_addr: &PeerAddress,
+    ) {
+        let mut this_locked = this.lock().await;
+        this_locked
+            .connections
+            .insert(session_id, peer_addr.clone());
+        log::info!(
+            "Client at {} connected, {} client(s) connected",
+            peer_addr,
+            this_locked.connections.len()
+        );
+    }
+    async fn unregister_connection(this: &Arc<TMutex<Self>>, session_id: &SessionId) {
+        let mut this_locked = this.lock().await;
+        if let Some(peer_addr) = this_locked.connections.remove(session_id) {
+            log::info!(
+                "Client at {} disconnected, {} client(s) connected",
+                peer_addr,
+                this_locked.connections.len()
+            );
+        }
+    }
+    /* Serves a single client over the stdin / stdout of this process, used when the server
+    is started by the client (typically through ssh). TLS and authentication are skipped as
+    whoever is able to start the process already has access to the editor. Returns when the
+    connection is closed. */
+    pub async fn serve_stdio(this: Arc<TMutex<Self>>, state_data: S::StateData) -> Result<()> {
+        let (close_receiver, config) = {
             let this_locked = this.lock().await;
-            this_locked.close_sender.subscribe()
+            (
+                this_locked.close_sender.subscribe(),
+                this_locked.config.clone(),
+            )
         };
-        log::trace!("Waiting for connection, got close channel {}", addr_spawn);
+        Self::run_connection(
+            this,
+            Box::new(tokio::io::stdin()),
+            Box::new(tokio::io::stdout()),
+            PeerAddress::Stdio,
+            close_receiver,
+            config,
+            state_data,
+        )
+        .await
+    }
+    /* Connects a client running in the same process as the server. The transfer buffers
+    produced by the connection state are handed directly to the client, and the messages
+    from the client directly to the state, without any serialization. The capabilities are
+    negotiated as for remote clients, and the returned server hello contains the result.
+    The connection state runs on its own task until either end closes its channel. */
+    pub async fn connect_in_process(
+        this: Arc<TMutex<Self>>,
+        client_hello: ClientHello,
+        state_data: S::StateData,
+    ) -> (
+        ServerHello,
+        Sender<TcpBridgeToServerMessage>,
+        Receiver<Arc<TransferBuffer>>,
+    ) {
+        let (close_receiver, config) = {
+            let this_locked = this.lock().await;
+            (
+                this_locked.close_sender.subscribe(),
+                this_locked.config.clone(),
+            )
+        };
+        /* The client can not lose the connection, so there is no session to resume */
+        let server_hello = ServerHello {
+            capabilities: client_hello.capabilities.intersection(&config.capabilities),
+            session_id: SessionId::generate(),
+            resumed: false,
+        };
+        let (client_tx, channel_rx) = mpsc::channel(32);
+        let (channel_tx, client_rx) = mpsc::channel(32);
+        let connection_info = ConnectionInfo {
+            peer_address: PeerAddress::InProcess,
+            client_hello,
+            capabilities: server_hello.capabilities.clone(),
+            /* Nothing is serialized, so there is no traffic to count (or round trip time
+            to measure, or queue to coalesce) */
+            byte_counters: Default::default(),
+            link_status: Default::default(),
+            send_queue: Default::default(),
+            payload_tx: None,
+            session_id: server_hello.session_id,
+            resumed: false,
+        };
+        let session_id = server_hello.session_id;
         tokio::spawn(async move {
-            log::trace!("Waiting for connection on {}", addr_spawn);
-            match Self::establish_connection(socket, peer_addr, close_receiver, state_data).await {
+            Self::register_connection(&this, session_id, &PeerAddress::InProcess).await;
+            if let Err(e) = Self::run_state(
+                connection_info,
+                channel_tx,
+                channel_rx,
+                close_receiver,
+                state_data,
+            )
+            .await
+            {
+                log::warn!("Got error while processing in process connection: {:?}", e)
+            }
+            Self::unregister_connection(&this, &session_id).await;
+        });
+        (server_hello, client_tx, client_rx)
+    }
+    async fn spawn_connection<T>(
+        this: Arc<TMutex<Self>>,
+        socket: T,
+        peer_addr: PeerAddress,
+        state_data: S::StateData,
+    ) where
+        T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (close_receiver, config) = {
+            let this_locked = this.lock().await;
+            (
+                this_locked.close_sender.subscribe(),
+                this_locked.config.clone(),
+            )
+        };
+        log::trace!("Waiting for connection, got close channel {}", peer_addr);
+        tokio::spawn(async move {
+            log::trace!("Establishing connection from {}", peer_addr);
+            match Self::establish_connection(
+                this,
+                socket,
+                peer_addr,
+                close_receiver,
+                config,
+                state_data,
+            )
+            .await
+            {
                 Ok(_) => {
                     log::trace!("Establish connection returned");
                 }
@@ -197,21 +1127,35 @@ impl<S: TcpBridgeServerConnectionState> TcpBridgeServer<S> {
                 }
             }
         });
-        Ok(())
     }
 }
-impl<S> Drop for TcpBridgeServer<S> {
+impl<S: TcpBridgeServerConnectionState> Drop for TcpBridgeServer<S> {
     fn drop(&mut self) {
         let _ = self.close_sender.send(());
     }
 }
 
 type TBSSerializer = AllocSerializer<0x4000>;
+/* Pongs are only queued while the send half is busy, a full queue means it is stuck */
+const KEEPALIVE_QUEUE_LENGTH: usize = 4;
+/* Lower bound for the ping interval, to not flood the connection with pings */
+const MIN_KEEPALIVE_INTERVAL: Duration = Duration::from_millis(100);
+/* Payloads queued for sending, the sender waits when the queue is full */
+const PAYLOAD_QUEUE_LENGTH: usize = 8;
+
 impl<M> TcpBridgeSend<M>
 where
-    M: SerializeWith,
+    M: BridgeSendMessage,
 {
-    fn new(writer: OwnedWriteHalf, close_chan: OReceiver<()>) -> Result<(Self, Sender<M>)> {
+    fn new(
+        writer: BridgeWriteHalf,
+        close_chan: OReceiver<()>,
+        keepalive_chan: Receiver<Keepalive>,
+        byte_counters: Arc<ByteCounters>,
+        link_status: Arc<LinkStatus>,
+        send_queue: Arc<SendQueueStatus>,
+        payload_chan: Receiver<Payload>,
+    ) -> Result<(Self, Sender<M>)> {
         let (tx, rx) = mpsc::channel(32);
         let serializer = Some(TBSSerializer::default());
         let dummy_serializer = Some(CompositeSerializer::new(
@@ -227,26 +1171,59 @@ where
                 dummy_serializer,
                 chan: rx,
                 close_chan,
+                compression: Default::default(),
+                compressed: Vec::new(),
+                byte_counters,
+                recorder: None,
+                keepalive: Default::default(),
+                keepalive_chan,
+                link_status,
+                send_queue,
+                payload_chan,
+                payloads: PayloadSplitter::default(),
             },
             tx,
         ))
     }
     pub async fn process(&mut self) -> Result<()> {
+        let keepalive_enabled = self.keepalive.enabled();
+        let mut keepalive_ticker =
+            tokio::time::interval(self.keepalive.interval.max(MIN_KEEPALIVE_INTERVAL));
+        keepalive_ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
         loop {
+            /* Polled in order, so the keepalive messages are sent without delay and a
+            payload chunk is only sent when nothing else is waiting */
             tokio::select! {
+                biased;
+                Some(keepalive) = self.keepalive_chan.recv() => {
+                    self.send_message(&M::Wire::from_keepalive(keepalive)).await?;
+                },
+                _ = keepalive_ticker.tick(), if keepalive_enabled => {
+                    let silent = self.link_status.since_last_received();
+                    if silent > self.keepalive.timeout {
+                        return Err(anyhow!(
+                            "Nothing received from the other end for {:?}, closing the connection",
+                            silent
+                        ));
+                    }
+                    let ping = Keepalive::Ping(self.link_status.ping_sent());
+                    self.send_message(&M::Wire::from_keepalive(ping)).await?;
+                },
                 received = self.chan.recv() => {
                     match received {
-                        Some(message) => {
-                            let mut serializer = self.serializer.take().unwrap();
-                            let mut dummy_serializer = self.dummy_serializer.take().unwrap();
-                            message.serialize(&mut serializer, &mut dummy_serializer).unwrap();
-                            let (inner_serializer, scratch, shared) = serializer.into_components();
-                            let mut bytes = inner_serializer.into_inner();
-                            log::trace!("Tcp bridge Sending {} bytes ({:?})", bytes.len(), bytes);
-                            self.tcp_conn.write(&bytes).await?;
-                            bytes.clear();
-                            self.serializer = Some(TBSSerializer::new(AlignedSerializer::new(bytes), scratch, shared));
-                            self.dummy_serializer = Some(dummy_serializer);
+                        Some(mut message) => {
+                            /* Messages queued while the previous ones were written are
+                            merged into this one where possible */
+                            while let Ok(later) = self.chan.try_recv() {
+                                match message.coalesce(later) {
+                                    None => self.send_queue.add_coalesced(),
+                                    Some(later) => {
+                                        self.send_queued(&message).await?;
+                                        message = later;
+                                    }
+                                }
+                            }
+                            self.send_queued(&message).await?;
                         }
                         None => {
                             break;
@@ -256,113 +1233,655 @@ where
                 _ = &mut self.close_chan => {
                     break;
                 }
+                Some(payload) = self.payload_chan.recv() => {
+                    self.payloads.push(payload);
+                },
+                _ = std::future::ready(()), if !self.payloads.is_empty() => {
+                    if let Some(chunk) = self.payloads.next_chunk() {
+                        self.send_message(&M::Wire::from_payload_chunk(chunk)).await?;
+                    }
+                }
             }
         }
         Ok(())
     }
+    /* Sends a message taken from the queue, keeping the queue status up to date */
+    async fn send_queued(&mut self, message: &M) -> Result<()> {
+        self.send_queue.set_unsent(self.chan.len() + 1);
+        self.send_message(message).await?;
+        self.send_queue.set_unsent(self.chan.len());
+        Ok(())
+    }
+    /* Serializes and writes a message. When the keepalive is enabled writing gives up after
+    the keepalive timeout, as writing to a dead connection blocks forever once the send
+    buffer is full. */
+    async fn send_message<T: SerializeWith>(&mut self, message: &T) -> Result<()> {
+        let mut serializer = self.serializer.take().unwrap();
+        let mut dummy_serializer = self.dummy_serializer.take().unwrap();
+        message
+            .serialize(&mut serializer, &mut dummy_serializer)
+            .unwrap();
+        let (inner_serializer, scratch, shared) = serializer.into_components();
+        let mut bytes = inner_serializer.into_inner();
+        log::trace!("Tcp bridge Sending {} bytes ({:?})", bytes.len(), bytes);
+        if let Some((recorder, direction)) = self.recorder.as_ref() {
+            recorder.record_frames(*direction, &bytes);
+        }
+        let frames: &[u8] = if self.compression.method.is_some() {
+            compress_frames(&bytes, &self.compression, &mut self.compressed);
+            &self.compressed
+        } else {
+            &bytes
+        };
+        let wire_length = frames.len();
+        let tcp_conn = &mut self.tcp_conn;
+        let write = async move {
+            tcp_conn.write_all(frames).await?;
+            tcp_conn.flush().await
+        };
+        if self.keepalive.enabled() {
+            tokio::time::timeout(self.keepalive.timeout, write)
+                .await
+                .map_err(|_| {
+                    anyhow!(
+                        "Sending to the other end did not complete within {:?}, closing the connection",
+                        self.keepalive.timeout
+                    )
+                })??;
+        } else {
+            write.await?;
+        }
+        self.byte_counters.add_sent(bytes.len(), wire_length);
+        bytes.clear();
+        self.serializer = Some(TBSSerializer::new(
+            AlignedSerializer::new(bytes),
+            scratch,
+            shared,
+        ));
+        self.dummy_serializer = Some(dummy_serializer);
+        Ok(())
+    }
 }
 
-#[repr(C, align(16))]
-struct AlignedBuffer {
-    contents: [u8; 0x10000],
+impl SendQueueStatus {
+    pub fn unsent(&self) -> usize {
+        self.unsent.load(Ordering::Relaxed)
+    }
+    pub fn coalesced(&self) -> usize {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+    /* Waits until all the messages handed to the bridge are written. This may also return
+    when the queue was drained earlier, so the number of unsent messages should be checked
+    again afterwards. */
+    pub async fn drained(&self) {
+        self.drained.notified().await
+    }
+    fn set_unsent(&self, unsent: usize) {
+        self.unsent.store(unsent, Ordering::Relaxed);
+        if unsent == 0 {
+            self.drained.notify_one();
+        }
+    }
+    fn add_coalesced(&self) {
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+    }
 }
-enum ReadResult {
-    GotPacket,
-    NoData,
-    StopReading,
+
+impl fmt::Display for SendQueueStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} queued messages coalesced", self.coalesced())
+    }
 }
-const PACKET_HEADER_LENGTH: usize = 4;
-const PACKET_HEADER_ADJUST: usize = 16 - PACKET_HEADER_LENGTH;
+
 impl<M: Archive> TcpBridgeReceive<M>
 where
-    M::Archived: Deserialize<M, rkyv::Infallible>,
+    M::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + Deserialize<M, rkyv::Infallible>,
 {
-    fn new(reader: OwnedReadHalf, close_chan: OSender<()>) -> Result<(Self, Receiver<M>)> {
+    /* Reads from the connection until a whole packet is received. Returns None if the
+    connection was closed by the other end. The processor only asks for the exact amount
+    of data remaining for the current packet, so nothing is read beyond it. Invalid packets
+    are returned as an InvalidPacketError. */
+    pub(crate) async fn read_packet(
+        tcp_conn: &mut BridgeReadHalf,
+        processor: &mut TcpBridgeReceiveProcessor<M>,
+    ) -> Result<Option<M>> {
+        loop {
+            if let Some(archive) = processor.extract_archive()? {
+                return Ok(Some(archive));
+            }
+            if let Some(read_buffer) = processor.next_read_buffer() {
+                let data_read_length = tcp_conn.read(read_buffer).await?;
+                processor.mark_data_read(data_read_length);
+                if data_read_length == 0 {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+impl<M: Archive + KeepaliveCarrier> TcpBridgeReceive<M>
+where
+    M::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + Deserialize<M, rkyv::Infallible>,
+{
+    fn new(
+        reader: BridgeReadHalf,
+        close_chan: OSender<()>,
+        keepalive_chan: Sender<Keepalive>,
+        byte_counters: Arc<ByteCounters>,
+        link_status: Arc<LinkStatus>,
+    ) -> Result<(Self, Receiver<M>)> {
         let (tx, rx) = mpsc::channel(32);
+        let mut processor = TcpBridgeReceiveProcessor::new();
+        processor.set_byte_counters(byte_counters);
         Ok((
             Self {
                 tcp_conn: reader,
                 chan: tx,
                 close_chan: Some(close_chan),
-                processor: TcpBridgeReceiveProcessor::new(),
+                processor,
+                keepalive_chan,
+                link_status,
             },
             rx,
         ))
     }
-    /* Returns true if interations should continue */
-    async fn try_read(
-        &mut self,
-        _buffer: &mut [u8],
-        _pkg_offset: &mut usize,
-        _pkg_len: &mut usize,
-        _buffer_filled: &mut usize,
-    ) -> Result<ReadResult> {
-        loop {
-            let read_buffer = self.processor.next_read_buffer();
-            if let Some(read_buffer) = read_buffer {
-                let data_read_length = match self.tcp_conn.try_read(read_buffer) {
-                    Ok(n) => n,
-                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                        self.processor.mark_data_read(0);
-                        log::trace!("Read would block");
-                        return Ok(ReadResult::NoData);
-                    }
-                    Err(e) => return Err(e.into()),
-                };
-                self.processor.mark_data_read(data_read_length);
-            }
-            if let Some(archive) = self.processor.extract_archive() {
-                match self.chan.send(archive).await {
-                    Ok(_) => {}
-                    Err(_e) => {
-                        /* There are no receiver anymore, close the socket receiver */
-                        log::debug!("Client channel send error");
-                        return Ok(ReadResult::StopReading);
-                    }
-                }
-                return Ok(ReadResult::GotPacket);
-            }
-        }
-    }
-
     pub async fn process(&mut self) -> Result<()> {
-        let mut backing_buffer = AlignedBuffer {
-            contents: [0u8; 0x10000],
-        };
-        let buffer = &mut backing_buffer.contents[PACKET_HEADER_ADJUST..];
-        let mut pkg_offset: usize = 0;
-        let mut pkg_len: usize = u32::MAX as usize;
-        let mut buffer_filled: usize = 0;
         log::trace!("TCPBR proc");
-        'outer_loop: loop {
+        let Self {
+            tcp_conn,
+            chan,
+            processor,
+            keepalive_chan,
+            link_status,
+            ..
+        } = self;
+        let result = loop {
             tokio::select! {
-            _readable = self.tcp_conn.readable() => {
-                'inner_read: loop{
-                        match self.try_read(
-                            buffer,
-                            &mut pkg_offset,
-                            &mut pkg_len,
-                            &mut buffer_filled,
-                        ).await?{
-                            ReadResult::GotPacket => {},
-                            ReadResult::NoData => {break 'inner_read;},
-                            ReadResult::StopReading => {break 'outer_loop;},
+                packet = Self::read_packet(tcp_conn, processor) => {
+                    match packet {
+                        Ok(Some(archive)) => {
+                            link_status.packet_received();
+                            if let Some(keepalive) = archive.keepalive() {
+                                Self::handle_keepalive(keepalive, link_status, keepalive_chan);
+                            } else if chan.send(archive).await.is_err() {
+                                /* There are no receiver anymore, close the socket receiver */
+                                log::debug!("Client channel send error");
+                                break Ok(());
+                            }
+                        }
+                        Ok(None) => {
+                            log::trace!("Connection closed by peer");
+                            break Ok(());
+                        }
+                        Err(e) => {
+                            log::warn!("Closing connection after receive error: {}", e);
+                            break Err(e);
                         }
                     }
                 },
-                  _ = self.chan.closed() =>{
-                        log::trace!("Client channel closed");
-                        break;
-                    }
+                _ = chan.closed() => {
+                    log::trace!("Client channel closed");
+                    break Ok(());
                 }
-        }
-        /* Tell the sender that the connection has closed */
+            }
+        };
+        /* Tell the sender that the connection has closed, also when receiving failed so the
+        whole connection is torn down */
         if let Some(close_chan) = self.close_chan.take() {
             close_chan
                 .send(())
                 .map_err(|_| anyhow!("Error while notifying sender about disconnect"))?;
         }
         log::trace!("TCPBR proc end");
-        Ok(())
+        result
+    }
+    fn handle_keepalive(
+        keepalive: Keepalive,
+        link_status: &LinkStatus,
+        keepalive_chan: &Sender<Keepalive>,
+    ) {
+        match keepalive {
+            Keepalive::Ping(id) => {
+                /* Answered by the send half, if its queue is full it is stuck and could not
+                answer in time anyway */
+                let _ = keepalive_chan.try_send(Keepalive::Pong(id));
+            }
+            Keepalive::Pong(id) => {
+                if let Some(round_trip_time) = link_status.pong_received(id) {
+                    log::debug!("Round trip time {:?}, {}", round_trip_time, link_status);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gfx::{
+        HelicoidToClientMessage, MetaDrawBlock, NewRenderBlock, PointF32,
+        RemoteSingleChangeElement, RenderBlockDescription, RenderBlockId, RenderBlockLocation,
+        RenderBlockPath, RenderBlockRemoveInstruction,
+    };
+    use crate::handshake::server_handshake;
+    use crate::input::HelicoidToServerMessage;
+    use crate::payload::{ClipboardSelection, PayloadAssembler, PAYLOAD_CHUNK_SIZE};
+    use smallvec::smallvec;
+
+    /* Connection state answering every character from the client with a removal of the
+    block with the same id */
+    struct EchoState {
+        channel_tx: Sender<Arc<TransferBuffer>>,
+        channel_rx: Receiver<TcpBridgeToServerMessage>,
+    }
+
+    #[async_trait]
+    impl TcpBridgeServerConnectionState for EchoState {
+        type StateData = ();
+        async fn new_state(
+            _connection_info: ConnectionInfo,
+            channel_tx: Sender<Arc<TransferBuffer>>,
+            channel_rx: Receiver<TcpBridgeToServerMessage>,
+            _close_rx: BReceiver<()>,
+            _state_data: Self::StateData,
+        ) -> Self {
+            Self {
+                channel_tx,
+                channel_rx,
+            }
+        }
+        async fn initialize(&mut self) -> Result<()> {
+            Ok(())
+        }
+        async fn event_loop(&mut self) -> Result<()> {
+            while let Some(message) = self.channel_rx.recv().await {
+                if let HelicoidToServerMessage::CharReceived(c) = message.message {
+                    let buffer = TransferBuffer::new_removals(
+                        RenderBlockPath::top(),
+                        smallvec![RenderBlockId(c as u16)],
+                    );
+                    self.channel_tx.send(Arc::new(buffer)).await?;
+                }
+            }
+            Ok(())
+        }
+        fn suspend(self) -> Option<Self::StateData> {
+            Some(())
+        }
+    }
+
+    #[tokio::test]
+    async fn in_process_connection() {
+        let server = Arc::new(TMutex::new(
+            TcpBridgeServer::<EchoState>::new().await.unwrap(),
+        ));
+        let client_hello = ClientHello {
+            capabilities: Capabilities::default(),
+            resume: None,
+            role: ClientRole::Editor,
+        };
+        let (server_hello, tx, mut rx) =
+            TcpBridgeServer::connect_in_process(server, client_hello, ()).await;
+        assert_eq!(server_hello.capabilities, Capabilities::default());
+        tx.send(TcpBridgeToServerMessage {
+            message: HelicoidToServerMessage::CharReceived('i' as u32),
+        })
+        .await
+        .unwrap();
+        let buffer = rx.recv().await.unwrap();
+        assert_eq!(
+            buffer.removals().get(&RenderBlockPath::top()).unwrap(),
+            &vec![RenderBlockId('i' as u16)]
+        );
+        assert_eq!(buffer.to_messages().len(), 2);
+        /* Closing the client end ends the connection state, which closes the server end */
+        drop(tx);
+        assert!(rx.recv().await.is_none());
+    }
+
+    /* Connects a client to the server over an in memory stream, the server side runs until
+    the client drops its bridge */
+    async fn duplex_connection(
+        server: &Arc<TMutex<TcpBridgeServer<EchoState>>>,
+        config: &ClientBridgeConfig,
+    ) -> (ClientTcpBridge, tokio::task::JoinHandle<Result<()>>) {
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let (sr, sw) = tokio::io::split(server_stream);
+        let (close_receiver, server_config) = {
+            let server_locked = server.lock().await;
+            (
+                server_locked.close_sender.subscribe(),
+                server_locked.config.clone(),
+            )
+        };
+        let connection = tokio::spawn(TcpBridgeServer::run_connection(
+            server.clone(),
+            Box::new(sr),
+            Box::new(sw),
+            PeerAddress::InProcess,
+            close_receiver,
+            server_config,
+            (),
+        ));
+        let (cr, cw) = tokio::io::split(client_stream);
+        let (bridge, _tx, _rx) =
+            ClientTcpBridge::connect_halves(Box::new(cr), Box::new(cw), config)
+                .await
+                .unwrap();
+        (bridge, connection)
+    }
+
+    #[tokio::test]
+    async fn session_is_resumed() {
+        let server = Arc::new(TMutex::new(
+            TcpBridgeServer::<EchoState>::new().await.unwrap(),
+        ));
+        let (bridge, connection) = duplex_connection(&server, &Default::default()).await;
+        let first_hello = bridge.server_hello().unwrap().clone();
+        assert!(!first_hello.resumed);
+        /* Losing the connection suspends the session */
+        drop(bridge);
+        connection.await.unwrap().unwrap();
+
+        let config = ClientBridgeConfig {
+            resume: Some(SessionResume {
+                session_id: first_hello.session_id,
+                held_blocks: Vec::new(),
+            }),
+            ..Default::default()
+        };
+        let (bridge, _connection) = duplex_connection(&server, &config).await;
+        let resumed_hello = bridge.server_hello().unwrap();
+        assert!(resumed_hello.resumed);
+        assert_eq!(resumed_hello.session_id, first_hello.session_id);
+        /* A session can only be resumed once */
+        let (bridge, _connection) = duplex_connection(&server, &config).await;
+        assert!(!bridge.server_hello().unwrap().resumed);
+    }
+
+    #[tokio::test]
+    async fn suspended_session_expires() {
+        let server = Arc::new(TMutex::new(
+            TcpBridgeServer::<EchoState>::with_config(ServerBridgeConfig {
+                session_grace_period: Duration::from_millis(50),
+                ..Default::default()
+            })
+            .await
+            .unwrap(),
+        ));
+        let (bridge, connection) = duplex_connection(&server, &Default::default()).await;
+        drop(bridge);
+        connection.await.unwrap().unwrap();
+        assert_eq!(server.lock().await.sessions.len(), 1);
+        /* The session is dropped without another client connecting */
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(server.lock().await.sessions.is_empty());
+    }
+
+    fn duplex_halves(stream: tokio::io::DuplexStream) -> (BridgeReadHalf, BridgeWriteHalf) {
+        let (r, w) = tokio::io::split(stream);
+        (Box::new(r), Box::new(w))
+    }
+
+    #[tokio::test]
+    async fn keepalive_measures_round_trip_time() {
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let (sr, sw) = duplex_halves(server_stream);
+        let (cr, cw) = duplex_halves(client_stream);
+        let (mut server_bridge, _server_tx, _server_rx) =
+            ServerSingleTcpBridge::handle_halves(sr, sw).unwrap();
+        let (mut client_bridge, _client_tx, _client_rx) =
+            ClientTcpBridge::from_halves(cr, cw).unwrap();
+        client_bridge.set_keepalive(KeepaliveOptions::with_timeout(Duration::from_secs(1)));
+        let link_status = client_bridge.link_status();
+        tokio::spawn(async move { server_bridge.process_rxtx().await });
+        tokio::spawn(async move { client_bridge.process_rxtx().await });
+        /* The first ping is sent right away, and answered by the server bridge */
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while link_status.round_trip_time().is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn queued_buffers_are_coalesced() {
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let (sr, sw) = duplex_halves(server_stream);
+        let (cr, cw) = duplex_halves(client_stream);
+        let (mut server_bridge, server_tx, _server_rx) =
+            ServerSingleTcpBridge::handle_halves(sr, sw).unwrap();
+        let (mut client_bridge, _client_tx, mut client_rx) =
+            ClientTcpBridge::from_halves(cr, cw).unwrap();
+        let send_queue = server_bridge.send_queue();
+        let new_block = |id: u16| NewRenderBlock {
+            id: RenderBlockId(id),
+            contents: RenderBlockDescription::MetaBox(MetaDrawBlock {
+                extent: PointF32::new(10.0, 10.0),
+                buffered: false,
+                alpha: None,
+                sub_blocks: smallvec![],
+            }),
+            update: true,
+        };
+        let location = |x: f32| RenderBlockLocation {
+            id: RenderBlockId(2),
+            location: PointF32::new(x, 0.0),
+            layer: 0,
+        };
+        /* Queue several frames before the bridge runs, like a slow connection would */
+        let top = RenderBlockPath::top();
+        let buffers = [
+            TransferBuffer::new_additions(top.clone(), smallvec![new_block(1), new_block(2)]),
+            TransferBuffer::new_moves(top.clone(), smallvec![location(1.0)]),
+            TransferBuffer::new_removals(top.clone(), smallvec![RenderBlockId(1)]),
+            TransferBuffer::new_moves(top.clone(), smallvec![location(2.0)]),
+        ];
+        for buffer in buffers {
+            server_tx.send(Arc::new(buffer)).await.unwrap();
+        }
+        tokio::spawn(async move { server_bridge.process_rxtx().await });
+        tokio::spawn(async move { client_bridge.process_rxtx().await });
+        let updates = match client_rx.recv().await.unwrap().message {
+            HelicoidToClientMessage::BlockUpdates(block_updates) => block_updates.updates,
+            other => panic!("Expected block updates, got: {:?}", other),
+        };
+        assert!(matches!(
+            client_rx.recv().await.unwrap().message,
+            HelicoidToClientMessage::FrameComplete(_)
+        ));
+        /* A single frame is sent, block 1 is never added and block 2 is only moved to where
+        it ends up */
+        assert_eq!(send_queue.coalesced(), 3);
+        let changes: Vec<_> = updates.into_iter().map(|update| update.change).collect();
+        assert_eq!(
+            changes,
+            vec![
+                RemoteSingleChangeElement::RemoveRenderBlocks(smallvec![
+                    RenderBlockRemoveInstruction {
+                        offset: RenderBlockId(1),
+                        mask: RenderBlockId(0),
+                    }
+                ]),
+                RemoteSingleChangeElement::NewRenderBlocks(smallvec![new_block(2)]),
+                RemoteSingleChangeElement::MoveBlockLocations(smallvec![location(2.0)]),
+            ]
+        );
+        tokio::time::timeout(Duration::from_secs(5), send_queue.drained())
+            .await
+            .unwrap();
+        assert_eq!(send_queue.unsent(), 0);
+    }
+
+    #[tokio::test]
+    async fn payload_is_sent_in_chunks() {
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let (sr, sw) = duplex_halves(server_stream);
+        let (cr, cw) = duplex_halves(client_stream);
+        let (mut server_bridge, _server_tx, mut server_rx) =
+            ServerSingleTcpBridge::handle_halves(sr, sw).unwrap();
+        let (mut client_bridge, client_tx, _client_rx) =
+            ClientTcpBridge::from_halves(cr, cw).unwrap();
+        let contents = "pasted text\n".repeat(10000);
+        client_bridge
+            .payload_sender()
+            .send(Payload::clipboard(
+                ClipboardSelection::Clipboard,
+                contents.clone(),
+            ))
+            .await
+            .unwrap();
+        client_tx
+            .send(TcpBridgeToServerMessage {
+                message: HelicoidToServerMessage::CharReceived('x' as u32),
+            })
+            .await
+            .unwrap();
+        tokio::spawn(async move { server_bridge.process_rxtx().await });
+        tokio::spawn(async move { client_bridge.process_rxtx().await });
+        /* The message queued after the payload is not held up by it */
+        assert_eq!(
+            server_rx.recv().await.unwrap().message,
+            HelicoidToServerMessage::CharReceived('x' as u32)
+        );
+        let mut assembler = PayloadAssembler::default();
+        let mut chunks = 0;
+        let payload = loop {
+            let HelicoidToServerMessage::PayloadChunk(chunk) =
+                server_rx.recv().await.unwrap().message
+            else {
+                panic!("Expected a payload chunk");
+            };
+            chunks += 1;
+            if let Some(payload) = assembler.add_chunk(chunk).unwrap() {
+                break payload;
+            }
+        };
+        assert_eq!(chunks, contents.len().div_ceil(PAYLOAD_CHUNK_SIZE));
+        assert_eq!(
+            payload,
+            Payload::clipboard(ClipboardSelection::Clipboard, contents)
+        );
+    }
+
+    #[tokio::test]
+    async fn silent_peer_is_disconnected() {
+        let (client_stream, _silent_stream) = tokio::io::duplex(4096);
+        let (cr, cw) = duplex_halves(client_stream);
+        let (mut bridge, _tx, _rx) = ClientTcpBridge::from_halves(cr, cw).unwrap();
+        bridge.set_keepalive(KeepaliveOptions::with_timeout(Duration::from_millis(300)));
+        let result = tokio::time::timeout(Duration::from_secs(5), bridge.process_rxtx())
+            .await
+            .unwrap();
+        assert!(result.unwrap_err().to_string().contains("Nothing received"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn addresses_are_parsed() {
+        assert_eq!(
+            BridgeAddress::parse("127.0.0.1:15566").unwrap(),
+            BridgeAddress::Tcp(String::from("127.0.0.1:15566"))
+        );
+        assert_eq!(
+            BridgeAddress::parse("unix:/tmp/helicoid.sock").unwrap(),
+            BridgeAddress::Unix(PathBuf::from("/tmp/helicoid.sock"))
+        );
+        assert!(BridgeAddress::parse("unix:").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn message_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("helicoid-test-{}.sock", std::process::id()));
+        let listener = bind_unix_listener(&path).unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (r, w) = tokio::io::split(stream);
+            let (mut r, mut w): (BridgeReadHalf, BridgeWriteHalf) = (Box::new(r), Box::new(w));
+            server_handshake(&mut r, &mut w, &Capabilities::default())
+                .await
+                .unwrap();
+            let (mut bridge, _tx, mut rx) = ServerSingleTcpBridge::handle_halves(r, w).unwrap();
+            tokio::spawn(async move { bridge.process_rxtx().await });
+            rx.recv().await.unwrap()
+        });
+
+        let address = BridgeAddress::Unix(path.clone()).to_string();
+        let (mut bridge, tx, _rx) = ClientTcpBridge::connect(&address).await.unwrap();
+        tokio::spawn(async move { bridge.process_rxtx().await });
+        tx.send(TcpBridgeToServerMessage {
+            message: HelicoidToServerMessage::CharReceived('u' as u32),
+        })
+        .await
+        .unwrap();
+        let received = server.await.unwrap();
+        assert_eq!(
+            received.message,
+            HelicoidToServerMessage::CharReceived('u' as u32)
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn simultaneous_clients() {
+        let path =
+            std::env::temp_dir().join(format!("helicoid-test-clients-{}.sock", std::process::id()));
+        let address = BridgeAddress::Unix(path.clone()).to_string();
+        let server = Arc::new(TMutex::new(
+            TcpBridgeServer::<EchoState>::new().await.unwrap(),
+        ));
+        TcpBridgeServer::bind(server.clone(), &address)
+            .await
+            .unwrap();
+        let accept_server = server.clone();
+        let accept_address = address.clone();
+        tokio::spawn(async move {
+            loop {
+                TcpBridgeServer::wait_for_connection(accept_server.clone(), &accept_address, ())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let mut clients = Vec::new();
+        for c in ['a', 'b'] {
+            let (mut bridge, tx, rx) = ClientTcpBridge::connect(&address).await.unwrap();
+            tokio::spawn(async move { bridge.process_rxtx().await });
+            clients.push((c, tx, rx));
+        }
+        /* Both clients are served while the other is connected */
+        for (c, tx, rx) in clients.iter_mut() {
+            tx.send(TcpBridgeToServerMessage {
+                message: HelicoidToServerMessage::CharReceived(*c as u32),
+            })
+            .await
+            .unwrap();
+            let received = rx.recv().await.unwrap();
+            assert!(matches!(
+                received.message,
+                HelicoidToClientMessage::BlockUpdates(_)
+            ));
+        }
+        assert_eq!(server.lock().await.connection_count(), 2);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn failing_server_command_is_reported() {
+        let config = ClientBridgeConfig::default();
+        assert!(ClientTcpBridge::connect_command(&[], &config)
+            .await
+            .is_err());
+        /* The command exits without talking the protocol, so the handshake fails */
+        let command = vec![String::from("true")];
+        assert!(ClientTcpBridge::connect_command(&command, &config)
+            .await
+            .is_err());
     }
 }
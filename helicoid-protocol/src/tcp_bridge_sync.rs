@@ -1,5 +1,7 @@
 use anyhow::Result;
 
+use bytecheck::CheckBytes;
+use rkyv::validation::validators::DefaultValidator;
 use rkyv::Deserialize;
 use rkyv::{Archive, Infallible};
 use std::io::{Read, Write};
@@ -10,7 +12,9 @@ use rkyv::ser::serializers::{
 };
 
 use crate::bridge_logic::SerializeWith;
-use crate::bridge_logic::{DummyWriter, TBSSerializer, TcpBridgeReceiveProcessor};
+use crate::bridge_logic::{
+    DummyWriter, InvalidPacketError, TBSSerializer, TcpBridgeReceiveProcessor,
+};
 
 pub struct TcpBridgeSend<M> {
     tcp_conn: TcpStream,
@@ -29,7 +33,7 @@ pub fn connect_to_server<R, S>(
 ) -> Result<(TcpBridgeReceive<R>, TcpBridgeSend<S>), std::io::Error>
 where
     R: Archive,
-    R::Archived: Deserialize<R, rkyv::Infallible>,
+    R::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + Deserialize<R, rkyv::Infallible>,
     S: SerializeWith,
 {
     let stream = TcpStream::connect(addr)?;
@@ -43,13 +47,15 @@ where
 pub enum TcpBridgeReceiveError {
     IoError(std::io::Error),
     NoMoreData,
+    /* The connection should be closed after receiving an invalid packet */
+    InvalidPacket(InvalidPacketError),
 }
 pub enum TcpBridgeSendError {
     IoError(std::io::Error),
 }
 impl<M: Archive> TcpBridgeReceive<M>
 where
-    M::Archived: Deserialize<M, rkyv::Infallible>,
+    M::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + Deserialize<M, rkyv::Infallible>,
 {
     pub fn new(reader: TcpStream) -> Result<Self, std::io::Error> {
         reader.set_nonblocking(true)?;
@@ -74,8 +80,10 @@ where
                 if num_read == 0 {
                     break;
                 }
-                if let Some(archive) = self.processor.extract_archive() {
-                    return Ok(archive);
+                match self.processor.extract_archive() {
+                    Ok(Some(archive)) => return Ok(archive),
+                    Ok(None) => {}
+                    Err(e) => return Err(TcpBridgeReceiveError::InvalidPacket(e)),
                 }
             }
         }
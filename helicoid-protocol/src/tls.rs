@@ -0,0 +1,229 @@
+/* Optional TLS layer for the async tcp bridge. The server is configured with a certificate
+chain and a private key, and the client with the certificate(s) it trusts (typically the
+self signed certificate of the server) and the name the server certificate is issued for. */
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::tcp_bridge_async::{BridgeReadHalf, BridgeWriteHalf};
+
+pub struct ServerTlsConfig {
+    acceptor: TlsAcceptor,
+}
+
+pub struct ClientTlsConfig {
+    connector: TlsConnector,
+    server_name: ServerName,
+}
+
+impl ServerTlsConfig {
+    pub fn from_pem_files(certificate_path: &Path, private_key_path: &Path) -> Result<Self> {
+        let certificates = load_pem_certificates(certificate_path)?;
+        let private_key = load_pem_private_key(private_key_path)?;
+        Self::from_der(certificates, private_key)
+    }
+    pub fn from_der(certificate_chain: Vec<Vec<u8>>, private_key: Vec<u8>) -> Result<Self> {
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                certificate_chain.into_iter().map(Certificate).collect(),
+                PrivateKey(private_key),
+            )?;
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+    pub async fn accept<S>(&self, stream: S) -> Result<(BridgeReadHalf, BridgeWriteHalf)>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let tls_stream = self.acceptor.accept(stream).await?;
+        let (r, w) = tokio::io::split(tls_stream);
+        Ok((Box::new(r), Box::new(w)))
+    }
+}
+
+impl ClientTlsConfig {
+    pub fn from_pem_file(trusted_certificates_path: &Path, server_name: &str) -> Result<Self> {
+        let certificates = load_pem_certificates(trusted_certificates_path)?;
+        Self::from_der(certificates, server_name)
+    }
+    pub fn from_der(trusted_certificates: Vec<Vec<u8>>, server_name: &str) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for certificate in trusted_certificates {
+            roots.add(&Certificate(certificate))?;
+        }
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let server_name = ServerName::try_from(server_name)
+            .map_err(|e| anyhow!("Invalid TLS server name {}: {:?}", server_name, e))?;
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+    pub async fn connect<S>(&self, stream: S) -> Result<(BridgeReadHalf, BridgeWriteHalf)>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let tls_stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await?;
+        let (r, w) = tokio::io::split(tls_stream);
+        Ok((Box::new(r), Box::new(w)))
+    }
+}
+
+fn load_pem_certificates(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certificates = rustls_pemfile::certs(&mut reader)?;
+    if certificates.is_empty() {
+        return Err(anyhow!("No certificates found in {:?}", path));
+    }
+    Ok(certificates)
+}
+
+fn load_pem_private_key(path: &Path) -> Result<Vec<u8>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(key),
+            _ => {}
+        }
+    }
+    Err(anyhow!("No private key found in {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge_logic::TcpBridgeToServerMessage;
+    use crate::capabilities::Capabilities;
+    use crate::handshake::server_handshake;
+    use crate::input::HelicoidToServerMessage;
+    use crate::tcp_bridge_async::{ClientBridgeConfig, ClientTcpBridge, ServerSingleTcpBridge};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /* A self signed certificate (and its private key) in DER format, valid for localhost */
+    fn generate_self_signed() -> (Vec<u8>, Vec<u8>) {
+        let certificate =
+            rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        (
+            certificate.serialize_der().unwrap(),
+            certificate.serialize_private_key_der(),
+        )
+    }
+
+    async fn loopback_listener() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        (listener, address)
+    }
+
+    #[tokio::test]
+    async fn message_over_tls_loopback() {
+        let (certificate, private_key) = generate_self_signed();
+        let server_tls = ServerTlsConfig::from_der(vec![certificate.clone()], private_key).unwrap();
+        let client_config = ClientBridgeConfig {
+            tls: Some(Arc::new(
+                ClientTlsConfig::from_der(vec![certificate], "localhost").unwrap(),
+            )),
+            ..Default::default()
+        };
+        let (listener, address) = loopback_listener().await;
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut r, mut w) = server_tls.accept(stream).await.unwrap();
+            server_handshake(&mut r, &mut w, &Capabilities::default())
+                .await
+                .unwrap();
+            let (mut bridge, _tx, mut rx) = ServerSingleTcpBridge::handle_halves(r, w).unwrap();
+            tokio::spawn(async move { bridge.process_rxtx().await });
+            rx.recv().await.unwrap()
+        });
+
+        let (mut bridge, tx, _rx) = ClientTcpBridge::connect_with(&address, &client_config)
+            .await
+            .unwrap();
+        tokio::spawn(async move { bridge.process_rxtx().await });
+        tx.send(TcpBridgeToServerMessage {
+            message: HelicoidToServerMessage::CharReceived('h' as u32),
+        })
+        .await
+        .unwrap();
+
+        let received = server.await.unwrap();
+        assert_eq!(
+            received.message,
+            HelicoidToServerMessage::CharReceived('h' as u32)
+        );
+    }
+
+    /* The server and the client configured from pem files, as by the command line options */
+    #[tokio::test]
+    async fn client_connects_with_pem_files() {
+        let certificate =
+            rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let directory = std::env::temp_dir().join(format!("helicoid-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let certificate_path = directory.join("certificate.pem");
+        let private_key_path = directory.join("private_key.pem");
+        std::fs::write(&certificate_path, certificate.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&private_key_path, certificate.serialize_private_key_pem()).unwrap();
+        let server_tls =
+            ServerTlsConfig::from_pem_files(&certificate_path, &private_key_path).unwrap();
+        let client_config = ClientBridgeConfig {
+            tls: Some(Arc::new(
+                ClientTlsConfig::from_pem_file(&certificate_path, "localhost").unwrap(),
+            )),
+            ..Default::default()
+        };
+        let _ = std::fs::remove_dir_all(&directory);
+        let (listener, address) = loopback_listener().await;
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut r, mut w) = server_tls.accept(stream).await.unwrap();
+            server_handshake(&mut r, &mut w, &Capabilities::default())
+                .await
+                .unwrap();
+        });
+        ClientTcpBridge::connect_with(&address, &client_config)
+            .await
+            .unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificate_is_rejected() {
+        let (certificate, private_key) = generate_self_signed();
+        let (other_certificate, _) = generate_self_signed();
+        let server_tls = ServerTlsConfig::from_der(vec![certificate], private_key).unwrap();
+        let client_tls = ClientTlsConfig::from_der(vec![other_certificate], "localhost").unwrap();
+        let (listener, address) = loopback_listener().await;
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            server_tls.accept(stream).await.is_err()
+        });
+        let stream = TcpStream::connect(&address).await.unwrap();
+        assert!(client_tls.connect(stream).await.is_err());
+        assert!(server.await.unwrap());
+    }
+}
@@ -6,20 +6,26 @@ use rkyv::ser::{ScratchSpace, Serializer};
 use smallvec::SmallVec;
 
 use crate::{
-    bridge_logic::{SerializeWith, TcpBridgeToClientMessage},
+    bridge_logic::{BridgeSendMessage, SerializeWith, TcpBridgeToClientMessage},
+    capabilities::Capabilities,
     gfx::{
-        HelicoidToClientMessage, NewRenderBlock, RemoteSingleChange, RenderBlockId,
-        RenderBlockLocation, RenderBlockPath, RenderBlockRemoveInstruction,
+        BlockUpdates, FrameInfo, HelicoidToClientMessage, NewRenderBlock, RemoteSingleChange,
+        RemoteSingleChangeElement, RenderBlockId, RenderBlockLocation, RenderBlockPath,
+        RenderBlockRemoveInstruction,
     },
 };
 
 /* Buffer that contains and reorganizes buffers to be transferred to the client */
 /* TODO: Use rkyv types where possible */
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct TransferBuffer {
     removals: BTreeMap<RenderBlockPath, Vec<RenderBlockId>>,
     additions: BTreeMap<RenderBlockPath, Vec<NewRenderBlock>>,
     moves: BTreeMap<RenderBlockPath, Vec<RenderBlockLocation>>,
+    /* Echoed to the client with the frame, see FrameInfo */
+    input_timestamp: Option<u32>,
+    /* Other messages for the client, sent before the block updates in the order added */
+    messages: Vec<HelicoidToClientMessage>,
 }
 
 impl SerializeWith for TransferBuffer {
@@ -40,6 +46,17 @@ impl SerializeWith for Arc<TransferBuffer> {
         TransferBuffer::serialize(self, serializer, dummy_serializer)
     }
 }
+/* The buffers are sent to the client as TcpBridgeToClientMessages. Buffers waiting to be
+sent are merged, so a client that falls behind gets the current state instead of every
+intermediate frame. */
+impl BridgeSendMessage for Arc<TransferBuffer> {
+    type Wire = TcpBridgeToClientMessage;
+    fn coalesce(&mut self, later: Self) -> Option<Self> {
+        /* The server may still hold the buffer for reuse, it is copied then */
+        Arc::make_mut(self).merge(&later);
+        None
+    }
+}
 
 impl TransferBuffer {
     pub fn new_moves(path: RenderBlockPath, moves: SmallVec<[RenderBlockLocation; 4]>) -> Self {
@@ -71,6 +88,90 @@ impl TransferBuffer {
         for (_path, moves) in self.moves.iter_mut() {
             moves.clear();
         }
+        self.input_timestamp = None;
+        self.messages.clear();
+    }
+
+    /* Drops any added blocks the client can not display, along with their moves and the
+    changes to the blocks inside them. Returns the number of blocks dropped. */
+    pub fn retain_supported(&mut self, capabilities: &Capabilities) -> usize {
+        let mut unsupported = Vec::new();
+        for (path, additions) in self.additions.iter() {
+            for block in additions.iter() {
+                if !capabilities.supports_block_kind(block.contents.kind()) {
+                    unsupported.push((path.clone(), block.id));
+                }
+            }
+        }
+        for (path, id) in unsupported.iter() {
+            log::debug!(
+                "Dropped block {:?} unsupported by the client under {:?}",
+                id,
+                path
+            );
+            self.forget_block(path, *id);
+        }
+        unsupported.len()
+    }
+
+    pub fn set_input_timestamp(&mut self, input_timestamp: Option<u32>) {
+        self.input_timestamp = input_timestamp;
+    }
+    pub fn input_timestamp(&self) -> Option<u32> {
+        self.input_timestamp
+    }
+    pub fn add_message(&mut self, message: HelicoidToClientMessage) {
+        self.messages.push(message);
+    }
+    pub fn messages(&self) -> &[HelicoidToClientMessage] {
+        &self.messages
+    }
+
+    /* Merges a buffer made after this one into it, so the result has the same effect as
+    applying both buffers in order. Later moves and additions of a block override earlier
+    ones, and additions (and moves) of blocks that are later removed are dropped, along
+    with the changes inside them. Used to combine frames that are waiting to be sent. */
+    pub fn merge(&mut self, later: &TransferBuffer) {
+        for (path, removals) in later.removals.iter() {
+            for id in removals.iter() {
+                self.forget_block(path, *id);
+            }
+            let path_entry = self.removals.entry(path.clone()).or_default();
+            for id in removals.iter() {
+                if !path_entry.contains(id) {
+                    path_entry.push(*id);
+                }
+            }
+        }
+        for (path, additions) in later.additions.iter() {
+            let path_entry = self.additions.entry(path.clone()).or_default();
+            path_entry.retain(|earlier| !additions.iter().any(|block| block.id == earlier.id));
+            path_entry.extend_from_slice(additions);
+        }
+        for (path, moves) in later.moves.iter() {
+            let path_entry = self.moves.entry(path.clone()).or_default();
+            path_entry.retain(|earlier| !moves.iter().any(|location| location.id == earlier.id));
+            path_entry.extend_from_slice(moves);
+        }
+        if later.input_timestamp.is_some() {
+            self.input_timestamp = later.input_timestamp;
+        }
+        self.messages.extend_from_slice(&later.messages);
+    }
+    /* Drops the additions and moves of a block that is removed, and all changes to the
+    blocks inside it (they are removed with it). Earlier removals are kept, as they are
+    applied before the removal of the block anyway. */
+    fn forget_block(&mut self, path: &RenderBlockPath, id: RenderBlockId) {
+        if let Some(additions) = self.additions.get_mut(path) {
+            additions.retain(|block| block.id != id);
+        }
+        if let Some(moves) = self.moves.get_mut(path) {
+            moves.retain(|location| location.id != id);
+        }
+        let block_path = RenderBlockPath::child(path, id);
+        let inside = |inner: &RenderBlockPath| inner.path().starts_with(block_path.path());
+        self.additions.retain(|inner, _| !inside(inner));
+        self.moves.retain(|inner, _| !inside(inner));
     }
 
     pub fn moves(&self) -> &BTreeMap<RenderBlockPath, Vec<RenderBlockLocation>> {
@@ -122,91 +223,255 @@ impl TransferBuffer {
         path_entry.extend(new);
     }
 
-    fn serialize<R: Serializer + ScratchSpace, D: Serializer + ScratchSpace>(
-        &self,
-        serializer: &mut R,
-        dummy_serializer: &mut D,
-    ) -> Result<usize, ()> {
-        let mut size = 0usize;
-        log::trace!("Serialize transfer buffer start");
+    /* All the changes in the buffer in the order they have to be applied: removals first,
+    with the deepest paths handled first, then additions (parents before their children)
+    and finally moves. Paths without any changes are left out. */
+    pub fn changes(&self) -> Vec<RemoteSingleChange> {
+        let mut changes = Vec::new();
         /* Removals */
         for (path, removals) in self.removals.iter().rev() {
-            let removal = TcpBridgeToClientMessage {
-                message: HelicoidToClientMessage {
-                    updates: vec![RemoteSingleChange {
-                        parent: path.clone(),
-                        change: crate::gfx::RemoteSingleChangeElement::RemoveRenderBlocks(
-                            SmallVec::from_iter(removals.iter().map(|id| {
-                                RenderBlockRemoveInstruction {
-                                    offset: id.clone(),
-                                    mask: RenderBlockId(0),
-                                }
-                            })),
-                        ),
-                    }],
-                },
-            };
-            let before_pos = dummy_serializer.pos();
-            let _dummy_root_pos = dummy_serializer
-                .serialize_value(&removal)
-                .map_err(|_e| ())?;
-            serializer
-                .write(&u32::to_le_bytes(
-                    (dummy_serializer.pos() - before_pos) as u32,
-                ))
-                .map_err(|_e| ())?;
-            let _root_pos = serializer.serialize_value(&removal).map_err(|_e| ())?;
-            size += dummy_serializer.pos() - before_pos;
+            if removals.is_empty() {
+                continue;
+            }
+            changes.push(RemoteSingleChange {
+                parent: path.clone(),
+                change: RemoteSingleChangeElement::RemoveRenderBlocks(removal_instructions(
+                    removals,
+                )),
+            });
         }
         /* Additions */
         for (path, additions) in self.additions.iter() {
-            let addition = TcpBridgeToClientMessage {
-                message: HelicoidToClientMessage {
-                    updates: vec![RemoteSingleChange {
-                        parent: path.clone(),
-                        change: crate::gfx::RemoteSingleChangeElement::NewRenderBlocks(
-                            additions.iter().map(|b| b.clone()).collect(),
-                        ),
-                    }],
-                },
-            };
-            let before_pos = dummy_serializer.pos();
-            let _dummy_root_pos = dummy_serializer
-                .serialize_value(&addition)
-                .map_err(|_e| ())?;
-            serializer
-                .write(&u32::to_le_bytes(
-                    (dummy_serializer.pos() - before_pos) as u32,
-                ))
-                .map_err(|_e| ())?;
-            let _root_pos = serializer.serialize_value(&addition).map_err(|_e| ())?;
-            size += dummy_serializer.pos() - before_pos;
+            if additions.is_empty() {
+                continue;
+            }
+            changes.push(RemoteSingleChange {
+                parent: path.clone(),
+                change: RemoteSingleChangeElement::NewRenderBlocks(
+                    additions.iter().map(|b| b.clone()).collect(),
+                ),
+            });
         }
         /* Moves */
         for (path, moves) in self.moves.iter() {
-            let movement = TcpBridgeToClientMessage {
-                message: HelicoidToClientMessage {
-                    updates: vec![RemoteSingleChange {
-                        parent: path.clone(),
-                        change: crate::gfx::RemoteSingleChangeElement::MoveBlockLocations(
-                            moves.iter().map(|b| b.clone()).collect(),
-                        ),
-                    }],
-                },
-            };
+            if moves.is_empty() {
+                continue;
+            }
+            changes.push(RemoteSingleChange {
+                parent: path.clone(),
+                change: RemoteSingleChangeElement::MoveBlockLocations(
+                    moves.iter().map(|b| b.clone()).collect(),
+                ),
+            });
+        }
+        changes
+    }
+
+    /* The messages sent to the client for the contents of the buffer: the other messages
+    added, then a single message with all the changes (if there are any) followed by the
+    frame complete marker, so the client never presents a partially applied update. The marker
+    is only sent when there are changes or an input to acknowledge. Clients
+    running in the same process as the server use this instead of deserializing the
+    serialized buffer. */
+    pub fn to_messages(&self) -> Vec<TcpBridgeToClientMessage> {
+        let mut messages = Vec::with_capacity(self.messages.len() + 2);
+        messages.extend(
+            self.messages
+                .iter()
+                .map(|message| TcpBridgeToClientMessage {
+                    message: message.clone(),
+                }),
+        );
+        let updates = self.changes();
+        let has_updates = !updates.is_empty();
+        if has_updates {
+            messages.push(TcpBridgeToClientMessage {
+                message: HelicoidToClientMessage::BlockUpdates(BlockUpdates { updates }),
+            });
+        }
+        /* Other messages sent on their own (like a clipboard request) do not make a frame */
+        if has_updates || self.input_timestamp.is_some() {
+            messages.push(TcpBridgeToClientMessage {
+                message: HelicoidToClientMessage::FrameComplete(FrameInfo {
+                    input_timestamp: self.input_timestamp,
+                }),
+            });
+        }
+        messages
+    }
+
+    fn serialize<R: Serializer + ScratchSpace, D: Serializer + ScratchSpace>(
+        &self,
+        serializer: &mut R,
+        dummy_serializer: &mut D,
+    ) -> Result<usize, ()> {
+        let mut size = 0usize;
+        log::trace!("Serialize transfer buffer start");
+        for message in self.to_messages() {
             let before_pos = dummy_serializer.pos();
             let _dummy_root_pos = dummy_serializer
-                .serialize_value(&movement)
+                .serialize_value(&message)
                 .map_err(|_e| ())?;
             serializer
                 .write(&u32::to_le_bytes(
                     (dummy_serializer.pos() - before_pos) as u32,
                 ))
                 .map_err(|_e| ())?;
-            let _root_pos = serializer.serialize_value(&movement).map_err(|_e| ())?;
+            let _root_pos = serializer.serialize_value(&message).map_err(|_e| ())?;
             size += dummy_serializer.pos() - before_pos;
         }
         log::trace!("Serialize transfer buffer end, size:{}", size);
         Ok(size)
     }
 }
+
+/* The instructions removing the blocks with the given ids. Runs of consecutive ids are
+coalesced into masked instructions (see RenderBlockRemoveInstruction), which is what a big
+jump in a document that drops a screenful of paragraphs produces. A masked instruction is
+only used when every id it covers is removed. */
+fn removal_instructions(ids: &[RenderBlockId]) -> SmallVec<[RenderBlockRemoveInstruction; 4]> {
+    let mut ids = ids.iter().map(|id| id.0 as u32).collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids.dedup();
+    let mut instructions = SmallVec::new();
+    let mut index = 0;
+    while index < ids.len() {
+        let base = ids[index];
+        /* The largest aligned run of ids starting at base that are all removed, the ids are
+        sorted and unique so the run is complete if its last id is where it is expected */
+        let mut count = 1;
+        while base & (count * 2 - 1) == 0
+            && index + (count * 2) as usize <= ids.len()
+            && ids[index + (count * 2) as usize - 1] == base + count * 2 - 1
+        {
+            count *= 2;
+        }
+        instructions.push(RenderBlockRemoveInstruction {
+            offset: RenderBlockId(base as u16),
+            mask: RenderBlockId((count - 1) as u16),
+        });
+        index += count as usize;
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capabilities::BlockKind;
+    use crate::gfx::{
+        ImageBlock, ImagePixels, ImageScaling, ImageSource, MetaDrawBlock, PointF32,
+        RenderBlockDescription,
+    };
+    use crate::payload::ClipboardSelection;
+    use smallvec::smallvec;
+
+    fn new_block(id: u16, contents: RenderBlockDescription) -> NewRenderBlock {
+        NewRenderBlock {
+            id: RenderBlockId(id),
+            contents,
+            update: false,
+        }
+    }
+    fn location(id: u16) -> RenderBlockLocation {
+        RenderBlockLocation {
+            id: RenderBlockId(id),
+            location: PointF32::default(),
+            layer: 0,
+        }
+    }
+
+    #[test]
+    fn unsupported_blocks_are_dropped_with_their_changes() {
+        let meta_box = RenderBlockDescription::MetaBox(MetaDrawBlock {
+            extent: PointF32::new(10.0, 10.0),
+            buffered: false,
+            alpha: None,
+            sub_blocks: smallvec![],
+        });
+        let image = RenderBlockDescription::Image(ImageBlock {
+            source: ImageSource::Pixels(ImagePixels {
+                width: 1,
+                height: 1,
+                data: vec![0; 4],
+            }),
+            location: PointF32::default(),
+            extent: PointF32::new(10.0, 10.0),
+            scaling: ImageScaling::Stretch,
+        });
+        let top = RenderBlockPath::top();
+        let kept = RenderBlockPath::child(&top, RenderBlockId(1));
+        let dropped = RenderBlockPath::child(&top, RenderBlockId(2));
+        let mut buffer = TransferBuffer::new();
+        buffer.add_news(&top, &[new_block(1, meta_box.clone()), new_block(2, image)]);
+        buffer.add_moves(&top, &[location(1), location(2)]);
+        for path in [&kept, &dropped] {
+            buffer.add_news(path, &[new_block(3, meta_box.clone())]);
+            buffer.add_moves(path, &[location(3)]);
+        }
+        buffer.add_news(
+            &RenderBlockPath::child(&dropped, RenderBlockId(3)),
+            &[new_block(4, meta_box.clone())],
+        );
+
+        let capabilities = Capabilities::none().with_block_kind(BlockKind::MetaBox);
+        assert_eq!(buffer.retain_supported(&capabilities), 1);
+        assert_eq!(buffer.additions().get(&top).unwrap().len(), 1);
+        assert_eq!(buffer.moves().get(&top).unwrap(), &vec![location(1)]);
+        assert_eq!(buffer.additions().get(&kept).unwrap().len(), 1);
+        assert_eq!(buffer.moves().get(&kept).unwrap(), &vec![location(3)]);
+        /* Nothing is left under the dropped block */
+        assert!(buffer
+            .additions()
+            .keys()
+            .chain(buffer.moves().keys())
+            .all(|path| !path.path().starts_with(dropped.path())));
+    }
+
+    #[test]
+    fn frames_are_only_completed_with_changes_or_input() {
+        let mut buffer = TransferBuffer::new();
+        buffer.add_message(HelicoidToClientMessage::RequestClipboard(
+            ClipboardSelection::Clipboard,
+        ));
+        assert_eq!(buffer.to_messages().len(), 1);
+        buffer.set_input_timestamp(Some(1));
+        let messages = buffer.to_messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[1].message,
+            HelicoidToClientMessage::FrameComplete(FrameInfo {
+                input_timestamp: Some(1)
+            })
+        );
+    }
+
+    #[test]
+    fn consecutive_removals_are_masked() {
+        let ids = [0x13, 0x10, 0x11, 0x12, 0x14, 0x16, 0x17, 0x12, 0x20, 0xffff]
+            .into_iter()
+            .map(RenderBlockId)
+            .collect::<Vec<_>>();
+        let instructions = removal_instructions(&ids)
+            .into_iter()
+            .map(|instruction| (instruction.offset.0, instruction.mask.0))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            instructions,
+            vec![
+                (0x10, 0x3),
+                (0x14, 0x0),
+                (0x16, 0x1),
+                (0x20, 0x0),
+                (0xffff, 0x0)
+            ]
+        );
+        /* All the ids of a full range are removed by a single instruction */
+        let ids = (0..0x10000)
+            .map(|id| RenderBlockId(id as u16))
+            .collect::<Vec<_>>();
+        let instructions = removal_instructions(&ids);
+        assert_eq!(instructions.len(), 1);
+        assert_eq!(instructions[0].mask, RenderBlockId(0xffff));
+    }
+}
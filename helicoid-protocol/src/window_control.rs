@@ -0,0 +1,45 @@
+/* Window level effects the server asks the client for, like the title of the window and the
+shape of the mouse cursor. These are applied by the client to its window (e.g. through winit)
+rather than drawn as blocks. */
+
+use bytecheck::CheckBytes;
+use rkyv::{Archive, Deserialize, Serialize};
+
+#[derive(Debug, Hash, Eq, Clone, PartialEq, Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub enum WindowControl {
+    SetTitle(String),
+    /* The shape of the mouse cursor while it is over the window */
+    SetCursorShape(CursorShape),
+    /* Visual bell, the client briefly flashes its window */
+    Bell,
+    /* Asks the window system to get the attention of the user (e.g. by highlighting the
+    task bar entry) when the window is not focused, None cancels an earlier request */
+    RequestAttention(Option<AttentionLevel>),
+}
+
+#[derive(Debug, Hash, Eq, Clone, Copy, PartialEq, Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub enum CursorShape {
+    Default,
+    /* Over text that can be selected or edited */
+    Text,
+    /* Over something that can be clicked, like a link */
+    Pointer,
+    Crosshair,
+    Move,
+    NotAllowed,
+    Wait,
+    Progress,
+    ColumnResize,
+    RowResize,
+}
+
+#[derive(Debug, Hash, Eq, Clone, Copy, PartialEq, Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub enum AttentionLevel {
+    /* Something happened the user may want to know about */
+    Informational,
+    /* Something the user has to act on */
+    Critical,
+}
@@ -0,0 +1,198 @@
+/* Replays a recorded session (see recording.rs in helicoid-protocol) without the other side
+present. Replaying into a client listens for a client to connect and sends it the frames the
+server sent, to reproduce rendering bugs. Replaying into a server connects to it and sends
+the messages the client sent, to reproduce editor bugs. The frames are sent with the same
+timing as when they were recorded, unless the speed is changed. */
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, ValueEnum};
+use helicoid_protocol::{
+    auth::AuthKey,
+    bridge_logic::{KeepaliveCarrier, TcpBridgeToServerMessage},
+    capabilities::Capabilities,
+    handshake::server_handshake,
+    recording::{RecordDirection, RecordingReader},
+    tcp_bridge_async::{
+        BridgeAddress, BridgeReadHalf, BridgeWriteHalf, ClientBridgeConfig, ClientTcpBridge,
+    },
+};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::time::Instant;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayTarget {
+    /// Wait for a client to connect and send it what the server sent
+    Client,
+    /// Connect to a server and send it what the client sent
+    Server,
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct CommandLineArguments {
+    /// The recording to replay
+    pub recording: PathBuf,
+    /// Which side of the connection to replay the recording into
+    #[arg(long, value_enum)]
+    pub into: ReplayTarget,
+    /// Address to listen on (when replaying into a client) or connect to (when replaying into a server), either host:port or unix:/path/to/socket
+    #[arg(short, long, default_value = "127.0.0.1:15566")]
+    pub address: String,
+    /// Replay speed relative to the recording, 0 sends everything as fast as possible
+    #[arg(long, default_value_t = 1.0)]
+    pub speed: f32,
+    /// Token to authenticate to the server with
+    #[arg(long, conflicts_with = "auth_key_file")]
+    pub auth_token: Option<String>,
+    /// File containing the key to authenticate to the server with
+    #[arg(long)]
+    pub auth_key_file: Option<PathBuf>,
+}
+
+/* Delays the frames so they are sent with the (scaled) spacing they were recorded with,
+counted from the first frame replayed */
+struct Pacer {
+    speed: f32,
+    start: Option<(Instant, Duration)>,
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let args = CommandLineArguments::parse();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_time()
+        .enable_io()
+        .build()?;
+    runtime.block_on(async move {
+        match args.into {
+            ReplayTarget::Client => replay_into_client(&args).await,
+            ReplayTarget::Server => replay_into_server(&args).await,
+        }
+    })
+}
+
+impl Pacer {
+    fn new(speed: f32) -> Self {
+        Self { speed, start: None }
+    }
+    async fn wait_for(&mut self, timestamp: Duration) {
+        if self.speed <= 0.0 {
+            return;
+        }
+        let (start, first_timestamp) = *self.start.get_or_insert((Instant::now(), timestamp));
+        let offset = timestamp
+            .saturating_sub(first_timestamp)
+            .div_f32(self.speed);
+        tokio::time::sleep_until(start + offset).await;
+    }
+}
+
+async fn replay_into_client(args: &CommandLineArguments) -> Result<()> {
+    let recording = RecordingReader::open(&args.recording)?;
+    let (mut reader, mut writer) = accept_client(&args.address).await?;
+    let (client_hello, _) =
+        server_handshake(&mut reader, &mut writer, &Capabilities::default()).await?;
+    log::info!("Client connected: {:?}", client_hello);
+    /* The messages from the client are not used, but have to be read so it is not blocked */
+    let drain = tokio::spawn(async move {
+        let _ = tokio::io::copy(&mut reader, &mut tokio::io::sink()).await;
+    });
+    let mut pacer = Pacer::new(args.speed);
+    let mut replayed = 0;
+    for frame in recording {
+        let frame = frame?;
+        if frame.direction != RecordDirection::ToClient {
+            continue;
+        }
+        pacer.wait_for(frame.timestamp).await;
+        /* Frames are sent uncompressed, which the client accepts regardless of what was
+        negotiated */
+        writer
+            .write_all(&(frame.contents.len() as u32).to_le_bytes())
+            .await?;
+        writer.write_all(&frame.contents).await?;
+        writer.flush().await?;
+        replayed += 1;
+    }
+    log::info!(
+        "Replayed {} frames, waiting for the client to disconnect",
+        replayed
+    );
+    drain.await?;
+    Ok(())
+}
+
+async fn replay_into_server(args: &CommandLineArguments) -> Result<()> {
+    let recording = RecordingReader::open(&args.recording)?;
+    let mut config = ClientBridgeConfig::default();
+    if let Some(key_file) = &args.auth_key_file {
+        config.auth = Some(AuthKey::from_file(key_file)?);
+    } else if let Some(token) = &args.auth_token {
+        config.auth = Some(AuthKey::from_token(token)?);
+    }
+    let (mut bridge, sender, mut receiver) =
+        ClientTcpBridge::connect_with(&args.address, &config).await?;
+    tokio::spawn(async move {
+        if let Err(e) = bridge.process_rxtx().await {
+            log::warn!("Connection to the server closed: {}", e);
+        }
+    });
+    /* The updates from the server are not used, but have to be received so the server is
+    not blocked */
+    tokio::spawn(async move { while receiver.recv().await.is_some() {} });
+    let mut pacer = Pacer::new(args.speed);
+    let mut replayed = 0;
+    for frame in recording {
+        let frame = frame?;
+        if frame.direction != RecordDirection::ToServer {
+            continue;
+        }
+        let message = frame.decode::<TcpBridgeToServerMessage>()?;
+        /* The bridge pings the server by itself, the recorded pings would only confuse it */
+        if message.keepalive().is_some() {
+            continue;
+        }
+        pacer.wait_for(frame.timestamp).await;
+        sender
+            .send(message)
+            .await
+            .map_err(|_| anyhow!("The server closed the connection during the replay"))?;
+        replayed += 1;
+    }
+    /* Keep the connection (and with it the state in the server) until told to quit, so the
+    result can be inspected */
+    log::info!(
+        "Replayed {} messages, press Ctrl-C to disconnect from the server",
+        replayed
+    );
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+async fn accept_client(address: &str) -> Result<(BridgeReadHalf, BridgeWriteHalf)> {
+    match BridgeAddress::parse(address)? {
+        BridgeAddress::Tcp(tcp_address) => {
+            let listener = TcpListener::bind(&tcp_address).await?;
+            log::info!("Waiting for a client on {}", tcp_address);
+            let (stream, peer_address) = listener.accept().await?;
+            log::debug!("Accepted connection from {}", peer_address);
+            let (r, w) = stream.into_split();
+            Ok((Box::new(r), Box::new(w)))
+        }
+        #[cfg(unix)]
+        BridgeAddress::Unix(path) => {
+            let listener = UnixListener::bind(&path)?;
+            log::info!("Waiting for a client on {:?}", path);
+            let (stream, _) = listener.accept().await?;
+            let _ = std::fs::remove_file(&path);
+            let (r, w) = stream.into_split();
+            Ok((Box::new(r), Box::new(w)))
+        }
+    }
+}
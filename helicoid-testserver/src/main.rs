@@ -14,7 +14,7 @@ mod testserver;
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct CommandLineArguments {
-    /// Listening address of editor server, for connecting to an existing server
+    /// Listening address of editor server, either host:port or unix:/path/to/socket
     #[arg(short, long, default_value = "127.0.0.1:15566")]
     pub server_address: String,
     /*    /// Number of times to greet
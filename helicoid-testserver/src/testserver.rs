@@ -2,20 +2,25 @@ use anyhow::Result;
 use async_trait::async_trait;
 use helicoid_protocol::{
     bridge_logic::TcpBridgeToServerMessage,
-    caching_shaper::CachingShaper,
+    caching_shaper::{base_asset_path, CachingShaper},
     gfx::{
-        FontPaint, MetaDrawBlock, NewRenderBlock, PathVerb, PointF32, RenderBlockDescription,
-        RenderBlockId, RenderBlockLocation, RenderBlockPath, SimpleDrawBlock, SimpleDrawElement,
-        SimpleDrawPath, SimpleDrawPolygon, SimplePaint, SimpleRoundRect, SimpleSvg,
+        FontPaint, ImageBlock, ImagePixels, ImageScaling, ImageSource, MetaDrawBlock,
+        NewRenderBlock, PathVerb, PointF32, RenderBlockDescription, RenderBlockId,
+        RenderBlockLocation, RenderBlockPath, SimpleDrawBlock, SimpleDrawElement, SimpleDrawPath,
+        SimpleDrawPolygon, SimplePaint, SimpleRoundRect, SimpleSvg,
     },
     input::{HelicoidToServerMessage, ViewportInfo, VirtualKeycode},
-    tcp_bridge_async::{TcpBridgeServer, TcpBridgeServerConnectionState},
+    payload::Payload,
+    resources::{resource_name, ResourceKind},
+    tcp_bridge_async::{
+        ConnectionInfo, PeerAddress, TcpBridgeServer, TcpBridgeServerConnectionState,
+    },
     text::{FontEdging, FontHinting, ShapableString},
     transferbuffer::TransferBuffer,
 };
 use ordered_float::OrderedFloat;
 use smallvec::smallvec;
-use std::{net::SocketAddr, sync::Arc};
+use std::sync::Arc;
 use tokio::sync::{
     broadcast::{self, Receiver as BReceiver, Sender as BSender},
     mpsc::{Receiver, Sender},
@@ -37,12 +42,15 @@ struct ServerStateData {
 
 struct ServerState {
     _pending_message: Option<TcpBridgeToServerMessage>,
-    _peer_address: SocketAddr,
+    _peer_address: PeerAddress,
     channel_tx: Sender<Arc<TransferBuffer>>,
     channel_rx: Receiver<TcpBridgeToServerMessage>,
+    payload_tx: Option<Sender<Payload>>,
     close_rx: BReceiver<()>,
     editor_update_rx: BReceiver<()>,
     state_data: ServerStateData,
+    /* Timestamp of the newest input from the client, echoed with the text updates */
+    latest_input_timestamp: Option<u32>,
 
     viewport_size: Option<ViewportInfo>,
 }
@@ -98,7 +106,13 @@ impl ServerState {
     //    async fn process_event(&mut self, e: &mut DummyEditor) {}
     async fn handle_client_message(&mut self, message: TcpBridgeToServerMessage) -> Result<()> {
         log::trace!("Handle client message: {:?}", message.message);
+        if let Some(timestamp) = message.message.input_timestamp() {
+            self.latest_input_timestamp = Some(timestamp);
+        }
         match message.message {
+            HelicoidToServerMessage::Hello(_) => {
+                log::warn!("Ignoring hello received after connection setup");
+            }
             HelicoidToServerMessage::ViewportSizeUpdate(viewportinfo) => {
                 self.viewport_size = Some(viewportinfo);
                 self.sync_screen().await?;
@@ -109,7 +123,11 @@ impl ServerState {
             HelicoidToServerMessage::CursorMoved(_cursormovedevent) => {}
             HelicoidToServerMessage::CharReceived(_ch) => {}
             HelicoidToServerMessage::Ime(_imeevent) => {}
-            HelicoidToServerMessage::ClipboardEvent(_clipboard) => {}
+            HelicoidToServerMessage::PayloadChunk(_chunk) => {}
+            /* Answered by the bridge, never passed on */
+            HelicoidToServerMessage::Keepalive(_) => {}
+            /* The test server does not announce its fonts */
+            HelicoidToServerMessage::RequestFont(_hash) => {}
             HelicoidToServerMessage::KeyInputEvent(event) => {
                 if event.pressed {
                     let text = match event.virtual_keycode {
@@ -187,12 +205,12 @@ impl ServerState {
             contents: RenderBlockDescription::ShapedTextBlock(shaped),
             update: true,
         };
-        self.channel_tx
-            .send(Arc::new(TransferBuffer::new_additions(
-                RenderBlockPath::new(smallvec![RenderBlockId::normal(1).unwrap()]),
-                smallvec![new_shaped_string_block],
-            )))
-            .await?;
+        let mut transfer_buffer = TransferBuffer::new_additions(
+            RenderBlockPath::new(smallvec![RenderBlockId::normal(1).unwrap()]),
+            smallvec![new_shaped_string_block],
+        );
+        transfer_buffer.set_input_timestamp(self.latest_input_timestamp);
+        self.channel_tx.send(Arc::new(transfer_buffer)).await?;
 
         log::trace!("Prepared message3, now sending it to the tcp bridge");
         Ok(())
@@ -210,6 +228,27 @@ impl ServerState {
         (after unlocking the editor)*/
         Ok(())
     }
+    /* Pushes the image drawn by the svg element of the test screen to the client */
+    async fn push_test_resource(&mut self) -> Result<()> {
+        let Some(payload_tx) = self.payload_tx.as_ref() else {
+            return Ok(());
+        };
+        let data = match std::fs::read(base_asset_path().join("test.svg")) {
+            Ok(data) => data,
+            Err(e) => {
+                log::warn!("Could not load the test resource: {}", e);
+                return Ok(());
+            }
+        };
+        payload_tx
+            .send(Payload::resource(
+                ResourceKind::Svg,
+                resource_name("test")?,
+                data,
+            ))
+            .await?;
+        Ok(())
+    }
     async fn send_simple_test_shaped_string(&mut self) -> Result<()> {
         //        let editor = self.state_data.editor.lock();
         let mut shaper = CachingShaper::new(1.0f32, 12.0f32);
@@ -359,16 +398,39 @@ impl ServerState {
             layer: 0,
             location: PointF32::new(10.0, 10.0),
         };
+        /* A gradient sent as pixels, to test the image blocks */
+        let gradient = ImagePixels {
+            width: 64,
+            height: 64,
+            data: (0..64u8)
+                .flat_map(|y| (0..64u8).flat_map(move |x| [x * 4, y * 4, 0xAA, 0xFF]))
+                .collect(),
+        };
+        let image_block = NewRenderBlock {
+            id: RenderBlockId::normal(1003).unwrap(),
+            contents: RenderBlockDescription::Image(ImageBlock {
+                source: ImageSource::Pixels(gradient),
+                location: PointF32::new(0.0, 0.0),
+                extent: PointF32::new(128.0, 96.0),
+                scaling: ImageScaling::Fit,
+            }),
+            update: false,
+        };
+        let image_location = RenderBlockLocation {
+            id: RenderBlockId::normal(1003).unwrap(),
+            layer: 1,
+            location: PointF32::new(800.0, 60.0),
+        };
 
         log::trace!("Prepared message2, now sending it to the tcp bridge");
         let mut buf = TransferBuffer::new();
         buf.add_news(
             &RenderBlockPath::new(smallvec![RenderBlockId::normal(1).unwrap()]),
-            &[new_shaped_string_block, fill_block],
+            &[new_shaped_string_block, fill_block, image_block],
         );
         buf.add_moves(
             &RenderBlockPath::new(smallvec![RenderBlockId::normal(1).unwrap()]),
-            &[shaped_string_location, fill_location],
+            &[shaped_string_location, fill_location, image_location],
         );
         self.channel_tx.send(Arc::new(buf)).await?;
         let mut overlay_paint = SimplePaint::new(Some(0x03110022), Some(0x88009255), Some(0.5));
@@ -419,7 +481,7 @@ impl ServerState {
 impl TcpBridgeServerConnectionState for ServerState {
     type StateData = ServerStateData;
     async fn new_state(
-        peer_address: SocketAddr,
+        connection_info: ConnectionInfo,
         channel_tx: Sender<Arc<TransferBuffer>>,
         channel_rx: Receiver<TcpBridgeToServerMessage>,
         close_rx: BReceiver<()>,
@@ -431,17 +493,19 @@ impl TcpBridgeServerConnectionState for ServerState {
         };
         Self {
             _pending_message: None,
-            _peer_address: peer_address,
+            _peer_address: connection_info.peer_address,
             channel_tx,
             channel_rx,
+            payload_tx: connection_info.payload_tx,
             close_rx,
             state_data,
             editor_update_rx,
+            latest_input_timestamp: None,
             viewport_size: None,
         }
     }
     async fn initialize(&mut self) -> Result<()> {
-        Ok(())
+        self.push_test_resource().await
     }
     async fn event_loop(&mut self) -> Result<()> {
         loop {
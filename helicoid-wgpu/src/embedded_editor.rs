@@ -0,0 +1,400 @@
+/* Runs the helix editor server in this process, connected to the window without any network
+(see HelicoidServer::connect_in_process). The transfer buffers from the editor are applied to a
+block manager when a frame is complete, like a remote client applies the messages it receives,
+and the blocks are drawn together with the rest of the window. */
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use hashbrown::HashMap;
+use helicoid_gpurender::{
+    font::{
+        fontcache::{FontCache, FontId, RenderSpec, RenderTargetId},
+        swash_font::SwashFont,
+    },
+    renderer::{
+        block_renderer::{
+            WGpuClientRenderBlock, WGpuClientRenderTarget, WGpuDrawList, WGpuGfxManager,
+        },
+        fontconverter::FontConverter,
+        image_cache::ImageCache,
+    },
+};
+use helicoid_helixserver::server::HelicoidServer;
+use helicoid_protocol::{
+    block_manager::Manager,
+    bridge_logic::TcpBridgeToServerMessage,
+    capabilities::{Capabilities, ClientHello, ClientRole},
+    gfx::{BlockUpdates, HelicoidToClientMessage, PointF32, RenderBlockId},
+    input::{
+        ComplexKeyEvent, CursorMovedEvent, HelicoidToServerMessage, MouseButtonStateChangeEvent,
+        ViewportInfo, VirtualKeycode,
+    },
+    payload::{PayloadAssembler, PayloadKind},
+    tcp_bridge_async::ServerBridgeConfig,
+    transferbuffer::TransferBuffer,
+};
+use tokio::{
+    runtime::Runtime,
+    sync::mpsc::{error::TryRecvError, Receiver, Sender},
+};
+use wgpu::RenderPass;
+use winit::{
+    dpi::{PhysicalPosition, PhysicalSize},
+    event::{ElementState, Event, MouseButton, WindowEvent},
+    keyboard::KeyCode,
+    window::Window,
+};
+
+use crate::window_control::WindowControls;
+
+/* The cursor positions are only used for the shape of the mouse cursor, so they are throttled
+to not fill the channel to the editor (as in helicoid-client) */
+const CURSOR_MOVED_INTERVAL: Duration = Duration::from_millis(20);
+/* The font renderer used for the window */
+const RENDER_TARGET_ID: RenderTargetId = 0;
+
+pub struct EmbeddedEditor {
+    /* Runs the editor and the connection state, which live as long as the runtime */
+    _runtime: Runtime,
+    _server: HelicoidServer,
+    to_server: Sender<TcpBridgeToServerMessage>,
+    from_server: Receiver<Arc<TransferBuffer>>,
+    blocks: Manager<WGpuClientRenderBlock>,
+    gfx_manager: WGpuGfxManager,
+    /* Updates received for the frame currently being transferred, they are applied when
+    the frame is complete so a partially updated tree is never drawn */
+    pending_frame: Vec<BlockUpdates>,
+    connected: bool,
+    font_caches: HashMap<FontId, FontCache<SwashFont>>,
+    font_convertor: FontConverter,
+    /* The images pushed by the editor are kept until it evicts them */
+    image_cache: ImageCache,
+    payloads: PayloadAssembler,
+    draw_list: WGpuDrawList,
+    /* Reference for the timestamps of the input events */
+    time_ref_base: Instant,
+    pending_cursor_position: Option<PhysicalPosition<f64>>,
+    cursor_moved_sent: Option<Instant>,
+}
+
+impl EmbeddedEditor {
+    pub fn start() -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            /* The font shaping may need some more stack, as in the server binary */
+            .thread_stack_size(16 * 1024 * 1024)
+            .build()?;
+        let (server, to_server, from_server) = runtime.block_on(async {
+            /* Nothing is listened on, the editor is only reached through this connection */
+            let mut server =
+                HelicoidServer::new(String::new(), ServerBridgeConfig::default()).await?;
+            let (server_hello, to_server, from_server) = server
+                .connect_in_process(ClientHello {
+                    capabilities: Capabilities::default(),
+                    resume: None,
+                    role: ClientRole::Editor,
+                })
+                .await;
+            log::info!(
+                "Connected to the embedded editor: {:?}",
+                server_hello.capabilities
+            );
+            Ok::<_, anyhow::Error>((server, to_server, from_server))
+        })?;
+        Ok(Self {
+            _runtime: runtime,
+            _server: server,
+            to_server,
+            from_server,
+            blocks: Manager::new(),
+            gfx_manager: WGpuGfxManager::new(),
+            pending_frame: Vec::new(),
+            connected: true,
+            font_caches: HashMap::new(),
+            font_convertor: FontConverter {
+                temp_spec: RenderSpec::default(),
+            },
+            image_cache: ImageCache::new(),
+            payloads: PayloadAssembler::default(),
+            draw_list: WGpuDrawList::new(),
+            time_ref_base: Instant::now(),
+            pending_cursor_position: None,
+            cursor_moved_sent: None,
+        })
+    }
+    /* Tells the editor the size of the window, nothing is drawn by the editor before it
+    knows the size */
+    pub fn set_viewport(&self, size: PhysicalSize<u32>, scale_factor: f64) {
+        self.send(HelicoidToServerMessage::ViewportSizeUpdate(ViewportInfo {
+            physical_size: (size.width, size.height),
+            scale_factor: (scale_factor as f32).into(),
+            container_physical_size: None,
+            container_scale_factor: None,
+        }));
+    }
+    fn send(&self, message: HelicoidToServerMessage) {
+        if let Err(e) = self
+            .to_server
+            .try_send(TcpBridgeToServerMessage { message })
+        {
+            log::warn!("Could not send to the embedded editor: {}", e);
+        }
+    }
+    /* Adds a font for the text of the editor, the font must have a renderer set up for the
+    window. Text in fonts that have not been added are drawn with font 0, nothing is drawn
+    before font 0 is added. */
+    pub fn add_font_cache(&mut self, font_id: FontId, font_cache: FontCache<SwashFont>) {
+        self.font_caches.insert(font_id, font_cache);
+    }
+    /* Images are not drawn before the renderer is set up, the multisample state must match
+    the one of the font caches */
+    pub fn setup_image_renderer(
+        &mut self,
+        device: &wgpu::Device,
+        multisample: wgpu::MultisampleState,
+    ) {
+        self.image_cache.setup_renderer(device, multisample);
+    }
+    fn now_timestamp(&self) -> u32 {
+        (Instant::now()
+            .saturating_duration_since(self.time_ref_base)
+            .as_millis()
+            % (u32::MAX as u128)) as u32
+    }
+    /* Forwards the keyboard and mouse input of the window to the editor */
+    pub fn forward_input(&mut self, event: &Event<()>) {
+        match event {
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        event,
+                        is_synthetic,
+                        ..
+                    },
+                ..
+            } => {
+                /* The editor acts on the virtual key code, the native key code is not known */
+                self.send(HelicoidToServerMessage::KeyInputEvent(ComplexKeyEvent {
+                    key_code: 0,
+                    timestamp: self.now_timestamp(),
+                    virtual_keycode: convert_key_code(event.physical_key),
+                    pressed: event.state == ElementState::Pressed,
+                    synthetic: *is_synthetic,
+                }));
+            }
+            Event::WindowEvent {
+                event: WindowEvent::MouseInput { state, button, .. },
+                ..
+            } => {
+                let button = match button {
+                    MouseButton::Left => 0,
+                    MouseButton::Right => 1,
+                    MouseButton::Middle => 2,
+                    MouseButton::Other(button) => *button,
+                    /* The back and forward buttons are not used by the editor */
+                    _ => return,
+                };
+                self.send(HelicoidToServerMessage::MouseButtonStateChange(
+                    MouseButtonStateChangeEvent {
+                        pressed: *state == ElementState::Pressed,
+                        button,
+                        timestamp: self.now_timestamp(),
+                    },
+                ));
+            }
+            Event::WindowEvent {
+                event: WindowEvent::CursorMoved { position, .. },
+                ..
+            } => {
+                self.pending_cursor_position = Some(*position);
+            }
+            /* The event loop polls, so the last position is sent also when the mouse stops */
+            Event::MainEventsCleared => {
+                let interval_passed = self
+                    .cursor_moved_sent
+                    .map_or(true, |sent| sent.elapsed() >= CURSOR_MOVED_INTERVAL);
+                if !interval_passed {
+                    return;
+                }
+                if let Some(position) = self.pending_cursor_position.take() {
+                    self.send(HelicoidToServerMessage::CursorMoved(CursorMovedEvent {
+                        physical_position_x: (position.x as f32).into(),
+                        physical_position_y: (position.y as f32).into(),
+                        area_id: 0,
+                        timestamp: self.now_timestamp(),
+                    }));
+                    self.cursor_moved_sent = Some(Instant::now());
+                }
+            }
+            _ => {}
+        }
+    }
+    /* Applies the transfer buffers received from the editor, and the window controls sent
+    with them. Returns true when a frame has been completed, and the window should be redrawn. */
+    pub fn process_received(
+        &mut self,
+        window: &Window,
+        window_controls: &mut WindowControls,
+    ) -> bool {
+        let client_id = RenderBlockId::normal(0).unwrap();
+        let mut changed = false;
+        while self.connected && !changed {
+            let buffer = match self.from_server.try_recv() {
+                Ok(buffer) => buffer,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    log::warn!("The embedded editor closed the connection");
+                    self.connected = false;
+                    self.pending_frame.clear();
+                    break;
+                }
+            };
+            for message in buffer.to_messages() {
+                match message.message {
+                    HelicoidToClientMessage::BlockUpdates(block_updates) => {
+                        self.pending_frame.push(block_updates);
+                    }
+                    HelicoidToClientMessage::FrameComplete(_) => {
+                        for block_updates in self.pending_frame.drain(..) {
+                            self.blocks.handle_block_update(
+                                client_id,
+                                &block_updates.updates,
+                                &mut self.gfx_manager,
+                            );
+                        }
+                        /* The completed frame is drawn before the next buffer is applied */
+                        changed = true;
+                    }
+                    HelicoidToClientMessage::WindowControl(control) => {
+                        window_controls.apply(window, &control)
+                    }
+                    HelicoidToClientMessage::PayloadChunk(chunk) => {
+                        match self.payloads.add_chunk(chunk) {
+                            Ok(Some(payload)) => match payload.kind {
+                                PayloadKind::Resource(kind, name) => {
+                                    self.image_cache.push_resource(name, kind, payload.data)
+                                }
+                                kind => log::trace!(
+                                    "Ignoring payload from the embedded editor: {:?}",
+                                    kind
+                                ),
+                            },
+                            Ok(None) => {}
+                            Err(e) => {
+                                log::warn!("Dropping payload from the embedded editor: {}", e)
+                            }
+                        }
+                    }
+                    HelicoidToClientMessage::EvictResource(name) => {
+                        self.image_cache.evict_resource(&name)
+                    }
+                    message => {
+                        log::trace!("Ignoring message from the embedded editor: {:?}", message)
+                    }
+                }
+            }
+        }
+        changed
+    }
+    /* Renders the blocks of the editor to the draw list, and transfers it to the device.
+    Must be called before the render pass the editor is drawn in is started. */
+    pub fn prepare_draw(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        resolution: (f32, f32),
+    ) {
+        self.draw_list.clear();
+        if !self.font_caches.contains_key(&0) {
+            return;
+        }
+        let mut target = WGpuClientRenderTarget {
+            offset: PointF32::default(),
+            target_device: device,
+            target_id: RENDER_TARGET_ID,
+            font_caches: &mut self.font_caches,
+            font_convertor: &mut self.font_convertor,
+            image_cache: &mut self.image_cache,
+            draw_list: &mut self.draw_list,
+        };
+        self.blocks
+            .process_blocks_for_client(RenderBlockId::normal(0).unwrap(), &mut target);
+        self.draw_list.upload(
+            device,
+            queue,
+            RENDER_TARGET_ID,
+            resolution,
+            &mut self.font_caches,
+            &mut self.image_cache,
+        );
+        self.image_cache.end_frame();
+    }
+    pub fn draw<'p>(&'p mut self, pass: &mut RenderPass<'p>) {
+        self.draw_list.draw(
+            pass,
+            RENDER_TARGET_ID,
+            &mut self.font_caches,
+            &self.image_cache,
+        );
+    }
+}
+
+/* Only the keys the editor acts on are converted */
+fn convert_key_code(key: KeyCode) -> VirtualKeycode {
+    match key {
+        KeyCode::Digit0 => VirtualKeycode::Key0,
+        KeyCode::Digit1 => VirtualKeycode::Key1,
+        KeyCode::Digit2 => VirtualKeycode::Key2,
+        KeyCode::Digit3 => VirtualKeycode::Key3,
+        KeyCode::Digit4 => VirtualKeycode::Key4,
+        KeyCode::Digit5 => VirtualKeycode::Key5,
+        KeyCode::Digit6 => VirtualKeycode::Key6,
+        KeyCode::Digit7 => VirtualKeycode::Key7,
+        KeyCode::Digit8 => VirtualKeycode::Key8,
+        KeyCode::Digit9 => VirtualKeycode::Key9,
+        KeyCode::KeyA => VirtualKeycode::A,
+        KeyCode::KeyB => VirtualKeycode::B,
+        KeyCode::KeyC => VirtualKeycode::C,
+        KeyCode::KeyD => VirtualKeycode::D,
+        KeyCode::KeyE => VirtualKeycode::E,
+        KeyCode::KeyF => VirtualKeycode::F,
+        KeyCode::KeyG => VirtualKeycode::G,
+        KeyCode::KeyH => VirtualKeycode::H,
+        KeyCode::KeyI => VirtualKeycode::I,
+        KeyCode::KeyJ => VirtualKeycode::J,
+        KeyCode::KeyK => VirtualKeycode::K,
+        KeyCode::KeyL => VirtualKeycode::L,
+        KeyCode::KeyM => VirtualKeycode::M,
+        KeyCode::KeyN => VirtualKeycode::N,
+        KeyCode::KeyO => VirtualKeycode::O,
+        KeyCode::KeyP => VirtualKeycode::P,
+        KeyCode::KeyQ => VirtualKeycode::Q,
+        KeyCode::KeyR => VirtualKeycode::R,
+        KeyCode::KeyS => VirtualKeycode::S,
+        KeyCode::KeyT => VirtualKeycode::T,
+        KeyCode::KeyU => VirtualKeycode::U,
+        KeyCode::KeyV => VirtualKeycode::V,
+        KeyCode::KeyW => VirtualKeycode::W,
+        KeyCode::KeyX => VirtualKeycode::X,
+        KeyCode::KeyY => VirtualKeycode::Y,
+        KeyCode::KeyZ => VirtualKeycode::Z,
+        KeyCode::Space => VirtualKeycode::Space,
+        KeyCode::Enter => VirtualKeycode::Return,
+        KeyCode::Backspace => VirtualKeycode::Backspace,
+        KeyCode::Tab => VirtualKeycode::Tab,
+        KeyCode::Escape => VirtualKeycode::Escape,
+        KeyCode::Insert => VirtualKeycode::Insert,
+        KeyCode::Delete => VirtualKeycode::Delete,
+        KeyCode::Home => VirtualKeycode::Home,
+        KeyCode::End => VirtualKeycode::End,
+        KeyCode::PageUp => VirtualKeycode::PageUp,
+        KeyCode::PageDown => VirtualKeycode::PageDown,
+        KeyCode::ArrowLeft => VirtualKeycode::Left,
+        KeyCode::ArrowRight => VirtualKeycode::Right,
+        KeyCode::ArrowUp => VirtualKeycode::Up,
+        KeyCode::ArrowDown => VirtualKeycode::Down,
+        _ => VirtualKeycode::None,
+    }
+}
@@ -43,6 +43,13 @@ use swash::FontRef;
 
 //use log;
 
+#[cfg(feature = "embedded-editor")]
+mod embedded_editor;
+mod window_control;
+#[cfg(feature = "embedded-editor")]
+use embedded_editor::EmbeddedEditor;
+use window_control::WindowControls;
+
 const PRIM_BUFFER_LEN: usize = 256;
 
 #[repr(C)]
@@ -425,8 +432,20 @@ println!(\"Insert-err: {:?} {:?}\", &key, e);            ",
     };
 
     let event_loop = EventLoop::new();
-    let window_builder = WindowBuilder::new().with_inner_size(scene.window_size);
+    let window_builder = WindowBuilder::new()
+        .with_title("helicoid")
+        .with_inner_size(scene.window_size);
     let window = window_builder.build(&event_loop).unwrap();
+    let mut window_controls = WindowControls::default();
+    /* The demo is shown without the editor if it can not be started */
+    #[cfg(feature = "embedded-editor")]
+    let mut embedded_editor = match EmbeddedEditor::start() {
+        Ok(embedded_editor) => Some(embedded_editor),
+        Err(e) => {
+            log::error!("Could not start the embedded editor: {:?}", e);
+            None
+        }
+    };
 
     // create an instance
     let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
@@ -1013,6 +1032,17 @@ println!(\"Insert-err: {:?} {:?}\", &key, e);            ",
     );
     font_cache.renderer_setup_resources(&0, &device);
 
+    #[cfg(feature = "embedded-editor")]
+    if let Some(embedded_editor) = embedded_editor.as_mut() {
+        let mut editor_font_cache =
+            create_font_cache(&device, target_multisample_state, font_subpixel_color);
+        editor_font_cache.set_palette_entry(0, 0xFFFFFFFF);
+        editor_font_cache.update_palette(&queue);
+        editor_font_cache.renderer_setup_resources(&0, &device);
+        embedded_editor.add_font_cache(0, editor_font_cache);
+        embedded_editor.setup_image_renderer(&device, target_multisample_state);
+    }
+
     let mut depth_texture_view = None;
 
     let start = Instant::now();
@@ -1023,7 +1053,20 @@ println!(\"Insert-err: {:?} {:?}\", &key, e);            ",
     window.request_redraw();
 
     event_loop.run(move |event, _, control_flow| {
-        if !update_inputs(event, &window, control_flow, &mut scene) {
+        #[cfg(feature = "embedded-editor")]
+        if let Some(embedded_editor) = embedded_editor.as_mut() {
+            embedded_editor.forward_input(&event);
+            if embedded_editor.process_received(&window, &mut window_controls) {
+                scene.changed = true;
+            }
+        }
+        if !update_inputs(
+            event,
+            &window,
+            control_flow,
+            &mut scene,
+            &mut window_controls,
+        ) {
             // keep polling inputs.
             return;
         }
@@ -1035,6 +1078,10 @@ println!(\"Insert-err: {:?} {:?}\", &key, e);            ",
             surface_desc.width = physical.width;
             surface_desc.height = physical.height;
             surface.configure(&device, &surface_desc);
+            #[cfg(feature = "embedded-editor")]
+            if let Some(embedded_editor) = embedded_editor.as_ref() {
+                embedded_editor.set_viewport(physical, window.scale_factor());
+            }
 
             let depth_texture = device.create_texture(&wgpu::TextureDescriptor {
                 label: Some("Depth texture"),
@@ -1071,6 +1118,8 @@ println!(\"Insert-err: {:?} {:?}\", &key, e);            ",
 
         scene.render = false;
         scene.changed = false;
+        /* The visual bell flashes the background */
+        let bell = window_controls.bell_intensity(Instant::now()) as f64 * 0.3;
 
         let frame = match surface.get_current_texture() {
             Ok(texture) => texture,
@@ -1156,6 +1205,17 @@ println!(\"Insert-err: {:?} {:?}\", &key, e);            ",
         if let Some(text_render_run) = text_render_run.as_mut() {
             text_render_run.queue_write_buffer(&queue);
         }
+        #[cfg(feature = "embedded-editor")]
+        if let Some(embedded_editor) = embedded_editor.as_mut() {
+            embedded_editor.prepare_draw(
+                &device,
+                &queue,
+                (
+                    scene.window_size.width as f32,
+                    scene.window_size.height as f32,
+                ),
+            );
+        }
         {
             // A resolve target is only supported if the attachment actually uses anti-aliasing
             // So if sample_count == 1 then we must render directly to the surface's buffer
@@ -1164,9 +1224,9 @@ println!(\"Insert-err: {:?} {:?}\", &key, e);            ",
                     view: msaa_target,
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.0,
-                            g: 0.0,
-                            b: 0.0,
+                            r: bell,
+                            g: bell,
+                            b: bell,
                             a: 1.0,
                         }),
                         store: true,
@@ -1178,9 +1238,9 @@ println!(\"Insert-err: {:?} {:?}\", &key, e);            ",
                     view: &frame_view,
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.0,
-                            g: 0.0,
-                            b: 0.0,
+                            r: bell,
+                            g: bell,
+                            b: bell,
                             a: 1.0,
                         }),
                         //                        load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
@@ -1255,6 +1315,11 @@ println!(\"Insert-err: {:?} {:?}\", &key, e);            ",
                     0..1,
                 );
             }
+            /* The editor is drawn after the demo */
+            #[cfg(feature = "embedded-editor")]
+            if let Some(embedded_editor) = embedded_editor.as_mut() {
+                embedded_editor.draw(&mut pass);
+            }
         }
 
         queue.submit(Some(encoder.finish()));
@@ -1326,13 +1391,17 @@ fn update_inputs(
     window: &Window,
     control_flow: &mut ControlFlow,
     scene: &mut SceneParams,
+    window_controls: &mut WindowControls,
 ) -> bool {
     match event {
         Event::RedrawRequested(_) => {
             scene.render = true;
         }
         Event::RedrawEventsCleared => {
-            if scene.changed || scene.size_changed {
+            if scene.changed
+                || scene.size_changed
+                || window_controls.bell_intensity(Instant::now()) > 0.0
+            {
                 window.request_redraw();
             }
         }
@@ -0,0 +1,66 @@
+/* Applies the window controls from the server (see window_control.rs in helicoid-protocol)
+to the winit window */
+
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "embedded-editor")]
+use helicoid_protocol::window_control::{AttentionLevel, CursorShape, WindowControl};
+#[cfg(feature = "embedded-editor")]
+use winit::window::{CursorIcon, UserAttentionType, Window};
+
+/* How long the window is flashed for the visual bell */
+const BELL_DURATION: Duration = Duration::from_millis(150);
+
+#[derive(Debug, Default)]
+pub struct WindowControls {
+    /* When the visual bell was rung last */
+    bell_rung: Option<Instant>,
+}
+
+impl WindowControls {
+    /* The controls come from the embedded editor, there is no other server to receive them
+    from */
+    #[cfg(feature = "embedded-editor")]
+    pub fn apply(&mut self, window: &Window, control: &WindowControl) {
+        log::trace!("Applying window control: {:?}", control);
+        match control {
+            WindowControl::SetTitle(title) => window.set_title(title),
+            WindowControl::SetCursorShape(shape) => window.set_cursor_icon(cursor_icon(*shape)),
+            WindowControl::Bell => {
+                self.bell_rung = Some(Instant::now());
+                window.request_redraw();
+            }
+            WindowControl::RequestAttention(level) => {
+                window.request_user_attention(level.map(|level| match level {
+                    AttentionLevel::Informational => UserAttentionType::Informational,
+                    AttentionLevel::Critical => UserAttentionType::Critical,
+                }))
+            }
+        }
+    }
+    /* How strongly the window is flashed for the visual bell at the given time, from 1 when
+    it is rung fading to 0. The window has to be redrawn while it is above 0. */
+    pub fn bell_intensity(&self, now: Instant) -> f32 {
+        let Some(rung) = self.bell_rung else {
+            return 0.0;
+        };
+        let elapsed = now.saturating_duration_since(rung);
+        (1.0 - elapsed.as_secs_f32() / BELL_DURATION.as_secs_f32()).max(0.0)
+    }
+}
+
+#[cfg(feature = "embedded-editor")]
+fn cursor_icon(shape: CursorShape) -> CursorIcon {
+    match shape {
+        CursorShape::Default => CursorIcon::Default,
+        CursorShape::Text => CursorIcon::Text,
+        CursorShape::Pointer => CursorIcon::Pointer,
+        CursorShape::Crosshair => CursorIcon::Crosshair,
+        CursorShape::Move => CursorIcon::Move,
+        CursorShape::NotAllowed => CursorIcon::NotAllowed,
+        CursorShape::Wait => CursorIcon::Wait,
+        CursorShape::Progress => CursorIcon::Progress,
+        CursorShape::ColumnResize => CursorIcon::ColResize,
+        CursorShape::RowResize => CursorIcon::RowResize,
+    }
+}